@@ -0,0 +1,231 @@
+//! With the `validate-kernels` feature, the OpenCL program of a few representative configs is run
+//! through an offline compiler, so that syntax errors in the kernels fail the build of the crate
+//! instead of the first `GPU::new`. The compiler is `clang`, or the one named by
+//! `NSE_CL_COMPILER` if it accepts the same arguments.
+//!
+//! The versions of the field crates the kernels are generated from are read from `Cargo.lock`
+//! for `build_info`. They are "unknown" when no lockfile is found above the manifest or output
+//! directory, e.g. for a crate built from a registry into a `CARGO_TARGET_DIR` outside the
+//! dependent workspace.
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+// Packages whose resolved version is exported as `NSE_<NAME>_VERSION`, see `src/build_info.rs`.
+const VERSIONED_PACKAGES: &[(&str, &str)] = &[
+    ("fff", "NSE_FF_VERSION"),
+    ("paired", "NSE_PAIRED_VERSION"),
+    ("ff-cl-gen", "NSE_FF_CL_GEN_VERSION"),
+];
+
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    export_versions();
+    #[cfg(feature = "validate-kernels")]
+    validate::validate_kernels();
+}
+
+// The lockfile of the workspace being built, the one of the crate itself or of a dependent
+// crate, whose target directory is usually below it.
+fn find_lockfile() -> Option<PathBuf> {
+    let dirs = ["OUT_DIR", "CARGO_MANIFEST_DIR"]
+        .iter()
+        .filter_map(|var| env::var_os(var))
+        .map(PathBuf::from)
+        .collect::<Vec<_>>();
+    dirs.iter()
+        .flat_map(|dir| dir.ancestors())
+        .map(|dir| dir.join("Cargo.lock"))
+        .find(|path| path.is_file())
+}
+
+// Versions of `name` in the lockfile, comma-separated if it is resolved more than once.
+fn locked_version(lockfile: &str, name: &str) -> Option<String> {
+    let mut versions = Vec::new();
+    let mut package = None;
+    for line in lockfile.lines().map(str::trim) {
+        if line == "[[package]]" {
+            package = None;
+        } else if line.starts_with("name = ") {
+            package = Some(line["name = ".len()..].trim_matches('"'));
+        } else if line.starts_with("version = ") && package == Some(name) {
+            versions.push(line["version = ".len()..].trim_matches('"').to_string());
+        }
+    }
+    if versions.is_empty() {
+        None
+    } else {
+        Some(versions.join(","))
+    }
+}
+
+fn export_versions() {
+    let lockfile = find_lockfile();
+    if let Some(path) = &lockfile {
+        println!("cargo:rerun-if-changed={}", path.display());
+    }
+    let contents = lockfile
+        .as_ref()
+        .and_then(|path| fs::read_to_string(path).ok())
+        .unwrap_or_default();
+    for (name, var) in VERSIONED_PACKAGES {
+        let version = locked_version(&contents, name).unwrap_or_else(|| "unknown".to_string());
+        println!("cargo:rustc-env={}={}", var, version);
+    }
+}
+
+#[cfg(feature = "validate-kernels")]
+mod validate {
+    use paired::bls12_381::Fr;
+    use std::env;
+    use std::fs;
+    use std::path::{Path, PathBuf};
+    use std::process::Command;
+
+    const COMPILER_ENV_VAR: &str = "NSE_CL_COMPILER";
+
+    // Concatenated after the defines, in the order of `sources::KERNEL_FILES`. Only the
+    // generic variant is validated: the AMD and NVIDIA ones use intrinsics and inline PTX that
+    // only their vendor's compiler knows.
+    const VENDOR_FILE: &str = "vendor/generic.cl";
+    const KERNEL_FILES: &[&str] = &[
+        "hash/sha256.cl",
+        "common.cl",
+        "aes.cl",
+        "mask.cl",
+        "expander.cl",
+        "butterfly.cl",
+        "combine.cl",
+        "gather.cl",
+        "commitment.cl",
+        "small_windows.cl",
+        "poseidon.cl",
+    ];
+
+    struct ValidatedConfig {
+        name: &'static str,
+        k: u32,
+        num_nodes_window: usize,
+        degree_expander: usize,
+        degree_butterfly: usize,
+        num_expander_layers: usize,
+        num_butterfly_layers: usize,
+        aes_mask: bool,
+    }
+
+    // Between them, they take both branches of every `#if` of the kernels.
+    const CONFIGS: &[ValidatedConfig] = &[
+        // Byte-aligned expander parents, as in the tests.
+        ValidatedConfig {
+            name: "test",
+            k: 2,
+            num_nodes_window: 512,
+            degree_expander: 96,
+            degree_butterfly: 4,
+            num_expander_layers: 4,
+            num_butterfly_layers: 3,
+            aes_mask: false,
+        },
+        // Unaligned expander parents and the AES mask.
+        ValidatedConfig {
+            name: "unaligned-aes",
+            k: 2,
+            num_nodes_window: 1 << 10,
+            degree_expander: 96,
+            degree_butterfly: 4,
+            num_expander_layers: 4,
+            num_butterfly_layers: 3,
+            aes_mask: true,
+        },
+        // The defaults of the bench.
+        ValidatedConfig {
+            name: "bench",
+            k: 8,
+            num_nodes_window: 1 << 19,
+            degree_expander: 384,
+            degree_butterfly: 16,
+            num_expander_layers: 8,
+            num_butterfly_layers: 7,
+            aes_mask: false,
+        },
+    ];
+
+    // Same defines as `sources::config`, with untagged domains.
+    fn defines(conf: &ValidatedConfig) -> String {
+        let bit_size = (conf.num_nodes_window.trailing_zeros() - conf.k.trailing_zeros()) as usize;
+        let stream_hash_count = ((conf.degree_expander * bit_size) as f64 / 256f64).ceil() as usize;
+        format!(
+            "#define N ({}ul)
+             #define K ({})
+             #define LOG2_K ({})
+             #define DEGREE_EXPANDER ({})
+             #define DEGREE_BUTTERFLY ({})
+             #define LOG2_DEGREE_BUTTERFLY ({})
+             #define NUM_EXPANDER_LAYERS ({})
+             #define NUM_BUTTERFLY_LAYERS ({})
+             #define BIT_SIZE ({})
+             #define STREAM_HASH_COUNT ({})
+             #define DOMAIN_TAG_MASK (0u)
+             #define DOMAIN_TAG_EXPANDER (0u)
+             #define DOMAIN_TAG_BUTTERFLY (0u)
+             #define MASK_PRF_AES ({})\n",
+            conf.num_nodes_window,
+            conf.k,
+            conf.k.trailing_zeros(),
+            conf.degree_expander,
+            conf.degree_butterfly,
+            conf.degree_butterfly.trailing_zeros(),
+            conf.num_expander_layers,
+            conf.num_butterfly_layers,
+            bit_size,
+            stream_hash_count,
+            conf.aes_mask as u32,
+        )
+    }
+
+    fn read_kernel(cl_dir: &Path, file: &str) -> String {
+        let path = cl_dir.join(file);
+        println!("cargo:rerun-if-changed={}", path.display());
+        fs::read_to_string(&path)
+            .unwrap_or_else(|e| panic!("Cannot read {}: {}", path.display(), e))
+    }
+
+    pub fn validate_kernels() {
+        println!("cargo:rerun-if-env-changed={}", COMPILER_ENV_VAR);
+        let compiler = env::var(COMPILER_ENV_VAR).unwrap_or_else(|_| "clang".to_string());
+        let cl_dir = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap()).join("src/cl");
+        let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
+
+        let mut kernels = vec![
+            read_kernel(&cl_dir, VENDOR_FILE),
+            ff_cl_gen::field::<Fr>("Fr"),
+        ];
+        kernels.extend(KERNEL_FILES.iter().map(|file| read_kernel(&cl_dir, file)));
+        let kernels = kernels.join("\n");
+
+        for conf in CONFIGS {
+            let path = out_dir.join(format!("nse-{}.cl", conf.name));
+            fs::write(&path, format!("{}\n{}", defines(conf), kernels)).unwrap();
+            let output = Command::new(&compiler)
+                .args(&["-x", "cl", "-cl-std=CL1.2", "-fsyntax-only"])
+                .args(&["-Xclang", "-finclude-default-header"])
+                .arg(&path)
+                .output()
+                .unwrap_or_else(|e| {
+                    panic!(
+                        "Cannot run `{}` to validate the kernels ({}), install clang or set {}!",
+                        compiler, e, COMPILER_ENV_VAR
+                    )
+                });
+            if !output.status.success() {
+                panic!(
+                    "The kernels of the `{}` config ({}) do not compile:\n{}",
+                    conf.name,
+                    path.display(),
+                    String::from_utf8_lossy(&output.stderr)
+                );
+            }
+        }
+    }
+}
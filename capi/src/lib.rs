@@ -0,0 +1,3 @@
+//! Builds the C bindings of rust-fil-nse-gpu (its `capi` feature) as a static library, so that
+//! Rust dependents of the crate don't build one too. See `include/nse_gpu.h`.
+pub use rust_fil_nse_gpu::capi::*;
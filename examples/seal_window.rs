@@ -0,0 +1,83 @@
+//! Seals and unseals a single window of random data, to validate a driver setup in one command:
+//!
+//! ```text
+//! cargo run --release --example seal_window
+//! ```
+//!
+//! The devices are selected like in the other binaries, see `NSE_GPU_BACKEND` and
+//! `NSE_GPU_DEVICES`.
+use rand::{thread_rng, Rng};
+use rust_fil_nse_gpu::*;
+use std::process;
+use std::time::Instant;
+
+/// A test config (not the production one) with a window of 2^22 nodes, i.e. 128MiB. Expander
+/// parents have 19 bits, not a whole number of bytes.
+const TEST_WINDOW_CONFIG: Config = Config {
+    k: 8,
+    num_nodes_window: 1 << 22,
+    degree_expander: 384,
+    degree_butterfly: 16,
+    num_expander_layers: 8,
+    num_butterfly_layers: 7,
+    encoding_mode: EncodingMode::FieldAdd,
+    domain_tags: DomainTags::UNTAGGED,
+    mask_prf: MaskPrf::Sha256,
+};
+
+fn main() {
+    env_logger::init();
+
+    let config = TEST_WINDOW_CONFIG;
+    config.validate().unwrap();
+    println!(
+        "Window of {} nodes ({}MiB), {} layers",
+        config.leaf_count(),
+        config.window_byte_len() >> 20,
+        config.num_layers()
+    );
+
+    let start = Instant::now();
+    let mut gpu = Backend::from_env()
+        .and_then(|backend| backend.gpu(config, TreeOptions::Disabled))
+        .unwrap_or_else(|e| {
+            eprintln!("Cannot initialize the GPU: {}", e);
+            process::exit(1);
+        });
+    println!("GPU initialized in {:?}", start.elapsed());
+
+    let mut rng = thread_rng();
+    let replica_id = ReplicaId::random(&mut rng);
+    let window_index = WindowIndex::from(rng.gen::<u32>());
+    let original_data = Layer::random(&mut rng, config.leaf_count());
+
+    let start = Instant::now();
+    let input = SealerInput {
+        replica_id,
+        window_index,
+        original_data: original_data.clone(),
+    };
+    let sealed = Sealer::builder(config, input)
+        .retain_key_layers(false)
+        .build(&mut gpu)
+        .and_then(|sealer| sealer.seal())
+        .unwrap()
+        .layers
+        .pop()
+        .unwrap()
+        .base;
+    println!("Sealed in {:?}", start.elapsed());
+
+    let start = Instant::now();
+    let unsealed = Unsealer::new(config, replica_id, window_index, &mut gpu)
+        .and_then(|mut unsealer| unsealer.unseal_layer(sealed))
+        .unwrap();
+    println!("Unsealed in {:?}", start.elapsed());
+
+    if unsealed == original_data {
+        println!("OK: unsealed data matches the original data.");
+    } else {
+        eprintln!("FAILED: unsealed data differs from the original data!");
+        process::exit(1);
+    }
+}
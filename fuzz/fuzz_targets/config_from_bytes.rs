@@ -0,0 +1,13 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use rust_fil_nse_gpu::Config;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(config) = Config::from_bytes(data) {
+        assert_eq!(data, &config.to_bytes()[..]);
+        if config.validate().is_ok() {
+            assert!(config.num_layers() > 0);
+            assert!(config.leaf_count().is_power_of_two());
+        }
+    }
+});
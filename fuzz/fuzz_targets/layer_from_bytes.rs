@@ -0,0 +1,11 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use rust_fil_nse_gpu::Layer;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(layer) = Layer::try_from_bytes(data) {
+        // Valid encodings are canonical.
+        assert_eq!(data, Vec::<u8>::from(&layer).as_slice());
+        assert_eq!(layer.digest(), Layer::from(data).digest());
+    }
+});
@@ -0,0 +1,21 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use rust_fil_nse_gpu::segment_range;
+use std::convert::TryInto;
+
+// Offsets and lengths of `combine_segment` calls come from callers.
+fuzz_target!(|data: &[u8]| {
+    if data.len() < 24 {
+        return;
+    }
+    let read = |i: usize| u64::from_le_bytes(data[i * 8..(i + 1) * 8].try_into().unwrap()) as usize;
+    let (offset, len, leaf_count) = (read(0), read(1), read(2));
+    match segment_range(offset, len, leaf_count) {
+        Ok(range) => {
+            assert_eq!(offset, range.start);
+            assert_eq!(len, range.len());
+            assert!(range.end <= leaf_count);
+        }
+        Err(_) => assert!(offset.checked_add(len).map_or(true, |end| end > leaf_count)),
+    }
+});
@@ -0,0 +1,158 @@
+//! Conversions between the types of `rust-fil-nse-gpu` and the NSE types of `storage-proofs`,
+//! and a GPU-backed drop-in for the CPU labeling functions of `storage_proofs::porep::nse`. It
+//! lives here rather than behind a feature of `rust-fil-nse-gpu`, so that building the library
+//! never fetches storage-proofs.
+use ff::PrimeField;
+use paired::bls12_381::{Fr, FrRepr};
+use rust_fil_nse_gpu::{
+    Config, DomainTags, EncodingMode, GPUContext, Layer, MaskPrf, NSEError, NSEResult,
+    NarrowStackedExpander, Node, ReplicaId, Sha256Domain, TreeOptions, WindowIndex, GPU, NODE_SIZE,
+};
+use storage_proofs::hasher::Domain;
+use storage_proofs::porep::nse;
+
+pub fn to_nse_config(config: Config, sector_size: usize) -> nse::Config {
+    nse::Config {
+        k: config.k,
+        num_nodes_window: config.num_nodes_window,
+        degree_expander: config.degree_expander,
+        degree_butterfly: config.degree_butterfly,
+        num_expander_layers: config.num_expander_layers,
+        num_butterfly_layers: config.num_butterfly_layers,
+        sector_size,
+    }
+}
+
+pub fn from_nse_config(config: &nse::Config) -> Config {
+    Config {
+        k: config.k,
+        num_nodes_window: config.num_nodes_window,
+        degree_expander: config.degree_expander,
+        degree_butterfly: config.degree_butterfly,
+        num_expander_layers: config.num_expander_layers,
+        num_butterfly_layers: config.num_butterfly_layers,
+        encoding_mode: EncodingMode::FieldAdd,
+        domain_tags: DomainTags::UNTAGGED,
+        mask_prf: MaskPrf::Sha256,
+    }
+}
+
+fn domain_error<E: std::fmt::Display>(e: E) -> NSEError {
+    NSEError::InvalidInput(format!("Invalid domain element: {}", e))
+}
+
+fn domain_bytes<D: Domain>(domain: &D) -> NSEResult<[u8; 32]> {
+    let bytes = domain.into_bytes();
+    if bytes.len() != 32 {
+        return Err(domain_error(format!("{} bytes, expected 32", bytes.len())));
+    }
+    let mut ret = [0u8; 32];
+    ret.copy_from_slice(&bytes);
+    Ok(ret)
+}
+
+pub fn sha256_domain_from_domain<D: Domain>(domain: &D) -> NSEResult<Sha256Domain> {
+    Ok(Sha256Domain(domain_bytes(domain)?))
+}
+
+pub fn sha256_domain_to_domain<D: Domain>(domain: Sha256Domain) -> NSEResult<D> {
+    D::try_from_bytes(&domain.0).map_err(domain_error)
+}
+
+pub fn replica_id_from_domain<D: Domain>(domain: &D) -> NSEResult<ReplicaId> {
+    Ok(ReplicaId(domain_bytes(domain)?))
+}
+
+pub fn replica_id_to_domain<D: Domain>(replica_id: ReplicaId) -> NSEResult<D> {
+    sha256_domain_to_domain(replica_id.into())
+}
+
+pub fn node_from_domain<D: Domain>(domain: &D) -> NSEResult<Node> {
+    let bytes = domain_bytes(domain)?;
+    let repr = unsafe { std::mem::transmute::<[u8; NODE_SIZE], FrRepr>(bytes) };
+    Ok(Node(Fr::from_repr(repr).map_err(domain_error)?))
+}
+
+pub fn node_to_domain<D: Domain>(node: Node) -> NSEResult<D> {
+    D::try_from_bytes(&Vec::<u8>::from(&Layer(vec![node]))).map_err(domain_error)
+}
+
+/// Labels layers on the GPU with the signatures of the CPU labeling functions of
+/// `storage_proofs::porep::nse`, operating on the byte representation of layers.
+pub struct GpuLabeler {
+    gpu: GPU,
+}
+
+impl GpuLabeler {
+    pub fn new(config: &nse::Config) -> NSEResult<Self> {
+        let config = from_nse_config(config);
+        let ctx = GPUContext::default(config, TreeOptions::Disabled)?;
+        Ok(GpuLabeler {
+            gpu: GPU::new(ctx, config)?,
+        })
+    }
+
+    pub fn gpu(&mut self) -> &mut GPU {
+        &mut self.gpu
+    }
+
+    fn write_layer(layer: &Layer, layer_out: &mut [u8]) -> NSEResult<()> {
+        let bytes = Vec::<u8>::from(layer);
+        if layer_out.len() != bytes.len() {
+            return Err(NSEError::InvalidInput(format!(
+                "Output layer has {} bytes, expected {}!",
+                layer_out.len(),
+                bytes.len()
+            )));
+        }
+        layer_out.copy_from_slice(&bytes);
+        Ok(())
+    }
+
+    pub fn mask_layer<D: Domain>(
+        &mut self,
+        window_index: u32,
+        replica_id: &D,
+        layer_out: &mut [u8],
+    ) -> NSEResult<()> {
+        let layer = self.gpu.generate_mask_layer(
+            replica_id_from_domain(replica_id)?,
+            WindowIndex::from(window_index),
+        )?;
+        Self::write_layer(&layer, layer_out)
+    }
+
+    pub fn expander_layer<D: Domain>(
+        &mut self,
+        window_index: u32,
+        replica_id: &D,
+        layer_index: u32,
+        layer_in: &[u8],
+        layer_out: &mut [u8],
+    ) -> NSEResult<()> {
+        self.gpu.push_layer(&Layer::try_from_bytes(layer_in)?)?;
+        let layer = self.gpu.generate_expander_layer(
+            replica_id_from_domain(replica_id)?,
+            WindowIndex::from(window_index),
+            layer_index as usize,
+        )?;
+        Self::write_layer(&layer, layer_out)
+    }
+
+    pub fn butterfly_layer<D: Domain>(
+        &mut self,
+        window_index: u32,
+        replica_id: &D,
+        layer_index: u32,
+        layer_in: &[u8],
+        layer_out: &mut [u8],
+    ) -> NSEResult<()> {
+        self.gpu.push_layer(&Layer::try_from_bytes(layer_in)?)?;
+        let layer = self.gpu.generate_butterfly_layer(
+            replica_id_from_domain(replica_id)?,
+            WindowIndex::from(window_index),
+            layer_index as usize,
+        )?;
+        Self::write_layer(&layer, layer_out)
+    }
+}
@@ -1,8 +1,11 @@
+pub mod adapter;
+
 #[cfg(test)]
 mod tests {
-    use ff::{Field, PrimeField};
+    use crate::adapter;
+    use ff::Field;
     use merkletree::store::StoreConfig;
-    use paired::bls12_381::{Fr, FrRepr};
+    use paired::bls12_381::Fr;
     use rand::{thread_rng, Rng};
     use rust_fil_nse_gpu::*;
     use storage_proofs::cache_key::CacheKey;
@@ -18,26 +21,21 @@ mod tests {
         degree_butterfly: 4,
         num_expander_layers: 4,
         num_butterfly_layers: 3,
+        encoding_mode: EncodingMode::FieldAdd,
+        domain_tags: DomainTags::UNTAGGED,
+        mask_prf: MaskPrf::Sha256,
     };
 
     fn to_cpu_config(conf: Config) -> nse::Config {
-        nse::Config {
-            k: conf.k,
-            num_nodes_window: conf.num_nodes_window,
-            degree_expander: conf.degree_expander,
-            degree_butterfly: conf.degree_butterfly,
-            num_expander_layers: conf.num_expander_layers,
-            num_butterfly_layers: conf.num_butterfly_layers,
-            sector_size: 0,
-        }
+        adapter::to_nse_config(conf, 0)
     }
 
     fn replica_id_to_poseidon_domain(replica_id: ReplicaId) -> poseidon::PoseidonDomain {
-        unsafe { std::mem::transmute::<ReplicaId, poseidon::PoseidonDomain>(replica_id) }
+        adapter::replica_id_to_domain(replica_id).unwrap()
     }
 
     fn node_to_poseidon_domain(node: Node) -> poseidon::PoseidonDomain {
-        unsafe { std::mem::transmute::<FrRepr, poseidon::PoseidonDomain>(node.0.into_repr()) }
+        adapter::node_to_domain(node).unwrap()
     }
 
     fn accumulate(l: &Vec<Node>) -> Node {
@@ -57,26 +55,26 @@ mod tests {
         for _ in 0..10 {
             let prev_layer = Layer::random(&mut rng, TEST_CONFIG.num_nodes_window);
             let replica_id = ReplicaId::random(&mut rng);
-            let window_index: usize = rng.gen();
+            let window_index: u32 = rng.gen();
             let layer_index = 2;
 
             gpu.push_layer(&prev_layer).unwrap();
             let gpu_output = gpu
-                .generate_expander_layer(replica_id, window_index, layer_index)
+                .generate_expander_layer(replica_id, window_index.into(), layer_index)
                 .unwrap();
 
             let layer_a = Vec::<u8>::from(&prev_layer);
             let mut layer_b = layer_a.clone();
             nse::expander_layer(
                 &to_cpu_config(TEST_CONFIG),
-                window_index as u32,
+                window_index,
                 &replica_id_to_poseidon_domain(replica_id),
                 layer_index as u32,
                 &layer_a,
                 &mut layer_b,
             )
             .unwrap();
-            let cpu_output = Layer::from(&layer_b);
+            let cpu_output = Layer::try_from_bytes(&layer_b).unwrap();
 
             assert_eq!(accumulate(&cpu_output.0), accumulate(&gpu_output.0));
         }
@@ -91,31 +89,83 @@ mod tests {
         for _ in 0..10 {
             let prev_layer = Layer::random(&mut rng, TEST_CONFIG.num_nodes_window);
             let replica_id = ReplicaId::random(&mut rng);
-            let window_index: usize = rng.gen();
+            let window_index: u32 = rng.gen();
             let layer_index = 5;
 
             gpu.push_layer(&prev_layer).unwrap();
             let gpu_output = gpu
-                .generate_butterfly_layer(replica_id, window_index, layer_index)
+                .generate_butterfly_layer(replica_id, window_index.into(), layer_index)
                 .unwrap();
 
             let layer_a = Vec::<u8>::from(&prev_layer);
             let mut layer_b = layer_a.clone();
             nse::butterfly_layer(
                 &to_cpu_config(TEST_CONFIG),
-                window_index as u32,
+                window_index,
                 &replica_id_to_poseidon_domain(replica_id),
                 layer_index as u32,
                 &layer_a,
                 &mut layer_b,
             )
             .unwrap();
-            let cpu_output = Layer::from(&layer_b);
+            let cpu_output = Layer::try_from_bytes(&layer_b).unwrap();
 
             assert_eq!(accumulate(&cpu_output.0), accumulate(&gpu_output.0));
         }
     }
 
+    #[test]
+    fn test_labeler_compatibility() {
+        let mut rng = thread_rng();
+        let cpu_config = to_cpu_config(TEST_CONFIG);
+        let mut labeler = adapter::GpuLabeler::new(&cpu_config).unwrap();
+
+        for _ in 0..10 {
+            let prev_layer =
+                Vec::<u8>::from(&Layer::random(&mut rng, TEST_CONFIG.num_nodes_window));
+            let replica_id = replica_id_to_poseidon_domain(ReplicaId::random(&mut rng));
+            let window_index: u32 = rng.gen();
+
+            let mut gpu_expander = vec![0u8; prev_layer.len()];
+            let mut cpu_expander = prev_layer.clone();
+            labeler
+                .expander_layer(window_index, &replica_id, 2, &prev_layer, &mut gpu_expander)
+                .unwrap();
+            nse::expander_layer(
+                &cpu_config,
+                window_index,
+                &replica_id,
+                2,
+                &prev_layer,
+                &mut cpu_expander,
+            )
+            .unwrap();
+            assert_eq!(cpu_expander, gpu_expander);
+
+            let mut gpu_butterfly = vec![0u8; prev_layer.len()];
+            let mut cpu_butterfly = prev_layer.clone();
+            labeler
+                .butterfly_layer(
+                    window_index,
+                    &replica_id,
+                    5,
+                    &prev_layer,
+                    &mut gpu_butterfly,
+                )
+                .unwrap();
+            nse::butterfly_layer(
+                &cpu_config,
+                window_index,
+                &replica_id,
+                5,
+                &prev_layer,
+                &mut cpu_butterfly,
+            )
+            .unwrap();
+            assert_eq!(cpu_butterfly, gpu_butterfly);
+        }
+    }
+
     #[test]
     fn test_sealer_compatibility() {
         let mut rng = thread_rng();
@@ -126,12 +176,12 @@ mod tests {
         for _ in 0..10 {
             let data = Layer::random(&mut rng, TEST_CONFIG.num_nodes_window);
             let replica_id = ReplicaId::random(&mut rng);
-            let window_index: usize = rng.gen();
+            let window_index: u32 = rng.gen();
             let sealer = Sealer::new(
                 TEST_CONFIG,
                 SealerInput {
                     replica_id,
-                    window_index,
+                    window_index: window_index.into(),
                     original_data: data.clone(),
                 },
                 &mut gpu,
@@ -163,12 +213,12 @@ mod tests {
                 nse::encode_with_trees::<OctLCMerkleTree<poseidon::PoseidonHasher>>(
                     &cpu_config,
                     store_configs,
-                    window_index as u32,
+                    window_index,
                     &replica_id_to_poseidon_domain(replica_id),
                     &mut cpu_output,
                 )
                 .unwrap();
-            let cpu_output = Layer::from(&cpu_output);
+            let cpu_output = Layer::try_from_bytes(&cpu_output).unwrap();
             let cpu_roots = {
                 let mut roots = cpu_trees.iter().map(|t| t.root()).collect::<Vec<_>>();
                 roots.push(cpu_replica_tree.root());
@@ -0,0 +1,86 @@
+/*
+ * C bindings of rust-fil-nse-gpu, built with the `capi` feature into the static library of the
+ * `capi` crate. See `src/capi.rs`.
+ *
+ * Every function returns an NSE_GPU_* status code. On failure, `nse_gpu_last_error` describes
+ * the error. Windows are sealed on a GPU created once with `nse_gpu_create`, which compiles the
+ * kernels, and reused until `nse_gpu_destroy`. A GPU must not be used by several threads at
+ * once. Nodes are passed in their byte representation, 32 little-endian bytes each.
+ */
+#ifndef NSE_GPU_H
+#define NSE_GPU_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define NSE_GPU_OK 0
+#define NSE_GPU_INVALID_INPUT 1
+#define NSE_GPU_DEVICE_ERROR 2
+#define NSE_GPU_ERROR 3
+#define NSE_GPU_PANIC 4
+
+/* Parameters of NSE. `encoding_mode` is 0 for field addition, 1 for XOR. `mask_prf` is 0 for
+ * SHA-256, 1 for AES-256-CTR (experimental, not part of NSE). */
+typedef struct {
+  uint32_t k;
+  uint64_t num_nodes_window;
+  uint64_t degree_expander;
+  uint64_t degree_butterfly;
+  uint64_t num_expander_layers;
+  uint64_t num_butterfly_layers;
+  uint32_t encoding_mode;
+  uint32_t domain_tag_mask;
+  uint32_t domain_tag_expander;
+  uint32_t domain_tag_butterfly;
+  uint32_t mask_prf;
+} nse_gpu_config;
+
+/* A GPU sealing and unsealing windows of a single config. */
+typedef struct nse_gpu nse_gpu;
+
+/* Message of the error of the last call of the calling thread to this API, valid until its
+ * next call. NULL if the last call succeeded. */
+const char *nse_gpu_last_error(void);
+
+/* Number of devices sealing can run on. */
+int32_t nse_gpu_device_count(size_t *count);
+
+/* Writes the NUL-terminated name of device `device_index` in `name`, of `name_len` bytes. */
+int32_t nse_gpu_device_name(size_t device_index, char *name, size_t name_len);
+
+/* Creates a GPU on device `device_index` for windows of `config`, written in `gpu`. It must be
+ * freed with `nse_gpu_destroy`. */
+int32_t nse_gpu_create(size_t device_index, const nse_gpu_config *config, nse_gpu **gpu);
+
+/* Frees a GPU created by `nse_gpu_create`. Does nothing if `gpu` is NULL. */
+void nse_gpu_destroy(nse_gpu *gpu);
+
+/* Seals window `window_index` of `data` (`num_nodes_window` nodes, i.e. `data_len` bytes) on
+ * `gpu`, writing the replica (`data_len` bytes) in `replica`. */
+int32_t nse_gpu_seal_window(nse_gpu *gpu,
+                            const uint8_t replica_id[32],
+                            uint64_t window_index,
+                            const uint8_t *data,
+                            size_t data_len,
+                            uint8_t *replica);
+
+/* Unseals the `sealed_len` bytes of `sealed`, the nodes of the replica of window
+ * `window_index` starting at node `offset`, on `gpu`, writing `sealed_len` bytes in
+ * `unsealed`. */
+int32_t nse_gpu_unseal_range(nse_gpu *gpu,
+                             const uint8_t replica_id[32],
+                             uint64_t window_index,
+                             uint64_t offset,
+                             const uint8_t *sealed,
+                             size_t sealed_len,
+                             uint8_t *unsealed);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* NSE_GPU_H */
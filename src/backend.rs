@@ -0,0 +1,220 @@
+use crate::utils::Device;
+use crate::{
+    utils, Config, GPUContext, NSEError, NSEResult, NarrowStackedExpander, TreeOptions, GPU,
+};
+use std::env;
+use std::fmt;
+use std::str::FromStr;
+
+/// Selects the implementation, e.g. `NSE_GPU_BACKEND=opencl`.
+pub const BACKEND_ENV_VAR: &str = "NSE_GPU_BACKEND";
+/// Comma-separated indices of the devices to use, e.g. `NSE_GPU_DEVICES=0,2`.
+pub const DEVICES_ENV_VAR: &str = "NSE_GPU_DEVICES";
+/// Names of backends without an implementation yet, rejected by `Backend::from_str`.
+pub const PLANNED_BACKENDS: [&str; 3] = ["cuda", "cpu", "mock"];
+
+/// The implementations of NSE a binary may switch between at runtime. Only OpenCL is
+/// implemented so far, variants are added along with their implementation. Parsing the name of
+/// one of the others (see `PLANNED_BACKENDS`) fails with an error saying so.
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Hash)]
+pub enum Backend {
+    OpenCl,
+}
+
+impl Default for Backend {
+    fn default() -> Self {
+        Backend::OpenCl
+    }
+}
+
+impl fmt::Display for Backend {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Backend::OpenCl => "opencl",
+        })
+    }
+}
+
+impl FromStr for Backend {
+    type Err = NSEError;
+
+    fn from_str(s: &str) -> NSEResult<Self> {
+        match s.trim().to_lowercase().as_str() {
+            "opencl" | "ocl" => Ok(Backend::OpenCl),
+            other if PLANNED_BACKENDS.contains(&other) => Err(NSEError::InvalidInput(format!(
+                "Backend `{}` is not implemented yet, expected opencl",
+                other
+            ))),
+            other => Err(NSEError::InvalidInput(format!(
+                "Unknown backend `{}`, expected opencl",
+                other
+            ))),
+        }
+    }
+}
+
+impl Backend {
+    /// All backends, e.g. for tests to run against the ones supported by the build.
+    pub const ALL: [Backend; 1] = [Backend::OpenCl];
+
+    /// Reads the backend from `NSE_GPU_BACKEND`, defaulting to OpenCL when unset.
+    pub fn from_env() -> NSEResult<Self> {
+        match env::var(BACKEND_ENV_VAR) {
+            Ok(s) => s.parse(),
+            Err(_) => Ok(Backend::default()),
+        }
+    }
+
+    /// Whether this build of the crate implements the backend.
+    pub fn is_supported(&self) -> bool {
+        match self {
+            Backend::OpenCl => cfg!(feature = "gpu"),
+        }
+    }
+
+    /// Devices of the backend, restricted to the ones listed in `NSE_GPU_DEVICES` if set.
+    pub fn devices(&self) -> NSEResult<Vec<Device>> {
+        self.check_supported()?;
+        let all = utils::all_devices()?;
+        match env::var(DEVICES_ENV_VAR) {
+            Ok(s) => parse_device_indices(&s)?
+                .into_iter()
+                .map(|i| {
+                    all.get(i).cloned().ok_or_else(|| {
+                        NSEError::InvalidInput(format!(
+                            "Device index {} out of range, {} devices found",
+                            i,
+                            all.len()
+                        ))
+                    })
+                })
+                .collect(),
+            Err(_) => Ok(all),
+        }
+    }
+
+    /// The first device of the backend, see `devices`. Fails with `NSEError::NoDevice` if
+    /// there is none.
+    pub fn first_device(&self) -> NSEResult<Device> {
+        self.devices()?
+            .into_iter()
+            .next()
+            .ok_or(NSEError::NoDevice(*self))
+    }
+
+    /// Creates a GPU on the first device of the backend.
+    pub fn gpu(&self, config: Config, tree_options: TreeOptions) -> NSEResult<GPU> {
+        let device = self.first_device()?;
+        GPU::new(GPUContext::new(device, config, tree_options)?, config)
+    }
+
+    fn check_supported(&self) -> NSEResult<()> {
+        if self.is_supported() {
+            Ok(())
+        } else {
+            Err(NSEError::UnsupportedBackend(*self))
+        }
+    }
+}
+
+/// Creates the `NarrowStackedExpander` of an implementation as a trait object, so that
+/// schedulers can hold expanders of different implementations side by side.
+pub trait BackendFactory {
+    fn create(
+        &self,
+        config: Config,
+        tree_options: TreeOptions,
+    ) -> NSEResult<Box<dyn NarrowStackedExpander + Send>>;
+}
+
+/// Creates a GPU on the first device of the backend, see `Backend::gpu`.
+impl BackendFactory for Backend {
+    fn create(
+        &self,
+        config: Config,
+        tree_options: TreeOptions,
+    ) -> NSEResult<Box<dyn NarrowStackedExpander + Send>> {
+        Ok(Box::new(self.gpu(config, tree_options)?))
+    }
+}
+
+impl BackendFactory for Device {
+    fn create(
+        &self,
+        config: Config,
+        tree_options: TreeOptions,
+    ) -> NSEResult<Box<dyn NarrowStackedExpander + Send>> {
+        let context = GPUContext::new(*self, config, tree_options)?;
+        Ok(Box::new(GPU::new(context, config)?))
+    }
+}
+
+fn parse_device_indices(s: &str) -> NSEResult<Vec<usize>> {
+    s.split(',')
+        .map(|i| i.trim())
+        .filter(|i| !i.is_empty())
+        .map(|i| {
+            i.parse::<usize>()
+                .map_err(|_| NSEError::InvalidInput(format!("Invalid device index `{}`", i)))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{DomainTags, EncodingMode, MaskPrf, ReplicaId};
+
+    #[test]
+    fn test_parse_backend() {
+        assert_eq!(Backend::OpenCl, "opencl".parse().unwrap());
+        assert_eq!(Backend::OpenCl, " OCL ".parse().unwrap());
+        for name in PLANNED_BACKENDS.iter() {
+            match name.parse::<Backend>() {
+                Err(NSEError::InvalidInput(msg)) => assert!(msg.contains("not implemented")),
+                other => panic!("{}: {:?}", name, other),
+            }
+        }
+        assert!("vulkan".parse::<Backend>().is_err());
+        for backend in Backend::ALL.iter() {
+            assert_eq!(*backend, backend.to_string().parse().unwrap());
+        }
+    }
+
+    #[test]
+    fn test_backend_factory() {
+        let config = Config {
+            k: 2,
+            num_nodes_window: 512,
+            degree_expander: 96,
+            degree_butterfly: 4,
+            num_expander_layers: 4,
+            num_butterfly_layers: 3,
+            encoding_mode: EncodingMode::FieldAdd,
+            domain_tags: DomainTags::UNTAGGED,
+            mask_prf: MaskPrf::Sha256,
+        };
+        let factories: Vec<Box<dyn BackendFactory>> = vec![
+            Box::new(Backend::OpenCl),
+            Box::new(utils::default_device().unwrap()),
+        ];
+        let masks = factories
+            .iter()
+            .map(|factory| {
+                let mut expander = factory.create(config, TreeOptions::Disabled).unwrap();
+                assert_eq!(config.num_nodes_window, expander.leaf_count());
+                expander
+                    .generate_mask_layer(ReplicaId([9u8; 32]), WindowIndex(3))
+                    .unwrap()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(masks[0], masks[1]);
+    }
+
+    #[test]
+    fn test_parse_device_indices() {
+        assert_eq!(vec![0, 2], parse_device_indices("0,2").unwrap());
+        assert_eq!(vec![1], parse_device_indices(" 1, ").unwrap());
+        assert!(parse_device_indices("0,x").is_err());
+    }
+}
@@ -1,6 +1,7 @@
 use rand::{thread_rng, Rng};
 use rust_fil_nse_gpu::*;
-use std::time::Instant;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 use structopt::StructOpt;
 
 macro_rules! timer {
@@ -17,7 +18,7 @@ macro_rules! timer {
 fn bench_mask(gpu: &mut GPU, samples: usize) -> u64 {
     let mut rng = thread_rng();
     let replica_id = ReplicaId::random(&mut rng);
-    let window_index: usize = rng.gen();
+    let window_index = WindowIndex::from(rng.gen::<u32>());
     timer!(
         gpu.generate_mask_layer(replica_id, window_index).unwrap(),
         samples
@@ -27,7 +28,7 @@ fn bench_mask(gpu: &mut GPU, samples: usize) -> u64 {
 fn bench_expander(gpu: &mut GPU, samples: usize) -> u64 {
     let mut rng = thread_rng();
     let replica_id = ReplicaId::random(&mut rng);
-    let window_index: usize = rng.gen();
+    let window_index = WindowIndex::from(rng.gen::<u32>());
     gpu.generate_mask_layer(replica_id, window_index).unwrap();
     timer!(
         gpu.generate_expander_layer(replica_id, window_index, rng.gen())
@@ -39,7 +40,7 @@ fn bench_expander(gpu: &mut GPU, samples: usize) -> u64 {
 fn bench_butterfly(gpu: &mut GPU, samples: usize) -> u64 {
     let mut rng = thread_rng();
     let replica_id = ReplicaId::random(&mut rng);
-    let window_index: usize = rng.gen();
+    let window_index = WindowIndex::from(rng.gen::<u32>());
     gpu.generate_mask_layer(replica_id, window_index).unwrap();
     timer!(
         gpu.generate_butterfly_layer(replica_id, window_index, rng.gen())
@@ -51,10 +52,13 @@ fn bench_butterfly(gpu: &mut GPU, samples: usize) -> u64 {
 fn bench_combine(gpu: &mut GPU, samples: usize) -> u64 {
     let mut rng = thread_rng();
     let replica_id = ReplicaId::random(&mut rng);
-    let window_index: usize = rng.gen();
+    let window_index = WindowIndex::from(rng.gen::<u32>());
     let data = Layer::random(&mut rng, gpu.leaf_count());
     gpu.generate_mask_layer(replica_id, window_index).unwrap();
-    timer!(gpu.combine_layer(&data, false).unwrap(), samples)
+    timer!(
+        gpu.combine_layer(&data, CombineMode::Encode).unwrap(),
+        samples
+    )
 }
 
 fn bench_sealer(
@@ -68,11 +72,11 @@ fn bench_sealer(
     let inputs: Vec<SealerInput> = (0..num_windows)
         .map(|_| SealerInput {
             replica_id: ReplicaId::random(&mut rng),
-            window_index: rng.gen(),
-            original_data: Layer::random(&mut rng, config.num_nodes_window),
+            window_index: WindowIndex::from(rng.gen::<u32>()),
+            original_data: Layer::random(&mut rng, config.leaf_count()),
         })
         .collect();
-    let mut pool = SealerPool::new(utils::all_devices().unwrap(), config, tree_options).unwrap();
+    let mut pool = SealerPool::from_env(config, tree_options).unwrap();
 
     timer!(
         {
@@ -89,7 +93,21 @@ fn bench_sealer(
     )
 }
 
-#[derive(Debug, StructOpt, Clone, Copy)]
+fn dump_key_layers(gpu: &mut GPU, dir: &Path) {
+    let mut rng = thread_rng();
+    let config = gpu.config();
+    std::fs::create_dir_all(dir).unwrap();
+    let window_index = WindowIndex::from(rng.gen::<u32>());
+    let generator =
+        KeyGenerator::new(config, ReplicaId::random(&mut rng), window_index, gpu).unwrap();
+    for (i, layer) in generator.enumerate() {
+        let path = dir.join(format!("layer-{}.{}", i + 1, LAYER_FILE_EXTENSION));
+        write_layer_file(&path, &config, &layer.unwrap()).unwrap();
+        println!("Wrote {}", path.display());
+    }
+}
+
+#[derive(Debug, StructOpt, Clone)]
 #[structopt(name = "NSE Bench", about = "Benchmarking NSE operations on GPU.")]
 struct Opts {
     #[structopt(short = "k", default_value = "8")]
@@ -104,6 +122,10 @@ struct Opts {
     num_expander_layers: usize,
     #[structopt(long = "num-butterfly-layers", default_value = "7")]
     num_butterfly_layers: usize,
+    #[structopt(long = "xor")]
+    xor: bool,
+    #[structopt(long = "aes-mask")]
+    aes_mask: bool,
     #[structopt(long = "samples", default_value = "10")]
     samples: usize,
     #[structopt(long = "sealer")]
@@ -112,6 +134,53 @@ struct Opts {
     num_windows: usize,
     #[structopt(long = "trees")]
     build_trees: bool,
+    #[structopt(long = "num-queues")]
+    num_queues: Option<usize>,
+    #[structopt(long = "global-work-size")]
+    global_work_size: Option<usize>,
+    #[structopt(long = "local-work-size")]
+    local_work_size: Option<usize>,
+    #[structopt(long = "kernel-stats")]
+    kernel_stats: bool,
+    #[structopt(long = "bandwidth")]
+    bandwidth: bool,
+    #[structopt(long = "k-sweep")]
+    k_sweep: bool,
+    #[structopt(long = "max-alloc-chunk")]
+    max_alloc_chunk: Option<usize>,
+    #[structopt(long = "cache-expander-parents")]
+    cache_expander_parents: bool,
+    #[structopt(long = "kernel-timeout-ms")]
+    kernel_timeout_ms: Option<u64>,
+    #[structopt(long = "mask-cache-size")]
+    mask_cache_size: Option<usize>,
+    #[structopt(long = "readback-chunk-bytes")]
+    readback_chunk_bytes: Option<usize>,
+    /// Writes the key layers of a random window to this directory, as layer files.
+    #[structopt(long = "dump-key-layers", parse(from_os_str))]
+    dump_key_layers: Option<PathBuf>,
+}
+
+impl Opts {
+    fn gpu_config(&self, defaults: GpuConfig) -> GpuConfig {
+        GpuConfig {
+            num_queues: self.num_queues.unwrap_or(defaults.num_queues),
+            global_work_size: self.global_work_size.or(defaults.global_work_size),
+            local_work_size: self.local_work_size.or(defaults.local_work_size),
+            max_alloc_chunk: self.max_alloc_chunk.or(defaults.max_alloc_chunk),
+            cache_expander_parents: self.cache_expander_parents || defaults.cache_expander_parents,
+            kernel_timeout: self
+                .kernel_timeout_ms
+                .map(Duration::from_millis)
+                .or(defaults.kernel_timeout),
+            mask_cache_size: self.mask_cache_size.unwrap_or(defaults.mask_cache_size),
+            readback_chunk_size: self
+                .readback_chunk_bytes
+                .map(|bytes| std::cmp::max(bytes / NODE_SIZE, 1))
+                .unwrap_or(defaults.readback_chunk_size),
+            ..defaults
+        }
+    }
 }
 
 impl From<Opts> for Config {
@@ -123,6 +192,17 @@ impl From<Opts> for Config {
             degree_butterfly: cli.degree_butterfly,
             num_expander_layers: cli.num_expander_layers,
             num_butterfly_layers: cli.num_butterfly_layers,
+            encoding_mode: if cli.xor {
+                EncodingMode::Xor
+            } else {
+                EncodingMode::FieldAdd
+            },
+            domain_tags: DomainTags::UNTAGGED,
+            mask_prf: if cli.aes_mask {
+                MaskPrf::Aes256Ctr
+            } else {
+                MaskPrf::Sha256
+            },
         }
     }
 }
@@ -133,7 +213,8 @@ fn main() {
     let opts = Opts::from_args();
     println!("Options: {:?}", opts);
 
-    let config: Config = Config::from(opts);
+    let config: Config = Config::from(opts.clone());
+    println!("{}", config.describe());
     let tree_options = if opts.build_trees {
         TreeOptions::Enabled { rows_to_discard: 2 }
     } else {
@@ -146,8 +227,42 @@ fn main() {
             bench_sealer(config, opts.samples, tree_options, opts.num_windows)
         );
     } else {
-        let ctx = GPUContext::default(config, tree_options).unwrap();
-        let mut gpu = GPU::new(ctx, config).unwrap();
+        let device = Backend::from_env()
+            .and_then(|backend| backend.first_device())
+            .unwrap_or_else(|e| {
+                eprintln!("{}", e);
+                std::process::exit(1)
+            });
+        let ctx = GPUContext::new(device, config, tree_options).unwrap();
+        let gpu_config = opts.gpu_config(ctx.gpu_config());
+        println!("GPU config: {:?}", gpu_config);
+        let mut gpu = GPU::with_gpu_config(ctx, config, gpu_config).unwrap();
+        if opts.kernel_stats {
+            for stats in gpu.kernel_stats().unwrap() {
+                println!("{:?}", stats);
+            }
+        }
+        if opts.bandwidth {
+            let bandwidth = gpu.measure_bandwidth().unwrap();
+            println!(
+                "Bandwidth: {:.2}GB/s to device, {:.2}GB/s from device",
+                bandwidth.host_to_device / 1e9,
+                bandwidth.device_to_host / 1e9
+            );
+        }
+        if opts.k_sweep {
+            let ks = (0..32)
+                .map(|i| 1u32 << i)
+                .filter(|&k| k >= MIN_K && k <= MAX_K && (k as usize) < config.num_nodes_window)
+                .collect::<Vec<_>>();
+            for t in gpu.bench_k_sweep(&ks, opts.samples).unwrap() {
+                println!("k = {}: {:.0} nodes/s", t.k, t.nodes_per_sec);
+            }
+        }
+
+        if let Some(dir) = &opts.dump_key_layers {
+            dump_key_layers(&mut gpu, dir);
+        }
 
         println!("Mask: {}ms", bench_mask(&mut gpu, opts.samples));
         println!("Expander: {}ms", bench_expander(&mut gpu, opts.samples));
@@ -0,0 +1,225 @@
+//! Seals and unseals random windows on one GPU for hours, asserting that host memory and, with
+//! the `leak-detection` feature, device buffers stay stable, so that leaks and fragmentation of
+//! the buffer reuse and caches show up before production does:
+//!
+//! ```text
+//! cargo run --release --features leak-detection --bin soak -- --hours 4
+//! ```
+
+use rand::{thread_rng, Rng};
+use rust_fil_nse_gpu::*;
+use std::time::{Duration, Instant};
+use structopt::StructOpt;
+
+#[derive(Debug, StructOpt, Clone)]
+#[structopt(
+    name = "NSE Soak",
+    about = "Sealing and unsealing continuously on GPU."
+)]
+struct Opts {
+    #[structopt(short = "k", default_value = "8")]
+    k: u32,
+    #[structopt(long = "num-nodes-window", default_value = "65536")]
+    num_nodes_window: usize,
+    #[structopt(long = "degree-expander", default_value = "384")]
+    degree_expander: usize,
+    #[structopt(long = "degree-butterfly", default_value = "16")]
+    degree_butterfly: usize,
+    #[structopt(long = "num-expander-layers", default_value = "8")]
+    num_expander_layers: usize,
+    #[structopt(long = "num-butterfly-layers", default_value = "7")]
+    num_butterfly_layers: usize,
+    #[structopt(long = "aes-mask")]
+    aes_mask: bool,
+    /// Stops after this many hours.
+    #[structopt(long = "hours", default_value = "1")]
+    hours: f64,
+    /// Stops after this many windows, if it comes before `hours`.
+    #[structopt(long = "iterations")]
+    iterations: Option<u64>,
+    /// Windows sealed before memory is measured, for caches and allocators to settle.
+    #[structopt(long = "warmup", default_value = "10")]
+    warmup: u64,
+    /// Maximum growth of the resident memory of the process over the one after the warmup.
+    #[structopt(long = "max-rss-growth-mb", default_value = "64")]
+    max_rss_growth_mb: u64,
+    #[structopt(long = "mask-cache-size", default_value = "2")]
+    mask_cache_size: usize,
+    #[structopt(long = "report-every", default_value = "100")]
+    report_every: u64,
+}
+
+impl From<&Opts> for Config {
+    fn from(cli: &Opts) -> Self {
+        Config {
+            k: cli.k,
+            num_nodes_window: cli.num_nodes_window,
+            degree_expander: cli.degree_expander,
+            degree_butterfly: cli.degree_butterfly,
+            num_expander_layers: cli.num_expander_layers,
+            num_butterfly_layers: cli.num_butterfly_layers,
+            encoding_mode: EncodingMode::FieldAdd,
+            domain_tags: DomainTags::UNTAGGED,
+            mask_prf: if cli.aes_mask {
+                MaskPrf::Aes256Ctr
+            } else {
+                MaskPrf::Sha256
+            },
+        }
+    }
+}
+
+// Resident memory of the process in bytes, `None` where it cannot be read.
+#[cfg(target_os = "linux")]
+fn resident_bytes() -> Option<u64> {
+    let statm = std::fs::read_to_string("/proc/self/statm").ok()?;
+    let pages = statm.split_whitespace().nth(1)?.parse::<u64>().ok()?;
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+    Some(pages * page_size as u64)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn resident_bytes() -> Option<u64> {
+    None
+}
+
+#[cfg(feature = "leak-detection")]
+fn check_device_buffers(gpu: &mut GPU, baseline: usize, iteration: u64) {
+    let leaked = gpu.leaked_buffers();
+    assert!(
+        leaked.is_empty(),
+        "Window {}: leaked device buffers:\n{}",
+        iteration,
+        leaked.join("\n")
+    );
+    assert_eq!(
+        baseline,
+        gpu.live_buffers(),
+        "Window {}: the number of device buffers changed",
+        iteration
+    );
+}
+
+#[cfg(not(feature = "leak-detection"))]
+fn check_device_buffers(_gpu: &mut GPU, _baseline: usize, _iteration: u64) {}
+
+#[cfg(feature = "leak-detection")]
+fn live_buffers(gpu: &mut GPU) -> usize {
+    gpu.live_buffers()
+}
+
+#[cfg(not(feature = "leak-detection"))]
+fn live_buffers(_gpu: &mut GPU) -> usize {
+    0
+}
+
+// Seals a window and unseals its replica, checking the original data comes back. Every other
+// window is the previous one sealed again, so that the mask cache is hit as well.
+fn seal_unseal(gpu: &mut GPU, config: Config, input: SealerInput) -> NSEResult<()> {
+    let output = SealerBuilder::new(config, input.clone())
+        .build(gpu)?
+        .seal()?;
+    let replica = output
+        .layers
+        .last()
+        .expect("There is a replica")
+        .base
+        .clone();
+    let unsealed = Unsealer::new(config, input.replica_id, input.window_index, gpu)?
+        .decode_only()
+        .unseal_layer(replica)?;
+    if unsealed != input.original_data {
+        return Err(NSEError::RoundtripMismatch {
+            what: "the unsealed window",
+            node: input
+                .original_data
+                .diff(&unsealed, 1)
+                .first()
+                .map_or(0, |(i, _, _)| *i),
+        });
+    }
+    Ok(())
+}
+
+fn main() {
+    env_logger::init();
+
+    let opts = Opts::from_args();
+    let config = Config::from(&opts);
+    println!("{}", config.describe());
+    if !cfg!(feature = "leak-detection") {
+        eprintln!(
+            "WARNING: built without the `leak-detection` feature, device buffers are NOT \
+             checked, only the resident memory of the process is."
+        );
+    }
+
+    let device = Backend::from_env()
+        .and_then(|backend| backend.first_device())
+        .unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            std::process::exit(1)
+        });
+    let ctx = GPUContext::new(device, config, TreeOptions::Disabled).unwrap();
+    let gpu_config = GpuConfig {
+        mask_cache_size: opts.mask_cache_size,
+        ..ctx.gpu_config()
+    };
+    let mut gpu = GPU::with_gpu_config(ctx, config, gpu_config).unwrap();
+
+    let deadline = Duration::from_secs_f64(opts.hours * 3600f64);
+    let max_growth = opts.max_rss_growth_mb << 20;
+    let start = Instant::now();
+    let mut rng = thread_rng();
+    let mut input = None;
+    let mut baseline = None;
+    let mut iteration = 0;
+    while start.elapsed() < deadline && opts.iterations.map_or(true, |max| iteration < max) {
+        let window = match input.take() {
+            Some(previous) if iteration % 2 == 1 => previous,
+            _ => SealerInput {
+                replica_id: ReplicaId::random(&mut rng),
+                window_index: WindowIndex::from(rng.gen::<u32>()),
+                original_data: Layer::random(&mut rng, config.num_nodes_window),
+            },
+        };
+        seal_unseal(&mut gpu, config, window.clone())
+            .unwrap_or_else(|e| panic!("Window {}: {}", iteration, e));
+        input = Some(window);
+        iteration += 1;
+
+        if baseline.is_none() && iteration >= opts.warmup {
+            baseline = Some((resident_bytes(), live_buffers(&mut gpu)));
+        }
+        if let Some((rss_baseline, buffers)) = baseline {
+            check_device_buffers(&mut gpu, buffers, iteration);
+            if let (Some(baseline), Some(rss)) = (rss_baseline, resident_bytes()) {
+                assert!(
+                    rss <= baseline + max_growth,
+                    "Window {}: resident memory grew from {} to {} bytes",
+                    iteration,
+                    baseline,
+                    rss
+                );
+            }
+        }
+        if iteration % opts.report_every == 0 {
+            println!(
+                "{} windows in {:.0}s, resident memory: {}",
+                iteration,
+                start.elapsed().as_secs_f64(),
+                resident_bytes().map_or("unknown".to_string(), |rss| format!("{} bytes", rss))
+            );
+        }
+    }
+    println!(
+        "Soak passed: {} windows in {:.0}s{}",
+        iteration,
+        start.elapsed().as_secs_f64(),
+        if cfg!(feature = "leak-detection") {
+            ""
+        } else {
+            " (device buffers not checked)"
+        }
+    );
+}
@@ -0,0 +1,139 @@
+use crate::sources::kernel_sources;
+use crate::{Backend, Sha256Domain, LIBRARY_VERSION};
+#[cfg(feature = "json")]
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+
+/// Resolved versions of the crates doing the field arithmetic, from the `Cargo.lock` found by
+/// `build.rs` above the manifest or the output directory, "unknown" when there is none: e.g. when
+/// the crate is built from a registry with a `CARGO_TARGET_DIR` outside the workspace of the
+/// dependent crate. Cargo only passes `DEP_*` variables for crates with a `links` key, which
+/// the field crates don't have.
+const FF_VERSION: &str = env!("NSE_FF_VERSION");
+const PAIRED_VERSION: &str = env!("NSE_PAIRED_VERSION");
+const FF_CL_GEN_VERSION: &str = env!("NSE_FF_CL_GEN_VERSION");
+
+/// The crate features, see `Cargo.toml`.
+const FEATURES: &[(&str, bool)] = &[
+    ("gpu", cfg!(feature = "gpu")),
+    ("host-combine", cfg!(feature = "host-combine")),
+    ("leak-detection", cfg!(feature = "leak-detection")),
+    ("cl_test", cfg!(feature = "cl_test")),
+    ("launch-logging", cfg!(feature = "launch-logging")),
+    ("fault-injection", cfg!(feature = "fault-injection")),
+    ("raw-handles", cfg!(feature = "raw-handles")),
+    ("capi", cfg!(feature = "capi")),
+    ("json", cfg!(feature = "json")),
+    ("validate-kernels", cfg!(feature = "validate-kernels")),
+];
+
+/// What a build of the crate is made of, returned by `build_info`, so that bug reports and
+/// `SealReceipt`s capture exactly which code produced an artifact.
+#[derive(PartialEq, Eq, Debug, Clone)]
+#[cfg_attr(feature = "json", derive(Serialize, Deserialize))]
+pub struct BuildInfo {
+    /// See `LIBRARY_VERSION`.
+    pub library_version: String,
+    /// The backends this build supports, see `Backend::is_supported`.
+    pub backends: Vec<String>,
+    /// The enabled crate features.
+    pub features: Vec<String>,
+    /// SHA-256 of every kernel source, by file name relative to `src/cl`. The field arithmetic
+    /// generated by `ff-cl-gen` is `field.cl`.
+    pub kernel_sources: BTreeMap<String, Sha256Domain>,
+    pub ff_version: String,
+    pub paired_version: String,
+    pub ff_cl_gen_version: String,
+}
+
+/// Reports the backends, features, kernel sources and field crates this build is made of.
+pub fn build_info() -> BuildInfo {
+    BuildInfo {
+        library_version: LIBRARY_VERSION.to_string(),
+        backends: Backend::ALL
+            .iter()
+            .filter(|backend| backend.is_supported())
+            .map(Backend::to_string)
+            .collect(),
+        features: FEATURES
+            .iter()
+            .filter(|(_, enabled)| *enabled)
+            .map(|(name, _)| name.to_string())
+            .collect(),
+        kernel_sources: kernel_sources()
+            .into_iter()
+            .map(|(file, src)| {
+                let mut digest = [0u8; 32];
+                digest.copy_from_slice(&Sha256::digest(src.as_bytes()));
+                (file.to_string(), Sha256Domain(digest))
+            })
+            .collect(),
+        ff_version: FF_VERSION.to_string(),
+        paired_version: PAIRED_VERSION.to_string(),
+        ff_cl_gen_version: FF_CL_GEN_VERSION.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeSet;
+    use std::fs;
+    use std::path::Path;
+
+    // The `.cl` files below `dir`, by path relative to `root`.
+    fn cl_files(root: &Path, dir: &Path, files: &mut BTreeSet<String>) {
+        for entry in fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.is_dir() {
+                cl_files(root, &path, files);
+            } else if path.extension().map_or(false, |ext| ext == "cl") {
+                let relative = path.strip_prefix(root).unwrap();
+                let components = relative
+                    .components()
+                    .map(|c| c.as_os_str().to_string_lossy().into_owned())
+                    .collect::<Vec<_>>();
+                files.insert(components.join("/"));
+            }
+        }
+    }
+
+    #[test]
+    fn test_build_info() {
+        let info = build_info();
+        assert_eq!(LIBRARY_VERSION, info.library_version);
+        assert_eq!(
+            cfg!(feature = "gpu"),
+            info.backends == vec!["opencl".to_string()]
+        );
+        assert_eq!(
+            cfg!(feature = "gpu"),
+            info.features.contains(&"gpu".to_string())
+        );
+        // Every kernel file, none forgotten when one is added.
+        let cl_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("src/cl");
+        let mut files = BTreeSet::new();
+        cl_files(&cl_dir, &cl_dir, &mut files);
+        files.insert("field.cl".to_string());
+        assert_eq!(
+            files,
+            info.kernel_sources.keys().cloned().collect::<BTreeSet<_>>()
+        );
+        assert_ne!(
+            info.kernel_sources["combine.cl"],
+            info.kernel_sources["mask.cl"]
+        );
+        assert!(!info.paired_version.is_empty());
+        // Stable across calls.
+        assert_eq!(info, build_info());
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn test_build_info_json() {
+        let info = build_info();
+        let json = serde_json::to_string(&info).unwrap();
+        assert_eq!(info, serde_json::from_str(&json).unwrap());
+    }
+}
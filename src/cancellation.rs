@@ -0,0 +1,31 @@
+use crate::{NSEError, NSEResult};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// A cloneable flag used to abort long-running jobs. Once cancelled, a `Sealer` stops at the
+/// next layer boundary and yields `NSEError::Cancelled`, releasing the GPU.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    /// Returns `NSEError::Cancelled` if the token has been cancelled.
+    pub fn check(&self) -> NSEResult<()> {
+        if self.is_cancelled() {
+            Err(NSEError::Cancelled)
+        } else {
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,411 @@
+//! C bindings of the sealing API, enabled by the `capi` feature. See `include/nse_gpu.h` for
+//! the declarations and their documentation, which must be kept in sync with this module.
+//!
+//! Functions return an `NSE_GPU_*` status code, details of the last error of the calling thread
+//! are available from `nse_gpu_last_error`. Windows are sealed on a GPU created once with
+//! `nse_gpu_create`, which compiles the kernels, and reused until `nse_gpu_destroy`. Nodes are passed in their byte representation (see
+//! `From<&Layer> for Vec<u8>`), 32 bytes per node.
+
+use crate::{
+    utils, Config, DomainTags, EncodingMode, GPUContext, Layer, MaskPrf, NSEError, NSEResult,
+    ReplicaId, Sealer, SealerInput, TreeOptions, Unsealer, WindowIndex, GPU, NODE_SIZE,
+};
+use std::cell::RefCell;
+use std::convert::TryFrom;
+use std::ffi::CString;
+use std::os::raw::c_char;
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use std::slice;
+
+pub const NSE_GPU_OK: i32 = 0;
+pub const NSE_GPU_INVALID_INPUT: i32 = 1;
+pub const NSE_GPU_DEVICE_ERROR: i32 = 2;
+pub const NSE_GPU_ERROR: i32 = 3;
+pub const NSE_GPU_PANIC: i32 = 4;
+
+/// Mirrors `Config`, `encoding_mode` being 0 for `FieldAdd` and 1 for `Xor`, and `mask_prf`
+/// 0 for `Sha256` and 1 for `Aes256Ctr`.
+#[repr(C)]
+#[allow(non_camel_case_types)]
+#[derive(Debug, Clone, Copy)]
+pub struct nse_gpu_config {
+    pub k: u32,
+    pub num_nodes_window: u64,
+    pub degree_expander: u64,
+    pub degree_butterfly: u64,
+    pub num_expander_layers: u64,
+    pub num_butterfly_layers: u64,
+    pub encoding_mode: u32,
+    pub domain_tag_mask: u32,
+    pub domain_tag_expander: u32,
+    pub domain_tag_butterfly: u32,
+    pub mask_prf: u32,
+}
+
+impl nse_gpu_config {
+    fn to_config(&self) -> NSEResult<Config> {
+        let encoding_mode = match self.encoding_mode {
+            0 => EncodingMode::FieldAdd,
+            1 => EncodingMode::Xor,
+            mode => {
+                return Err(NSEError::InvalidInput(format!(
+                    "Unknown encoding mode {}!",
+                    mode
+                )))
+            }
+        };
+        let mask_prf = match self.mask_prf {
+            0 => MaskPrf::Sha256,
+            1 => MaskPrf::Aes256Ctr,
+            prf => return Err(NSEError::InvalidInput(format!("Unknown mask PRF {}!", prf))),
+        };
+        let config = Config {
+            k: self.k,
+            num_nodes_window: to_usize(self.num_nodes_window, "num_nodes_window")?,
+            degree_expander: to_usize(self.degree_expander, "degree_expander")?,
+            degree_butterfly: to_usize(self.degree_butterfly, "degree_butterfly")?,
+            num_expander_layers: to_usize(self.num_expander_layers, "num_expander_layers")?,
+            num_butterfly_layers: to_usize(self.num_butterfly_layers, "num_butterfly_layers")?,
+            encoding_mode,
+            domain_tags: DomainTags {
+                mask: self.domain_tag_mask,
+                expander: self.domain_tag_expander,
+                butterfly: self.domain_tag_butterfly,
+            },
+            mask_prf,
+        };
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = RefCell::new(None);
+}
+
+fn set_last_error(message: String) {
+    // Interior NUL bytes would truncate the message, drop them.
+    let message = CString::new(message.replace('\0', "")).unwrap();
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(message));
+}
+
+fn status(error: &NSEError) -> i32 {
+    match error {
+        NSEError::InvalidInput(_)
+        | NSEError::InvalidRange { .. }
+        | NSEError::InvalidDataNode(_) => NSE_GPU_INVALID_INPUT,
+        NSEError::GPU(_)
+        | NSEError::NoGpuSupport
+        | NSEError::NoDevice(_)
+        | NSEError::KernelTimeout(_)
+        | NSEError::InsufficientDeviceMemory { .. }
+        | NSEError::SpotCheckFailed { .. }
+        | NSEError::RoundtripMismatch { .. }
+        | NSEError::FieldMismatch { .. }
+        | NSEError::Divergence { .. } => NSE_GPU_DEVICE_ERROR,
+        _ => NSE_GPU_ERROR,
+    }
+}
+
+// Runs `f`, turning errors and panics into status codes. The last error is cleared first, so
+// that it is only set if this call fails.
+fn call<F: FnOnce() -> NSEResult<()>>(f: F) -> i32 {
+    LAST_ERROR.with(|last| *last.borrow_mut() = None);
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(())) => NSE_GPU_OK,
+        Ok(Err(e)) => {
+            set_last_error(e.to_string());
+            status(&e)
+        }
+        Err(_) => {
+            set_last_error("Panicked!".into());
+            NSE_GPU_PANIC
+        }
+    }
+}
+
+fn non_null<T>(p: *const T, name: &str) -> NSEResult<()> {
+    if p.is_null() {
+        return Err(NSEError::InvalidInput(format!("`{}` is null!", name)));
+    }
+    Ok(())
+}
+
+fn gpu(device_index: usize, config: Config) -> NSEResult<GPU> {
+    let devices = utils::all_devices()?;
+    let device = *devices.get(device_index).ok_or_else(|| {
+        NSEError::InvalidInput(format!(
+            "No device {}, {} devices found!",
+            device_index,
+            devices.len()
+        ))
+    })?;
+    GPU::new(
+        GPUContext::new(device, config, TreeOptions::Disabled)?,
+        config,
+    )
+}
+
+/// A GPU sealing and unsealing windows of a single config, see `nse_gpu_create`.
+#[allow(non_camel_case_types)]
+pub struct nse_gpu {
+    gpu: GPU,
+    config: Config,
+}
+
+/// Message of the error of the last call of the calling thread to this API, valid until its
+/// next call. Null if the last call succeeded.
+#[no_mangle]
+pub extern "C" fn nse_gpu_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| match &*last.borrow() {
+        Some(message) => message.as_ptr(),
+        None => ptr::null(),
+    })
+}
+
+/// # Safety
+/// `count` must be valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn nse_gpu_device_count(count: *mut usize) -> i32 {
+    call(|| {
+        non_null(count, "count")?;
+        *count = utils::all_devices()?.len();
+        Ok(())
+    })
+}
+
+/// # Safety
+/// `name` must be valid for writes of `name_len` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn nse_gpu_device_name(
+    device_index: usize,
+    name: *mut c_char,
+    name_len: usize,
+) -> i32 {
+    call(|| {
+        non_null(name, "name")?;
+        let devices = utils::all_devices()?;
+        let device = devices
+            .get(device_index)
+            .ok_or_else(|| NSEError::InvalidInput(format!("No device {}!", device_index)))?;
+        let device_name = device.name()?;
+        if name_len <= device_name.len() {
+            return Err(NSEError::InvalidInput(format!(
+                "Name of {} bytes does not fit in {} bytes!",
+                device_name.len(),
+                name_len
+            )));
+        }
+        let out = slice::from_raw_parts_mut(name as *mut u8, name_len);
+        out[..device_name.len()].copy_from_slice(device_name.as_bytes());
+        out[device_name.len()] = 0;
+        Ok(())
+    })
+}
+
+/// # Safety
+/// `config` must point to a config, `gpu` must be valid for writes. The GPU written there must
+/// be freed with `nse_gpu_destroy`.
+#[no_mangle]
+pub unsafe extern "C" fn nse_gpu_create(
+    device_index: usize,
+    config: *const nse_gpu_config,
+    out: *mut *mut nse_gpu,
+) -> i32 {
+    call(|| {
+        non_null(config, "config")?;
+        non_null(out, "gpu")?;
+        let config = (*config).to_config()?;
+        let handle = nse_gpu {
+            gpu: gpu(device_index, config)?,
+            config,
+        };
+        *out = Box::into_raw(Box::new(handle));
+        Ok(())
+    })
+}
+
+/// # Safety
+/// `gpu` must be null or have been created by `nse_gpu_create`, and not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn nse_gpu_destroy(gpu: *mut nse_gpu) {
+    if !gpu.is_null() {
+        drop(Box::from_raw(gpu));
+    }
+}
+
+// Sizes are 64-bit in the C API, they may not fit in `usize` on 32-bit targets.
+fn to_usize(value: u64, name: &str) -> NSEResult<usize> {
+    usize::try_from(value)
+        .map_err(|_| NSEError::InvalidInput(format!("{} {} does not fit in usize!", name, value)))
+}
+
+/// # Safety
+/// `gpu` must have been created by `nse_gpu_create`, `replica_id` must point to 32 bytes,
+/// `data` and `replica` to `data_len` bytes each.
+#[no_mangle]
+pub unsafe extern "C" fn nse_gpu_seal_window(
+    gpu: *mut nse_gpu,
+    replica_id: *const u8,
+    window_index: u64,
+    data: *const u8,
+    data_len: usize,
+    replica: *mut u8,
+) -> i32 {
+    call(|| {
+        non_null(gpu, "gpu")?;
+        non_null(replica_id, "replica_id")?;
+        non_null(data, "data")?;
+        non_null(replica, "replica")?;
+        let handle = &mut *gpu;
+        let config = handle.config;
+        let window_bytes = config.leaf_count() * NODE_SIZE;
+        if data_len != window_bytes {
+            return Err(NSEError::InvalidInput(format!(
+                "Window data has {} bytes, expected {}!",
+                data_len, window_bytes
+            )));
+        }
+        let mut id = [0u8; 32];
+        id.copy_from_slice(slice::from_raw_parts(replica_id, 32));
+        let input = SealerInput {
+            replica_id: ReplicaId(id),
+            window_index: WindowIndex(window_index),
+            original_data: Layer::try_from_bytes(slice::from_raw_parts(data, data_len))?,
+        };
+        let output = Sealer::builder(config, input)
+            .retain_key_layers(false)
+            .build(&mut handle.gpu)?
+            .seal()?;
+        let bytes = Vec::<u8>::from(&output.layers[output.layers.len() - 1].base);
+        slice::from_raw_parts_mut(replica, window_bytes).copy_from_slice(&bytes);
+        Ok(())
+    })
+}
+
+/// # Safety
+/// `gpu` must have been created by `nse_gpu_create`, `replica_id` must point to 32 bytes,
+/// `sealed` and `unsealed` to `sealed_len` bytes each.
+#[no_mangle]
+pub unsafe extern "C" fn nse_gpu_unseal_range(
+    gpu: *mut nse_gpu,
+    replica_id: *const u8,
+    window_index: u64,
+    offset: u64,
+    sealed: *const u8,
+    sealed_len: usize,
+    unsealed: *mut u8,
+) -> i32 {
+    call(|| {
+        non_null(gpu, "gpu")?;
+        non_null(replica_id, "replica_id")?;
+        non_null(sealed, "sealed")?;
+        non_null(unsealed, "unsealed")?;
+        let handle = &mut *gpu;
+        let config = handle.config;
+        let mut id = [0u8; 32];
+        id.copy_from_slice(slice::from_raw_parts(replica_id, 32));
+        let sealed_data = Layer::try_from_bytes(slice::from_raw_parts(sealed, sealed_len))?;
+        let nodes = Unsealer::new(
+            config,
+            ReplicaId(id),
+            WindowIndex(window_index),
+            &mut handle.gpu,
+        )?
+        .unseal_range(to_usize(offset, "offset")?, &sealed_data.0)?;
+        let bytes = Vec::<u8>::from(&Layer(nodes));
+        slice::from_raw_parts_mut(unsealed, sealed_len).copy_from_slice(&bytes);
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::CStr;
+
+    const TEST_CONFIG: nse_gpu_config = nse_gpu_config {
+        k: 2,
+        num_nodes_window: 512,
+        degree_expander: 12,
+        degree_butterfly: 4,
+        num_expander_layers: 3,
+        num_butterfly_layers: 2,
+        encoding_mode: 0,
+        domain_tag_mask: 0,
+        domain_tag_expander: 0,
+        domain_tag_butterfly: 0,
+        mask_prf: 0,
+    };
+
+    #[test]
+    fn test_capi() {
+        let replica_id = [7u8; 32];
+        let data = Vec::<u8>::from(&Layer::random(&mut rand::thread_rng(), 512));
+        let mut replica = vec![0u8; data.len()];
+        let mut unsealed = vec![0u8; 100 * NODE_SIZE];
+        unsafe {
+            let mut gpu = ptr::null_mut();
+            assert_eq!(NSE_GPU_OK, nse_gpu_create(0, &TEST_CONFIG, &mut gpu));
+            assert!(nse_gpu_last_error().is_null());
+            assert_eq!(
+                NSE_GPU_OK,
+                nse_gpu_seal_window(
+                    gpu,
+                    replica_id.as_ptr(),
+                    3,
+                    data.as_ptr(),
+                    data.len(),
+                    replica.as_mut_ptr()
+                )
+            );
+            assert_eq!(
+                NSE_GPU_OK,
+                nse_gpu_unseal_range(
+                    gpu,
+                    replica_id.as_ptr(),
+                    3,
+                    10,
+                    replica[10 * NODE_SIZE..].as_ptr(),
+                    unsealed.len(),
+                    unsealed.as_mut_ptr()
+                )
+            );
+            assert_eq!(&data[10 * NODE_SIZE..110 * NODE_SIZE], unsealed.as_slice());
+
+            let invalid = nse_gpu_config {
+                encoding_mode: 2,
+                ..TEST_CONFIG
+            };
+            let mut invalid_gpu = ptr::null_mut();
+            assert_eq!(
+                NSE_GPU_INVALID_INPUT,
+                nse_gpu_create(0, &invalid, &mut invalid_gpu)
+            );
+            assert!(invalid_gpu.is_null());
+            let error = CStr::from_ptr(nse_gpu_last_error());
+            assert!(error.to_str().unwrap().contains("encoding mode"));
+
+            // Errors are cleared by the next call.
+            assert_eq!(
+                NSE_GPU_INVALID_INPUT,
+                nse_gpu_seal_window(
+                    gpu,
+                    replica_id.as_ptr(),
+                    3,
+                    data.as_ptr(),
+                    data.len() - 1,
+                    replica.as_mut_ptr()
+                )
+            );
+            assert!(!nse_gpu_last_error().is_null());
+            let mut count = 0;
+            assert_eq!(NSE_GPU_OK, nse_gpu_device_count(&mut count));
+            assert!(nse_gpu_last_error().is_null());
+
+            nse_gpu_destroy(gpu);
+            nse_gpu_destroy(ptr::null_mut());
+        }
+    }
+}
@@ -1,13 +1,10 @@
-__kernel void generate_butterfly(__global Fr *input,
-                                 __global Fr *output,
-                                 replica_id id,
-                                 uint window_index,
-                                 uint layer_index) {
-
-  uint v = get_global_id(0); // Nodes are processed in parallel
-  ulong node_absolute_index = (ulong)window_index * N + v;
-
+Fr butterfly_label(layer input,
+                   replica_id id,
+                   ulong window_index,
+                   uint layer_index,
+                   ulong v) {
   uint factor = 1 << (LOG2_DEGREE_BUTTERFLY * (NUM_LAYERS - layer_index));
+  ulong node_absolute_index = window_index * N + v;
 
   sha256_domain state = sha256_INIT;
   state = sha256_update(state, hash_prefix(layer_index, node_absolute_index, id));
@@ -16,13 +13,36 @@ __kernel void generate_butterfly(__global Fr *input,
     uint i_1 = i * 2;
     uint i_2 = i * 2 + 1;
 
-    uint parent_1 = (v + i_1 * factor) & MODULO_N_MASK;
-    uint parent_2 = (v + i_2 * factor) & MODULO_N_MASK;
+    ulong parent_1 = (v + i_1 * factor) & MODULO_N_MASK;
+    ulong parent_2 = (v + i_2 * factor) & MODULO_N_MASK;
 
-    state = sha256_update(state, Fr_to_sha256_block(input[parent_1], input[parent_2]));
+    state = sha256_update(state, Fr_to_sha256_block(NODE(input, parent_1), NODE(input, parent_2)));
   }
 
   state = sha256_finish(state, DEGREE_BUTTERFLY / 2 + 1);
 
-  output[v] = sha256_domain_to_Fr(state);
+  return sha256_domain_to_Fr(state);
+}
+
+__kernel void generate_butterfly(LAYER_ARGS(input),
+                                 LAYER_ARGS(output),
+                                 replica_id id,
+                                 ulong window_index,
+                                 uint layer_index) {
+  layer in = LAYER(input), out = LAYER(output);
+  FOR_EACH_NODE(v) // Nodes are processed in parallel
+    NODE(out, v) = butterfly_label(in, id, window_index, layer_index, v);
+}
+
+__kernel void generate_butterfly_batch(__global Fr *input,
+                                       __global Fr *output,
+                                       __global replica_id *ids,
+                                       __global ulong *window_indices,
+                                       uint layer_index,
+                                       uint batch_size) {
+  FOR_EACH_BATCH_NODE(i, batch_size) {
+    uint window = i / N;
+    output[i] = butterfly_label(whole_layer(input + (ulong)window * N), ids[window],
+                                window_indices[window], layer_index, i % N);
+  }
 }
@@ -1,16 +1,88 @@
-__kernel void combine_segment(__global Fr *mask,
-                              __global Fr *data,
-                              uint offset,
-                              uint len,
-                              uint is_decode) {
-  uint node = get_global_id(0); // Nodes are processed in parallel
-
-  // TODO: Delete this in future, and limit global work size
-  if(node < offset || node >= offset + len)
-    return;
+// Values of `EncodingMode`
+#define ENCODING_FIELD_ADD (0)
+#define ENCODING_XOR (1)
 
+// `data` and `mask` are in Montgomery form.
+Fr combine_node(Fr data, Fr mask, uint is_decode, uint mode) {
+  if(mode == ENCODING_XOR) {
+    // XOR is its own inverse, `is_decode` makes no difference. Only the low 253 bits are
+    // combined, so that the result is a field element whatever the data, as `XOR_MASK` on the
+    // host.
+    Fr d = Fr_unmont(data);
+    Fr m = Fr_unmont(mask);
+    for(uint i = 0; i < Fr_LIMBS; i++)
+      d.val[i] ^= m.val[i];
+    d.val[Fr_LIMBS - 1] = (d.val[Fr_LIMBS - 1] << 3) >> 3;
+    return Fr_mont(d);
+  }
   if(is_decode)
-    data[node] = Fr_sub(data[node], mask[node]);
+    return Fr_sub(data, mask);
   else
-    data[node] = Fr_add(data[node], mask[node]);
+    return Fr_add(data, mask);
+}
+
+__kernel void combine_segment(LAYER_ARGS(mask),
+                              LAYER_ARGS(data),
+                              ulong offset,
+                              ulong len,
+                              uint is_decode,
+                              uint mode) {
+  layer m = LAYER(mask), d = LAYER(data);
+  FOR_EACH_NODE(node) { // Nodes are processed in parallel
+
+    // TODO: Delete this in future, and limit global work size
+    if(node < offset || node >= offset + len)
+      continue;
+
+    NODE(d, node) = combine_node(NODE(d, node), NODE(m, node), is_decode, mode);
+  }
+}
+
+// Combines segments laid out one after another in `data`. Segment `s` starts at node
+// `starts[s]` of `data` and node `offsets[s]` of the window, and is decoded if `is_decode[s]`.
+__kernel void combine_segments(LAYER_ARGS(mask),
+                               __global Fr *data,
+                               __global ulong *starts,
+                               __global ulong *offsets,
+                               __global uint *is_decode,
+                               uint count,
+                               ulong len,
+                               uint mode) {
+  layer m = LAYER(mask);
+  for(ulong i = get_global_id(0); i < len; i += get_global_size(0)) {
+    // Binary search of the last segment starting at or before `i`.
+    uint s = 0, last = count - 1;
+    while(s < last) {
+      uint mid = (s + last + 1) / 2;
+      if(starts[mid] <= i) s = mid;
+      else last = mid - 1;
+    }
+    data[i] = combine_node(data[i], NODE(m, offsets[s] + (i - starts[s])), is_decode[s], mode);
+  }
+}
+
+// The last butterfly layer, labeled from `input` and combined right away with `data`, without
+// the key ever being written to device memory, see `GPU::label_butterfly_and_combine`.
+__kernel void generate_butterfly_combine(LAYER_ARGS(input),
+                                         LAYER_ARGS(data),
+                                         replica_id id,
+                                         ulong window_index,
+                                         uint layer_index,
+                                         uint is_decode,
+                                         uint mode) {
+  layer in = LAYER(input), d = LAYER(data);
+  FOR_EACH_NODE(v) { // Nodes are processed in parallel
+    Fr key = Fr_mont(butterfly_label(in, id, window_index, layer_index, v));
+    NODE(d, v) = combine_node(NODE(d, v), key, is_decode, mode);
+  }
+}
+
+__kernel void combine_batch(__global Fr *mask,
+                            __global Fr *data,
+                            uint is_decode,
+                            uint mode,
+                            uint batch_size) {
+  FOR_EACH_BATCH_NODE(i, batch_size) {
+    data[i] = combine_node(data[i], mask[i], is_decode, mode);
+  }
 }
@@ -0,0 +1,33 @@
+// Binary SHA-254 tree of the data commitment (CommD), see `DataCommitment`. Each kernel computes
+// one level of a subtree: `output[i]` is the hash of nodes `2i` and `2i + 1` of the level below,
+// in ordinary form.
+
+Fr commitment_hash(Fr a, Fr b) {
+  return sha256_domain_to_Fr(sha256(Fr_to_sha256_block(a, b)));
+}
+
+// Lowest level, over the `2 * count` nodes of `data` starting at `offset`, in Montgomery form if
+// `montgomery`, in ordinary form otherwise (e.g. the current layer, see `GPU::layer_digest`).
+__kernel void commitment_leaves(LAYER_ARGS(data),
+                                ulong offset,
+                                __global Fr *output,
+                                ulong count,
+                                uint montgomery) {
+  layer d = LAYER(data);
+  for(ulong i = get_global_id(0); i < count; i += get_global_size(0)) {
+    Fr a = NODE(d, offset + 2 * i);
+    Fr b = NODE(d, offset + 2 * i + 1);
+    if(montgomery) {
+      a = Fr_unmont(a);
+      b = Fr_unmont(b);
+    }
+    output[i] = commitment_hash(a, b);
+  }
+}
+
+__kernel void commitment_level(__global Fr *input,
+                               __global Fr *output,
+                               ulong count) {
+  for(ulong i = get_global_id(0); i < count; i += get_global_size(0))
+    output[i] = commitment_hash(input[2 * i], input[2 * i + 1]);
+}
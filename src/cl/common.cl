@@ -2,25 +2,103 @@
 #define NUM_LAYERS (NUM_EXPANDER_LAYERS + NUM_BUTTERFLY_LAYERS)
 #define MODULO_N_MASK (N - 1)
 
+// Kernels may be launched with fewer work-items than nodes, in which case each work-item
+// processes every `get_global_size(0)`th node. `N` may be as big as 2^32, the loop variable
+// needs to be 64-bit for the loop to terminate.
+#define FOR_EACH_NODE(node) \
+  for(ulong node = get_global_id(0); node < N; node += get_global_size(0))
+
+// Same as `FOR_EACH_NODE`, over the `batch_size * N` nodes of a batch of windows
+// laid out one after another.
+#define FOR_EACH_BATCH_NODE(i, batch_size) \
+  for(ulong i = get_global_id(0); i < (ulong)(batch_size) * N; i += get_global_size(0))
+
 typedef struct {
   uint vals[8];
 } replica_id;
 
-__kernel void to_montgomery(__global Fr *buffer) {
-  uint node = get_global_id(0);
-  buffer[node] = Fr_mont(buffer[node]);
+// A layer may be split into up to `MAX_LAYER_CHUNKS` device buffers of `2^shift` nodes each,
+// see `GpuConfig::max_alloc_chunk`. Kernels take the chunks of a layer (unused ones repeat the
+// first one) and the shift as separate arguments, declared by `LAYER_ARGS`.
+#define MAX_LAYER_CHUNKS (4)
+#define LAYER_ARGS(name) \
+  __global Fr *name##_0, __global Fr *name##_1, __global Fr *name##_2, __global Fr *name##_3, \
+  uint name##_shift
+#define LAYER(name) {{name##_0, name##_1, name##_2, name##_3}, name##_shift}
+// Shift of layers held in a single buffer, windows have at most 2^32 nodes.
+#define WHOLE_LAYER_SHIFT (32)
+
+typedef struct {
+  __global Fr *chunks[MAX_LAYER_CHUNKS];
+  uint shift;
+} layer;
+
+layer whole_layer(__global Fr *nodes) {
+  layer l = {{nodes, nodes, nodes, nodes}, WHOLE_LAYER_SHIFT};
+  return l;
+}
+
+__global Fr *layer_node(layer *l, ulong i) {
+  return l->chunks[i >> l->shift] + (i & ((1ul << l->shift) - 1));
+}
+
+// The `i`th node of layer `l`, usable on both sides of an assignment.
+#define NODE(l, i) (*layer_node(&(l), (i)))
+
+__kernel void to_montgomery(LAYER_ARGS(buffer)) {
+  layer buf = LAYER(buffer);
+  FOR_EACH_NODE(node)
+    NODE(buf, node) = Fr_mont(NODE(buf, node));
+}
+
+__kernel void generate_montgomery(LAYER_ARGS(input),
+                                  LAYER_ARGS(output)) {
+  layer in = LAYER(input), out = LAYER(output);
+  FOR_EACH_NODE(node)
+    NODE(out, node) = Fr_mont(NODE(in, node));
+}
+
+__kernel void generate_ordinary(LAYER_ARGS(input),
+                                LAYER_ARGS(output)) {
+  layer in = LAYER(input), out = LAYER(output);
+  FOR_EACH_NODE(node)
+    NODE(out, node) = Fr_unmont(NODE(in, node));
+}
+
+// SplitMix64 finalizer, a cheap generator of test data, not meant to be secure.
+ulong splitmix64(ulong x) {
+  x += 0x9e3779b97f4a7c15;
+  x = (x ^ (x >> 30)) * 0xbf58476d1ce4e5b9;
+  x = (x ^ (x >> 27)) * 0x94d049bb133111eb;
+  return x ^ (x >> 31);
 }
 
-__kernel void generate_montgomery(__global Fr *input,
-                                  __global Fr *output) {
-  uint node = get_global_id(0);
-  output[node] = Fr_mont(input[node]);
+// Pseudo-random nodes in ordinary form, see `Layer::from_seed`. Limb `j` of node `i` is
+// derived from `seed + i * Fr_LIMBS + j`, and the top 3 bits are cleared so that nodes are
+// below the modulus.
+__kernel void generate_random(LAYER_ARGS(output),
+                              ulong seed) {
+  layer out = LAYER(output);
+  FOR_EACH_NODE(node) {
+    Fr x;
+    for(uint j = 0; j < Fr_LIMBS; j++)
+      x.val[j] = splitmix64(seed + node * Fr_LIMBS + j);
+    x.val[Fr_LIMBS - 1] &= 0x1ffffffffffffffful;
+    NODE(out, node) = x;
+  }
 }
 
-__kernel void generate_ordinary(__global Fr *input,
-                                __global Fr *output) {
-  uint node = get_global_id(0);
-  output[node] = Fr_unmont(input[node]);
+__kernel void to_montgomery_batch(__global Fr *buffer,
+                                  uint batch_size) {
+  FOR_EACH_BATCH_NODE(i, batch_size)
+    buffer[i] = Fr_mont(buffer[i]);
+}
+
+__kernel void generate_montgomery_batch(__global Fr *input,
+                                        __global Fr *output,
+                                        uint batch_size) {
+  FOR_EACH_BATCH_NODE(i, batch_size)
+    output[i] = Fr_mont(input[i]);
 }
 
 uint reverse_bytes(uint a) {
@@ -28,6 +106,13 @@ uint reverse_bytes(uint a) {
          (a & 0x00ff0000) >> 8 | (a & 0xff000000) >> 24;
 }
 
+// Domain separation tag of the labels of layer `layer_index` (1-based).
+uint domain_tag(uint layer_index) {
+  if(layer_index == 1) return DOMAIN_TAG_MASK;
+  else if(layer_index <= NUM_EXPANDER_LAYERS) return DOMAIN_TAG_EXPANDER;
+  else return DOMAIN_TAG_BUTTERFLY;
+}
+
 sha256_block hash_prefix(uint layer_index, ulong node_absolute_index, replica_id id) {
   sha256_block data = sha256_ZERO;
   data.vals[0] = layer_index;
@@ -37,6 +122,7 @@ sha256_block hash_prefix(uint layer_index, ulong node_absolute_index, replica_id
   // `(node_absolute_index >> 32)` and higher part is `node_absolute_index & 0xffffffff`
   data.vals[1] = node_absolute_index >> 32;
   data.vals[2] = node_absolute_index;
+  data.vals[3] = domain_tag(layer_index);
   for(uint i = 0; i < 8; i++) {
     data.vals[8 + i] = reverse_bytes(id.vals[i]);
   }
@@ -63,3 +149,50 @@ Fr sha256_domain_to_Fr(sha256_domain state) {
   f.val[Fr_LIMBS - 1] >>= 2;
   return f;
 }
+
+// Field arithmetic on `get_global_size(0)` pairs of nodes in Montgomery form, compared with the
+// host by `check_field` when a context is created: `output[2 * i]` is `a * b + a - b` and
+// `output[2 * i + 1]` is `a` in ordinary form.
+__kernel void field_self_check(__global Fr *a,
+                               __global Fr *b,
+                               __global Fr *output) {
+  uint i = get_global_id(0);
+  output[2 * i] = Fr_sub(Fr_add(Fr_mul(a[i], b[i]), a[i]), b[i]);
+  output[2 * i + 1] = Fr_unmont(a[i]);
+}
+
+// Statistics of the nodes of `input`, in Montgomery form if `montgomery`, see
+// `GPU::layer_stats`. Work-item `i` covers nodes `i`, `i + partials`, ... and writes the number
+// of zero nodes and of nodes equal to the node before them to `counts[2 * i]` and
+// `counts[2 * i + 1]`, and the smallest and largest nodes, by value and in Montgomery form, to
+// `extremes[2 * i]` and `extremes[2 * i + 1]`.
+__kernel void layer_stats(LAYER_ARGS(input),
+                          __global ulong *counts,
+                          __global Fr *extremes,
+                          uint partials,
+                          uint montgomery) {
+  uint i = get_global_id(0);
+  if(i >= partials) return;
+  layer in = LAYER(input);
+  ulong zeros = 0, repeated = 0;
+  Fr min = NODE(in, i), max = min;
+  Fr min_value = montgomery ? Fr_unmont(min) : min, max_value = min_value;
+  for(ulong node = i; node < N; node += partials) {
+    Fr x = NODE(in, node);
+    Fr value = montgomery ? Fr_unmont(x) : x;
+    if(Fr_eq(x, Fr_ZERO)) zeros++;
+    if(node > 0 && Fr_eq(x, NODE(in, node - 1))) repeated++;
+    if(!Fr_gte(value, min_value)) {
+      min = x;
+      min_value = value;
+    }
+    if(!Fr_gte(max_value, value)) {
+      max = x;
+      max_value = value;
+    }
+  }
+  counts[2 * i] = zeros;
+  counts[2 * i + 1] = repeated;
+  extremes[2 * i] = montgomery ? min : Fr_mont(min);
+  extremes[2 * i + 1] = montgomery ? max : Fr_mont(max);
+}
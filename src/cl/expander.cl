@@ -1,4 +1,5 @@
 #define BYTE_SIZE (BIT_SIZE / 8)
+#define BYTE_ALIGNED (BIT_SIZE % 8 == 0)
 
 // We are going to generate and store the entire bit-stream per node
 // before running the expander algorithm. Is this a good idea?
@@ -7,6 +8,7 @@ typedef struct {
   sha256_domain bit_source[STREAM_HASH_COUNT];
 } bit_stream;
 
+// Nodes are hashed as 32-bit integers, windows have at most 2^32 nodes.
 bit_stream gen_stream(uint node) {
   bit_stream stream;
   sha256_block data = sha256_ZERO;
@@ -27,10 +29,20 @@ uchar get_byte(bit_stream *stream, uint i) {
 // Result is in the range `[0, 2^BIT_SIZE)`
 uint get_parent(bit_stream *stream, uint i) {
   uint ret = 0;
+#if BYTE_ALIGNED
   for(uint j = 0; j < BYTE_SIZE; j++) {
     uint bt = get_byte(stream, i * BYTE_SIZE + j);
     ret |= (bt << (j * BITS_PER_BYTE));
   }
+#else
+  // Small windows: chunks are not byte aligned, read them bit by bit (least significant first),
+  // which gives the same result as above for whole bytes.
+  for(uint j = 0; j < BIT_SIZE; j++) {
+    uint bit = i * BIT_SIZE + j;
+    uint bt = get_byte(stream, bit / BITS_PER_BYTE);
+    ret |= ((bt >> (bit % BITS_PER_BYTE)) & 1) << j;
+  }
+#endif
   return ret;
 }
 
@@ -47,16 +59,24 @@ uint get_expanded_parent(bit_stream *stream, uint i) {
   return get_parent(stream, x) * K + offset;
 }
 
-__kernel void generate_expander(__global Fr *input,
-                                __global Fr *output,
-                                replica_id id,
-                                uint window_index,
-                                uint layer_index) {
+// Same as `get_expanded_parent`, from the `DEGREE_EXPANDER` non-expanded parents of the node.
+uint get_cached_expanded_parent(__global uint *parents, uint i) {
+  return parents[i >> LOG2_K] * K + (i & (K - 1));
+}
 
-  uint node = get_global_id(0); // Nodes are processed in parallel
-  ulong node_absolute_index = (ulong)window_index * N + node;
+// Parents are read from `parents`, the row of `node` in the parent cache (see
+// `generate_parent_cache`), or recomputed from the bit-stream of `node` if it is null.
+Fr expander_label(layer input,
+                  replica_id id,
+                  ulong window_index,
+                  uint layer_index,
+                  ulong node,
+                  __global uint *parents) {
+  ulong node_absolute_index = window_index * N + node;
 
-  bit_stream stream = gen_stream(node); // 1152 Bytes ~ 1KB
+  bit_stream stream; // 1152 Bytes ~ 1KB
+  if(!parents)
+    stream = gen_stream((uint)node);
 
   sha256_domain state = sha256_INIT;
   state = sha256_update(state, hash_prefix(layer_index, node_absolute_index, id));
@@ -69,11 +89,13 @@ __kernel void generate_expander(__global Fr *input,
     Fr x_2 = Fr_ZERO;
 
     for(uint j = 0; j < K; j++) {
-      uint parent_1 = get_expanded_parent(&stream, i_1 + j * DEGREE_EXPANDER);
-      uint parent_2 = get_expanded_parent(&stream, i_2 + j * DEGREE_EXPANDER);
+      uint parent_1 = parents ? get_cached_expanded_parent(parents, i_1 + j * DEGREE_EXPANDER)
+                              : get_expanded_parent(&stream, i_1 + j * DEGREE_EXPANDER);
+      uint parent_2 = parents ? get_cached_expanded_parent(parents, i_2 + j * DEGREE_EXPANDER)
+                              : get_expanded_parent(&stream, i_2 + j * DEGREE_EXPANDER);
 
-      x_1 = Fr_add(x_1, input[parent_1]);
-      x_2 = Fr_add(x_2, input[parent_2]);
+      x_1 = Fr_add(x_1, NODE(input, parent_1));
+      x_2 = Fr_add(x_2, NODE(input, parent_2));
     }
 
     state = sha256_update(state, Fr_to_sha256_block(x_1, x_2));
@@ -81,5 +103,50 @@ __kernel void generate_expander(__global Fr *input,
 
   state = sha256_finish(state, DEGREE_EXPANDER / 2 + 1);
 
-  output[node] = sha256_domain_to_Fr(state);
+  return sha256_domain_to_Fr(state);
+}
+
+__kernel void generate_expander(LAYER_ARGS(input),
+                                LAYER_ARGS(output),
+                                replica_id id,
+                                ulong window_index,
+                                uint layer_index) {
+  layer in = LAYER(input), out = LAYER(output);
+  FOR_EACH_NODE(node) // Nodes are processed in parallel
+    NODE(out, node) = expander_label(in, id, window_index, layer_index, node, 0);
+}
+
+// Non-expanded parents of every node, `DEGREE_EXPANDER` per node. They only depend on the
+// config, so the table is computed once and shared by all expander layers of all windows.
+__kernel void generate_parent_cache(__global uint *parents) {
+  FOR_EACH_NODE(node) { // Nodes are processed in parallel
+    bit_stream stream = gen_stream((uint)node);
+    for(uint i = 0; i < DEGREE_EXPANDER; i++)
+      parents[node * DEGREE_EXPANDER + i] = get_parent(&stream, i);
+  }
+}
+
+__kernel void generate_expander_cached(LAYER_ARGS(input),
+                                       LAYER_ARGS(output),
+                                       __global uint *parents,
+                                       replica_id id,
+                                       ulong window_index,
+                                       uint layer_index) {
+  layer in = LAYER(input), out = LAYER(output);
+  FOR_EACH_NODE(node) // Nodes are processed in parallel
+    NODE(out, node) = expander_label(in, id, window_index, layer_index, node,
+                                     parents + node * DEGREE_EXPANDER);
+}
+
+__kernel void generate_expander_batch(__global Fr *input,
+                                      __global Fr *output,
+                                      __global replica_id *ids,
+                                      __global ulong *window_indices,
+                                      uint layer_index,
+                                      uint batch_size) {
+  FOR_EACH_BATCH_NODE(i, batch_size) {
+    uint window = i / N;
+    output[i] = expander_label(whole_layer(input + (ulong)window * N), ids[window],
+                               window_indices[window], layer_index, i % N, 0);
+  }
 }
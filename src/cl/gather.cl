@@ -0,0 +1,54 @@
+// Kernels reading a handful of nodes of the current layer, e.g. challenged nodes and their
+// parents, so that proofs don't need whole layers to be downloaded. They all take the same
+// arguments; `indices` are `count` node indices within the window and `input` is in ordinary
+// form, unless `is_montgomery` is set. Output nodes are in Montgomery form.
+
+Fr gather_node(layer input, uint node, uint is_montgomery) {
+  return is_montgomery ? NODE(input, node) : Fr_mont(NODE(input, node));
+}
+
+__kernel void gather_nodes(LAYER_ARGS(input),
+                           __global uint *indices,
+                           __global Fr *output,
+                           uint layer_index,
+                           uint count,
+                           uint is_montgomery) {
+  layer in = LAYER(input);
+  for(uint i = get_global_id(0); i < count; i += get_global_size(0))
+    output[i] = gather_node(in, indices[i], is_montgomery);
+}
+
+// `K * DEGREE_EXPANDER` expanded parents per node of expander layer `layer_index`,
+// which are read from the previous layer.
+__kernel void gather_expander_parents(LAYER_ARGS(input),
+                                      __global uint *indices,
+                                      __global Fr *output,
+                                      uint layer_index,
+                                      uint count,
+                                      uint is_montgomery) {
+  layer in = LAYER(input);
+  for(uint i = get_global_id(0); i < count; i += get_global_size(0)) {
+    bit_stream stream = gen_stream(indices[i]);
+    for(uint p = 0; p < K * DEGREE_EXPANDER; p++)
+      output[(ulong)i * K * DEGREE_EXPANDER + p] =
+        gather_node(in, get_expanded_parent(&stream, p), is_montgomery);
+  }
+}
+
+// `DEGREE_BUTTERFLY` parents per node of butterfly layer `layer_index`, which are read from
+// the previous layer.
+__kernel void gather_butterfly_parents(LAYER_ARGS(input),
+                                       __global uint *indices,
+                                       __global Fr *output,
+                                       uint layer_index,
+                                       uint count,
+                                       uint is_montgomery) {
+  layer in = LAYER(input);
+  uint factor = 1 << (LOG2_DEGREE_BUTTERFLY * (NUM_LAYERS - layer_index));
+  for(uint i = get_global_id(0); i < count; i += get_global_size(0)) {
+    uint v = indices[i];
+    for(uint j = 0; j < DEGREE_BUTTERFLY; j++)
+      output[i * DEGREE_BUTTERFLY + j] =
+        gather_node(in, (v + j * factor) & MODULO_N_MASK, is_montgomery);
+  }
+}
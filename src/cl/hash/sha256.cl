@@ -36,10 +36,11 @@ __constant uint SHA256_K[64] =
 #define ChI(x, y, z) ( z ^ (x & ( y ^ z)) )
 #define MajI(x, y, z) ( (x & y) | (z & (x | y)) )
 
-#define S0I(x) ((uint)rotate((uint)x,(uint)30) ^ (uint)rotate((uint)x,(uint)19) ^ (uint)rotate((uint)x,(uint)10))
-#define S1I(x) ((uint)rotate((uint)x,(uint)26) ^ (uint)rotate((uint)x,(uint)21) ^ (uint)rotate((uint)x,(uint)7))
-#define s0I(x) ((uint)rotate((uint)x,(uint)25) ^ (uint)rotate((uint)x,(uint)14) ^ (x>>3))
-#define s1I(x) ((uint)rotate((uint)x,(uint)15) ^ (uint)rotate((uint)x,(uint)13) ^ (x>>10))
+// `ROTL32` is defined by the vendor specific sources (See `src/cl/vendor`)
+#define S0I(x) (ROTL32(x,30) ^ ROTL32(x,19) ^ ROTL32(x,10))
+#define S1I(x) (ROTL32(x,26) ^ ROTL32(x,21) ^ ROTL32(x,7))
+#define s0I(x) (ROTL32(x,25) ^ ROTL32(x,14) ^ ((uint)(x)>>3))
+#define s1I(x) (ROTL32(x,15) ^ ROTL32(x,13) ^ ((uint)(x)>>10))
 
 sha256_domain sha256_update(sha256_domain state, sha256_block data)
 {
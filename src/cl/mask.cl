@@ -1,10 +1,80 @@
-__kernel void generate_mask(__global Fr *output,
+// Labels of the mask layer are the 32 bytes of the seed of their node, read in little-endian
+// order and trimmed to 254 bits, see `mask_label`.
+#if MASK_PRF_AES
+// Seeds are 32 bytes of AES-256-CTR keystream, keyed by the replica id: node `n` of the
+// window is encrypted counters `2n` and `2n + 1`, taken as absolute node indices, the counter
+// blocks starting with the domain tag of the mask layer. The key is expanded again by every
+// node, which is cheap next to the 30 rounds of its two blocks.
+sha256_domain mask_seed(replica_id id,
+                        ulong window_index,
+                        ulong node) {
+  ulong node_absolute_index = window_index * N + node;
+  uint key[8];
+  for(uint i = 0; i < 8; i++)
+    key[i] = reverse_bytes(id.vals[i]);
+  aes256_key k = aes256_expand_key(key);
+  uchar keystream[32];
+  for(uint j = 0; j < 2; j++) {
+    ulong counter = node_absolute_index * 2 + j;
+    aes_block block;
+    for(uint i = 0; i < 4; i++)
+      block.bytes[i] = (uchar)(domain_tag(1) >> (24 - 8 * i));
+    for(uint i = 0; i < 4; i++)
+      block.bytes[4 + i] = 0;
+    for(uint i = 0; i < 8; i++)
+      block.bytes[8 + i] = (uchar)(counter >> (56 - 8 * i));
+    block = aes256_encrypt(&k, block);
+    for(uint i = 0; i < 16; i++)
+      keystream[16 * j + i] = block.bytes[i];
+  }
+  // Big-endian words, like SHA-256 digests.
+  sha256_domain seed;
+  for(uint i = 0; i < 8; i++)
+    seed.vals[i] = ((uint)keystream[4 * i] << 24) | ((uint)keystream[4 * i + 1] << 16) |
+                   ((uint)keystream[4 * i + 2] << 8) | keystream[4 * i + 3];
+  return seed;
+}
+#else
+sha256_domain mask_seed(replica_id id,
+                        ulong window_index,
+                        ulong node) {
+  ulong node_absolute_index = window_index * N + node;
+  uint layer_index = 1; // Mask layer is always layer 1 (Or 0?)
+  return sha256(hash_prefix(layer_index, node_absolute_index, id));
+}
+#endif
+
+Fr mask_label(replica_id id,
+              ulong window_index,
+              ulong node) {
+  return sha256_domain_to_Fr(mask_seed(id, window_index, node));
+}
+
+// The seeds of the nodes of a window, as 8 big-endian words each.
+__kernel void expand_seed(__global uint *output,
+                          replica_id id,
+                          ulong window_index) {
+  FOR_EACH_NODE(node) {
+    sha256_domain seed = mask_seed(id, window_index, node);
+    for(uint i = 0; i < 8; i++)
+      output[node * 8 + i] = seed.vals[i];
+  }
+}
+
+__kernel void generate_mask(LAYER_ARGS(output),
                             replica_id id,
-                            uint window_index) {
+                            ulong window_index) {
+  layer out = LAYER(output);
+  FOR_EACH_NODE(node) // Nodes are processed in parallel
+    NODE(out, node) = mask_label(id, window_index, node);
+}
 
-  uint node_index = get_global_id(0); // Nodes are processed in parallel
-  ulong node_absolute_index = (ulong)window_index * N + node_index;
-  uint layer_index = 1; // Mask layer is always layer 1 (Or 0?)
-  sha256_domain state = sha256(hash_prefix(layer_index, node_absolute_index, id));
-  output[node_index] = sha256_domain_to_Fr(state);
+__kernel void generate_mask_batch(__global Fr *output,
+                                  __global replica_id *ids,
+                                  __global ulong *window_indices,
+                                  uint batch_size) {
+  FOR_EACH_BATCH_NODE(i, batch_size) {
+    uint window = i / N;
+    output[i] = mask_label(ids[window], window_indices[window], i % N);
+  }
 }
@@ -0,0 +1,51 @@
+// Poseidon hashing of columns of nodes, see `GPU::hash_columns`. Round constants and the MDS
+// matrix are not part of the program but passed by the host (they are generated by neptune),
+// so a single program hashes with any arity up to `POSEIDON_MAX_ARITY`. All nodes are in
+// Montgomery form.
+
+#define POSEIDON_MAX_WIDTH (12)
+
+Fr poseidon_sbox(Fr x) {
+  Fr x2 = Fr_sqr(x);
+  return Fr_mul(Fr_sqr(x2), x);
+}
+
+// Hashes `count` preimages of `arity` nodes each, stored one after the other in `input`.
+// Every round adds its `arity + 1` constants to the state, applies the S-boxes (only to the
+// first element during the partial rounds) and multiplies the state by the MDS matrix.
+__kernel void poseidon_hash(__global Fr *input,
+                            __global Fr *output,
+                            __global Fr *round_constants,
+                            __global Fr *mds,
+                            Fr domain_tag,
+                            uint arity,
+                            uint full_rounds,
+                            uint partial_rounds,
+                            uint count) {
+  uint width = arity + 1;
+  uint half_full_rounds = full_rounds / 2;
+  for(uint h = get_global_id(0); h < count; h += get_global_size(0)) {
+    Fr state[POSEIDON_MAX_WIDTH];
+    Fr mixed[POSEIDON_MAX_WIDTH];
+    state[0] = domain_tag;
+    for(uint i = 0; i < arity; i++)
+      state[i + 1] = input[(ulong)h * arity + i];
+
+    for(uint r = 0; r < full_rounds + partial_rounds; r++) {
+      bool full = r < half_full_rounds || r >= half_full_rounds + partial_rounds;
+      for(uint i = 0; i < width; i++) {
+        state[i] = Fr_add(state[i], round_constants[r * width + i]);
+        if(full || i == 0)
+          state[i] = poseidon_sbox(state[i]);
+      }
+      for(uint j = 0; j < width; j++) {
+        mixed[j] = Fr_ZERO;
+        for(uint i = 0; i < width; i++)
+          mixed[j] = Fr_add(mixed[j], Fr_mul(state[i], mds[i * width + j]));
+      }
+      for(uint j = 0; j < width; j++)
+        state[j] = mixed[j];
+    }
+    output[h] = state[1];
+  }
+}
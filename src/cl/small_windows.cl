@@ -0,0 +1,32 @@
+// Labels all layers of `batch_size` windows in a single launch, see `GPU::label_small_windows`.
+// Each work-group labels whole windows, synchronizing between layers, so windows are meant to
+// fit in a work-group. Layers of a window are laid out one after another, followed by those of
+// the next window, and are converted to Montgomery form once all are labeled.
+__kernel void generate_small_windows(__global Fr *layers,
+                                     __global replica_id *ids,
+                                     __global ulong *window_indices,
+                                     uint batch_size) {
+  for(uint window = get_group_id(0); window < batch_size; window += get_num_groups(0)) {
+    __global Fr *window_layers = layers + (ulong)window * NUM_LAYERS * N;
+    replica_id id = ids[window];
+    ulong window_index = window_indices[window];
+
+    for(uint layer_index = 1; layer_index <= NUM_LAYERS; layer_index++) {
+      __global Fr *output = window_layers + (ulong)(layer_index - 1) * N;
+      layer in = whole_layer(layer_index == 1 ? output : output - N);
+      for(ulong node = get_local_id(0); node < N; node += get_local_size(0)) {
+        if(layer_index == 1)
+          output[node] = mask_label(id, window_index, node);
+        else if(layer_index <= NUM_EXPANDER_LAYERS)
+          output[node] = expander_label(in, id, window_index, layer_index, node, 0);
+        else
+          output[node] = butterfly_label(in, id, window_index, layer_index, node);
+      }
+      // The next layer reads nodes labeled by other work-items.
+      barrier(CLK_GLOBAL_MEM_FENCE);
+    }
+
+    for(ulong i = get_local_id(0); i < (ulong)NUM_LAYERS * N; i += get_local_size(0))
+      window_layers[i] = Fr_mont(window_layers[i]);
+  }
+}
@@ -0,0 +1,386 @@
+//! Harness running single kernels of the NSE program on small inputs, so that kernel changes can
+//! be validated without whole seals. Enabled by the `cl_test` feature.
+//!
+//! Kernels work on the ordinary form of nodes, see `to_ordinary` and `from_ordinary`.
+
+use crate::{program_cache, utils, Config, NSEResult, Node};
+use ocl::builders::KernelBuilder;
+use ocl::{Buffer, OclPrm, ProQue};
+use paired::bls12_381::FrRepr;
+
+/// Shift of layers held in a single buffer, see `WHOLE_LAYER_SHIFT` in the kernels.
+const WHOLE_LAYER_SHIFT: u32 = 32;
+
+/// Compiles the program of a config on the default device, and runs its kernels one by one.
+pub struct KernelHarness {
+    pro_que: ProQue,
+    config: Config,
+}
+
+impl KernelHarness {
+    pub fn new(config: Config) -> NSEResult<Self> {
+        config.validate()?;
+        let pro_que = program_cache::pro_que(utils::default_device()?, config)?;
+        Ok(KernelHarness { pro_que, config })
+    }
+
+    pub fn config(&self) -> Config {
+        self.config
+    }
+
+    /// Copies `data` into a new device buffer.
+    pub fn buffer<T: OclPrm>(&self, data: &[T]) -> NSEResult<Buffer<T>> {
+        Ok(Buffer::<T>::builder()
+            .queue(self.pro_que.queue().clone())
+            .len(data.len())
+            .copy_host_slice(data)
+            .build()?)
+    }
+
+    /// Reads a whole device buffer back.
+    pub fn read<T: OclPrm>(&self, buffer: &Buffer<T>) -> NSEResult<Vec<T>> {
+        let mut data = vec![T::default(); buffer.len()];
+        buffer.read(&mut data).enq()?;
+        Ok(data)
+    }
+
+    /// Runs kernel `name` with one work-item per element of `global_work_size`, `set_args`
+    /// pushing its arguments in order, and waits for it to finish.
+    pub fn run<'a, F: FnOnce(&mut KernelBuilder<'a>)>(
+        &'a self,
+        name: &str,
+        global_work_size: usize,
+        set_args: F,
+    ) -> NSEResult<()> {
+        let mut builder = self.pro_que.kernel_builder(name);
+        builder.global_work_size([global_work_size]);
+        set_args(&mut builder);
+        let kernel = builder.build()?;
+        unsafe {
+            kernel.enq()?;
+        }
+        self.pro_que.queue().finish()?;
+        Ok(())
+    }
+}
+
+/// Pushes the arguments of a layer held in a single buffer (see `LAYER_ARGS` in the kernels).
+pub fn layer_arg<'b>(kernel: &mut KernelBuilder<'b>, nodes: &'b Buffer<Node>) {
+    for _ in 0..crate::MAX_LAYER_CHUNKS {
+        kernel.arg(nodes);
+    }
+    kernel.arg(WHOLE_LAYER_SHIFT);
+}
+
+/// The ordinary form of a node read from a kernel, which isn't in Montgomery form.
+pub fn to_ordinary(node: Node) -> FrRepr {
+    // Safe: `Node` is a transparent wrapper around the limbs of an `Fr`.
+    unsafe { std::mem::transmute::<Node, FrRepr>(node) }
+}
+
+/// A node holding `repr` as is, i.e. in ordinary form, to be passed to a kernel.
+pub fn from_ordinary(repr: FrRepr) -> Node {
+    // Safe: `Node` is a transparent wrapper around the limbs of an `Fr`.
+    unsafe { std::mem::transmute::<FrRepr, Node>(repr) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{CombineMode, DomainTags, EncodingMode, MaskPrf, ReplicaId};
+    use ff::{Field, PrimeField};
+    use paired::bls12_381::Fr;
+    use sha2::{Digest, Sha256};
+
+    const TAGGED_TEST_CONFIG: Config = Config {
+        k: 2,
+        num_nodes_window: 512,
+        degree_expander: 12,
+        degree_butterfly: 4,
+        num_expander_layers: 3,
+        num_butterfly_layers: 2,
+        encoding_mode: EncodingMode::FieldAdd,
+        domain_tags: DomainTags {
+            mask: 1,
+            expander: 2,
+            butterfly: 3,
+        },
+        mask_prf: MaskPrf::Sha256,
+    };
+    const TEST_WINDOW_INDEX: u64 = 42;
+    const TEST_REPLICA_ID: ReplicaId = ReplicaId([7u8; 32]);
+
+    fn harness() -> KernelHarness {
+        KernelHarness::new(TAGGED_TEST_CONFIG).unwrap()
+    }
+
+    // Sum of nodes in ordinary form, as output by the labeling kernels.
+    fn accumulate_ordinary(nodes: &[Node]) -> Fr {
+        let mut acc = Fr::zero();
+        for n in nodes.iter() {
+            acc.add_assign(&Fr::from_repr(to_ordinary(*n)).unwrap());
+        }
+        acc
+    }
+
+    fn from_hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    // Layer whose node `i` is `i`, in ordinary form.
+    fn ordinary_layer() -> Vec<Node> {
+        (0..TAGGED_TEST_CONFIG.num_nodes_window as u64)
+            .map(|i| from_ordinary(FrRepr::from(i)))
+            .collect()
+    }
+
+    #[test]
+    fn test_sha256_kernel() {
+        let h = harness();
+        let words = (0..16u32)
+            .map(|i| i.wrapping_mul(0x0102_0304))
+            .collect::<Vec<_>>();
+        let data = h.buffer(&words).unwrap();
+        let digest = h.buffer(&[0u32; 8]).unwrap();
+        h.run("sha256_test", 1, |k| {
+            k.arg(&data).arg(&digest);
+        })
+        .unwrap();
+        // The kernel hashes words in big-endian order.
+        let bytes = words
+            .iter()
+            .flat_map(|w| w.to_be_bytes().to_vec())
+            .collect::<Vec<_>>();
+        let expected = Sha256::digest(&bytes)
+            .chunks(4)
+            .map(|c| u32::from_be_bytes([c[0], c[1], c[2], c[3]]))
+            .collect::<Vec<_>>();
+        assert_eq!(expected, h.read(&digest).unwrap());
+    }
+
+    #[test]
+    fn test_aes256_kernel() {
+        // Example vector of FIPS-197, appendix C.3.
+        let h = harness();
+        let key_bytes = (0..32u8).collect::<Vec<_>>();
+        let key_words = key_bytes
+            .chunks(4)
+            .map(|c| u32::from_be_bytes([c[0], c[1], c[2], c[3]]))
+            .collect::<Vec<_>>();
+        let key = h.buffer(&key_words).unwrap();
+        let input = h
+            .buffer(&(0..16u8).map(|i| i * 0x11).collect::<Vec<_>>())
+            .unwrap();
+        let output = h.buffer(&[0u8; 16]).unwrap();
+        h.run("aes256_test", 1, |k| {
+            k.arg(&key).arg(&input).arg(&output);
+        })
+        .unwrap();
+        assert_eq!(
+            vec![
+                0x8e, 0xa2, 0xb7, 0xca, 0x51, 0x67, 0x45, 0xbf, 0xea, 0xfc, 0x49, 0x90, 0x4b, 0x49,
+                0x60, 0x89
+            ],
+            h.read(&output).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_aes256_ctr_kernel() {
+        // AES-256-CTR example vector of NIST SP 800-38A, F.5.5, first block: the keystream is
+        // the encrypted counter block.
+        let h = harness();
+        let key_bytes =
+            from_hex("603deb1015ca71be2b73aef0857d77811f352c073b6108d72d9810a30914dff4");
+        let key_words = key_bytes
+            .chunks(4)
+            .map(|c| u32::from_be_bytes([c[0], c[1], c[2], c[3]]))
+            .collect::<Vec<_>>();
+        let key = h.buffer(&key_words).unwrap();
+        let counter = h
+            .buffer(&from_hex("f0f1f2f3f4f5f6f7f8f9fafbfcfdfeff"))
+            .unwrap();
+        let keystream = h.buffer(&[0u8; 16]).unwrap();
+        h.run("aes256_test", 1, |k| {
+            k.arg(&key).arg(&counter).arg(&keystream);
+        })
+        .unwrap();
+        let plaintext = from_hex("6bc1bee22e409f96e93d7e117393172a");
+        let ciphertext = h
+            .read(&keystream)
+            .unwrap()
+            .iter()
+            .zip(plaintext.iter())
+            .map(|(k, p)| k ^ p)
+            .collect::<Vec<_>>();
+        assert_eq!(from_hex("601ec313775789a5b7a7f504bbf3d228"), ciphertext);
+    }
+
+    #[test]
+    fn test_aes_mask_seed_kernel() {
+        // Reference seeds are AES-256-CTR keystreams computed by a host implementation, keyed
+        // by the replica id, of initial counter block `tag || 0u32 || 2 * absolute index`,
+        // all big-endian.
+        let h = KernelHarness::new(Config {
+            mask_prf: MaskPrf::Aes256Ctr,
+            ..TAGGED_TEST_CONFIG
+        })
+        .unwrap();
+        let n = TAGGED_TEST_CONFIG.num_nodes_window;
+        let output = h.buffer(&vec![0u32; n * 8]).unwrap();
+        h.run("expand_seed", n, |k| {
+            k.arg(&output).arg(TEST_REPLICA_ID).arg(TEST_WINDOW_INDEX);
+        })
+        .unwrap();
+        let seeds = h.read(&output).unwrap();
+        let expected: [(usize, [u32; 8]); 3] = [
+            (
+                0,
+                [
+                    0x3042_51e9,
+                    0x346b_d440,
+                    0x82c7_8d34,
+                    0x3aa4_b0b9,
+                    0xc3d3_c6cd,
+                    0x548c_c61b,
+                    0xcbcf_fe0b,
+                    0x801d_4740,
+                ],
+            ),
+            (
+                1,
+                [
+                    0x2bb1_45db,
+                    0x9090_bf8d,
+                    0x3afc_8d09,
+                    0xa3fc_5b9f,
+                    0xf92a_a34d,
+                    0xe9b6_77ff,
+                    0x88b8_dc64,
+                    0x56a2_0c59,
+                ],
+            ),
+            (
+                511,
+                [
+                    0xd141_9ced,
+                    0x3e3f_13b0,
+                    0x8c37_e4b2,
+                    0x1910_b2ac,
+                    0xa12c_918c,
+                    0x8ff8_761d,
+                    0x5b67_2aed,
+                    0xf4a8_9833,
+                ],
+            ),
+        ];
+        for (node, seed) in expected.iter() {
+            assert_eq!(&seed[..], &seeds[node * 8..(node + 1) * 8]);
+        }
+    }
+
+    #[test]
+    fn test_mask_kernel() {
+        let h = harness();
+        let output = h.buffer(&ordinary_layer()).unwrap();
+        h.run("generate_mask", TAGGED_TEST_CONFIG.num_nodes_window, |k| {
+            layer_arg(k, &output);
+            k.arg(TEST_REPLICA_ID).arg(TEST_WINDOW_INDEX);
+        })
+        .unwrap();
+        assert_eq!(
+            Fr::from_str(
+                "36712257389171499108716801943982746485358507754714513267476034128785294480846"
+            )
+            .unwrap(),
+            accumulate_ordinary(&h.read(&output).unwrap())
+        );
+    }
+
+    #[test]
+    fn test_expander_kernel() {
+        let h = harness();
+        let input = h.buffer(&ordinary_layer()).unwrap();
+        let output = h.buffer(&ordinary_layer()).unwrap();
+        h.run(
+            "generate_expander",
+            TAGGED_TEST_CONFIG.num_nodes_window,
+            |k| {
+                layer_arg(k, &input);
+                layer_arg(k, &output);
+                k.arg(TEST_REPLICA_ID).arg(TEST_WINDOW_INDEX).arg(2u32);
+            },
+        )
+        .unwrap();
+        assert_eq!(
+            Fr::from_str(
+                "16877499465576930107102683215542415406760862069964709342482092164562132983664"
+            )
+            .unwrap(),
+            accumulate_ordinary(&h.read(&output).unwrap())
+        );
+    }
+
+    #[test]
+    fn test_butterfly_kernel() {
+        let h = harness();
+        let input = h.buffer(&ordinary_layer()).unwrap();
+        let output = h.buffer(&ordinary_layer()).unwrap();
+        h.run(
+            "generate_butterfly",
+            TAGGED_TEST_CONFIG.num_nodes_window,
+            |k| {
+                layer_arg(k, &input);
+                layer_arg(k, &output);
+                k.arg(TEST_REPLICA_ID).arg(TEST_WINDOW_INDEX).arg(4u32);
+            },
+        )
+        .unwrap();
+        assert_eq!(
+            Fr::from_str(
+                "25518562275333346439805888134225839273387651182012625796746751722379511185369"
+            )
+            .unwrap(),
+            accumulate_ordinary(&h.read(&output).unwrap())
+        );
+    }
+
+    #[test]
+    fn test_combine_kernel() {
+        let h = harness();
+        // Combine works on Montgomery form.
+        let nodes = |start: u64| {
+            (start..start + TAGGED_TEST_CONFIG.num_nodes_window as u64)
+                .map(|i| Node(Fr::from_repr(FrRepr::from(i)).unwrap()))
+                .collect::<Vec<_>>()
+        };
+        let (mask_nodes, data_nodes) = (nodes(234), nodes(567));
+        let mask = h.buffer(&mask_nodes).unwrap();
+        let data = h.buffer(&data_nodes).unwrap();
+        let (offset, len) = (10u64, 100u64);
+        h.run(
+            "combine_segment",
+            TAGGED_TEST_CONFIG.num_nodes_window,
+            |k| {
+                layer_arg(k, &mask);
+                layer_arg(k, &data);
+                k.arg(offset)
+                    .arg(len)
+                    .arg(CombineMode::Decode as u32)
+                    .arg(EncodingMode::FieldAdd as u32);
+            },
+        )
+        .unwrap();
+        let output = h.read(&data).unwrap();
+        for (i, node) in output.iter().enumerate() {
+            let mut expected = data_nodes[i];
+            if i as u64 >= offset && (i as u64) < offset + len {
+                expected.0.sub_assign(&mask_nodes[i].0);
+            }
+            assert_eq!(expected, *node);
+        }
+    }
+}
@@ -0,0 +1,216 @@
+use crate::{Domain, Layer, NSEError, NSEResult, Node, Sha256Domain};
+use sha2::{Digest, Sha256};
+
+/// Hash of two nodes of the data commitment tree: SHA-256 of their concatenation, trimmed to a
+/// field element (SHA-254).
+pub fn commitment_hash(left: &Sha256Domain, right: &Sha256Domain) -> Sha256Domain {
+    let mut hasher = Sha256::new();
+    hasher.input(left);
+    hasher.input(right);
+    let mut digest = Sha256Domain::from_slice(&hasher.result()).expect("SHA-256 has 32 bytes");
+    digest.0[31] &= 0b0011_1111;
+    digest
+}
+
+/// Streaming computation of the data commitment (CommD) of a window: the root of the binary
+/// tree over its nodes hashed with `D::hash2`, SHA-254 of their byte representation by default.
+/// Nodes are added in order, as the roots of the complete subtrees they form (see
+/// `GPU::combine_segment_with_commitment`, which hashes them on the device) or one by one.
+#[derive(Debug, Clone)]
+pub struct DataCommitment<D: Domain = Sha256Domain> {
+    leaf_count: usize,
+    next_leaf: usize,
+    // Roots of the subtrees not merged yet, along with their heights, which are decreasing.
+    stack: Vec<(u32, D)>,
+}
+
+impl<D: Domain> DataCommitment<D> {
+    /// `leaf_count` must be a power of two, e.g. the leaf count of a window.
+    pub fn new(leaf_count: usize) -> NSEResult<Self> {
+        if !leaf_count.is_power_of_two() {
+            return Err(NSEError::InvalidInput(format!(
+                "Cannot commit to {} nodes, not a power of two!",
+                leaf_count
+            )));
+        }
+        Ok(DataCommitment {
+            leaf_count,
+            next_leaf: 0,
+            stack: Vec::new(),
+        })
+    }
+
+    /// Index of the next node to add.
+    pub fn next_leaf(&self) -> usize {
+        self.next_leaf
+    }
+
+    /// Adds the root of the subtree of height `height` whose first node is `next_leaf()`. It
+    /// must be aligned to its size.
+    pub fn push_subtree(&mut self, height: u32, root: D) -> NSEResult<()> {
+        let size = 1usize << height;
+        if self.next_leaf % size != 0 || self.next_leaf + size > self.leaf_count {
+            return Err(NSEError::InvalidInput(format!(
+                "Subtree of {} nodes cannot start at node {} of {}!",
+                size, self.next_leaf, self.leaf_count
+            )));
+        }
+        self.next_leaf += size;
+        let (mut height, mut root) = (height, root);
+        while let Some(&(last_height, last_root)) = self.stack.last() {
+            if last_height != height {
+                break;
+            }
+            self.stack.pop();
+            root = D::hash2(&last_root, &root);
+            height += 1;
+        }
+        self.stack.push((height, root));
+        Ok(())
+    }
+
+    /// Adds nodes one by one, hashing them on the host.
+    pub fn push_nodes(&mut self, nodes: &[Node]) -> NSEResult<()> {
+        for node in nodes.iter() {
+            self.push_subtree(0, D::from(*node))?;
+        }
+        Ok(())
+    }
+
+    /// The commitment, once all nodes are added.
+    pub fn root(&self) -> NSEResult<D> {
+        if self.next_leaf != self.leaf_count {
+            return Err(NSEError::InvalidInput(format!(
+                "Only {} nodes of {} are committed to!",
+                self.next_leaf, self.leaf_count
+            )));
+        }
+        Ok(self.stack[0].1)
+    }
+}
+
+/// Computes the data commitment of a whole layer on the host.
+pub fn comm_d(data: &Layer) -> NSEResult<Sha256Domain> {
+    let mut commitment = DataCommitment::<Sha256Domain>::new(data.0.len())?;
+    commitment.push_nodes(&data.0)?;
+    commitment.root()
+}
+
+// Splits `len` nodes starting at `offset` into the largest subtrees aligned to their size, as
+// `(first node, height)` pairs.
+pub(crate) fn aligned_subtrees(mut offset: usize, mut len: usize) -> Vec<(usize, u32)> {
+    let mut subtrees = Vec::new();
+    while len > 0 {
+        let max_height = 63 - (len as u64).leading_zeros();
+        let height = if offset == 0 {
+            max_height
+        } else {
+            std::cmp::min(offset.trailing_zeros(), max_height)
+        };
+        subtrees.push((offset, height));
+        offset += 1 << height;
+        len -= 1 << height;
+    }
+    subtrees
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ff::Field;
+    use rand::thread_rng;
+
+    #[test]
+    fn test_data_commitment() {
+        let data = Layer::random(&mut thread_rng(), 16);
+        let leaves = data
+            .0
+            .iter()
+            .map(|n| Sha256Domain::from(*n))
+            .collect::<Vec<_>>();
+        // Naive tree, level by level.
+        let mut level = leaves.clone();
+        while level.len() > 1 {
+            level = level
+                .chunks(2)
+                .map(|pair| commitment_hash(&pair[0], &pair[1]))
+                .collect();
+        }
+        assert_eq!(level[0], comm_d(&data).unwrap());
+
+        // Same root from unaligned segments split into subtrees.
+        let mut commitment = DataCommitment::new(16).unwrap();
+        for &(offset, len) in [(0, 3), (3, 7), (10, 6)].iter() {
+            for (start, height) in aligned_subtrees(offset, len) {
+                let subtree = Layer(data.0[start..start + (1 << height)].to_vec());
+                let root = if height == 0 {
+                    leaves[start]
+                } else {
+                    comm_d(&subtree).unwrap()
+                };
+                commitment.push_subtree(height, root).unwrap();
+            }
+        }
+        assert_eq!(level[0], commitment.root().unwrap());
+
+        assert_eq!(vec![(3, 0), (4, 2), (8, 1)], aligned_subtrees(3, 7));
+        let mut misaligned = DataCommitment::new(16).unwrap();
+        misaligned.push_subtree(0, leaves[0]).unwrap();
+        assert!(misaligned.push_subtree(1, leaves[1]).is_err());
+        assert!(misaligned.root().is_err());
+        assert!(DataCommitment::<Sha256Domain>::new(12).is_err());
+    }
+
+    // Sums of nodes, to check that commitments only rely on `Domain`.
+    #[derive(PartialEq, Eq, Debug, Clone, Copy, Default, Hash)]
+    struct SumDomain([u8; 32]);
+
+    impl AsRef<[u8]> for SumDomain {
+        fn as_ref(&self) -> &[u8] {
+            &self.0
+        }
+    }
+
+    impl From<Node> for SumDomain {
+        fn from(node: Node) -> Self {
+            SumDomain(node.to_le_bytes())
+        }
+    }
+
+    impl Domain for SumDomain {
+        // Sum of the 32-byte chunks of the data, the last one padded with zeros.
+        fn digest(data: &[u8]) -> Self {
+            data.chunks(32)
+                .map(|chunk| {
+                    let mut bytes = [0u8; 32];
+                    bytes[..chunk.len()].copy_from_slice(chunk);
+                    SumDomain(bytes)
+                })
+                .fold(SumDomain::default(), |sum, chunk| Self::hash2(&sum, &chunk))
+        }
+
+        fn hash2(left: &Self, right: &Self) -> Self {
+            let mut sum = left.to_node();
+            sum.0.add_assign(&right.to_node().0);
+            SumDomain::from(sum)
+        }
+
+        fn to_node(&self) -> Node {
+            Sha256Domain(self.0).to_node()
+        }
+    }
+
+    #[test]
+    fn test_data_commitment_domain() {
+        let data = Layer::random(&mut thread_rng(), 16);
+        let mut sum = Node::default();
+        for node in data.0.iter() {
+            sum.0.add_assign(&node.0);
+        }
+        let mut commitment = DataCommitment::<SumDomain>::new(16).unwrap();
+        commitment.push_nodes(&data.0).unwrap();
+        assert_eq!(SumDomain::from(sum), commitment.root().unwrap());
+        let bytes = Vec::<u8>::from(&data);
+        assert_eq!(SumDomain::from(sum), SumDomain::digest(&bytes));
+    }
+}
@@ -0,0 +1,218 @@
+//! Conformance of the backends to the NSE spec: small fixed cases, with the SHA-256 digests of
+//! their layers checked into the repo. The host reference (`label_node` and the host combine)
+//! and every backend supported by the build run every case, so a regression in any of them is
+//! caught immediately, even on a machine without a device.
+//!
+//! Digests are taken over the canonical encoding of the layers (`From<&Layer> for Vec<u8>`).
+//!
+//! # Provenance
+//!
+//! Digests were computed by a standalone implementation of the host reference, outside of this
+//! crate, which reproduces the layer sums of the kernel tests of `gpu.rs` (mask, expander and
+//! butterfly layers, recorded on devices upstream). They are checked against the host reference
+//! of the crate and against the kernels on every run. Agreement with the CPU implementation of
+//! storage-proofs is established separately by the `gpu-cpu-test` crate.
+//!
+//! To regenerate the digests after an intended change of the layers, e.g. of the parents or of
+//! an encoding mode:
+//!
+//! 1. check that `cargo test` in `gpu-cpu-test` passes on the device used,
+//! 2. run `cargo test print_conformance_digests -- --ignored --nocapture`,
+//! 3. paste the printed digests into `CASES`, and explain the change in the commit message.
+//!
+//! Every case must have its digests recorded, the test fails otherwise.
+
+use crate::{
+    combine_nodes, label_node, Backend, CombineMode, Config, DomainTags, EncodingMode,
+    KeyGenerator, Layer, MaskPrf, NSEResult, Node, ReplicaId, TreeOptions, WindowIndex,
+};
+use ff::PrimeField;
+use paired::bls12_381::Fr;
+use sha2::{Digest, Sha256};
+
+struct Case {
+    name: &'static str,
+    config: Config,
+    replica_id: ReplicaId,
+    window_index: WindowIndex,
+    // Original data of the window are the nodes `data_start`, `data_start + 1`, ...
+    data_start: usize,
+    // Digests of the key layers, followed by the one of the encoded data.
+    digests: &'static [&'static str],
+}
+
+const CASES: [Case; 2] = [
+    Case {
+        name: "byte-aligned parents, field addition",
+        config: Config {
+            k: 2,
+            num_nodes_window: 512,
+            degree_expander: 12,
+            degree_butterfly: 4,
+            num_expander_layers: 3,
+            num_butterfly_layers: 2,
+            encoding_mode: EncodingMode::FieldAdd,
+            domain_tags: DomainTags::UNTAGGED,
+            mask_prf: MaskPrf::Sha256,
+        },
+        replica_id: ReplicaId([
+            0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23,
+            24, 25, 26, 27, 28, 29, 30, 31,
+        ]),
+        window_index: WindowIndex(3),
+        data_start: 1000,
+        digests: &[
+            "b9dfeafaf7679acf5dbc6f73f75ca34457e311f7d69004377cddc94ff835f632",
+            "1a8a72b2422acf681d69eb60ff8aa81a2a5a9bdbb92b5321378620d7c7d4972f",
+            "ed0bd0c2deb7b7141cce8c2d73922d609b67ea87440a41768b09103e14fe40bc",
+            "4124003add678ca98a55a3e87e6348fa30975f6c6088775251e3d852e73004ee",
+            "256db1a085b79e0b136501b0a76577856e9299a101e69bf5774e07fb11b2a6d9",
+            "8d7fded6e3d731a368176e5865297c8886357e486cef979a29d82bfcda443707",
+        ],
+    },
+    Case {
+        name: "unaligned parents, domain tags",
+        config: Config {
+            k: 4,
+            num_nodes_window: 256,
+            degree_expander: 8,
+            degree_butterfly: 2,
+            num_expander_layers: 2,
+            num_butterfly_layers: 3,
+            encoding_mode: EncodingMode::FieldAdd,
+            domain_tags: DomainTags {
+                mask: 1,
+                expander: 2,
+                butterfly: 3,
+            },
+            mask_prf: MaskPrf::Sha256,
+        },
+        replica_id: ReplicaId([0xa5; 32]),
+        window_index: WindowIndex(123456),
+        data_start: 0,
+        digests: &[
+            "a949ec95390428a31ce677811857796aead32ccc37c777e093ad795371f8521b",
+            "e870a514e5adb9708e9ab2b69934d8229c005687c293cc546c80fa9e19a474cb",
+            "4febbe39334c1d1dffe2af713545f1c295fd9daffe29d1007403688a44b06d5b",
+            "99feaf60a705e41de34b25a21d63973eb226d78553985aba179daed52ab8bfb2",
+            "1daaf74fff27dd0f389a401d779dfa22ffcf578a7369eb6ada4fa0bff2c84517",
+            "85c30f963d2f060d6d0ef50d4735c072be241ba9ca23733afc470d7b784f1c95",
+        ],
+    },
+];
+
+fn layer_digest(layer: &Layer) -> String {
+    Sha256::digest(&Vec::<u8>::from(layer))
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+fn data_layer(case: &Case) -> Layer {
+    Layer(
+        (case.data_start..case.data_start + case.config.num_nodes_window)
+            .map(|i| Node(Fr::from_str(&i.to_string()).unwrap()))
+            .collect(),
+    )
+}
+
+// Digests of the key layers and of the encoded data of `case`, computed on `backend`.
+fn run_case(backend: Backend, case: &Case) -> Vec<String> {
+    case.config.validate().unwrap();
+    let mut gpu = backend.gpu(case.config, TreeOptions::Disabled).unwrap();
+    let mut keygen =
+        KeyGenerator::new(case.config, case.replica_id, case.window_index, &mut gpu).unwrap();
+    let mut layers = keygen.by_ref().collect::<NSEResult<Vec<_>>>().unwrap();
+    layers.push(
+        keygen
+            .combine_layer(&data_layer(case), CombineMode::Encode)
+            .unwrap(),
+    );
+    layers.iter().map(layer_digest).collect()
+}
+
+// Same as `run_case`, labeled node by node and combined on the host.
+fn run_host_case(case: &Case) -> Vec<String> {
+    let config = case.config;
+    let mut layers: Vec<Layer> = Vec::new();
+    for layer_index in 1..=config.num_layers() {
+        let empty = Layer::default();
+        let previous = layers.last().unwrap_or(&empty);
+        let layer = (0..config.num_nodes_window)
+            .map(|node| {
+                label_node(
+                    &config,
+                    case.replica_id,
+                    case.window_index,
+                    layer_index,
+                    previous,
+                    node,
+                )
+            })
+            .collect::<NSEResult<Vec<_>>>()
+            .unwrap();
+        layers.push(Layer(layer));
+    }
+    let mut encoded = data_layer(case);
+    let key = &layers.last().unwrap().0;
+    combine_nodes(
+        &mut encoded.0,
+        key,
+        CombineMode::Encode,
+        config.encoding_mode,
+    )
+    .unwrap();
+    layers.push(encoded);
+    layers.iter().map(layer_digest).collect()
+}
+
+fn check_digests(implementation: &str, case: &Case, digests: &[String]) {
+    assert_eq!(case.digests.len(), digests.len());
+    for (i, (expected, actual)) in case.digests.iter().zip(digests.iter()).enumerate() {
+        assert_eq!(
+            expected,
+            actual,
+            "{}, case `{}`: layer {} differs!",
+            implementation,
+            case.name,
+            i + 1
+        );
+    }
+}
+
+#[test]
+fn test_conformance() {
+    for case in CASES.iter() {
+        assert_eq!(
+            case.config.num_layers() + 1,
+            case.digests.len(),
+            "Case `{}`: digests not recorded!",
+            case.name
+        );
+        check_digests("Host reference", case, &run_host_case(case));
+        // Backends not supported by the build are skipped.
+        for &backend in Backend::ALL.iter().filter(|b| b.is_supported()) {
+            let digests = run_case(backend, case);
+            assert_eq!(
+                digests,
+                run_case(backend, case),
+                "Backend {}, case `{}`: not deterministic!",
+                backend,
+                case.name
+            );
+            check_digests(&format!("Backend {}", backend), case, &digests);
+        }
+    }
+}
+
+#[test]
+#[ignore]
+fn print_conformance_digests() {
+    let backend = Backend::from_env().unwrap();
+    for case in CASES.iter() {
+        println!("{} ({}):", case.name, backend);
+        for digest in run_case(backend, case) {
+            println!("    \"{}\",", digest);
+        }
+    }
+}
@@ -0,0 +1,224 @@
+use crate::{commitment_hash, NSEError, NSEResult, Node, ReplicaId};
+use ff::PrimeField;
+use paired::bls12_381::{Fr, FrRepr};
+#[cfg(feature = "json")]
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use sha2::{Digest, Sha256};
+use std::convert::TryFrom;
+use std::fmt;
+use std::hash::Hash;
+use std::str::FromStr;
+
+/// A 32-byte domain and the host-side hashing into it. The orchestration on the host (e.g.
+/// `DataCommitment`) is written against this trait, so that NSE variants hashing into other
+/// domains (Blake2s, Poseidon) can reuse it; `Sha256Domain` is the one of the current kernels.
+pub trait Domain:
+    Copy + Clone + Default + Eq + fmt::Debug + Hash + AsRef<[u8]> + From<Node> + Send + Sync
+{
+    /// Digest of arbitrary data.
+    fn digest(data: &[u8]) -> Self;
+
+    /// Hash of two nodes of a binary tree, e.g. the data commitment tree. It must be a valid
+    /// field element, see `to_node`.
+    fn hash2(left: &Self, right: &Self) -> Self;
+
+    /// The field element of the digest.
+    fn to_node(&self) -> Node;
+}
+
+/// A 32-byte SHA-256 digest, the domain of replica ids and of layer labels before they are
+/// trimmed into field elements.
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Default, Hash)]
+pub struct Sha256Domain(pub [u8; 32]);
+
+impl Sha256Domain {
+    /// Fails unless `bytes` are exactly 32 bytes long.
+    pub fn from_slice(bytes: &[u8]) -> NSEResult<Self> {
+        if bytes.len() != 32 {
+            return Err(NSEError::InvalidInput(format!(
+                "Expected 32 bytes, got {}!",
+                bytes.len()
+            )));
+        }
+        let mut domain = Sha256Domain::default();
+        domain.0.copy_from_slice(bytes);
+        Ok(domain)
+    }
+
+    /// The field element of the digest, read in little-endian order and trimmed to 254 bits
+    /// (the two most significant bits are cleared), like labels are by the kernels.
+    pub fn to_fr(&self) -> Fr {
+        let mut trimmed = self.0;
+        trimmed[31] &= 0b0011_1111;
+        let mut repr = FrRepr::default();
+        for (limb, chunk) in repr.as_mut().iter_mut().zip(trimmed.chunks(8)) {
+            let mut limb_bytes = [0u8; 8];
+            limb_bytes.copy_from_slice(chunk);
+            *limb = u64::from_le_bytes(limb_bytes);
+        }
+        Fr::from_repr(repr).expect("254-bit values are below the modulus")
+    }
+
+    /// Same as `to_fr`, as a node.
+    pub fn to_node(&self) -> Node {
+        Node(self.to_fr())
+    }
+}
+
+/// Plain SHA-256 for `digest`, and SHA-254 (see `commitment_hash`) for `hash2`.
+impl Domain for Sha256Domain {
+    fn digest(data: &[u8]) -> Self {
+        Sha256Domain::from_slice(&Sha256::digest(data)).expect("SHA-256 has 32 bytes")
+    }
+
+    fn hash2(left: &Self, right: &Self) -> Self {
+        commitment_hash(left, right)
+    }
+
+    fn to_node(&self) -> Node {
+        Sha256Domain::to_node(self)
+    }
+}
+
+impl AsRef<[u8]> for Sha256Domain {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl From<ReplicaId> for Sha256Domain {
+    fn from(replica_id: ReplicaId) -> Self {
+        Sha256Domain(replica_id.0)
+    }
+}
+
+impl From<Sha256Domain> for ReplicaId {
+    fn from(domain: Sha256Domain) -> Self {
+        ReplicaId(domain.0)
+    }
+}
+
+/// The little-endian bytes of the field element, which `to_fr` maps back to it.
+impl From<Fr> for Sha256Domain {
+    fn from(fr: Fr) -> Self {
+        Sha256Domain(Node(fr).to_le_bytes())
+    }
+}
+
+impl From<Node> for Sha256Domain {
+    fn from(node: Node) -> Self {
+        Sha256Domain(node.to_le_bytes())
+    }
+}
+
+impl TryFrom<&[u8]> for Sha256Domain {
+    type Error = NSEError;
+
+    fn try_from(bytes: &[u8]) -> NSEResult<Self> {
+        Sha256Domain::from_slice(bytes)
+    }
+}
+
+/// Lowercase hex, without prefix.
+impl fmt::Display for Sha256Domain {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for b in self.0.iter() {
+            write!(f, "{:02x}", b)?;
+        }
+        Ok(())
+    }
+}
+
+/// Parses 64 hex digits, optionally prefixed with `0x`.
+impl FromStr for Sha256Domain {
+    type Err = NSEError;
+
+    fn from_str(s: &str) -> NSEResult<Self> {
+        let hex = if s.starts_with("0x") { &s[2..] } else { s };
+        let invalid = || NSEError::InvalidInput(format!("Invalid SHA-256 digest `{}`", s));
+        if hex.len() != 64 || !hex.is_ascii() {
+            return Err(invalid());
+        }
+        let mut domain = Sha256Domain::default();
+        for (i, b) in domain.0.iter_mut().enumerate() {
+            *b = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16).map_err(|_| invalid())?;
+        }
+        Ok(domain)
+    }
+}
+
+/// As its hex string, see `Display`.
+#[cfg(feature = "json")]
+impl Serialize for Sha256Domain {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+#[cfg(feature = "json")]
+impl<'de> Deserialize<'de> for Sha256Domain {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(de::Error::custom)
+    }
+}
+
+/// As the hex string of its bytes, like `Sha256Domain`.
+#[cfg(feature = "json")]
+impl Serialize for ReplicaId {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        Sha256Domain::from(*self).serialize(serializer)
+    }
+}
+
+#[cfg(feature = "json")]
+impl<'de> Deserialize<'de> for ReplicaId {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Sha256Domain::deserialize(deserializer).map(ReplicaId::from)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::thread_rng;
+
+    #[test]
+    fn test_sha256_domain_conversions() {
+        let domain = Sha256Domain([0xffu8; 32]);
+        let hex = domain.to_string();
+        assert_eq!("ff".repeat(32), hex);
+        assert_eq!(domain, hex.parse().unwrap());
+        assert_eq!(domain, format!("0x{}", hex).parse().unwrap());
+        assert!(hex[1..].parse::<Sha256Domain>().is_err());
+        assert!("zz".repeat(32).parse::<Sha256Domain>().is_err());
+
+        assert_eq!(domain, Sha256Domain::try_from(&[0xffu8; 32][..]).unwrap());
+        assert!(Sha256Domain::from_slice(&[0u8; 31]).is_err());
+
+        // Trimming clears the two most significant bits only.
+        let mut trimmed = [0xffu8; 32];
+        trimmed[31] = 0x3f;
+        assert_eq!(Sha256Domain(trimmed), Sha256Domain::from(domain.to_fr()));
+
+        let node = Node::random(&mut thread_rng());
+        assert_eq!(node, Sha256Domain::from(node).to_node());
+    }
+
+    #[test]
+    fn test_sha256_domain_hashing() {
+        let (left, right) = (Sha256Domain([1u8; 32]), Sha256Domain([2u8; 32]));
+        assert_eq!(
+            Sha256::digest(b"abc").as_slice(),
+            Sha256Domain::digest(b"abc").as_ref()
+        );
+        let mut concatenated = left.0.to_vec();
+        concatenated.extend_from_slice(&right.0);
+        let hash2 = Sha256Domain::hash2(&left, &right);
+        assert_eq!(hash2.0[..31], Sha256Domain::digest(&concatenated).0[..31]);
+        assert_eq!(hash2, commitment_hash(&left, &right));
+        // Trimmed to a field element.
+        assert_eq!(hash2, Sha256Domain::from(Domain::to_node(&hash2)));
+    }
+}
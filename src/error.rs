@@ -1,7 +1,10 @@
 #[derive(thiserror::Error, Debug)]
 pub enum GPUError {
+    #[cfg(feature = "gpu")]
     #[error("Ocl Error: {0}")]
     Ocl(ocl::Error),
+    #[error("Kernel did not finish within {0:?}")]
+    KernelTimeout(std::time::Duration),
     #[error("Error: {0}")]
     Other(String),
 }
@@ -9,22 +12,110 @@ pub enum GPUError {
 #[allow(dead_code)]
 pub type GPUResult<T> = std::result::Result<T, GPUError>;
 
+#[cfg(feature = "gpu")]
 impl From<ocl::Error> for GPUError {
     fn from(error: ocl::Error) -> Self {
         GPUError::Ocl(error)
     }
 }
 
+#[cfg(feature = "gpu")]
+impl From<ocl::core::Error> for GPUError {
+    fn from(error: ocl::core::Error) -> Self {
+        GPUError::Ocl(error.into())
+    }
+}
+
 #[derive(thiserror::Error, Debug)]
 pub enum NSEError {
     #[error("Ocl Error: {0}")]
-    GPU(#[from] GPUError),
+    GPU(#[source] GPUError),
     #[error("Neptune Error: {0}")]
     Neptune(#[from] neptune::error::Error),
+    #[error("Invalid input: {0}")]
+    InvalidInput(String),
+    #[error("Backend not supported by this build: {0}")]
+    UnsupportedBackend(crate::Backend),
+    #[error("This build of the crate has no GPU support, enable the `gpu` feature")]
+    NoGpuSupport,
+    #[error("No device found for backend {0}")]
+    NoDevice(crate::Backend),
+    #[error("Operation cancelled")]
+    Cancelled,
+    /// A persisted key layer was generated with another config, see `Config::fingerprint`.
+    #[error("{0} was generated with a different config")]
+    ConfigMismatch(std::path::PathBuf),
+    /// A persisted layer is truncated, corrupted or of an unsupported version, see
+    /// `LayerFileHeader`.
+    #[error("{0} is not a valid layer file: {1}")]
+    InvalidLayerFile(std::path::PathBuf, String),
+    /// The device cannot hold the buffers of a GPU, see `MinimumMemory`.
+    #[error("Cannot reserve {minimum} of device memory: {reason}")]
+    InsufficientDeviceMemory {
+        minimum: crate::MinimumMemory,
+        reason: String,
+    },
+    #[error("Device {0} is locked by another process")]
+    DeviceBusy(String),
+    /// Every device of a `SealerPool` died or is quarantined, see `SealerPool::health`.
+    #[error("No healthy device left in the pool")]
+    NoHealthyDevice,
+    /// A kernel did not finish within `GpuConfig::kernel_timeout`, the device is probably hung.
+    #[error("Kernel did not finish within {0:?}, the GPU should be recreated")]
+    KernelTimeout(std::time::Duration),
+    /// A node of a generated layer differs from its label computed on the host, see
+    /// `SpotCheckConfig`. The device memory is probably corrupted.
+    #[error("Spot check of node {node} of layer {layer_index} failed")]
+    SpotCheckFailed { layer_index: usize, node: usize },
+    /// A node of the original data to seal is not a field element, or not below 2^253 with
+    /// `EncodingMode::Xor`, see `SealerBuilder::validate_data`.
+    #[error("Node {0} of the original data is not a valid field element")]
+    InvalidDataNode(usize),
+    /// Unsealing the replica of a window did not give its original data back, see
+    /// `roundtrip_check`.
+    #[error("Roundtrip failed: {what} differs at node {node}")]
+    RoundtripMismatch { what: &'static str, node: usize },
+    /// The field arithmetic of the kernels disagrees with the host's, checked when a
+    /// `GPUContext` is created.
+    #[error(
+        "Kernel field arithmetic differs from the host ({what} of sample {sample}), the versions \
+         of ff-cl-gen and ff/paired probably mismatch"
+    )]
+    FieldMismatch { what: &'static str, sample: usize },
+    /// An operation of a `ParanoidMode` backend gave a different result than the host
+    /// reference. The device, or its driver, cannot be trusted.
+    #[error(
+        "The {operation} diverged from the host reference at node {node} (digest {device} on \
+         the device, {host} on the host)"
+    )]
+    Divergence {
+        operation: String,
+        node: usize,
+        device: crate::Sha256Domain,
+        host: crate::Sha256Domain,
+    },
+    #[error("IO Error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Invalid range: offset {offset} + length {len} exceeds leaf count {leaf_count}")]
+    InvalidRange {
+        offset: usize,
+        len: usize,
+        leaf_count: usize,
+    },
 }
 
 pub type NSEResult<T> = std::result::Result<T, NSEError>;
 
+impl From<GPUError> for NSEError {
+    fn from(error: GPUError) -> Self {
+        match error {
+            GPUError::KernelTimeout(timeout) => NSEError::KernelTimeout(timeout),
+            error => NSEError::GPU(error),
+        }
+    }
+}
+
+#[cfg(feature = "gpu")]
 impl From<ocl::Error> for NSEError {
     fn from(error: ocl::Error) -> Self {
         NSEError::GPU(GPUError::Ocl(error))
@@ -0,0 +1,161 @@
+//! Testing aid forcing chosen device operations of a `GPU` to fail, so that error propagation,
+//! resubmission and checkpoint recovery can be covered deterministically. Enabled by the
+//! `fault-injection` feature, see `GPU::set_fault_injector`.
+use crate::{GPUError, GPUResult};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+
+/// The kinds of device operations that can be made to fail.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FaultPoint {
+    /// A kernel launch, failing once the kernel is enqueued.
+    KernelLaunch,
+    /// A transfer from host to device.
+    Write,
+    /// A transfer from device to host.
+    Readback,
+}
+
+#[derive(Debug, Default)]
+struct Faults {
+    counts: HashMap<FaultPoint, usize>,
+    failing: HashSet<(FaultPoint, usize)>,
+}
+
+/// Counts the operations of a GPU by kind, and fails the chosen ones with a `GPUError::Other`.
+/// Clones share their state, so a test keeps a handle on the injector attached to a GPU.
+#[derive(Debug, Clone, Default)]
+pub struct FaultInjector(Arc<Mutex<Faults>>);
+
+impl FaultInjector {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Makes the `n`th (0-based) operation of kind `point` fail, counting from the attachment
+    /// of the injector.
+    pub fn fail_nth(&self, point: FaultPoint, n: usize) -> &Self {
+        self.0.lock().unwrap().failing.insert((point, n));
+        self
+    }
+
+    /// Number of operations of kind `point` seen so far, failed ones included.
+    pub fn count(&self, point: FaultPoint) -> usize {
+        *self.0.lock().unwrap().counts.get(&point).unwrap_or(&0)
+    }
+
+    pub(crate) fn check(&self, point: FaultPoint) -> GPUResult<()> {
+        let mut faults = self.0.lock().unwrap();
+        let count = faults.counts.entry(point).or_insert(0);
+        let n = *count;
+        *count += 1;
+        if faults.failing.remove(&(point, n)) {
+            return Err(GPUError::Other(format!(
+                "Injected failure of {:?} #{}",
+                point, n
+            )));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        GPUContext, KeyGenerator, Layer, NSEError, NSEResult, NarrowStackedExpander, ReplicaId,
+        Sealer, SealerInput, TreeOptions, WindowIndex, GPU, SHALLOW_TEST_CONFIG,
+    };
+
+    const TEST_REPLICA_ID: ReplicaId = ReplicaId([3u8; 32]);
+
+    fn is_injected(error: &NSEError) -> bool {
+        match error {
+            NSEError::GPU(GPUError::Other(message)) => message.starts_with("Injected failure"),
+            _ => false,
+        }
+    }
+
+    #[test]
+    fn test_fault_schedule() {
+        let faults = FaultInjector::new();
+        faults
+            .fail_nth(FaultPoint::Readback, 1)
+            .fail_nth(FaultPoint::Readback, 2);
+        let shared = faults.clone();
+        assert!(shared.check(FaultPoint::Readback).is_ok());
+        assert!(shared.check(FaultPoint::Write).is_ok());
+        assert!(shared.check(FaultPoint::Readback).is_err());
+        assert!(shared.check(FaultPoint::Readback).is_err());
+        assert!(shared.check(FaultPoint::Readback).is_ok());
+        assert_eq!(4, faults.count(FaultPoint::Readback));
+        assert_eq!(1, faults.count(FaultPoint::Write));
+        assert_eq!(0, faults.count(FaultPoint::KernelLaunch));
+    }
+
+    #[test]
+    fn test_kernel_launch_failure() {
+        let ctx = GPUContext::default(SHALLOW_TEST_CONFIG, TreeOptions::Disabled).unwrap();
+        let mut gpu = GPU::new(ctx, SHALLOW_TEST_CONFIG).unwrap();
+        let faults = FaultInjector::new();
+        gpu.set_fault_injector(Some(faults.clone()));
+
+        faults.fail_nth(FaultPoint::KernelLaunch, 0);
+        let error = gpu
+            .generate_mask_layer(TEST_REPLICA_ID, WindowIndex(0))
+            .unwrap_err();
+        assert!(is_injected(&error));
+        // Later operations are unaffected.
+        let layers = KeyGenerator::new(
+            SHALLOW_TEST_CONFIG,
+            TEST_REPLICA_ID,
+            WindowIndex(0),
+            &mut gpu,
+        )
+        .unwrap()
+        .collect::<NSEResult<Vec<_>>>()
+        .unwrap();
+        assert_eq!(SHALLOW_TEST_CONFIG.num_layers(), layers.len());
+        assert!(faults.count(FaultPoint::KernelLaunch) > 1);
+    }
+
+    #[test]
+    fn test_resume_after_readback_failure() {
+        let ctx = GPUContext::default(SHALLOW_TEST_CONFIG, TreeOptions::Disabled).unwrap();
+        let mut gpu = GPU::new(ctx, SHALLOW_TEST_CONFIG).unwrap();
+        let input = SealerInput {
+            replica_id: TEST_REPLICA_ID,
+            window_index: WindowIndex(7),
+            original_data: Layer::random(
+                &mut rand::thread_rng(),
+                SHALLOW_TEST_CONFIG.num_nodes_window,
+            ),
+        };
+        let expected = Sealer::new(SHALLOW_TEST_CONFIG, input.clone(), &mut gpu, false)
+            .unwrap()
+            .collect::<NSEResult<Vec<_>>>()
+            .unwrap();
+
+        let faults = FaultInjector::new();
+        gpu.set_fault_injector(Some(faults.clone()));
+        faults.fail_nth(FaultPoint::Readback, 3);
+        let checkpoint_dir = tempfile::tempdir().unwrap();
+        let error = Sealer::builder(SHALLOW_TEST_CONFIG, input.clone())
+            .checkpoint_dir(checkpoint_dir.path())
+            .build(&mut gpu)
+            .unwrap()
+            .find_map(|output| output.err())
+            .unwrap();
+        assert!(is_injected(&error));
+
+        // The layers labeled before the failure are not labeled again.
+        let resumed = Sealer::builder(SHALLOW_TEST_CONFIG, input)
+            .checkpoint_dir(checkpoint_dir.path())
+            .build(&mut gpu)
+            .unwrap()
+            .collect::<NSEResult<Vec<_>>>()
+            .unwrap();
+        assert!(resumed.len() < expected.len());
+        assert_eq!(expected.last(), resumed.last());
+    }
+}
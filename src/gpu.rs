@@ -1,14 +1,35 @@
 use super::{
-    sources, utils, Config, GPUError, GPUResult, Layer, NSEResult, NarrowStackedExpander, Node,
-    ReplicaId, COMBINE_BATCH_SIZE,
+    check_combine_output, program_cache, segment_range, utils, Bandwidth, CombineMode, Config,
+    DataCommitment, GPUError, GPUResult, GpuConfig, HeapAllocator, HostAllocator, KThroughput,
+    KernelStats, Layer, LayerContentStats, MinimumMemory, NSEError, NSEResult,
+    NarrowStackedExpander, Node, PoseidonConstants, ReplicaId, Sha256Domain, WindowIndex,
+    COMBINE_BATCH_SIZE, MAX_LAYER_CHUNKS, NODE_SIZE,
 };
+#[cfg(feature = "fault-injection")]
+use crate::fault_injection::{FaultInjector, FaultPoint};
+#[cfg(feature = "leak-detection")]
+use crate::leak_detection::AllocationTracker;
+use crate::mask_cache::MaskCache;
+use ff::{Field, PrimeField};
 use generic_array::typenum::U8;
-use log::info;
+#[cfg(feature = "launch-logging")]
+use log::debug;
+use log::{info, warn};
 use neptune::batch_hasher::BatcherType;
 use neptune::cl::GPUSelector;
 use neptune::tree_builder::TreeBuilder;
 use ocl::builders::KernelBuilder;
-use ocl::{Buffer, Device, OclPrm, ProQue};
+use ocl::enums::{
+    KernelWorkGroupInfo, KernelWorkGroupInfoResult, ProfilingInfo, ProfilingInfoResult,
+    ProgramInfo, ProgramInfoResult,
+};
+use ocl::flags::MemFlags;
+use ocl::{Buffer, Device, Event, Kernel, OclPrm, ProQue, Program, Queue};
+use paired::bls12_381::{Fr, FrRepr};
+use std::ops::Range;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
 
 pub fn is_little_endian(d: ocl::Device) -> GPUResult<bool> {
     match d.info(ocl::enums::DeviceInfo::EndianLittle)? {
@@ -17,37 +38,300 @@ pub fn is_little_endian(d: ocl::Device) -> GPUResult<bool> {
     }
 }
 
-fn write_buffer<T: OclPrm>(buff: &mut Buffer<T>, offset: usize, segment: &[T]) -> GPUResult<()> {
-    info!("Pushing data...");
-    buff.create_sub_buffer(None, offset, segment.len())?
-        .write(segment)
-        .enq()?;
+/// Number of field elements whose arithmetic `check_field` compares with the host.
+const FIELD_CHECK_SAMPLES: usize = 64;
+
+// Runs the field arithmetic generated by `ff_cl_gen` on a few elements (zero, one, minus one and
+// random ones) and compares it with the host's `Fr`. Mismatched versions of `ff-cl-gen` and
+// `ff`/`paired` disagree on the Montgomery representation, which would otherwise silently
+// corrupt every layer.
+fn check_field(pro_que: &ProQue) -> NSEResult<()> {
+    let mut minus_one = Fr::one();
+    minus_one.negate();
+    let mut rng = rand::thread_rng();
+    let a = [Fr::zero(), Fr::one(), minus_one]
+        .iter()
+        .map(|&f| Node(f))
+        .chain((3..FIELD_CHECK_SAMPLES).map(|_| Node::random(&mut rng)))
+        .collect::<Vec<_>>();
+    let b = a.iter().rev().cloned().collect::<Vec<_>>();
+    let input = |nodes: &[Node]| {
+        Buffer::<Node>::builder()
+            .queue(pro_que.queue().clone())
+            .len(nodes.len())
+            .copy_host_slice(nodes)
+            .build()
+    };
+    let (a_buff, b_buff) = (input(&a)?, input(&b)?);
+    let output = Buffer::<Node>::builder()
+        .queue(pro_que.queue().clone())
+        .len(2 * FIELD_CHECK_SAMPLES)
+        .build()?;
+    let kernel = pro_que
+        .kernel_builder("field_self_check")
+        .global_work_size([FIELD_CHECK_SAMPLES])
+        .arg(&a_buff)
+        .arg(&b_buff)
+        .arg(&output)
+        .build()?;
+    unsafe {
+        kernel.enq()?;
+    }
+    let mut results = vec![Node::default(); 2 * FIELD_CHECK_SAMPLES];
+    output.read(&mut results).enq()?;
+
+    for (sample, (x, y)) in a.iter().zip(b.iter()).enumerate() {
+        let mut expected = x.0;
+        expected.mul_assign(&y.0);
+        expected.add_assign(&x.0);
+        expected.sub_assign(&y.0);
+        if results[2 * sample].0 != expected {
+            return Err(NSEError::FieldMismatch {
+                what: "a * b + a - b",
+                sample,
+            });
+        }
+        // Safe: `Fr` is a `FrRepr` in Montgomery form, any value of it is a valid `FrRepr`.
+        let ordinary = unsafe { std::mem::transmute::<Fr, FrRepr>(results[2 * sample + 1].0) };
+        if ordinary != x.0.into_repr() {
+            return Err(NSEError::FieldMismatch {
+                what: "the ordinary form",
+                sample,
+            });
+        }
+    }
     Ok(())
 }
 
-fn read_buffer<T: OclPrm>(buff: &Buffer<T>, offset: usize, segment: &mut [T]) -> GPUResult<()> {
-    info!("Pulling results...");
-    buff.create_sub_buffer(None, offset, segment.len())?
-        .read(segment)
-        .enq()?;
-    Ok(())
+// Global memory size and maximum allocation size of the device, in bytes.
+fn device_memory(d: Device) -> GPUResult<(u64, u64)> {
+    let global = match d.info(ocl::enums::DeviceInfo::GlobalMemSize)? {
+        ocl::enums::DeviceInfoResult::GlobalMemSize(s) => s,
+        _ => return Err(GPUError::Other("Cannot get device memory size!".into())),
+    };
+    let max_alloc = match d.info(ocl::enums::DeviceInfo::MaxMemAllocSize)? {
+        ocl::enums::DeviceInfoResult::MaxMemAllocSize(s) => s,
+        _ => return Err(GPUError::Other("Cannot get device allocation size!".into())),
+    };
+    Ok((global, max_alloc))
+}
+
+// Device timestamp, in nanoseconds, of a command whose queue has profiling enabled.
+fn profiled_time(event: &Event, info: ProfilingInfo) -> GPUResult<u64> {
+    match event.profiling_info(info)? {
+        ProfilingInfoResult::Start(time) | ProfilingInfoResult::End(time) => Ok(time),
+        other => Err(GPUError::Other(format!(
+            "Unexpected profiling info: {:?}",
+            other
+        ))),
+    }
+}
+
+fn kernel_stats(program: &Program, device: Device, name: &str) -> GPUResult<KernelStats> {
+    let kernel = ocl::core::create_kernel(program.as_core(), name)?;
+    let info = |request| ocl::core::get_kernel_work_group_info(&kernel, device.as_core(), request);
+    let mut stats = KernelStats {
+        name: name.to_string(),
+        max_work_group_size: 0,
+        preferred_work_group_size_multiple: 0,
+        local_mem_size: 0,
+        private_mem_size: 0,
+    };
+    if let KernelWorkGroupInfoResult::WorkGroupSize(s) = info(KernelWorkGroupInfo::WorkGroupSize)? {
+        stats.max_work_group_size = s;
+    }
+    if let KernelWorkGroupInfoResult::PreferredWorkGroupSizeMultiple(s) =
+        info(KernelWorkGroupInfo::PreferredWorkGroupSizeMultiple)?
+    {
+        stats.preferred_work_group_size_multiple = s;
+    }
+    if let KernelWorkGroupInfoResult::LocalMemSize(s) = info(KernelWorkGroupInfo::LocalMemSize)? {
+        stats.local_mem_size = s;
+    }
+    if let KernelWorkGroupInfoResult::PrivateMemSize(s) = info(KernelWorkGroupInfo::PrivateMemSize)?
+    {
+        stats.private_mem_size = s;
+    }
+    Ok(stats)
 }
 
 // Make `Node` movable to GPU buffers by implementing `OclPrm`
 unsafe impl OclPrm for Node {}
 unsafe impl OclPrm for ReplicaId {}
+unsafe impl OclPrm for WindowIndex {}
+
+/// A layer on the device, split into buffers of `chunk_len` nodes, see
+/// `GpuConfig::max_alloc_chunk`. Kernels receive all the chunks, see `LAYER_ARGS` in the kernels.
+pub(crate) struct LayerBuffer {
+    chunks: Vec<Buffer<Node>>,
+    chunk_len: usize, // A power of two
+}
+
+impl LayerBuffer {
+    // Splits the `len` nodes starting at `offset` along chunks: returns the chunk, the offset
+    // within the chunk, and the nodes of the segment falling in it.
+    fn split(&self, offset: usize, len: usize) -> Vec<(usize, usize, Range<usize>)> {
+        let mut parts = Vec::new();
+        let mut done = 0;
+        while done < len {
+            let node = offset + done;
+            let chunk_offset = node % self.chunk_len;
+            let count = std::cmp::min(len - done, self.chunk_len - chunk_offset);
+            parts.push((node / self.chunk_len, chunk_offset, done..done + count));
+            done += count;
+        }
+        parts
+    }
+}
+
+/// A value that can be passed to the kernels by `call_kernel!`.
+pub(crate) trait KernelArg<'b> {
+    fn push(self, kernel: &mut KernelBuilder<'b>);
+
+    /// Short description of the argument, for launch logs.
+    #[cfg(feature = "launch-logging")]
+    fn describe(&self) -> String;
+}
+
+impl<'b> KernelArg<'b> for &'b LayerBuffer {
+    fn push(self, kernel: &mut KernelBuilder<'b>) {
+        // Unused chunk arguments repeat the first chunk, they are never accessed.
+        for i in 0..MAX_LAYER_CHUNKS {
+            kernel.arg(self.chunks.get(i).unwrap_or(&self.chunks[0]));
+        }
+        kernel.arg(self.chunk_len.trailing_zeros());
+    }
+
+    #[cfg(feature = "launch-logging")]
+    fn describe(&self) -> String {
+        format!(
+            "layer of {} chunks of {} nodes",
+            self.chunks.len(),
+            self.chunk_len
+        )
+    }
+}
+
+impl<'b, T: OclPrm> KernelArg<'b> for &'b Buffer<T> {
+    fn push(self, kernel: &mut KernelBuilder<'b>) {
+        kernel.arg(self);
+    }
+
+    #[cfg(feature = "launch-logging")]
+    fn describe(&self) -> String {
+        format!(
+            "buffer of {} elements ({} bytes)",
+            self.len(),
+            self.len() * std::mem::size_of::<T>()
+        )
+    }
+}
+
+macro_rules! impl_kernel_arg {
+    ($($t:ty),*) => {
+        $(
+            impl<'b> KernelArg<'b> for $t {
+                fn push(self, kernel: &mut KernelBuilder<'b>) {
+                    kernel.arg(self);
+                }
+
+                #[cfg(feature = "launch-logging")]
+                fn describe(&self) -> String {
+                    format!("{:?}", self)
+                }
+            }
+        )*
+    };
+}
+
+impl_kernel_arg!(u32, u64, ReplicaId, WindowIndex);
 
 #[derive(Debug, Clone, Copy)]
 pub enum TreeOptions {
-    Enabled { rows_to_discard: usize },
+    Enabled {
+        rows_to_discard: usize,
+    },
+    /// Trees are built on `device` rather than on the labeling device, e.g. to pair a strong
+    /// labeling card with a cheaper hashing one. Layers are shipped through the host.
+    EnabledOn {
+        rows_to_discard: usize,
+        device: Device,
+    },
     Disabled,
 }
 
+impl TreeOptions {
+    pub fn is_enabled(&self) -> bool {
+        match self {
+            TreeOptions::Disabled => false,
+            _ => true,
+        }
+    }
+}
+
+// The GPU tree builder of neptune holds raw pointers to its Futhark context, so it is not
+// `Send` although the context is not tied to the thread that created it. It is owned by a single
+// `GPUContext` and only used through `&mut`, so never from two threads at once.
+struct SendTreeBuilder(TreeBuilder<U8>);
+
+unsafe impl Send for SendTreeBuilder {}
+
+fn tree_builder(
+    device: Device,
+    config: Config,
+    tree_options: TreeOptions,
+) -> NSEResult<Option<SendTreeBuilder>> {
+    let (device, rows_to_discard) = match tree_options {
+        TreeOptions::Enabled { rows_to_discard } => (device, rows_to_discard),
+        TreeOptions::EnabledOn {
+            rows_to_discard,
+            device: tree_device,
+        } => {
+            info!("Building trees on device: {}", tree_device.name()?);
+            (tree_device, rows_to_discard)
+        }
+        TreeOptions::Disabled => return Ok(None),
+    };
+    Ok(Some(SendTreeBuilder(TreeBuilder::<U8>::new(
+        Some(BatcherType::CustomGPU(GPUSelector::BusId(
+            utils::get_bus_id(device)?,
+        ))),
+        config.num_nodes_window,
+        TREE_BUILDER_BATCH_SIZE,
+        rows_to_discard,
+    )?)))
+}
+
 // Manages buffers
 pub struct GPUContext {
     pro_que: ProQue,
-    tree_builder: Option<TreeBuilder<U8>>,
+    queues: Vec<Queue>, // Transfer queues, `queues[0]` is the queue of `pro_que`
+    tree_builder: Option<SendTreeBuilder>,
+    tree_options: TreeOptions,
     config: Config,
+    gpu_config: GpuConfig,
+    timings: OpTimings,
+    spare_layers: Vec<LayerBuffer>, // Allocated by `reserve`, reused by `create_buffer`
+    pending_kernels: Vec<Event>,    // Enqueued since the last `sync_kernels`, to be profiled
+    poisoned: Option<Duration>,     // Timeout a kernel or transfer exceeded, see `wait_event`
+    host_allocator: Arc<dyn HostAllocator>, // Of the vectors read back into, see `alloc_nodes`
+    #[cfg(feature = "leak-detection")]
+    allocations: AllocationTracker,
+    #[cfg(feature = "fault-injection")]
+    faults: Option<FaultInjector>,
+}
+
+/// Time spent on the device since the timings were last taken.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct OpTimings {
+    /// Time spent running kernels, as profiled by the device.
+    pub kernel: Duration,
+    /// Time spent transferring data between host and device.
+    pub transfer: Duration,
+    /// Bytes written to the device.
+    pub bytes_written: u64,
+    /// Bytes read back from the device.
+    pub bytes_read: u64,
 }
 
 impl GPUContext {
@@ -65,239 +349,2305 @@ impl GPUContext {
             Err(GPUError::Other("Device should be little-endian!".into()))?;
         }
 
-        info!("Compiling kernels...");
-        let code = sources::generate_nse_program(config);
-        let pro_que = ProQue::builder()
-            .device(device)
-            .src(code)
-            .dims(config.num_nodes_window)
-            .build()?;
+        let pro_que = program_cache::pro_que(device, config)?;
+        #[cfg(feature = "launch-logging")]
+        debug!("Program defines:\n{}", crate::sources::config(config));
+        check_field(&pro_que)?;
 
+        let gpu_config = GpuConfig::for_device(device)?;
         Ok(GPUContext {
+            queues: vec![pro_que.queue().clone()],
             pro_que,
             config,
-            tree_builder: match tree_options {
-                TreeOptions::Enabled { rows_to_discard } => Some(TreeBuilder::<U8>::new(
-                    Some(BatcherType::CustomGPU(GPUSelector::BusId(
-                        utils::get_bus_id(device)?,
-                    ))),
-                    config.num_nodes_window,
-                    TREE_BUILDER_BATCH_SIZE,
-                    rows_to_discard,
-                )?),
-                TreeOptions::Disabled => None,
-            },
+            gpu_config,
+            timings: OpTimings::default(),
+            spare_layers: Vec::new(),
+            pending_kernels: Vec::new(),
+            poisoned: None,
+            host_allocator: Arc::new(HeapAllocator),
+            #[cfg(feature = "leak-detection")]
+            allocations: AllocationTracker::default(),
+            #[cfg(feature = "fault-injection")]
+            faults: None,
+            tree_builder: tree_builder(device, config, tree_options)?,
+            tree_options,
         })
     }
 
-    pub(crate) fn build_kernel(&mut self, kernel_name: &str) -> KernelBuilder {
+    // See `GPU::reconfigure`. Buffers of the previous config belong to another OpenCL context,
+    // they must not be used anymore.
+    pub(crate) fn reconfigure(&mut self, config: Config) -> NSEResult<()> {
+        config.validate()?;
+        self.gpu_config.validate(config.leaf_count())?;
+        let device = self.pro_que.device();
+        let pro_que = program_cache::pro_que(device, config)?;
+        #[cfg(feature = "launch-logging")]
+        debug!("Program defines:\n{}", crate::sources::config(config));
+        check_field(&pro_que)?;
+        self.tree_builder = tree_builder(device, config, self.tree_options)?;
+        self.pro_que = pro_que;
+        self.config = config;
+        self.set_gpu_config(self.gpu_config)?;
+        Ok(())
+    }
+
+    pub fn gpu_config(&self) -> GpuConfig {
+        self.gpu_config
+    }
+
+    pub(crate) fn device(&self) -> Device {
+        self.pro_que.device()
+    }
+
+    // See `GPU::device_key`.
+    pub(crate) fn device_key(&self) -> GPUResult<String> {
+        let device = self.pro_que.device();
+        Ok(match utils::get_bus_id(device) {
+            Ok(bus_id) => format!("bus-{}", bus_id),
+            Err(_) => device
+                .name()?
+                .chars()
+                .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+                .collect(),
+        })
+    }
+
+    /// Replaces the runtime tuning knobs, (re)creating the transfer queues.
+    pub(crate) fn set_gpu_config(&mut self, gpu_config: GpuConfig) -> GPUResult<()> {
+        gpu_config.validate(self.leaf_count())?;
+        // Spare layers may be chunked differently.
+        self.spare_layers.clear();
+        let mut queues = vec![self.pro_que.queue().clone()];
+        for _ in 1..gpu_config.num_queues {
+            queues.push(Queue::new(
+                self.pro_que.context(),
+                self.pro_que.device(),
+                None,
+            )?);
+        }
+        self.queues = queues;
+        self.gpu_config = gpu_config;
+        Ok(())
+    }
+
+    /// Work-group information of every kernel of the program, on the device of the context.
+    pub(crate) fn kernel_stats(&self) -> GPUResult<Vec<KernelStats>> {
+        let program = self.pro_que.program();
+        let names = match program.info(ProgramInfo::KernelNames)? {
+            ProgramInfoResult::KernelNames(names) => names,
+            _ => return Err(GPUError::Other("Cannot list the kernels!".into())),
+        };
+        names
+            .split(';')
+            .filter(|name| !name.is_empty())
+            .map(|name| kernel_stats(program, self.pro_que.device(), name))
+            .collect()
+    }
+
+    fn kernel_builder(
+        &self,
+        kernel_name: &str,
+        global_work_size: usize,
+        local_work_size: Option<usize>,
+    ) -> KernelBuilder {
         info!("Calling {}()...", kernel_name);
+        #[cfg(feature = "launch-logging")]
+        debug!(
+            "Launching {}: global work size {}, local work size {:?}, {:?}",
+            kernel_name, global_work_size, local_work_size, self.config
+        );
         let mut k = self.pro_que.kernel_builder(kernel_name);
-        k.global_work_size([self.leaf_count()]);
+        k.global_work_size([global_work_size]);
+        if let Some(local_work_size) = local_work_size {
+            // Work-groups cannot be larger than the launch, e.g. for small windows.
+            k.local_work_size([std::cmp::min(local_work_size, global_work_size)]);
+        }
         k
     }
 
-    pub(crate) fn create_buffer(&mut self) -> GPUResult<Buffer<Node>> {
-        info!("Creating buffer...");
-        Ok(self.pro_que.create_buffer::<Node>()?)
+    pub(crate) fn build_kernel(&mut self, kernel_name: &str) -> KernelBuilder {
+        self.kernel_builder(
+            kernel_name,
+            self.gpu_config.global_work_size(self.leaf_count()),
+            self.gpu_config.local_work_size,
+        )
     }
 
-    pub(crate) fn leaf_count(&self) -> usize {
-        self.config.num_nodes_window
+    // Kernels working on a few nodes only, launched with one work-item per node.
+    pub(crate) fn build_gather_kernel(&mut self, kernel_name: &str, count: usize) -> KernelBuilder {
+        self.kernel_builder(kernel_name, count, None)
     }
-}
 
-macro_rules! call_kernel {
-    ($ctx:expr, $name:expr, $($arg:expr),*) => {{
-        let kernel =
-            $ctx
-            .build_kernel($name)
-            $(.arg($arg))*
-            .build()?;
-        unsafe {
-            kernel.enq()?;
+    // Kernels reducing a whole layer to `partials` partial results, launched with one work-item
+    // per partial result, each going over every `partials`th node.
+    pub(crate) fn build_reduction_kernel(
+        &mut self,
+        kernel_name: &str,
+        partials: usize,
+    ) -> KernelBuilder {
+        self.kernel_builder(kernel_name, partials, None)
+    }
+
+    // Kernels labeling whole windows within a work-group, launched with one work-group per
+    // window, as big as the window or the kernel allows.
+    pub(crate) fn build_window_group_kernel(
+        &mut self,
+        kernel_name: &str,
+        batch_size: usize,
+    ) -> GPUResult<KernelBuilder> {
+        let stats = kernel_stats(self.pro_que.program(), self.pro_que.device(), kernel_name)?;
+        let local_work_size = std::cmp::min(self.leaf_count(), stats.max_work_group_size);
+        Ok(self.kernel_builder(
+            kernel_name,
+            local_work_size * batch_size,
+            Some(local_work_size),
+        ))
+    }
+
+    /// Allocates a layer, in chunks of at most `GpuConfig::max_alloc_chunk` bytes, unless a
+    /// spare one was reserved.
+    pub(crate) fn create_buffer(&mut self) -> GPUResult<LayerBuffer> {
+        if let Some(buffer) = self.spare_layers.pop() {
+            return Ok(buffer);
         }
-    }};
-}
+        let leaf_count = self.leaf_count();
+        let chunk_len = self.gpu_config.layer_chunk_len(leaf_count);
+        info!("Creating buffer in chunks of {} nodes...", chunk_len);
+        let chunks = (0..leaf_count / chunk_len)
+            .map(|_| self.create_buffer_with_len(chunk_len))
+            .collect::<GPUResult<Vec<_>>>()?;
+        Ok(LayerBuffer { chunks, chunk_len })
+    }
 
-const TREE_BUILDER_BATCH_SIZE: usize = 400_000;
+    /// Allocates the layers of `minimum` and writes them, as drivers only commit memory on
+    /// first use, so that a device too small for the config fails here rather than in the
+    /// middle of a window. They are kept as spares, see `recycle_buffer`.
+    pub(crate) fn reserve(&mut self, minimum: MinimumMemory) -> NSEResult<()> {
+        let insufficient = |reason: String| NSEError::InsufficientDeviceMemory { minimum, reason };
+        let (global, max_alloc) = device_memory(self.device())?;
+        if minimum.total_bytes() > global {
+            return Err(insufficient(format!("the device has {} bytes", global)));
+        }
+        if minimum.max_alloc_bytes > max_alloc {
+            return Err(insufficient(format!(
+                "the device allocates at most {} bytes at once, see `GpuConfig::max_alloc_chunk`",
+                max_alloc
+            )));
+        }
+        self.spare_layers.clear();
+        let mut layers = Vec::new();
+        for _ in 0..minimum.layer_buffers {
+            let layer = self
+                .create_buffer()
+                .and_then(|layer| {
+                    for chunk in &layer.chunks {
+                        chunk.cmd().fill(Node::default(), None).enq()?;
+                    }
+                    self.pro_que.queue().finish()?;
+                    Ok(layer)
+                })
+                .map_err(|e| insufficient(e.to_string()))?;
+            layers.push(layer);
+        }
+        self.spare_layers = layers;
+        Ok(())
+    }
 
-pub struct GPU {
-    context: GPUContext,
-    combine_batch_size: usize,
-    current_layer: Buffer<Node>, // This has the last generated layer (In ordinary form)
-    pub config: Config,
-}
+    /// Keeps `buffer`, a layer no longer used, as a spare for the next `create_buffer`, so that
+    /// labeling layers doesn't allocate once the GPU is created.
+    pub(crate) fn recycle_buffer(&mut self, buffer: LayerBuffer) {
+        if self.spare_layers.is_empty() {
+            self.spare_layers.push(buffer);
+        }
+    }
 
-impl GPU {
-    pub fn tree_builder(&mut self) -> &mut Option<TreeBuilder<U8>> {
-        &mut self.context.tree_builder
+    pub(crate) fn create_buffer_with_len<T: OclPrm>(&mut self, len: usize) -> GPUResult<Buffer<T>> {
+        info!("Creating buffer of {} elements...", len);
+        let mut flags = MemFlags::new().read_write();
+        if self.gpu_config.pinned_memory {
+            flags = flags.alloc_host_ptr();
+        }
+        let buffer = Buffer::<T>::builder()
+            .queue(self.pro_que.queue().clone())
+            .flags(flags)
+            .len(len)
+            .build()?;
+        #[cfg(feature = "leak-detection")]
+        self.allocations.track(&buffer);
+        Ok(buffer)
     }
 
-    fn replace_buffer(&mut self, buff: Buffer<Node>) {
-        std::mem::replace(&mut self.current_layer, buff);
+    // Kernels processing `batch_size` windows need `batch_size` times more work-items.
+    pub(crate) fn build_batch_kernel(
+        &mut self,
+        kernel_name: &str,
+        batch_size: usize,
+    ) -> KernelBuilder {
+        let global_work_size = self
+            .gpu_config
+            .global_work_size
+            .unwrap_or(self.leaf_count() * batch_size);
+        self.kernel_builder(
+            kernel_name,
+            global_work_size,
+            self.gpu_config.local_work_size,
+        )
     }
 
-    // Overwrite current layer
-    pub fn push_layer(&mut self, layer: &Layer) -> NSEResult<()> {
-        write_buffer(&mut self.current_layer, 0, &layer.0)?; // Push montgomery form in buffer
-        let ordinary = self.context.create_buffer()?; // Create new temp buffer
-        call_kernel!(
-            self.context,
-            "generate_ordinary",
-            &self.current_layer,
-            &ordinary
-        );
-        self.replace_buffer(ordinary); // Current buffer has now the ordinary form
+    // Enqueues a kernel on the main queue without waiting for it: the host only synchronizes
+    // with the device when reading results back (see `sync_kernels`), or after every kernel
+    // with `GpuConfig::kernel_timeout`. The running time of the kernel is profiled by its event.
+    pub(crate) unsafe fn enqueue_kernel(&mut self, kernel: &Kernel) -> GPUResult<()> {
+        self.check_poisoned()?;
+        #[cfg(feature = "fault-injection")]
+        self.inject(FaultPoint::KernelLaunch)?;
+        let mut event = Event::empty();
+        kernel.cmd().enew(&mut event).enq()?;
+        if let Some(timeout) = self.gpu_config.kernel_timeout {
+            self.pro_que.queue().flush()?;
+            self.wait_event(&event, timeout)?;
+        }
+        self.pending_kernels.push(event);
         Ok(())
     }
-}
 
-impl NarrowStackedExpander for GPU {
-    fn new(mut context: GPUContext, config: Config) -> NSEResult<Self> {
-        let current_layer = context.create_buffer()?;
+    // Waits for the kernels enqueued so far, and accounts for their running time.
+    pub(crate) fn sync_kernels(&mut self) -> GPUResult<()> {
+        self.pro_que.queue().finish()?;
+        for event in std::mem::replace(&mut self.pending_kernels, Vec::new()) {
+            let start = profiled_time(&event, ProfilingInfo::Start)?;
+            let end = profiled_time(&event, ProfilingInfo::End)?;
+            self.timings.kernel += Duration::from_nanos(end.saturating_sub(start));
+        }
+        Ok(())
+    }
 
-        Ok(GPU {
-            context,
-            current_layer,
-            combine_batch_size: COMBINE_BATCH_SIZE,
-            config,
+    // Polls the event of a flushed kernel or transfer, as `finish()` may block forever on a
+    // hung device. On timeout the context is poisoned: every later operation fails, and the
+    // buffers of the GPU are leaked rather than released while the device may still use them
+    // (see `Drop for GPU`). The program is evicted from the cache so that the contexts created
+    // next (e.g. when the caller recreates its GPU) don't share the OpenCL context of this one.
+    fn wait_event(&mut self, event: &Event, timeout: Duration) -> GPUResult<()> {
+        let start = Instant::now();
+        while !event.is_complete()? {
+            if start.elapsed() > timeout {
+                warn!(
+                    "Device still busy after {:?}, abandoning the context...",
+                    timeout
+                );
+                self.poisoned = Some(timeout);
+                program_cache::evict(self.pro_que.device(), self.config);
+                return Err(GPUError::KernelTimeout(timeout));
+            }
+            thread::sleep(KERNEL_POLL_INTERVAL);
+        }
+        Ok(())
+    }
+
+    fn check_poisoned(&self) -> GPUResult<()> {
+        match self.poisoned {
+            Some(timeout) => Err(GPUError::KernelTimeout(timeout)),
+            None => Ok(()),
+        }
+    }
+
+    /// Whether a kernel or transfer timed out, see `GpuConfig::kernel_timeout`. The GPU must then
+    /// be recreated.
+    pub(crate) fn is_poisoned(&self) -> bool {
+        self.poisoned.is_some()
+    }
+
+    #[cfg(feature = "fault-injection")]
+    fn inject(&self, point: FaultPoint) -> GPUResult<()> {
+        match &self.faults {
+            Some(faults) => faults.check(point),
+            None => Ok(()),
+        }
+    }
+
+    pub(crate) fn take_timings(&mut self) -> OpTimings {
+        // Kernels still running are accounted once done, a failure surfaces at the next sync.
+        if let Err(e) = self.sync_kernels() {
+            warn!("Cannot profile the kernels: {}", e);
+        }
+        std::mem::replace(&mut self.timings, OpTimings::default())
+    }
+
+    // Transfers `len` bytes each way through `write_buffer` and `read_buffer`, without
+    // accounting them in the timings.
+    pub(crate) fn measure_bandwidth(&mut self, len: usize) -> GPUResult<Bandwidth> {
+        let timings = self.take_timings();
+        let mut buffer = self.create_buffer_with_len::<u8>(len)?;
+        let mut data = vec![0u8; len];
+        self.write_buffer(&mut buffer, 0, &data)?;
+        let written = self.take_timings();
+        self.read_buffer(&buffer, 0, &mut data)?;
+        let read = std::mem::replace(&mut self.timings, timings);
+        let bytes_per_sec = |bytes: u64, time: Duration| {
+            if time > Duration::from_secs(0) {
+                bytes as f64 / time.as_secs_f64()
+            } else {
+                0f64
+            }
+        };
+        Ok(Bandwidth {
+            host_to_device: bytes_per_sec(written.bytes_written, written.transfer),
+            device_to_host: bytes_per_sec(read.bytes_read, read.transfer),
         })
     }
 
-    fn generate_mask_layer(
+    pub(crate) fn write_buffer<T: OclPrm>(
         &mut self,
-        replica_id: ReplicaId,
-        window_index: usize,
-    ) -> NSEResult<Layer> {
-        let mut l = Layer(vec![Node::default(); self.leaf_count()]);
-        let ord_output = self.context.create_buffer()?;
-        call_kernel!(
-            self.context,
-            "generate_mask",
-            &ord_output,
-            replica_id,
-            window_index as u32
-        );
-        call_kernel!(
-            self.context,
-            "generate_montgomery",
-            &ord_output,
-            &self.current_layer
-        );
-        read_buffer(&self.current_layer, 0, &mut l.0)?;
-        self.replace_buffer(ord_output);
-        Ok(l)
+        buff: &mut Buffer<T>,
+        offset: usize,
+        segment: &[T],
+    ) -> GPUResult<()> {
+        info!("Pushing data...");
+        self.check_poisoned()?;
+        #[cfg(feature = "fault-injection")]
+        self.inject(FaultPoint::Write)?;
+        let start = Instant::now();
+        match self.gpu_config.kernel_timeout {
+            None => buff.write(segment).offset(offset).enq()?,
+            Some(timeout) => {
+                // The device may still read the data after the wait is abandoned, it is copied
+                // to memory leaked then.
+                let data = segment.to_vec();
+                let mut event = Event::empty();
+                unsafe {
+                    buff.write(&data[..])
+                        .offset(offset)
+                        .block(false)
+                        .enew(&mut event)
+                        .enq()?;
+                }
+                buff.default_queue()
+                    .expect("Buffers are created with a queue")
+                    .flush()?;
+                if let Err(e) = self.wait_event(&event, timeout) {
+                    std::mem::forget(data);
+                    return Err(e);
+                }
+            }
+        }
+        self.timings.transfer += start.elapsed();
+        self.timings.bytes_written += (segment.len() * std::mem::size_of::<T>()) as u64;
+        Ok(())
     }
 
-    fn generate_expander_layer(
+    /// Reads `segment.len()` elements starting at `offset`, in chunks of
+    /// `readback_chunk_size`, spread across the transfer queues.
+    pub(crate) fn read_buffer<T: OclPrm>(
         &mut self,
-        replica_id: ReplicaId,
-        window_index: usize,
-        layer_index: usize,
-    ) -> NSEResult<Layer> {
-        let mut l = Layer(vec![Node::default(); self.leaf_count()]);
-        let ord_output = self.context.create_buffer()?;
-        call_kernel!(
-            self.context,
-            "generate_expander",
-            &self.current_layer,
-            &ord_output,
-            replica_id,
-            window_index as u32,
-            layer_index as u32
-        );
-        call_kernel!(
-            self.context,
-            "generate_montgomery",
-            &ord_output,
-            &self.current_layer
-        );
-        read_buffer(&self.current_layer, 0, &mut l.0)?;
-        self.replace_buffer(ord_output);
-        Ok(l)
+        buff: &Buffer<T>,
+        offset: usize,
+        segment: &mut [T],
+    ) -> GPUResult<()> {
+        self.read_buffer_segments(buff, offset, &mut [segment])
     }
 
-    fn generate_butterfly_layer(
+    // Reads consecutive segments starting at `offset`, the reads of all segments are enqueued
+    // before the queues are waited for once.
+    fn read_buffer_segments<T: OclPrm>(
         &mut self,
-        replica_id: ReplicaId,
-        window_index: usize,
-        layer_index: usize,
-    ) -> NSEResult<Layer> {
-        let mut l = Layer(vec![Node::default(); self.leaf_count()]);
-        let ord_output = self.context.create_buffer()?;
-        call_kernel!(
-            self.context,
-            "generate_butterfly",
-            &self.current_layer,
-            &ord_output,
-            replica_id,
-            window_index as u32,
-            layer_index as u32
-        );
-        call_kernel!(
-            self.context,
-            "generate_montgomery",
-            &ord_output,
-            &self.current_layer
-        );
-        read_buffer(&self.current_layer, 0, &mut l.0)?;
-        self.replace_buffer(ord_output);
-        Ok(l)
+        buff: &Buffer<T>,
+        offset: usize,
+        segments: &mut [&mut [T]],
+    ) -> GPUResult<()> {
+        info!("Pulling results...");
+        self.check_poisoned()?;
+        #[cfg(feature = "fault-injection")]
+        self.inject(FaultPoint::Readback)?;
+        // Make sure kernels writing to `buff` (enqueued on the main queue) are done.
+        self.sync_kernels()?;
+        let start = Instant::now();
+        let chunk_size = self.gpu_config.readback_chunk_size;
+        let timeout = self.gpu_config.kernel_timeout;
+        let len: usize = segments.iter().map(|segment| segment.len()).sum();
+        // With a timeout, the device may still write after the wait is abandoned: the data is
+        // read into staging memory, leaked then.
+        let mut staging = timeout.map(|_| vec![T::default(); len]);
+        let mut targets = match &mut staging {
+            Some(staging) => {
+                let mut targets = Vec::with_capacity(segments.len());
+                let mut rest = &mut staging[..];
+                for segment in segments.iter() {
+                    let (target, tail) = std::mem::take(&mut rest).split_at_mut(segment.len());
+                    targets.push(target);
+                    rest = tail;
+                }
+                targets
+            }
+            None => segments.iter_mut().map(|segment| &mut **segment).collect(),
+        };
+        let mut result = Ok(());
+        let mut events = Vec::new();
+        let mut target_offset = offset;
+        'enqueue: for target in targets.iter_mut() {
+            let target_len = target.len();
+            for (i, chunk) in target.chunks_mut(chunk_size).enumerate() {
+                let queue = &self.queues[events.len() % self.queues.len()];
+                let mut event = Event::empty();
+                // Safe: all reads are waited for below, even if enqueuing fails, before the
+                // targets are released.
+                result = unsafe {
+                    buff.read(chunk)
+                        .queue(queue)
+                        .offset(target_offset + i * chunk_size)
+                        .block(false)
+                        .enew(&mut event)
+                        .enq()
+                };
+                if result.is_err() {
+                    break 'enqueue;
+                }
+                events.push(event);
+            }
+            target_offset += target_len;
+        }
+        drop(targets);
+        match timeout {
+            None => {
+                for queue in self.queues.iter() {
+                    let finished = queue.finish();
+                    if result.is_ok() {
+                        result = finished;
+                    }
+                }
+            }
+            Some(timeout) => {
+                for queue in self.queues.iter() {
+                    let flushed = queue.flush();
+                    if result.is_ok() {
+                        result = flushed;
+                    }
+                }
+                for event in events.iter() {
+                    if let Err(e) = self.wait_event(event, timeout) {
+                        std::mem::forget(staging);
+                        return Err(e);
+                    }
+                }
+            }
+        }
+        result?;
+        if let Some(staging) = staging {
+            let mut rest = &staging[..];
+            for segment in segments.iter_mut() {
+                let (read, tail) = rest.split_at(segment.len());
+                segment.copy_from_slice(read);
+                rest = tail;
+            }
+        }
+        self.timings.transfer += start.elapsed();
+        self.timings.bytes_read += (len * std::mem::size_of::<T>()) as u64;
+        Ok(())
     }
 
-    fn finalize(&mut self) -> NSEResult<()> {
-        call_kernel!(self.context, "to_montgomery", &self.current_layer);
+    pub(crate) fn write_layer(
+        &mut self,
+        buff: &mut LayerBuffer,
+        offset: usize,
+        segment: &[Node],
+    ) -> GPUResult<()> {
+        for (chunk, chunk_offset, range) in buff.split(offset, segment.len()) {
+            self.write_buffer(&mut buff.chunks[chunk], chunk_offset, &segment[range])?;
+        }
         Ok(())
     }
 
-    fn combine_segment(
+    pub(crate) fn read_layer(
         &mut self,
+        buff: &LayerBuffer,
         offset: usize,
-        segment: &[Node],
-        is_decode: bool,
-    ) -> NSEResult<Vec<Node>> {
-        // Montgomery form of mask is in kernel_buffer!
-        let mut l = vec![Node::default(); segment.len()];
-        let mut data = self.context.create_buffer()?;
-        write_buffer(&mut data, offset, &segment)?;
-        call_kernel!(
-            self.context,
-            "combine_segment",
-            &self.current_layer,
-            &data,
-            offset as u32,
-            segment.len() as u32,
-            is_decode as u32
-        );
-        read_buffer(&data, offset, &mut l)?;
-        Ok(l)
+        segment: &mut [Node],
+    ) -> GPUResult<()> {
+        for (chunk, chunk_offset, range) in buff.split(offset, segment.len()) {
+            self.read_buffer(&buff.chunks[chunk], chunk_offset, &mut segment[range])?;
+        }
+        Ok(())
     }
 
-    fn combine_batch_size(&self) -> usize {
-        self.combine_batch_size
+    /// Staging vector of `len` nodes for `read_buffer`, from the `HostAllocator` of the GPU.
+    pub(crate) fn alloc_nodes(&self, len: usize) -> Vec<Node> {
+        self.host_allocator.alloc_nodes(len)
     }
 
-    fn leaf_count(&self) -> usize {
-        self.context.leaf_count()
+    /// Reads consecutive segments of `buff` of the given lengths, starting at the beginning of
+    /// the buffer, each into a vector of its own, e.g. the layers of a batch of windows.
+    pub(crate) fn read_segments(
+        &mut self,
+        buff: &Buffer<Node>,
+        lens: &[usize],
+    ) -> GPUResult<Vec<Vec<Node>>> {
+        let mut segments = lens
+            .iter()
+            .map(|&len| self.alloc_nodes(len))
+            .collect::<Vec<_>>();
+        {
+            let mut targets = segments
+                .iter_mut()
+                .map(|segment| &mut segment[..])
+                .collect::<Vec<_>>();
+            self.read_buffer_segments(buff, 0, &mut targets)?;
+        }
+        Ok(segments)
+    }
+
+    pub(crate) fn leaf_count(&self) -> usize {
+        self.config.leaf_count()
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use ff::{Field, PrimeField};
-    use paired::bls12_381::Fr;
+#[cfg(feature = "launch-logging")]
+fn log_kernel_args(kernel_name: &str, descriptions: &[String]) {
+    debug!(
+        "Arguments of {}: [{}]",
+        kernel_name,
+        descriptions.join(", ")
+    );
+}
 
-    const TEST_CONFIG: Config = Config {
-        k: 4,
-        num_nodes_window: 1024,
-        degree_expander: 96,
-        degree_butterfly: 4,
-        num_expander_layers: 4,
-        num_butterfly_layers: 3,
+// Arguments are pushed in a block of their own, so that the borrow of the context by the
+// kernel builder ends before the kernel is enqueued.
+macro_rules! call_kernel {
+    ($ctx:expr, $name:expr, $($arg:expr),*) => {{
+        let kernel = {
+            let mut builder = $ctx.build_kernel($name);
+            #[cfg(feature = "launch-logging")]
+            let mut descriptions = Vec::<String>::new();
+            $(
+                let arg = $arg;
+                #[cfg(feature = "launch-logging")]
+                descriptions.push(KernelArg::describe(&arg));
+                KernelArg::push(arg, &mut builder);
+            )*
+            #[cfg(feature = "launch-logging")]
+            log_kernel_args($name, &descriptions);
+            builder.build()?
+        };
+        unsafe {
+            $ctx.enqueue_kernel(&kernel)?;
+        }
+    }};
+}
+
+macro_rules! call_batch_kernel {
+    ($ctx:expr, $name:expr, $batch_size:expr, $($arg:expr),*) => {{
+        let kernel = {
+            let mut builder = $ctx.build_batch_kernel($name, $batch_size);
+            #[cfg(feature = "launch-logging")]
+            let mut descriptions = Vec::<String>::new();
+            $(
+                let arg = $arg;
+                #[cfg(feature = "launch-logging")]
+                descriptions.push(KernelArg::describe(&arg));
+                KernelArg::push(arg, &mut builder);
+            )*
+            #[cfg(feature = "launch-logging")]
+            log_kernel_args($name, &descriptions);
+            builder.arg($batch_size as u32);
+            builder.build()?
+        };
+        unsafe {
+            $ctx.enqueue_kernel(&kernel)?;
+        }
+    }};
+}
+
+const TREE_BUILDER_BATCH_SIZE: usize = 400_000;
+
+// Work-items computing `GPU::layer_stats`, each over every `LAYER_STATS_PARTIALS`th node.
+const LAYER_STATS_PARTIALS: usize = 4096;
+
+// How often a kernel is checked for completion when `GpuConfig::kernel_timeout` is set.
+const KERNEL_POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+/// A GPU is `Send` but not `Sync`: it can be moved to another thread, but every operation needs
+/// `&mut self`, as kernels and transfers share the buffers of the GPU. Use a `GpuHandle` to share
+/// one GPU between several threads.
+pub struct GPU {
+    context: GPUContext,
+    combine_batch_size: usize,
+    current_layer: LayerBuffer, // This has the last generated layer (In ordinary form)
+    finalized: bool,            // Whether `current_layer` has been converted to Montgomery form
+    parent_cache: Option<Buffer<u32>>, // See `GpuConfig::cache_expander_parents`
+    mask_cache: MaskCache,      // See `GpuConfig::mask_cache_size`
+    staged_data: Option<StagedData>, // See `GPU::stage_data`
+    pub config: Config,
+}
+
+// Original data uploaded by a thread on a transfer queue of its own, see `GPU::stage_data`.
+struct StagedData {
+    buffer: LayerBuffer,
+    upload: thread::JoinHandle<GPUResult<()>>,
+}
+
+/// The state of a window set aside by `GPU::suspend`, to be restored by `GPU::resume`.
+pub struct SuspendedWindow {
+    config: Config,
+    current_layer: LayerBuffer,
+    finalized: bool,
+    staged_data: Option<StagedData>,
+    timings: OpTimings,
+}
+
+/// A cloneable handle to a GPU, serializing all submissions through an internal lock.
+#[derive(Clone)]
+pub struct GpuHandle(Arc<Mutex<GPU>>);
+
+impl GpuHandle {
+    pub fn new(gpu: GPU) -> Self {
+        GpuHandle(Arc::new(Mutex::new(gpu)))
+    }
+
+    /// Locks the GPU for the lifetime of the returned guard, e.g. to run a whole `Sealer` on it.
+    pub fn lock(&self) -> MutexGuard<GPU> {
+        self.0.lock().unwrap()
+    }
+
+    /// Runs `f` with exclusive access to the GPU.
+    pub fn with<F: FnOnce(&mut GPU) -> R, R>(&self, f: F) -> R {
+        f(&mut self.lock())
+    }
+}
+
+#[cfg(feature = "leak-detection")]
+impl GPU {
+    /// Number of device buffers allocated by this GPU that are still alive.
+    pub fn live_buffers(&mut self) -> usize {
+        self.context.allocations.live()
+    }
+
+    /// Describes the device buffers allocated by this GPU that are still alive, apart from the
+    /// ones owned by the GPU itself.
+    pub fn leaked_buffers(&mut self) -> Vec<String> {
+        let owned = self
+            .current_layer
+            .chunks
+            .iter()
+            .map(|chunk| chunk.as_core())
+            .chain(self.parent_cache.iter().map(|parents| parents.as_core()))
+            .chain(
+                self.staged_data
+                    .iter()
+                    .flat_map(|staged| staged.buffer.chunks.iter())
+                    .map(|chunk| chunk.as_core()),
+            )
+            .chain(
+                self.context
+                    .spare_layers
+                    .iter()
+                    .flat_map(|layer| layer.chunks.iter())
+                    .map(|chunk| chunk.as_core()),
+            )
+            .collect::<Vec<_>>();
+        self.context.allocations.leaks(&owned)
+    }
+}
+
+#[cfg(feature = "fault-injection")]
+impl GPU {
+    /// Makes the device operations chosen by `faults` fail, see `FaultInjector`. `None`
+    /// detaches the current injector.
+    pub fn set_fault_injector(&mut self, faults: Option<FaultInjector>) {
+        self.context.faults = faults;
+    }
+}
+
+#[cfg(feature = "raw-handles")]
+impl GPU {
+    /// The OpenCL context the GPU allocates its buffers and compiles its program in, so that
+    /// other workloads on the same device can share it instead of creating their own.
+    ///
+    /// # Safety
+    /// The handle is borrowed from the GPU: it must not be released, nor used once the GPU is
+    /// dropped or reconfigured (see `GPU::reconfigure`), which switches to another context.
+    pub unsafe fn raw_context(&self) -> ocl::core::ffi::cl_context {
+        self.context.pro_que.context().as_core().as_ptr()
+    }
+
+    /// The command queue the kernels of the GPU are enqueued on, so that work enqueued on it by
+    /// others is serialized with the layers of the GPU. Transfers are also spread over the other
+    /// queues of `GpuConfig::num_queues`, which are not exposed.
+    ///
+    /// # Safety
+    /// Same as `raw_context`. Besides, the queue must not be left with unfinished commands
+    /// reading or writing buffers of the GPU.
+    pub unsafe fn raw_queue(&self) -> ocl::core::ffi::cl_command_queue {
+        self.context.pro_que.queue().as_core().as_ptr()
+    }
+}
+
+impl Drop for GPU {
+    fn drop(&mut self) {
+        if self.context.is_poisoned() {
+            // A hung kernel or transfer may still use the buffers, releasing them could corrupt
+            // the memory of whatever is allocated next.
+            warn!("Leaking the device buffers of a timed out GPU...");
+            let empty = LayerBuffer {
+                chunks: Vec::new(),
+                chunk_len: 0,
+            };
+            std::mem::forget(std::mem::replace(&mut self.current_layer, empty));
+            std::mem::forget(self.parent_cache.take());
+            std::mem::forget(self.staged_data.take());
+            std::mem::forget(std::mem::replace(
+                &mut self.context.spare_layers,
+                Vec::new(),
+            ));
+            return;
+        }
+        #[cfg(feature = "leak-detection")]
+        for leak in self.leaked_buffers() {
+            log::error!("Leaked device buffer! {}", leak);
+        }
+    }
+}
+
+impl From<GPU> for GpuHandle {
+    fn from(gpu: GPU) -> Self {
+        GpuHandle::new(gpu)
+    }
+}
+
+impl GPU {
+    /// Creates a GPU with the default runtime tuning knobs of its device.
+    pub fn new(context: GPUContext, config: Config) -> NSEResult<Self> {
+        let gpu_config = context.gpu_config();
+        GPU::with_gpu_config(context, config, gpu_config)
+    }
+
+    /// Creates a GPU with the given runtime tuning knobs instead of the per-vendor defaults.
+    pub fn with_gpu_config(
+        mut context: GPUContext,
+        config: Config,
+        gpu_config: GpuConfig,
+    ) -> NSEResult<Self> {
+        context.set_gpu_config(gpu_config)?;
+        context.reserve(MinimumMemory::new(&config, &gpu_config))?;
+        let current_layer = context.create_buffer()?;
+
+        let mut gpu = GPU {
+            context,
+            current_layer,
+            finalized: false,
+            parent_cache: None,
+            mask_cache: MaskCache::new(gpu_config.mask_cache_size),
+            staged_data: None,
+            combine_batch_size: COMBINE_BATCH_SIZE,
+            config,
+        };
+        if gpu_config.cache_expander_parents && gpu_config.parent_cache_bytes(&config) == 0 {
+            warn!("The expander parents don't fit in `max_alloc_chunk`, they are not cached.");
+        }
+        gpu.ensure_parent_cache()?;
+        Ok(gpu)
+    }
+
+    /// Device memory the GPU needs at least to seal a window, reserved when it is created, see
+    /// `MinimumMemory` for what comes on top of it.
+    pub fn minimum_memory(&self) -> MinimumMemory {
+        MinimumMemory::new(&self.config, &self.gpu_config())
+    }
+
+    pub fn gpu_config(&self) -> GpuConfig {
+        self.context.gpu_config()
+    }
+
+    /// Allocates the host vectors nodes are read back into with `allocator` from now on, e.g.
+    /// a `HugePageAllocator` for large windows. Defaults to `HeapAllocator`.
+    pub fn set_host_allocator(&mut self, allocator: Arc<dyn HostAllocator>) {
+        self.context.host_allocator = allocator;
+    }
+
+    pub fn config(&self) -> Config {
+        self.config
+    }
+
+    /// Switches to `config`, e.g. to seal another sector size, keeping the device, the
+    /// `GpuConfig` and the tree options. The program of `config` is compiled unless cached (see
+    /// `clear_program_cache`) and layers are reallocated, the current layer is lost as with a
+    /// new GPU. On error, the GPU should be dropped.
+    pub fn reconfigure(&mut self, config: Config) -> NSEResult<()> {
+        if config == self.config {
+            return Ok(());
+        }
+        info!("Reconfiguring the GPU: {:?}", config);
+        self.context.check_poisoned()?;
+        self.discard_staged_data()?;
+        self.context.reconfigure(config)?;
+        self.config = config;
+        self.parent_cache = None;
+        self.mask_cache.clear();
+        self.context.reserve(self.minimum_memory())?;
+        // Not recycled, the previous layer belongs to the previous context.
+        self.current_layer = self.context.create_buffer()?;
+        self.finalized = false;
+        self.ensure_parent_cache()?;
+        Ok(())
+    }
+
+    /// Identifies the device across processes, see `DeviceLock`: its PCI bus id where the
+    /// driver reports it, its name otherwise (so identical devices then share a lock).
+    pub fn device_key(&self) -> NSEResult<String> {
+        Ok(self.context.device_key()?)
+    }
+
+    /// Name of the device, as reported by the driver.
+    pub fn device_name(&self) -> NSEResult<String> {
+        Ok(self.context.pro_que.device().name()?)
+    }
+
+    /// Drops the GPU along with the cached program of its config on its device, which keeps the
+    /// OpenCL context alive otherwise, so that the device memory of both is freed (unless other
+    /// GPUs still use the program). The next GPU created for them compiles the kernels again.
+    pub(crate) fn release(self) {
+        program_cache::evict(self.context.pro_que.device(), self.config);
+    }
+
+    /// Returns the time spent on the device since the last call, and resets the counters.
+    pub fn take_timings(&mut self) -> OpTimings {
+        self.context.take_timings()
+    }
+
+    /// The tree builder of the context, if trees are enabled, see `TreeOptions`.
+    pub fn tree_builder(&mut self) -> Option<&mut TreeBuilder<U8>> {
+        self.context
+            .tree_builder
+            .as_mut()
+            .map(|tree_builder| &mut tree_builder.0)
+    }
+
+    /// Returns the work-group information of each compiled kernel, to reason about occupancy
+    /// when choosing `GpuConfig::local_work_size`.
+    pub fn kernel_stats(&self) -> NSEResult<Vec<KernelStats>> {
+        Ok(self.context.kernel_stats()?)
+    }
+
+    /// Measures the host/device transfer bandwidth, by writing and reading back a buffer of
+    /// `BANDWIDTH_TEST_BYTES` bytes with the transfer settings of the `GpuConfig`. Comparing it
+    /// with the bytes and time of `take_timings` tells whether a configuration is bound by
+    /// transfers or by kernels.
+    pub fn measure_bandwidth(&mut self) -> NSEResult<Bandwidth> {
+        Ok(self.context.measure_bandwidth(BANDWIDTH_TEST_BYTES)?)
+    }
+
+    /// Measures the expander labeling throughput of the config of this GPU with each `k` of
+    /// `ks`, on the same device and with the same `GpuConfig`, so that `k` can be chosen
+    /// empirically. Each `k` compiles its own program and allocates its own layers, the GPU is
+    /// left untouched. Expander layers are the only ones depending on `k`, `samples` of them
+    /// are timed (kernel time only) after a warm-up one.
+    pub fn bench_k_sweep(&self, ks: &[u32], samples: usize) -> NSEResult<Vec<KThroughput>> {
+        if samples == 0 {
+            return Err(NSEError::InvalidInput("No samples to time!".into()));
+        }
+        let replica_id = ReplicaId([0u8; 32]);
+        ks.iter()
+            .map(|&k| {
+                let config = Config { k, ..self.config };
+                config.validate()?;
+                let context =
+                    GPUContext::new(self.context.device(), config, TreeOptions::Disabled)?;
+                let mut gpu = GPU::with_gpu_config(context, config, self.gpu_config())?;
+                gpu.generate_mask_layer(replica_id, WindowIndex(0))?;
+                gpu.generate_expander_layer(replica_id, WindowIndex(0), 2)?;
+                gpu.take_timings();
+                for _ in 0..samples {
+                    gpu.generate_expander_layer(replica_id, WindowIndex(0), 2)?;
+                }
+                let kernel_secs = gpu.take_timings().kernel.as_secs_f64();
+                let nodes = (samples * config.num_nodes_window) as f64;
+                Ok(KThroughput {
+                    k,
+                    nodes_per_sec: if kernel_secs > 0f64 {
+                        nodes / kernel_secs
+                    } else {
+                        0f64
+                    },
+                })
+            })
+            .collect()
+    }
+
+    /// Generates the key layers of several windows at once, see `BatchKeyGenerator`.
+    pub fn batch_key_generator(
+        &mut self,
+        windows: &[(ReplicaId, WindowIndex)],
+    ) -> NSEResult<BatchKeyGenerator> {
+        BatchKeyGenerator::new(self, windows)
+    }
+
+    /// Generates all key layers of many small windows in a single kernel launch, e.g. to test
+    /// the labeling functions over thousands of windows at once. Returns the layers of each
+    /// window, starting with the mask layer, in the same form as `KeyGenerator`. Each window is
+    /// labeled by a single work-group, so this is slow for windows of production size. The
+    /// layers of all windows are allocated at once, in as many batches of windows as
+    /// `GpuConfig::max_alloc_chunk` requires.
+    pub fn label_small_windows(
+        &mut self,
+        windows: &[(ReplicaId, WindowIndex)],
+    ) -> NSEResult<Vec<Vec<Layer>>> {
+        if windows.is_empty() {
+            return Err(NSEError::InvalidInput("Empty batch of windows!".into()));
+        }
+        for (_, index) in windows {
+            index.validate(&self.config)?;
+        }
+        let window_bytes = self.config.num_layers() * self.leaf_count() * NODE_SIZE;
+        let batch_size = self
+            .gpu_config()
+            .items_per_alloc(window_bytes, windows.len());
+        if batch_size == 0 {
+            return Err(NSEError::InvalidInput(format!(
+                "The layers of a window ({} bytes) exceed `max_alloc_chunk`, use a KeyGenerator!",
+                window_bytes
+            )));
+        }
+        let mut layers = Vec::with_capacity(windows.len());
+        for batch in windows.chunks(batch_size) {
+            layers.extend(self.label_small_window_batch(batch)?);
+        }
+        Ok(layers)
+    }
+
+    // Labels windows whose layers fit in a single allocation, see `label_small_windows`.
+    fn label_small_window_batch(
+        &mut self,
+        windows: &[(ReplicaId, WindowIndex)],
+    ) -> NSEResult<Vec<Vec<Layer>>> {
+        let leaf_count = self.leaf_count();
+        let num_layers = self.config.num_layers();
+        let batch_size = windows.len();
+        let ids = windows.iter().map(|w| w.0).collect::<Vec<_>>();
+        let indices = windows.iter().map(|w| w.1).collect::<Vec<_>>();
+        let mut replica_ids = self.context.create_buffer_with_len(batch_size)?;
+        let mut window_indices = self.context.create_buffer_with_len(batch_size)?;
+        self.context.write_buffer(&mut replica_ids, 0, &ids)?;
+        self.context
+            .write_buffer(&mut window_indices, 0, &indices)?;
+        let layers = self
+            .context
+            .create_buffer_with_len::<Node>(batch_size * num_layers * leaf_count)?;
+        let kernel = {
+            let mut builder = self
+                .context
+                .build_window_group_kernel("generate_small_windows", batch_size)?;
+            #[cfg(feature = "launch-logging")]
+            log_kernel_args(
+                "generate_small_windows",
+                &[
+                    (&layers).describe(),
+                    (&replica_ids).describe(),
+                    (&window_indices).describe(),
+                    batch_size.to_string(),
+                ],
+            );
+            builder
+                .arg(&layers)
+                .arg(&replica_ids)
+                .arg(&window_indices)
+                .arg(batch_size as u32);
+            builder.build()?
+        };
+        unsafe {
+            self.context.enqueue_kernel(&kernel)?;
+        }
+        let mut nodes = self
+            .context
+            .read_segments(&layers, &vec![leaf_count; layers.len() / leaf_count])?
+            .into_iter()
+            .map(Layer);
+        Ok((0..windows.len())
+            .map(|_| nodes.by_ref().take(num_layers).collect())
+            .collect())
+    }
+
+    // Computes the expander parents of all nodes on first use, if they are to be cached, see
+    // `GpuConfig::parent_cache_bytes`.
+    fn ensure_parent_cache(&mut self) -> NSEResult<()> {
+        if self.parent_cache.is_none() && self.gpu_config().parent_cache_bytes(&self.config) > 0 {
+            let len = self.leaf_count() * self.config.degree_expander;
+            let parents = self.context.create_buffer_with_len(len)?;
+            call_kernel!(self.context, "generate_parent_cache", &parents);
+            self.parent_cache = Some(parents);
+        }
+        Ok(())
+    }
+
+    fn replace_buffer(&mut self, buff: LayerBuffer) {
+        let previous = std::mem::replace(&mut self.current_layer, buff);
+        self.context.recycle_buffer(previous);
+        self.finalized = false;
+    }
+
+    /// Reads the given nodes of the current layer, in Montgomery form.
+    pub fn extract_nodes(&mut self, node_indices: &[usize]) -> NSEResult<Vec<Node>> {
+        self.gather("gather_nodes", 0, node_indices, 1)
+    }
+
+    /// Reads the parents of the given nodes of layer `layer_index` from the current layer,
+    /// which must be layer `layer_index - 1`. Returns the parents of each node one after
+    /// another: the `K * degree_expander` expanded parents of expander nodes or the
+    /// `degree_butterfly` parents of butterfly nodes. Mask nodes have no parents.
+    pub fn extract_parents(
+        &mut self,
+        layer_index: usize,
+        node_indices: &[usize],
+    ) -> NSEResult<Vec<Node>> {
+        if layer_index <= 1 {
+            Ok(Vec::new())
+        } else if layer_index <= self.config.num_expander_layers {
+            let degree = self.config.k as usize * self.config.degree_expander;
+            self.gather("gather_expander_parents", layer_index, node_indices, degree)
+        } else {
+            let degree = self.config.degree_butterfly;
+            self.gather(
+                "gather_butterfly_parents",
+                layer_index,
+                node_indices,
+                degree,
+            )
+        }
+    }
+
+    fn gather(
+        &mut self,
+        kernel_name: &str,
+        layer_index: usize,
+        node_indices: &[usize],
+        degree: usize,
+    ) -> NSEResult<Vec<Node>> {
+        let leaf_count = self.leaf_count();
+        if let Some(i) = node_indices.iter().find(|&&i| i >= leaf_count) {
+            return Err(NSEError::InvalidInput(format!(
+                "Node {} is out of the window of {} nodes!",
+                i, leaf_count
+            )));
+        }
+        if node_indices.is_empty() {
+            return Ok(Vec::new());
+        }
+        let count = node_indices.len();
+        let indices = node_indices.iter().map(|&i| i as u32).collect::<Vec<_>>();
+        let mut indices_buff = self.context.create_buffer_with_len(count)?;
+        self.context.write_buffer(&mut indices_buff, 0, &indices)?;
+        let output = self.context.create_buffer_with_len(count * degree)?;
+        let kernel = {
+            let mut builder = self.context.build_gather_kernel(kernel_name, count);
+            #[cfg(feature = "launch-logging")]
+            log_kernel_args(
+                kernel_name,
+                &[
+                    (&self.current_layer).describe(),
+                    (&indices_buff).describe(),
+                    (&output).describe(),
+                ],
+            );
+            KernelArg::push(&self.current_layer, &mut builder);
+            builder
+                .arg(&indices_buff)
+                .arg(&output)
+                .arg(layer_index as u32)
+                .arg(count as u32)
+                .arg(self.finalized as u32);
+            builder.build()?
+        };
+        unsafe {
+            self.context.enqueue_kernel(&kernel)?;
+        }
+        let mut nodes = self.context.alloc_nodes(count * degree);
+        self.context.read_buffer(&output, 0, &mut nodes)?;
+        Ok(nodes)
+    }
+
+    /// Makes the current layer a layer of pseudo-random nodes derived from `seed`, generated
+    /// on the device, e.g. as benchmark input that would take minutes to generate on the host
+    /// and upload for big windows. See `Layer::from_seed` for the same nodes on the host.
+    pub fn generate_random_layer(&mut self, seed: u64) -> NSEResult<()> {
+        let output = self.context.create_buffer()?;
+        call_kernel!(self.context, "generate_random", &output, seed);
+        self.replace_buffer(output);
+        Ok(())
+    }
+
+    // Overwrite current layer
+    pub fn push_layer(&mut self, layer: &Layer) -> NSEResult<()> {
+        self.context
+            .write_layer(&mut self.current_layer, 0, &layer.0)?; // Push montgomery form in buffer
+        let ordinary = self.context.create_buffer()?; // Create new temp buffer
+        call_kernel!(
+            self.context,
+            "generate_ordinary",
+            &self.current_layer,
+            &ordinary
+        );
+        self.replace_buffer(ordinary); // Current buffer has now the ordinary form
+        Ok(())
+    }
+
+    /// Same as `combine_segment` encoding `segment`, adding it to the data commitment as it
+    /// is uploaded, so that the data doesn't need to be streamed twice. Segments must be
+    /// committed in order, i.e. `offset` must be `commitment.next_leaf()`.
+    pub fn combine_segment_with_commitment(
+        &mut self,
+        offset: usize,
+        segment: &[Node],
+        commitment: &mut DataCommitment,
+    ) -> NSEResult<Vec<Node>> {
+        segment_range(offset, segment.len(), self.leaf_count())?;
+        if offset != commitment.next_leaf() {
+            return Err(NSEError::InvalidInput(format!(
+                "Segment at {} committed out of order, expected node {}!",
+                offset,
+                commitment.next_leaf()
+            )));
+        }
+        let mut l = self.context.alloc_nodes(segment.len());
+        let mut data = self.context.create_buffer()?;
+        self.context.write_layer(&mut data, offset, &segment)?;
+        for (start, height) in crate::commitment::aligned_subtrees(offset, segment.len()) {
+            let root = if height == 0 {
+                Sha256Domain::from(segment[start - offset])
+            } else {
+                self.context
+                    .subtree_commitment(&data, start, height, true)?
+            };
+            commitment.push_subtree(height, root)?;
+        }
+        call_kernel!(
+            self.context,
+            "combine_segment",
+            &self.current_layer,
+            &data,
+            offset as u64,
+            segment.len() as u64,
+            CombineMode::Encode as u32,
+            self.config.encoding_mode as u32
+        );
+        self.context.read_layer(&data, offset, &mut l)?;
+        Ok(l)
+    }
+
+    /// Encodes the data layer of every `(key_layer, data)` pair with its key layer, as returned
+    /// by `KeyGenerator`, e.g. to re-encode data with keys reused across windows. The pairs are
+    /// staged into one buffer of keys and one of data, and combined back-to-back in a single
+    /// kernel launch, in as many batches as `GpuConfig::max_alloc_chunk` requires. The current
+    /// layer is left untouched.
+    pub fn encode_pairs(&mut self, pairs: &[(Layer, Layer)]) -> NSEResult<Vec<Layer>> {
+        let leaf_count = self.leaf_count();
+        if pairs
+            .iter()
+            .any(|(key, data)| key.0.len() != leaf_count || data.0.len() != leaf_count)
+        {
+            return Err(NSEError::InvalidInput(format!(
+                "Expected pairs of layers of {} nodes!",
+                leaf_count
+            )));
+        }
+        if pairs.is_empty() {
+            return Ok(Vec::new());
+        }
+        match self
+            .gpu_config()
+            .items_per_alloc(leaf_count * NODE_SIZE, pairs.len())
+        {
+            0 => pairs
+                .iter()
+                .map(|(key, data)| self.encode_chunked_pair(key, data))
+                .collect(),
+            batch_size => {
+                let mut encoded = Vec::with_capacity(pairs.len());
+                for batch in pairs.chunks(batch_size) {
+                    encoded.extend(self.encode_batch(batch)?);
+                }
+                Ok(encoded)
+            }
+        }
+    }
+
+    // Encodes pairs of layers fitting in a single allocation, see `encode_pairs`.
+    fn encode_batch(&mut self, pairs: &[(Layer, Layer)]) -> NSEResult<Vec<Layer>> {
+        let leaf_count = self.leaf_count();
+        let batch_size = pairs.len();
+        // Key layers are in Montgomery form, as the combine kernels expect them.
+        let mut keys = self
+            .context
+            .create_buffer_with_len(batch_size * leaf_count)?;
+        let mut data = self
+            .context
+            .create_buffer_with_len(batch_size * leaf_count)?;
+        for (i, (key, layer)) in pairs.iter().enumerate() {
+            self.context
+                .write_buffer(&mut keys, i * leaf_count, &key.0)?;
+            self.context
+                .write_buffer(&mut data, i * leaf_count, &layer.0)?;
+        }
+        call_batch_kernel!(
+            self.context,
+            "combine_batch",
+            batch_size,
+            &keys,
+            &data,
+            CombineMode::Encode as u32,
+            self.config.encoding_mode as u32
+        );
+        drop(keys);
+        let layers = self
+            .context
+            .read_segments(&data, &vec![leaf_count; batch_size])?;
+        Ok(layers.into_iter().map(Layer).collect())
+    }
+
+    // Encodes a pair of layers too large for a single allocation, in chunked layers.
+    fn encode_chunked_pair(&mut self, key: &Layer, data: &Layer) -> NSEResult<Layer> {
+        let leaf_count = self.leaf_count();
+        let mut keys = self.context.create_buffer()?;
+        let mut buffer = self.context.create_buffer()?;
+        self.context.write_layer(&mut keys, 0, &key.0)?;
+        self.context.write_layer(&mut buffer, 0, &data.0)?;
+        call_kernel!(
+            self.context,
+            "combine_segment",
+            &keys,
+            &buffer,
+            0u64,
+            leaf_count as u64,
+            CombineMode::Encode as u32,
+            self.config.encoding_mode as u32
+        );
+        let mut encoded = self.context.alloc_nodes(leaf_count);
+        self.context.read_layer(&buffer, 0, &mut encoded)?;
+        self.context.recycle_buffer(keys);
+        Ok(Layer(encoded))
+    }
+
+    /// Poseidon hashes of the columns of `layers`, i.e. node `i` of the result is the hash of
+    /// the nodes `i` of every layer, in order. There must be `constants.arity` layers, all of
+    /// the same length and in Montgomery form.
+    pub fn hash_columns(
+        &mut self,
+        constants: &PoseidonConstants,
+        layers: &[Layer],
+    ) -> NSEResult<Vec<Node>> {
+        constants.validate()?;
+        if layers.len() != constants.arity {
+            return Err(NSEError::InvalidInput(format!(
+                "Poseidon of arity {} cannot hash columns of {} layers!",
+                constants.arity,
+                layers.len()
+            )));
+        }
+        let count = layers[0].0.len();
+        if layers.iter().any(|layer| layer.0.len() != count) {
+            return Err(NSEError::InvalidInput(
+                "Columns are hashed from layers of different lengths!".into(),
+            ));
+        }
+        if count == 0 {
+            return Ok(Vec::new());
+        }
+        let mut preimages = Vec::with_capacity(count * constants.arity);
+        for i in 0..count {
+            preimages.extend(layers.iter().map(|layer| layer.0[i]));
+        }
+        let mut input = self.context.create_buffer_with_len(preimages.len())?;
+        self.context.write_buffer(&mut input, 0, &preimages)?;
+        let mut round_constants = self
+            .context
+            .create_buffer_with_len(constants.round_constants.len())?;
+        self.context
+            .write_buffer(&mut round_constants, 0, &constants.round_constants)?;
+        let mut mds = self.context.create_buffer_with_len(constants.mds.len())?;
+        self.context.write_buffer(&mut mds, 0, &constants.mds)?;
+        let output = self.context.create_buffer_with_len(count)?;
+        let kernel = {
+            let mut builder = self.context.build_gather_kernel("poseidon_hash", count);
+            #[cfg(feature = "launch-logging")]
+            log_kernel_args(
+                "poseidon_hash",
+                &[
+                    (&input).describe(),
+                    (&output).describe(),
+                    (&round_constants).describe(),
+                    (&mds).describe(),
+                ],
+            );
+            builder
+                .arg(&input)
+                .arg(&output)
+                .arg(&round_constants)
+                .arg(&mds)
+                .arg(constants.domain_tag)
+                .arg(constants.arity as u32)
+                .arg(constants.full_rounds as u32)
+                .arg(constants.partial_rounds as u32)
+                .arg(count as u32);
+            builder.build()?
+        };
+        unsafe {
+            self.context.enqueue_kernel(&kernel)?;
+        }
+        let mut hashes = self.context.alloc_nodes(count);
+        self.context.read_buffer(&output, 0, &mut hashes)?;
+        Ok(hashes)
+    }
+
+    /// Same as `generate_mask_layer`, leaving the layer on the device only, which saves its
+    /// readback when the host doesn't need it, e.g. when unsealing.
+    pub fn label_mask_layer(
+        &mut self,
+        replica_id: ReplicaId,
+        window_index: WindowIndex,
+    ) -> NSEResult<()> {
+        self.label_mask(replica_id, window_index, None)
+    }
+
+    /// Same as `generate_expander_layer`, leaving the layer on the device only.
+    pub fn label_expander_layer(
+        &mut self,
+        replica_id: ReplicaId,
+        window_index: WindowIndex,
+        layer_index: usize,
+    ) -> NSEResult<()> {
+        self.label_expander(replica_id, window_index, layer_index, None)
+    }
+
+    /// Same as `generate_butterfly_layer`, leaving the layer on the device only.
+    pub fn label_butterfly_layer(
+        &mut self,
+        replica_id: ReplicaId,
+        window_index: WindowIndex,
+        layer_index: usize,
+    ) -> NSEResult<()> {
+        self.label_butterfly(replica_id, window_index, layer_index, None)
+    }
+
+    /// Seeds of the mask layer of a window, one per node: the SHA-256 digests of the hash
+    /// prefixes of the nodes, or 32 bytes of AES-256-CTR keystream with `MaskPrf::Aes256Ctr`,
+    /// both domain-separated by the mask tag of the config. Mask labels are the seeds trimmed
+    /// into field elements, see `Sha256Domain::to_node`.
+    pub fn expand_seed(
+        &mut self,
+        replica_id: ReplicaId,
+        window_index: WindowIndex,
+    ) -> NSEResult<Vec<Sha256Domain>> {
+        window_index.validate(&self.config)?;
+        let leaf_count = self.leaf_count();
+        let output = self.context.create_buffer_with_len::<u32>(8 * leaf_count)?;
+        call_kernel!(
+            self.context,
+            "expand_seed",
+            &output,
+            replica_id,
+            window_index
+        );
+        let mut words = vec![0u32; 8 * leaf_count];
+        self.context.read_buffer(&output, 0, &mut words)?;
+        Ok(words
+            .chunks(8)
+            .map(|words| {
+                let mut seed = Sha256Domain::default();
+                for (chunk, word) in seed.0.chunks_mut(4).zip(words) {
+                    chunk.copy_from_slice(&word.to_be_bytes());
+                }
+                seed
+            })
+            .collect())
+    }
+
+    /// Same as `generate_mask_layer`, reading the layer into `out`, a whole window, e.g. caller
+    /// memory or a memory-mapped layer file, instead of allocating it.
+    pub fn generate_mask_layer_into(
+        &mut self,
+        replica_id: ReplicaId,
+        window_index: WindowIndex,
+        out: &mut [Node],
+    ) -> NSEResult<()> {
+        self.check_output_len(out)?;
+        self.label_mask(replica_id, window_index, Some(out))
+    }
+
+    /// Same as `generate_expander_layer`, reading the layer into `out`, a whole window.
+    pub fn generate_expander_layer_into(
+        &mut self,
+        replica_id: ReplicaId,
+        window_index: WindowIndex,
+        layer_index: usize,
+        out: &mut [Node],
+    ) -> NSEResult<()> {
+        self.check_output_len(out)?;
+        self.label_expander(replica_id, window_index, layer_index, Some(out))
+    }
+
+    /// Same as `generate_butterfly_layer`, reading the layer into `out`, a whole window.
+    pub fn generate_butterfly_layer_into(
+        &mut self,
+        replica_id: ReplicaId,
+        window_index: WindowIndex,
+        layer_index: usize,
+        out: &mut [Node],
+    ) -> NSEResult<()> {
+        self.check_output_len(out)?;
+        self.label_butterfly(replica_id, window_index, layer_index, Some(out))
+    }
+
+    /// Labels the last butterfly layer `layer_index` and combines it with `data`, a whole window,
+    /// in a single kernel. The key is never written to device memory, which saves a write and a
+    /// read of a whole layer over labeling then combining. The current layer stays the previous
+    /// one, so the key is not available for further combines.
+    pub fn label_butterfly_and_combine(
+        &mut self,
+        replica_id: ReplicaId,
+        window_index: WindowIndex,
+        layer_index: usize,
+        data: &[Node],
+        mode: impl Into<CombineMode>,
+    ) -> NSEResult<Vec<Node>> {
+        window_index.validate(&self.config)?;
+        if data.len() != self.leaf_count() {
+            return Err(NSEError::InvalidInput(format!(
+                "Cannot combine {} nodes with a whole layer of {}!",
+                data.len(),
+                self.leaf_count()
+            )));
+        }
+        let mut buffer = self.context.create_buffer()?;
+        self.context.write_layer(&mut buffer, 0, data)?;
+        self.label_butterfly_and_combine_buffer(
+            replica_id,
+            window_index,
+            layer_index,
+            buffer,
+            mode.into(),
+        )
+    }
+
+    /// Same as `label_butterfly_and_combine`, with the data of `stage_data`.
+    pub fn label_butterfly_and_combine_staged(
+        &mut self,
+        replica_id: ReplicaId,
+        window_index: WindowIndex,
+        layer_index: usize,
+        mode: impl Into<CombineMode>,
+    ) -> NSEResult<Vec<Node>> {
+        window_index.validate(&self.config)?;
+        let buffer = self.take_staged_buffer()?;
+        self.label_butterfly_and_combine_buffer(
+            replica_id,
+            window_index,
+            layer_index,
+            buffer,
+            mode.into(),
+        )
+    }
+
+    fn label_butterfly_and_combine_buffer(
+        &mut self,
+        replica_id: ReplicaId,
+        window_index: WindowIndex,
+        layer_index: usize,
+        buffer: LayerBuffer,
+        mode: CombineMode,
+    ) -> NSEResult<Vec<Node>> {
+        let mut output = self.context.alloc_nodes(self.leaf_count());
+        call_kernel!(
+            self.context,
+            "generate_butterfly_combine",
+            &self.current_layer,
+            &buffer,
+            replica_id,
+            window_index,
+            layer_index as u32,
+            mode as u32,
+            self.config.encoding_mode as u32
+        );
+        self.context.read_layer(&buffer, 0, &mut output)?;
+        Ok(output)
+    }
+
+    /// Starts uploading `data`, the original data of a whole window, on a transfer queue of its
+    /// own, so that the upload overlaps with labeling the key layers instead of stalling the
+    /// final combine. The next `combine_staged` or `label_butterfly_and_combine_staged` combines
+    /// it, staging again replaces it. Until then, it takes one more layer of device memory.
+    pub fn stage_data(&mut self, data: Layer) -> NSEResult<()> {
+        if data.0.len() != self.leaf_count() {
+            return Err(NSEError::InvalidInput(format!(
+                "Cannot stage {} nodes for a whole layer of {}!",
+                data.0.len(),
+                self.leaf_count()
+            )));
+        }
+        self.discard_staged_data()?;
+        #[cfg(feature = "fault-injection")]
+        self.context.inject(FaultPoint::Write)?;
+        let buffer = self.context.create_buffer()?;
+        let queue = Queue::new(
+            self.context.pro_que.context(),
+            self.context.pro_que.device(),
+            None,
+        )?;
+        let chunks = buffer
+            .split(0, data.0.len())
+            .into_iter()
+            .map(|(chunk, chunk_offset, range)| (buffer.chunks[chunk].clone(), chunk_offset, range))
+            .collect::<Vec<_>>();
+        let upload = thread::spawn(move || {
+            for (chunk, chunk_offset, range) in chunks {
+                chunk
+                    .write(&data.0[range])
+                    .queue(&queue)
+                    .offset(chunk_offset)
+                    .enq()?;
+            }
+            queue.finish()?;
+            Ok(())
+        });
+        self.staged_data = Some(StagedData { buffer, upload });
+        Ok(())
+    }
+
+    /// Same as `combine_layer`, with the data of `stage_data`.
+    pub fn combine_staged(&mut self, mode: impl Into<CombineMode>) -> NSEResult<Layer> {
+        let mode = mode.into();
+        let data = self.take_staged_buffer()?;
+        let mut output = self.context.alloc_nodes(self.leaf_count());
+        // Montgomery form of mask is in kernel_buffer!
+        call_kernel!(
+            self.context,
+            "combine_segment",
+            &self.current_layer,
+            &data,
+            0u64,
+            self.leaf_count() as u64,
+            mode as u32,
+            self.config.encoding_mode as u32
+        );
+        self.context.read_layer(&data, 0, &mut output)?;
+        Ok(Layer(output))
+    }
+
+    /// Drops the data of `stage_data`, if any, once its upload is done.
+    pub fn discard_staged_data(&mut self) -> NSEResult<()> {
+        if self.staged_data.is_some() {
+            let buffer = self.take_staged_buffer()?;
+            self.context.recycle_buffer(buffer);
+        }
+        Ok(())
+    }
+
+    /// Sets the window being worked on aside (its current layer, staged data and timings) so
+    /// that other jobs, e.g. unsealing a replica, can run on the GPU, until `resume` restores
+    /// it. The jobs start from the spare layer of the context, if any: until `resume`, they
+    /// take at most one more layer of device memory than `MinimumMemory`.
+    pub fn suspend(&mut self) -> NSEResult<SuspendedWindow> {
+        let spare = self.context.create_buffer()?;
+        let current_layer = std::mem::replace(&mut self.current_layer, spare);
+        Ok(SuspendedWindow {
+            config: self.config,
+            current_layer,
+            finalized: std::mem::replace(&mut self.finalized, false),
+            staged_data: self.staged_data.take(),
+            timings: self.take_timings(),
+        })
+    }
+
+    /// Restores a window set aside by `suspend`, dropping the current layer and staged data of
+    /// the jobs run meanwhile, or keeping the layer as the spare if there is none, so that the
+    /// GPU holds no more layers than `MinimumMemory` again. Their device time is not accounted
+    /// in the timings of the window.
+    pub fn resume(&mut self, suspended: SuspendedWindow) -> NSEResult<()> {
+        if suspended.config != self.config {
+            return Err(NSEError::InvalidInput(
+                "Cannot resume a window suspended with another config!".into(),
+            ));
+        }
+        self.discard_staged_data()?;
+        let jobs_layer = std::mem::replace(&mut self.current_layer, suspended.current_layer);
+        self.context.recycle_buffer(jobs_layer);
+        self.finalized = suspended.finalized;
+        self.staged_data = suspended.staged_data;
+        self.context.timings = suspended.timings;
+        Ok(())
+    }
+
+    // Waits for the upload of the staged data, the wait is accounted as transfer time.
+    fn take_staged_buffer(&mut self) -> NSEResult<LayerBuffer> {
+        let staged = self
+            .staged_data
+            .take()
+            .ok_or_else(|| NSEError::InvalidInput("No data was staged!".into()))?;
+        let start = Instant::now();
+        let uploaded = staged
+            .upload
+            .join()
+            .map_err(|_| GPUError::Other("Uploading the staged data panicked!".into()))?;
+        uploaded?;
+        self.context.timings.transfer += start.elapsed();
+        self.context.timings.bytes_written += (self.leaf_count() * NODE_SIZE) as u64;
+        Ok(staged.buffer)
+    }
+
+    // Makes `ord_output`, a layer just labeled in ordinary form, the current layer. It is also
+    // read into `out` in Montgomery form, if any, and never leaves the device otherwise.
+    fn complete_layer(
+        &mut self,
+        ord_output: LayerBuffer,
+        out: Option<&mut [Node]>,
+    ) -> NSEResult<()> {
+        if let Some(out) = out {
+            call_kernel!(
+                self.context,
+                "generate_montgomery",
+                &ord_output,
+                &self.current_layer
+            );
+            self.context.read_layer(&self.current_layer, 0, out)?;
+        }
+        self.replace_buffer(ord_output);
+        Ok(())
+    }
+
+    // Checks `out` can hold a layer.
+    fn check_output_len(&self, out: &[Node]) -> NSEResult<()> {
+        if out.len() != self.leaf_count() {
+            return Err(NSEError::InvalidInput(format!(
+                "Cannot read a layer of {} nodes into {} nodes!",
+                self.leaf_count(),
+                out.len()
+            )));
+        }
+        Ok(())
+    }
+
+    fn label_mask(
+        &mut self,
+        replica_id: ReplicaId,
+        window_index: WindowIndex,
+        out: Option<&mut [Node]>,
+    ) -> NSEResult<()> {
+        window_index.validate(&self.config)?;
+        if let Some(mask) = self.mask_cache.get(replica_id, window_index).cloned() {
+            info!("Reusing cached mask layer...");
+            self.push_layer(&mask)?;
+            if let Some(out) = out {
+                out.copy_from_slice(&mask.0);
+            }
+            return Ok(());
+        }
+        let ord_output = self.context.create_buffer()?;
+        call_kernel!(
+            self.context,
+            "generate_mask",
+            &ord_output,
+            replica_id,
+            window_index
+        );
+        match out {
+            Some(out) => {
+                self.complete_layer(ord_output, Some(&mut *out))?;
+                if self.mask_cache.is_enabled() {
+                    self.mask_cache
+                        .insert(replica_id, window_index, Layer(out.to_vec()));
+                }
+            }
+            None => self.complete_layer(ord_output, None)?,
+        }
+        Ok(())
+    }
+
+    fn label_expander(
+        &mut self,
+        replica_id: ReplicaId,
+        window_index: WindowIndex,
+        layer_index: usize,
+        out: Option<&mut [Node]>,
+    ) -> NSEResult<()> {
+        window_index.validate(&self.config)?;
+        let ord_output = self.context.create_buffer()?;
+        self.ensure_parent_cache()?;
+        if let Some(parents) = &self.parent_cache {
+            call_kernel!(
+                self.context,
+                "generate_expander_cached",
+                &self.current_layer,
+                &ord_output,
+                parents,
+                replica_id,
+                window_index,
+                layer_index as u32
+            );
+        } else {
+            call_kernel!(
+                self.context,
+                "generate_expander",
+                &self.current_layer,
+                &ord_output,
+                replica_id,
+                window_index,
+                layer_index as u32
+            );
+        }
+        self.complete_layer(ord_output, out)
+    }
+
+    fn label_butterfly(
+        &mut self,
+        replica_id: ReplicaId,
+        window_index: WindowIndex,
+        layer_index: usize,
+        out: Option<&mut [Node]>,
+    ) -> NSEResult<()> {
+        window_index.validate(&self.config)?;
+        let ord_output = self.context.create_buffer()?;
+        call_kernel!(
+            self.context,
+            "generate_butterfly",
+            &self.current_layer,
+            &ord_output,
+            replica_id,
+            window_index,
+            layer_index as u32
+        );
+        self.complete_layer(ord_output, out)
+    }
+
+    /// Digest of the current layer computed on the device: the root of the binary SHA-254 tree
+    /// over its nodes, see `Layer::tree_digest` for the host equivalent. Only the digest leaves
+    /// the device, so that machines can check they computed identical layers cheaply.
+    pub fn layer_digest(&mut self) -> NSEResult<Sha256Domain> {
+        let height = self.leaf_count().trailing_zeros();
+        Ok(self
+            .context
+            .subtree_commitment(&self.current_layer, 0, height, self.finalized)?)
+    }
+
+    /// Statistics of the nodes of `layer` computed on the device, see `Layer::content_stats`
+    /// for the host equivalent. Only the partial results of `LAYER_STATS_PARTIALS` work-items
+    /// are read back.
+    pub fn layer_stats(&mut self, layer: &Layer) -> NSEResult<LayerContentStats> {
+        let leaf_count = self.leaf_count();
+        if layer.0.len() != leaf_count {
+            return Err(NSEError::InvalidInput(format!(
+                "Expected a layer of {} nodes!",
+                leaf_count
+            )));
+        }
+        let mut data = self.context.create_buffer()?;
+        self.context.write_layer(&mut data, 0, &layer.0)?;
+        let stats = self.context.layer_stats(&data, true)?;
+        self.context.recycle_buffer(data);
+        Ok(stats)
+    }
+
+    /// Same as `layer_stats` for the current layer, which doesn't leave the device, e.g. to
+    /// check the layers of a `KeyGenerator` not read back.
+    pub fn current_layer_stats(&mut self) -> NSEResult<LayerContentStats> {
+        Ok(self
+            .context
+            .layer_stats(&self.current_layer, self.finalized)?)
+    }
+}
+
+impl GPUContext {
+    // Statistics of the nodes of `data`, in Montgomery form if `montgomery`, see
+    // `GPU::layer_stats`.
+    fn layer_stats(
+        &mut self,
+        data: &LayerBuffer,
+        montgomery: bool,
+    ) -> GPUResult<LayerContentStats> {
+        let leaf_count = self.leaf_count();
+        let partials = std::cmp::min(LAYER_STATS_PARTIALS, leaf_count);
+        let counts = self.create_buffer_with_len::<u64>(2 * partials)?;
+        let extremes = self.create_buffer_with_len::<Node>(2 * partials)?;
+        let kernel = {
+            let mut builder = self.build_reduction_kernel("layer_stats", partials);
+            #[cfg(feature = "launch-logging")]
+            log_kernel_args(
+                "layer_stats",
+                &[data.describe(), format!("{} partials", partials)],
+            );
+            KernelArg::push(data, &mut builder);
+            builder
+                .arg(&counts)
+                .arg(&extremes)
+                .arg(partials as u32)
+                .arg(montgomery as u32);
+            builder.build()?
+        };
+        unsafe {
+            self.enqueue_kernel(&kernel)?;
+        }
+        let digest = self.subtree_commitment(data, 0, leaf_count.trailing_zeros(), montgomery)?;
+
+        let mut partial_counts = vec![0u64; 2 * partials];
+        let mut partial_extremes = vec![Node::default(); 2 * partials];
+        self.read_buffer(&counts, 0, &mut partial_counts)?;
+        self.read_buffer(&extremes, 0, &mut partial_extremes)?;
+        let value = |node: &&Node| node.0.into_repr();
+        Ok(LayerContentStats {
+            zero_nodes: partial_counts.iter().step_by(2).sum(),
+            repeated_nodes: partial_counts.iter().skip(1).step_by(2).sum(),
+            min: *partial_extremes
+                .iter()
+                .step_by(2)
+                .min_by_key(value)
+                .expect("There is a partial"),
+            max: *partial_extremes
+                .iter()
+                .skip(1)
+                .step_by(2)
+                .max_by_key(value)
+                .expect("There is a partial"),
+            digest,
+        })
+    }
+
+    // Root of the commitment subtree of height `height` (at least 1) over the nodes of `data`
+    // starting at `start`, in Montgomery form if `montgomery`, hashed level by level on the
+    // device. Nodes are held as their limbs, as the tree is in ordinary form.
+    fn subtree_commitment(
+        &mut self,
+        data: &LayerBuffer,
+        start: usize,
+        height: u32,
+        montgomery: bool,
+    ) -> GPUResult<Sha256Domain> {
+        let mut count = 1usize << (height - 1);
+        let mut input = self.create_buffer_with_len::<u64>(4 * count)?;
+        call_kernel!(
+            self,
+            "commitment_leaves",
+            data,
+            start as u64,
+            &input,
+            count as u64,
+            montgomery as u32
+        );
+        if count > 1 {
+            let mut output = self.create_buffer_with_len::<u64>(4 * count / 2)?;
+            while count > 1 {
+                count /= 2;
+                call_kernel!(self, "commitment_level", &input, &output, count as u64);
+                std::mem::swap(&mut input, &mut output);
+            }
+        }
+        let mut limbs = [0u64; 4];
+        self.read_buffer(&input, 0, &mut limbs)?;
+        let mut root = Sha256Domain::default();
+        for (chunk, limb) in root.0.chunks_mut(8).zip(limbs.iter()) {
+            chunk.copy_from_slice(&limb.to_le_bytes());
+        }
+        Ok(root)
+    }
+}
+
+impl NarrowStackedExpander for GPU {
+    fn generate_mask_layer(
+        &mut self,
+        replica_id: ReplicaId,
+        window_index: WindowIndex,
+    ) -> NSEResult<Layer> {
+        let mut layer = Layer(self.context.alloc_nodes(self.leaf_count()));
+        self.label_mask(replica_id, window_index, Some(&mut layer.0))?;
+        Ok(layer)
+    }
+
+    fn generate_expander_layer(
+        &mut self,
+        replica_id: ReplicaId,
+        window_index: WindowIndex,
+        layer_index: usize,
+    ) -> NSEResult<Layer> {
+        let mut layer = Layer(self.context.alloc_nodes(self.leaf_count()));
+        self.label_expander(replica_id, window_index, layer_index, Some(&mut layer.0))?;
+        Ok(layer)
+    }
+
+    fn generate_butterfly_layer(
+        &mut self,
+        replica_id: ReplicaId,
+        window_index: WindowIndex,
+        layer_index: usize,
+    ) -> NSEResult<Layer> {
+        let mut layer = Layer(self.context.alloc_nodes(self.leaf_count()));
+        self.label_butterfly(replica_id, window_index, layer_index, Some(&mut layer.0))?;
+        Ok(layer)
+    }
+
+    fn finalize(&mut self) -> NSEResult<()> {
+        call_kernel!(self.context, "to_montgomery", &self.current_layer);
+        self.finalized = true;
+        Ok(())
+    }
+
+    // Same as the default implementations, with vectors from the host allocator.
+    fn combine_layer(&mut self, layer: &Layer, mode: CombineMode) -> NSEResult<Layer> {
+        let mut output = self.context.alloc_nodes(layer.0.len());
+        self.combine_segment_into(0, &layer.0, mode, &mut output)?;
+        Ok(Layer(output))
+    }
+
+    fn combine_segment(
+        &mut self,
+        offset: usize,
+        segment: &[Node],
+        mode: CombineMode,
+    ) -> NSEResult<Vec<Node>> {
+        let mut output = self.context.alloc_nodes(segment.len());
+        self.combine_segment_into(offset, segment, mode, &mut output)?;
+        Ok(output)
+    }
+
+    fn combine_segment_into(
+        &mut self,
+        offset: usize,
+        segment: &[Node],
+        mode: CombineMode,
+        out: &mut [Node],
+    ) -> NSEResult<()> {
+        segment_range(offset, segment.len(), self.leaf_count())?;
+        check_combine_output(segment, out)?;
+        // Montgomery form of mask is in kernel_buffer!
+        let mut data = self.context.create_buffer()?;
+        self.context.write_layer(&mut data, offset, &segment)?;
+        call_kernel!(
+            self.context,
+            "combine_segment",
+            &self.current_layer,
+            &data,
+            offset as u64,
+            segment.len() as u64,
+            mode as u32,
+            self.config.encoding_mode as u32
+        );
+        self.context.read_layer(&data, offset, out)?;
+        Ok(())
+    }
+
+    // Segments go through a single kernel launch, in as many groups of segments as
+    // `GpuConfig::max_alloc_chunk` requires. A segment too large for a single allocation is
+    // combined on its own, in a chunked layer.
+    fn combine_batches(
+        &mut self,
+        batches: Vec<(usize, &[Node], CombineMode)>,
+    ) -> NSEResult<Vec<Vec<Node>>> {
+        let leaf_count = self.leaf_count();
+        for &(offset, segment, _) in batches.iter() {
+            segment_range(offset, segment.len(), leaf_count)?;
+        }
+        let total = batches.iter().map(|(_, s, _)| s.len()).sum();
+        let max_nodes = self.gpu_config().items_per_alloc(NODE_SIZE, total);
+        let mut combined = Vec::with_capacity(batches.len());
+        let (mut group_start, mut group_nodes) = (0, 0);
+        for (i, &(offset, segment, mode)) in batches.iter().enumerate() {
+            if segment.len() > max_nodes || group_nodes + segment.len() > max_nodes {
+                combined.extend(self.combine_segment_group(&batches[group_start..i])?);
+                group_start = i;
+                group_nodes = 0;
+            }
+            if segment.len() > max_nodes {
+                combined.push(self.combine_segment(offset, segment, mode)?);
+                group_start = i + 1;
+            } else {
+                group_nodes += segment.len();
+            }
+        }
+        combined.extend(self.combine_segment_group(&batches[group_start..])?);
+        Ok(combined)
+    }
+
+    fn combine_batch_size(&self) -> usize {
+        self.combine_batch_size
+    }
+
+    fn leaf_count(&self) -> usize {
+        self.context.leaf_count()
+    }
+}
+
+impl GPU {
+    // Combines segments fitting in a single allocation, see `combine_batches`.
+    fn combine_segment_group(
+        &mut self,
+        batches: &[(usize, &[Node], CombineMode)],
+    ) -> NSEResult<Vec<Vec<Node>>> {
+        let mut starts = Vec::with_capacity(batches.len());
+        let mut offsets = Vec::with_capacity(batches.len());
+        let mut modes = Vec::with_capacity(batches.len());
+        let mut nodes = Vec::new();
+        for &(offset, segment, mode) in batches.iter() {
+            starts.push(nodes.len() as u64);
+            offsets.push(offset as u64);
+            modes.push(mode as u32);
+            nodes.extend_from_slice(segment);
+        }
+        if !nodes.is_empty() {
+            let count = batches.len();
+            let mut data = self.context.create_buffer_with_len(nodes.len())?;
+            let mut starts_buff = self.context.create_buffer_with_len(count)?;
+            let mut offsets_buff = self.context.create_buffer_with_len(count)?;
+            let mut modes_buff = self.context.create_buffer_with_len(count)?;
+            self.context.write_buffer(&mut data, 0, &nodes)?;
+            self.context.write_buffer(&mut starts_buff, 0, &starts)?;
+            self.context.write_buffer(&mut offsets_buff, 0, &offsets)?;
+            self.context.write_buffer(&mut modes_buff, 0, &modes)?;
+            let kernel = {
+                let mut builder = self
+                    .context
+                    .build_gather_kernel("combine_segments", nodes.len());
+                #[cfg(feature = "launch-logging")]
+                log_kernel_args(
+                    "combine_segments",
+                    &[
+                        (&self.current_layer).describe(),
+                        (&data).describe(),
+                        format!("{} segments", count),
+                    ],
+                );
+                KernelArg::push(&self.current_layer, &mut builder);
+                builder
+                    .arg(&data)
+                    .arg(&starts_buff)
+                    .arg(&offsets_buff)
+                    .arg(&modes_buff)
+                    .arg(count as u32)
+                    .arg(nodes.len() as u64)
+                    .arg(self.config.encoding_mode as u32);
+                builder.build()?
+            };
+            unsafe {
+                self.context.enqueue_kernel(&kernel)?;
+            }
+            drop(nodes);
+            let lens = batches.iter().map(|(_, s, _)| s.len()).collect::<Vec<_>>();
+            return Ok(self.context.read_segments(&data, &lens)?);
+        }
+        Ok(batches.iter().map(|_| Vec::new()).collect())
+    }
+}
+
+/// Generates the key layers of several windows, with distinct replica ids and window indices,
+/// in a single kernel launch per layer. For small windows, this amortizes the launch overhead
+/// that otherwise dominates. Layers of the batch are laid out one after another on the device,
+/// so a layer of every window must fit in a single allocation of `GpuConfig::max_alloc_chunk`
+/// bytes; larger batches are to be split by the caller.
+pub struct BatchKeyGenerator<'a> {
+    gpu: &'a mut GPU,
+    batch_size: usize,
+    replica_ids: Buffer<ReplicaId>,
+    window_indices: Buffer<WindowIndex>,
+    current_layers: Buffer<Node>, // Last generated layers (In ordinary form)
+    current_layer_index: usize,
+}
+
+impl<'a> BatchKeyGenerator<'a> {
+    pub fn new(gpu: &'a mut GPU, windows: &[(ReplicaId, WindowIndex)]) -> NSEResult<Self> {
+        if windows.is_empty() {
+            return Err(NSEError::InvalidInput("Empty batch of windows!".into()));
+        }
+        let batch_size = windows.len();
+        let ids = windows.iter().map(|w| w.0).collect::<Vec<_>>();
+        let indices = windows.iter().map(|w| w.1).collect::<Vec<_>>();
+        for index in &indices {
+            index.validate(&gpu.config)?;
+        }
+        let layer_bytes = gpu.leaf_count() * NODE_SIZE;
+        let max_batch_size = gpu.gpu_config().items_per_alloc(layer_bytes, batch_size);
+        if max_batch_size < batch_size {
+            return Err(NSEError::InvalidInput(format!(
+                "A batch of {} windows exceeds `max_alloc_chunk`, split it into batches of at most {} windows!",
+                batch_size, max_batch_size
+            )));
+        }
+        let mut replica_ids = gpu.context.create_buffer_with_len(batch_size)?;
+        let mut window_indices = gpu.context.create_buffer_with_len(batch_size)?;
+        gpu.context.write_buffer(&mut replica_ids, 0, &ids)?;
+        gpu.context.write_buffer(&mut window_indices, 0, &indices)?;
+        let current_layers = gpu
+            .context
+            .create_buffer_with_len(batch_size * gpu.leaf_count())?;
+        Ok(BatchKeyGenerator {
+            gpu,
+            batch_size,
+            replica_ids,
+            window_indices,
+            current_layers,
+            current_layer_index: 0,
+        })
+    }
+
+    pub fn batch_size(&self) -> usize {
+        self.batch_size
+    }
+
+    fn last_index(&self) -> usize {
+        self.gpu.config.num_layers()
+    }
+
+    // Convert the freshly generated (ordinary) layers into Montgomery form, read them back and
+    // make them the current layers.
+    fn read_layers(&mut self, ord_output: Buffer<Node>) -> NSEResult<Vec<Layer>> {
+        let leaf_count = self.gpu.leaf_count();
+        let batch_size = self.batch_size;
+        call_batch_kernel!(
+            self.gpu.context,
+            "generate_montgomery_batch",
+            batch_size,
+            &ord_output,
+            &self.current_layers
+        );
+        let layers = self
+            .gpu
+            .context
+            .read_segments(&self.current_layers, &vec![leaf_count; batch_size])?;
+        self.current_layers = ord_output;
+        Ok(layers.into_iter().map(Layer).collect())
+    }
+
+    fn generate_layers(&mut self) -> NSEResult<Vec<Layer>> {
+        let batch_size = self.batch_size;
+        let layer_index = self.current_layer_index as u32;
+        let ord_output = self
+            .gpu
+            .context
+            .create_buffer_with_len(batch_size * self.gpu.leaf_count())?;
+        if self.current_layer_index == 1 {
+            call_batch_kernel!(
+                self.gpu.context,
+                "generate_mask_batch",
+                batch_size,
+                &ord_output,
+                &self.replica_ids,
+                &self.window_indices
+            );
+        } else if self.current_layer_index <= self.gpu.config.num_expander_layers {
+            call_batch_kernel!(
+                self.gpu.context,
+                "generate_expander_batch",
+                batch_size,
+                &self.current_layers,
+                &ord_output,
+                &self.replica_ids,
+                &self.window_indices,
+                layer_index
+            );
+        } else {
+            call_batch_kernel!(
+                self.gpu.context,
+                "generate_butterfly_batch",
+                batch_size,
+                &self.current_layers,
+                &ord_output,
+                &self.replica_ids,
+                &self.window_indices,
+                layer_index
+            );
+        }
+        self.read_layers(ord_output)
+    }
+
+    fn finalize(&mut self) -> NSEResult<()> {
+        let batch_size = self.batch_size;
+        call_batch_kernel!(
+            self.gpu.context,
+            "to_montgomery_batch",
+            batch_size,
+            &self.current_layers
+        );
+        Ok(())
+    }
+
+    /// Combines the key layers of the batch with one data layer per window. All key layers
+    /// must have been generated first.
+    pub fn combine_layers(
+        &mut self,
+        layers: &[Layer],
+        mode: impl Into<CombineMode>,
+    ) -> NSEResult<Vec<Layer>> {
+        let mode = mode.into();
+        let leaf_count = self.gpu.leaf_count();
+        let batch_size = self.batch_size;
+        if self.current_layer_index != self.last_index() {
+            return Err(NSEError::InvalidInput(
+                "Key layers must be generated before combining!".into(),
+            ));
+        }
+        if layers.len() != batch_size || layers.iter().any(|l| l.0.len() != leaf_count) {
+            return Err(NSEError::InvalidInput(format!(
+                "Expected {} layers of {} nodes!",
+                batch_size, leaf_count
+            )));
+        }
+        // Montgomery form of masks is in `current_layers`!
+        let nodes = layers
+            .iter()
+            .flat_map(|l| l.0.iter().cloned())
+            .collect::<Vec<_>>();
+        let mut data = self.gpu.context.create_buffer_with_len(nodes.len())?;
+        self.gpu.context.write_buffer(&mut data, 0, &nodes)?;
+        call_batch_kernel!(
+            self.gpu.context,
+            "combine_batch",
+            batch_size,
+            &self.current_layers,
+            &data,
+            mode as u32,
+            self.gpu.config.encoding_mode as u32
+        );
+        drop(nodes);
+        let layers = self
+            .gpu
+            .context
+            .read_segments(&data, &vec![leaf_count; batch_size])?;
+        Ok(layers.into_iter().map(Layer).collect())
+    }
+}
+
+impl<'a> Iterator for BatchKeyGenerator<'a> {
+    type Item = NSEResult<Vec<Layer>>;
+
+    /// Returns successive layers of all windows of the batch, starting with mask layers.
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.current_layer_index >= self.last_index() {
+            return None;
+        }
+        self.current_layer_index += 1;
+        Some(self.generate_layers().and_then(|l| {
+            if self.current_layer_index == self.last_index() {
+                self.finalize()?;
+            }
+            Ok(l)
+        }))
+    }
+}
+
+impl<'a> ExactSizeIterator for BatchKeyGenerator<'a> {
+    fn len(&self) -> usize {
+        self.last_index()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::incrementing_layer;
+    use crate::{comm_d, DomainTags, EncodingMode, MaskPrf, NODE_SIZE};
+    use rand::{thread_rng, Rng};
+
+    const TEST_CONFIG: Config = Config {
+        k: 4,
+        num_nodes_window: 1024,
+        degree_expander: 96,
+        degree_butterfly: 4,
+        num_expander_layers: 4,
+        num_butterfly_layers: 3,
+        encoding_mode: EncodingMode::FieldAdd,
+        domain_tags: DomainTags::UNTAGGED,
+        mask_prf: MaskPrf::Sha256,
     };
-    const TEST_WINDOW_INDEX: usize = 1234567890;
+    const TEST_WINDOW_INDEX: WindowIndex = WindowIndex(1234567890);
     const TEST_REPLICA_ID: ReplicaId = ReplicaId([123u8; 32]);
 
     pub fn accumulate(l: &Vec<Node>) -> Node {
@@ -308,12 +2658,12 @@ mod tests {
         Node(acc)
     }
 
-    pub fn incrementing_layer(start: usize, count: usize) -> Layer {
-        Layer(
-            (start..start + count)
-                .map(|i| Node(Fr::from_str(&i.to_string()).unwrap()))
-                .collect(),
-        )
+    #[test]
+    fn test_gpu_is_send() {
+        // Without a blanket `unsafe impl`, every field of the GPU must be `Send` on its own.
+        fn assert_send<T: Send>() {}
+        assert_send::<GPU>();
+        assert_send::<SuspendedWindow>();
     }
 
     #[test]
@@ -332,6 +2682,46 @@ mod tests {
         );
     }
 
+    #[test]
+    #[cfg(feature = "raw-handles")]
+    fn test_raw_handles() {
+        let ctx = GPUContext::default(TEST_CONFIG, TreeOptions::Disabled).unwrap();
+        let gpu = GPU::new(ctx, TEST_CONFIG).unwrap();
+        unsafe {
+            assert!(!gpu.raw_context().is_null());
+            assert!(!gpu.raw_queue().is_null());
+        }
+    }
+
+    #[test]
+    fn test_aes_mask() {
+        let aes_config = Config {
+            mask_prf: MaskPrf::Aes256Ctr,
+            ..TEST_CONFIG
+        };
+        let layers = |config: Config, window_index: WindowIndex| {
+            let ctx = GPUContext::default(config, TreeOptions::Disabled).unwrap();
+            let mut gpu = GPU::new(ctx, config).unwrap();
+            crate::KeyGenerator::new(config, TEST_REPLICA_ID, window_index, &mut gpu)
+                .unwrap()
+                .collect::<NSEResult<Vec<_>>>()
+                .unwrap()
+        };
+        let sha = layers(TEST_CONFIG, TEST_WINDOW_INDEX);
+        let aes = layers(aes_config, TEST_WINDOW_INDEX);
+        assert_eq!(aes, layers(aes_config, TEST_WINDOW_INDEX));
+        assert_ne!(
+            aes,
+            layers(aes_config, WindowIndex(TEST_WINDOW_INDEX.0 + 1))
+        );
+        // Every layer depends on the mask.
+        for (a, s) in aes.iter().zip(sha.iter()) {
+            assert_ne!(a, s);
+        }
+        let mask = &aes[0].0;
+        assert!(mask.iter().skip(1).any(|n| *n != mask[0]));
+    }
+
     #[test]
     fn test_generate_expander_layer() {
         let ctx = GPUContext::default(TEST_CONFIG, TreeOptions::Disabled).unwrap();
@@ -368,6 +2758,18 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_check_field() {
+        // Already run by `GPUContext::new`, run it again on the cached program.
+        let ctx = GPUContext::default(TEST_CONFIG, TreeOptions::Disabled).unwrap();
+        check_field(&ctx.pro_que).unwrap();
+        let error = NSEError::FieldMismatch {
+            what: "the ordinary form",
+            sample: 3,
+        };
+        assert!(error.to_string().contains("the ordinary form of sample 3"));
+    }
+
     #[test]
     fn test_combine_layer() {
         let ctx = GPUContext::default(TEST_CONFIG, TreeOptions::Disabled).unwrap();
@@ -376,9 +2778,898 @@ mod tests {
         let mask = incrementing_layer(234, TEST_CONFIG.num_nodes_window);
         gpu.push_layer(&mask).unwrap();
         gpu.finalize().unwrap();
-        let encode = gpu.combine_segment(0, &data.0, false).unwrap();
-        let decode = gpu.combine_segment(0, &data.0, true).unwrap();
+        let encode = gpu
+            .combine_segment(0, &data.0, CombineMode::Encode)
+            .unwrap();
+        let decode = gpu
+            .combine_segment(0, &data.0, CombineMode::Decode)
+            .unwrap();
         assert_eq!(Fr::from_str("1867776").unwrap(), accumulate(&encode).0);
         assert_eq!(Fr::from_str("340992").unwrap(), accumulate(&decode).0);
     }
+
+    #[test]
+    fn test_combine_segment_into() {
+        let ctx = GPUContext::default(TEST_CONFIG, TreeOptions::Disabled).unwrap();
+        let mut gpu = GPU::new(ctx, TEST_CONFIG).unwrap();
+        let data = incrementing_layer(567, TEST_CONFIG.num_nodes_window);
+        gpu.push_layer(&incrementing_layer(234, TEST_CONFIG.num_nodes_window))
+            .unwrap();
+        gpu.finalize().unwrap();
+        for &mode in &[CombineMode::Encode, CombineMode::Decode] {
+            let expected = gpu.combine_segment(100, &data.0[100..300], mode).unwrap();
+            let mut out = vec![Node::default(); 200];
+            gpu.combine_segment_into(100, &data.0[100..300], mode, &mut out)
+                .unwrap();
+            assert_eq!(expected, out);
+            assert!(gpu
+                .combine_segment_into(100, &data.0[100..300], mode, &mut out[1..])
+                .is_err());
+            assert!(gpu
+                .combine_segment_into(1000, &data.0[..100], mode, &mut out[..100])
+                .is_err());
+        }
+        let layer = gpu.combine_layer(&data, CombineMode::Encode).unwrap();
+        assert_eq!(
+            layer.0,
+            gpu.combine_segment(0, &data.0, CombineMode::Encode)
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn test_layer_stats() {
+        let ctx = GPUContext::default(TEST_CONFIG, TreeOptions::Disabled).unwrap();
+        let mut gpu = GPU::new(ctx, TEST_CONFIG).unwrap();
+        let mut layer = Layer::random(&mut rand::thread_rng(), TEST_CONFIG.num_nodes_window);
+        let stats = gpu.layer_stats(&layer).unwrap();
+        assert_eq!(layer.content_stats().unwrap(), stats);
+        assert_eq!(layer.tree_digest().unwrap(), stats.digest);
+
+        // Zeros written at a bad offset, and a node copied over its neighbours.
+        for node in &mut layer.0[100..200] {
+            *node = Node::default();
+        }
+        for i in 300..310 {
+            layer.0[i + 1] = layer.0[i];
+        }
+        let stats = gpu.layer_stats(&layer).unwrap();
+        assert_eq!(layer.content_stats().unwrap(), stats);
+        assert_eq!(100, stats.zero_nodes);
+        assert!(stats.repeated_nodes >= 99 + 10);
+        assert_eq!(Node::default(), stats.min);
+
+        assert!(gpu.layer_stats(&Layer(layer.0[1..].to_vec())).is_err());
+
+        // The current layer, before and after it is finalized.
+        let mask = gpu
+            .generate_mask_layer(TEST_REPLICA_ID, TEST_WINDOW_INDEX)
+            .unwrap();
+        let expected = mask.content_stats().unwrap();
+        assert_eq!(expected, gpu.current_layer_stats().unwrap());
+        gpu.finalize().unwrap();
+        assert_eq!(expected, gpu.current_layer_stats().unwrap());
+    }
+
+    #[test]
+    fn test_encode_pairs() {
+        let ctx = GPUContext::default(TEST_CONFIG, TreeOptions::Disabled).unwrap();
+        let mut gpu = GPU::new(ctx, TEST_CONFIG).unwrap();
+        let key = incrementing_layer(234, TEST_CONFIG.num_nodes_window);
+        let pairs = vec![
+            (
+                key.clone(),
+                incrementing_layer(567, TEST_CONFIG.num_nodes_window),
+            ),
+            (
+                key.clone(),
+                incrementing_layer(890, TEST_CONFIG.num_nodes_window),
+            ),
+            (
+                incrementing_layer(345, TEST_CONFIG.num_nodes_window),
+                incrementing_layer(567, TEST_CONFIG.num_nodes_window),
+            ),
+        ];
+        let encoded = gpu.encode_pairs(&pairs).unwrap();
+        assert_eq!(pairs.len(), encoded.len());
+        for ((key, data), replica) in pairs.iter().zip(encoded.iter()) {
+            gpu.push_layer(key).unwrap();
+            gpu.finalize().unwrap();
+            assert_eq!(
+                gpu.combine_layer(data, CombineMode::Encode).unwrap(),
+                *replica
+            );
+        }
+        assert_ne!(encoded[0], encoded[1]);
+        assert_ne!(encoded[0], encoded[2]);
+
+        // In batches of two pairs, then one pair at a time in chunked layers.
+        let layer_bytes = TEST_CONFIG.num_nodes_window * NODE_SIZE;
+        for &max_alloc_chunk in &[2 * layer_bytes, layer_bytes / 4] {
+            let ctx = GPUContext::default(TEST_CONFIG, TreeOptions::Disabled).unwrap();
+            let gpu_config = GpuConfig {
+                max_alloc_chunk: Some(max_alloc_chunk),
+                ..GpuConfig::default()
+            };
+            let mut chunked = GPU::with_gpu_config(ctx, TEST_CONFIG, gpu_config).unwrap();
+            assert_eq!(encoded, chunked.encode_pairs(&pairs).unwrap());
+        }
+
+        assert!(gpu.encode_pairs(&[]).unwrap().is_empty());
+        let short = Layer(key.0[1..].to_vec());
+        assert!(gpu.encode_pairs(&[(key.clone(), short.clone())]).is_err());
+        assert!(gpu.encode_pairs(&[(short, key)]).is_err());
+    }
+
+    #[test]
+    fn test_combine_batches() {
+        let ctx = GPUContext::default(TEST_CONFIG, TreeOptions::Disabled).unwrap();
+        let mut gpu = GPU::new(ctx, TEST_CONFIG).unwrap();
+        let data = incrementing_layer(567, TEST_CONFIG.num_nodes_window);
+        gpu.push_layer(&incrementing_layer(234, TEST_CONFIG.num_nodes_window))
+            .unwrap();
+        gpu.finalize().unwrap();
+        let batches = vec![
+            (0, &data.0[..300], CombineMode::Encode),
+            (100, &data.0[100..150], CombineMode::Decode),
+            (500, &data.0[500..500], CombineMode::Encode),
+            (200, &data.0[200..1024], CombineMode::Decode),
+        ];
+        let expected = batches
+            .iter()
+            .map(|&(offset, segment, mode)| gpu.combine_segment(offset, segment, mode).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(expected, gpu.combine_batches(batches.clone()).unwrap());
+        assert!(gpu
+            .combine_batches(vec![(1000, &data.0[..100], CombineMode::Encode)])
+            .is_err());
+
+        // In groups of segments, the last one alone in a chunked layer.
+        let ctx = GPUContext::default(TEST_CONFIG, TreeOptions::Disabled).unwrap();
+        let gpu_config = GpuConfig {
+            max_alloc_chunk: Some(400 * NODE_SIZE),
+            ..GpuConfig::default()
+        };
+        let mut chunked = GPU::with_gpu_config(ctx, TEST_CONFIG, gpu_config).unwrap();
+        chunked
+            .push_layer(&incrementing_layer(234, TEST_CONFIG.num_nodes_window))
+            .unwrap();
+        chunked.finalize().unwrap();
+        assert_eq!(expected, chunked.combine_batches(batches).unwrap());
+    }
+
+    #[test]
+    fn test_domain_tags() {
+        let mask_and_expander = |config: Config| {
+            let ctx = GPUContext::default(config, TreeOptions::Disabled).unwrap();
+            let mut gpu = GPU::new(ctx, config).unwrap();
+            let mask = gpu
+                .generate_mask_layer(TEST_REPLICA_ID, TEST_WINDOW_INDEX)
+                .unwrap();
+            let expander = gpu
+                .generate_expander_layer(TEST_REPLICA_ID, TEST_WINDOW_INDEX, 2)
+                .unwrap();
+            (mask, expander)
+        };
+        let untagged = mask_and_expander(TEST_CONFIG);
+        let tagged = mask_and_expander(TEST_CONFIG.with_domain_tags(DomainTags {
+            expander: 2,
+            ..DomainTags::UNTAGGED
+        }));
+        assert_eq!(untagged.0, tagged.0);
+        assert_ne!(untagged.1, tagged.1);
+    }
+
+    #[test]
+    fn test_chunked_layers() {
+        let data = incrementing_layer(567, TEST_CONFIG.num_nodes_window);
+        let seal = |gpu_config: GpuConfig| {
+            let ctx = GPUContext::default(TEST_CONFIG, TreeOptions::Disabled).unwrap();
+            let mut gpu = GPU::with_gpu_config(ctx, TEST_CONFIG, gpu_config).unwrap();
+            let mut layers =
+                crate::KeyGenerator::new(TEST_CONFIG, TEST_REPLICA_ID, TEST_WINDOW_INDEX, &mut gpu)
+                    .unwrap()
+                    .collect::<NSEResult<Vec<_>>>()
+                    .unwrap();
+            layers.push(gpu.combine_layer(&data, CombineMode::Encode).unwrap());
+            layers.push(Layer(gpu.extract_nodes(&[0, 300, 1023]).unwrap()));
+            layers
+        };
+        let whole = seal(GpuConfig::default());
+        let chunked = seal(GpuConfig {
+            max_alloc_chunk: Some(256 * std::mem::size_of::<Node>()),
+            ..GpuConfig::default()
+        });
+        assert_eq!(whole, chunked);
+    }
+
+    #[test]
+    fn test_expander_parent_cache() {
+        let expander_layers = |gpu_config: GpuConfig| {
+            let ctx = GPUContext::default(TEST_CONFIG, TreeOptions::Disabled).unwrap();
+            let mut gpu = GPU::with_gpu_config(ctx, TEST_CONFIG, gpu_config).unwrap();
+            let mut layers = vec![gpu
+                .generate_mask_layer(TEST_REPLICA_ID, TEST_WINDOW_INDEX)
+                .unwrap()];
+            for l in 2..=TEST_CONFIG.num_expander_layers {
+                layers.push(
+                    gpu.generate_expander_layer(TEST_REPLICA_ID, TEST_WINDOW_INDEX, l)
+                        .unwrap(),
+                );
+            }
+            layers
+        };
+        let cached = expander_layers(GpuConfig {
+            cache_expander_parents: true,
+            ..GpuConfig::default()
+        });
+        assert_eq!(expander_layers(GpuConfig::default()), cached);
+    }
+
+    #[test]
+    fn test_kernel_timeout() {
+        // A single work-item spinning for seconds, far longer than the timeout below.
+        const SPIN_KERNEL: &str = "__kernel void spin(__global ulong *out, ulong iterations) {
+            ulong x = 0;
+            for(ulong i = 0; i < iterations; i++)
+                x = x * 6364136223846793005UL + i;
+            out[0] = x;
+        }";
+        let gpu = |kernel_timeout: Option<Duration>| {
+            let ctx = GPUContext::default(TEST_CONFIG, TreeOptions::Disabled).unwrap();
+            let gpu_config = GpuConfig {
+                kernel_timeout,
+                ..GpuConfig::default()
+            };
+            GPU::with_gpu_config(ctx, TEST_CONFIG, gpu_config).unwrap()
+        };
+        // Kernels and transfers finishing in time are unaffected.
+        let mut timed_gpu = gpu(Some(Duration::from_secs(60)));
+        let mask_layer = timed_gpu
+            .generate_mask_layer(TEST_REPLICA_ID, TEST_WINDOW_INDEX)
+            .unwrap();
+        assert_eq!(
+            gpu(None)
+                .generate_mask_layer(TEST_REPLICA_ID, TEST_WINDOW_INDEX)
+                .unwrap(),
+            mask_layer
+        );
+        timed_gpu.push_layer(&mask_layer).unwrap();
+
+        let mut gpu = gpu(Some(Duration::from_millis(100)));
+        let ctx = &mut gpu.context;
+        let program = Program::builder()
+            .devices(ctx.device())
+            .src(SPIN_KERNEL)
+            .build(ctx.pro_que.context())
+            .unwrap();
+        let out = ctx.create_buffer_with_len::<u64>(1).unwrap();
+        let kernel = Kernel::builder()
+            .program(&program)
+            .name("spin")
+            .queue(ctx.pro_que.queue().clone())
+            .global_work_size(1)
+            .arg(&out)
+            .arg(1u64 << 31)
+            .build()
+            .unwrap();
+        match unsafe { ctx.enqueue_kernel(&kernel) } {
+            Err(GPUError::KernelTimeout(_)) => {}
+            res => panic!("Expected a kernel timeout, got {:?}", res),
+        }
+        // The context is poisoned, its buffers are leaked when the GPU is dropped.
+        assert!(ctx.is_poisoned());
+        let queue = ctx.pro_que.queue().clone();
+        match gpu.generate_mask_layer(TEST_REPLICA_ID, TEST_WINDOW_INDEX) {
+            Err(NSEError::KernelTimeout(_)) => {}
+            res => panic!("Expected a kernel timeout, got {:?}", res.map(|_| ())),
+        }
+        // Not to slow down the other tests sharing the device.
+        let _ = queue.finish();
+    }
+
+    #[test]
+    fn test_combine_layer_xor() {
+        let config = Config {
+            encoding_mode: EncodingMode::Xor,
+            ..TEST_CONFIG
+        };
+        let ctx = GPUContext::default(config, TreeOptions::Disabled).unwrap();
+        let mut gpu = GPU::new(ctx, config).unwrap();
+        let data = incrementing_layer(567, config.num_nodes_window);
+        let mask = incrementing_layer(234, config.num_nodes_window);
+        gpu.push_layer(&mask).unwrap();
+        gpu.finalize().unwrap();
+        let encode = gpu
+            .combine_segment(0, &data.0, CombineMode::Encode)
+            .unwrap();
+        assert_eq!(Fr::from_str("1031168").unwrap(), accumulate(&encode).0);
+        assert_eq!(
+            data.0,
+            gpu.combine_segment(0, &encode, CombineMode::Decode)
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn test_combine_layer_xor_large_key() {
+        let config = Config {
+            encoding_mode: EncodingMode::Xor,
+            ..TEST_CONFIG
+        };
+        let ctx = GPUContext::default(config, TreeOptions::Disabled).unwrap();
+        let mut gpu = GPU::new(ctx, config).unwrap();
+        // Keys close to the modulus have bits above 2^253 set, which XOR must not carry into the
+        // replica.
+        let data = Layer::from_seed(7, config.num_nodes_window);
+        let mut mask = incrementing_layer(1, config.num_nodes_window);
+        for node in mask.0.iter_mut() {
+            node.0.negate();
+        }
+        gpu.push_layer(&mask).unwrap();
+        gpu.finalize().unwrap();
+        let encode = gpu
+            .combine_segment(0, &data.0, CombineMode::Encode)
+            .unwrap();
+        let mut expected = data.0.clone();
+        crate::combine_nodes(
+            &mut expected,
+            &mask.0,
+            CombineMode::Encode,
+            config.encoding_mode,
+        )
+        .unwrap();
+        assert_eq!(expected, encode);
+        assert!(encode
+            .iter()
+            .all(|node| node.is_valid_data(EncodingMode::Xor)));
+        assert_eq!(
+            data.0,
+            gpu.combine_segment(0, &encode, CombineMode::Decode)
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn test_generate_random_layer() {
+        let ctx = GPUContext::default(TEST_CONFIG, TreeOptions::Disabled).unwrap();
+        let mut gpu = GPU::new(ctx, TEST_CONFIG).unwrap();
+        let expected = Layer::from_seed(42, TEST_CONFIG.num_nodes_window);
+        let indices = [0, 1, 500, TEST_CONFIG.num_nodes_window - 1];
+        gpu.generate_random_layer(42).unwrap();
+        let nodes = gpu.extract_nodes(&indices).unwrap();
+        for (node, &i) in nodes.iter().zip(indices.iter()) {
+            assert_eq!(expected.0[i], *node);
+        }
+        // The whole layer is used as the key once finalized.
+        gpu.finalize().unwrap();
+        let zeros = Layer(vec![Node::default(); TEST_CONFIG.num_nodes_window]);
+        assert_eq!(
+            expected,
+            gpu.combine_layer(&zeros, CombineMode::Encode).unwrap()
+        );
+
+        gpu.generate_random_layer(43).unwrap();
+        assert_ne!(nodes, gpu.extract_nodes(&indices).unwrap());
+    }
+
+    #[test]
+    fn test_combine_segment_with_commitment() {
+        let ctx = GPUContext::default(TEST_CONFIG, TreeOptions::Disabled).unwrap();
+        let mut gpu = GPU::new(ctx, TEST_CONFIG).unwrap();
+        let leaf_count = TEST_CONFIG.num_nodes_window;
+        let mut rng = thread_rng();
+        let data = Layer::random(&mut rng, leaf_count);
+        gpu.push_layer(&Layer::random(&mut rng, leaf_count))
+            .unwrap();
+        gpu.finalize().unwrap();
+        let mut commitment = DataCommitment::new(leaf_count).unwrap();
+        let mut replica = Vec::new();
+        // Unaligned segments, hashed as several subtrees each.
+        for range in [0..3, 3..100, 100..leaf_count].iter() {
+            replica.extend(
+                gpu.combine_segment_with_commitment(
+                    range.start,
+                    &data.0[range.clone()],
+                    &mut commitment,
+                )
+                .unwrap(),
+            );
+        }
+        assert_eq!(
+            gpu.combine_layer(&data, CombineMode::Encode).unwrap().0,
+            replica
+        );
+        assert_eq!(comm_d(&data).unwrap(), commitment.root().unwrap());
+        // Segments are committed in order only.
+        assert!(gpu
+            .combine_segment_with_commitment(0, &data.0[..10], &mut commitment)
+            .is_err());
+    }
+
+    #[test]
+    fn test_hash_columns() {
+        use generic_array::typenum::U4;
+        use neptune::poseidon::Poseidon;
+        use paired::bls12_381::Bls12;
+
+        let ctx = GPUContext::default(TEST_CONFIG, TreeOptions::Disabled).unwrap();
+        let mut gpu = GPU::new(ctx, TEST_CONFIG).unwrap();
+        let neptune_constants = neptune::poseidon::PoseidonConstants::<Bls12, U4>::new();
+        let constants = PoseidonConstants::from(&neptune_constants);
+        let mut rng = thread_rng();
+        let layers = (0..4)
+            .map(|_| Layer::random(&mut rng, 100))
+            .collect::<Vec<_>>();
+        let hashes = gpu.hash_columns(&constants, &layers).unwrap();
+        assert_eq!(100, hashes.len());
+        for (i, hash) in hashes.iter().enumerate() {
+            let column = layers.iter().map(|layer| layer.0[i].0).collect::<Vec<_>>();
+            let expected = Poseidon::new_with_preimage(&column, &neptune_constants).hash();
+            assert_eq!(Node(expected), *hash);
+        }
+        // One layer per element of the preimage.
+        assert!(gpu.hash_columns(&constants, &layers[1..]).is_err());
+    }
+
+    #[test]
+    fn test_kernel_stats() {
+        let ctx = GPUContext::default(TEST_CONFIG, TreeOptions::Disabled).unwrap();
+        let gpu = GPU::new(ctx, TEST_CONFIG).unwrap();
+        let stats = gpu.kernel_stats().unwrap();
+        for name in &["generate_mask", "generate_expander", "combine_segment"] {
+            let s = stats.iter().find(|s| s.name == *name).unwrap();
+            assert!(s.max_work_group_size > 0);
+            assert!(s.preferred_work_group_size_multiple > 0);
+        }
+    }
+
+    #[test]
+    fn test_program_cache() {
+        let config = Config {
+            num_butterfly_layers: TEST_CONFIG.num_butterfly_layers + 1,
+            ..TEST_CONFIG
+        };
+        let mut gpu = GPU::new(
+            GPUContext::default(config, TreeOptions::Disabled).unwrap(),
+            config,
+        )
+        .unwrap();
+        assert!(crate::program_cache_len() >= 1);
+        let mut cached_gpu = GPU::new(
+            GPUContext::default(config, TreeOptions::Disabled).unwrap(),
+            config,
+        )
+        .unwrap();
+        let device = utils::default_device().unwrap();
+        assert_eq!(1, crate::program_cache::build_count(device, config));
+        assert_eq!(
+            gpu.generate_mask_layer(TEST_REPLICA_ID, TEST_WINDOW_INDEX)
+                .unwrap(),
+            cached_gpu
+                .generate_mask_layer(TEST_REPLICA_ID, TEST_WINDOW_INDEX)
+                .unwrap()
+        );
+    }
+
+    #[cfg(feature = "leak-detection")]
+    #[test]
+    fn test_no_leaked_buffers() {
+        let ctx = GPUContext::default(TEST_CONFIG, TreeOptions::Disabled).unwrap();
+        let mut gpu = GPU::new(ctx, TEST_CONFIG).unwrap();
+        let data = incrementing_layer(567, TEST_CONFIG.num_nodes_window);
+        {
+            let mut keygen =
+                crate::KeyGenerator::new(TEST_CONFIG, TEST_REPLICA_ID, TEST_WINDOW_INDEX, &mut gpu)
+                    .unwrap();
+            keygen.by_ref().collect::<NSEResult<Vec<_>>>().unwrap();
+            keygen.combine_layer(&data, CombineMode::Encode).unwrap();
+        }
+        assert!(gpu.leaked_buffers().is_empty());
+        // `current_layer` and the spare layers
+        assert_eq!(1 + gpu.context.spare_layers.len(), gpu.live_buffers());
+
+        let leaked = gpu.context.create_buffer().unwrap();
+        assert_eq!(1, gpu.leaked_buffers().len());
+        drop(leaked);
+        assert!(gpu.leaked_buffers().is_empty());
+    }
+
+    #[test]
+    fn test_memory_reservation() {
+        let ctx = GPUContext::default(TEST_CONFIG, TreeOptions::Disabled).unwrap();
+        let mut gpu = GPU::new(ctx, TEST_CONFIG).unwrap();
+        let minimum = gpu.minimum_memory();
+        assert_eq!(
+            2 * (TEST_CONFIG.num_nodes_window * NODE_SIZE) as u64,
+            minimum.total_bytes()
+        );
+        // The layer being labeled is the spare one, and the previous one becomes the spare.
+        assert_eq!(1, gpu.context.spare_layers.len());
+        gpu.generate_random_layer(1).unwrap();
+        assert_eq!(1, gpu.context.spare_layers.len());
+        // The jobs of a suspended window start from the spare, the layer they allocate on top
+        // of it is freed on resume.
+        let suspended = gpu.suspend().unwrap();
+        assert!(gpu.context.spare_layers.is_empty());
+        gpu.generate_random_layer(1).unwrap();
+        gpu.resume(suspended).unwrap();
+        assert_eq!(1, gpu.context.spare_layers.len());
+
+        let huge = MinimumMemory {
+            layer_bytes: 1 << 60,
+            ..minimum
+        };
+        match gpu.context.reserve(huge) {
+            Err(NSEError::InsufficientDeviceMemory { minimum, .. }) => assert_eq!(huge, minimum),
+            other => panic!("Unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_host_allocator() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        #[derive(Debug, Default)]
+        struct CountingAllocator(AtomicUsize);
+
+        impl HostAllocator for CountingAllocator {
+            fn alloc_nodes(&self, len: usize) -> Vec<Node> {
+                self.0.fetch_add(len, Ordering::SeqCst);
+                HeapAllocator.alloc_nodes(len)
+            }
+        }
+
+        let layers = (0..2)
+            .map(|i| {
+                let ctx = GPUContext::default(TEST_CONFIG, TreeOptions::Disabled).unwrap();
+                let mut gpu = GPU::new(ctx, TEST_CONFIG).unwrap();
+                let allocator = Arc::new(CountingAllocator::default());
+                if i == 1 {
+                    gpu.set_host_allocator(allocator.clone());
+                }
+                let layer = gpu
+                    .generate_mask_layer(TEST_REPLICA_ID, TEST_WINDOW_INDEX)
+                    .unwrap();
+                (layer, allocator.0.load(Ordering::SeqCst))
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(layers[0].0, layers[1].0);
+        assert_eq!(0, layers[0].1);
+        assert!(layers[1].1 >= TEST_CONFIG.num_nodes_window);
+    }
+
+    #[test]
+    fn test_gpu_handle() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<GpuHandle>();
+
+        let ctx = GPUContext::default(TEST_CONFIG, TreeOptions::Disabled).unwrap();
+        let handle = GpuHandle::new(GPU::new(ctx, TEST_CONFIG).unwrap());
+        let expected = handle
+            .with(|gpu| gpu.generate_mask_layer(TEST_REPLICA_ID, TEST_WINDOW_INDEX))
+            .unwrap();
+
+        let threads = (0..4)
+            .map(|_| {
+                let handle = handle.clone();
+                std::thread::spawn(move || {
+                    handle
+                        .with(|gpu| gpu.generate_mask_layer(TEST_REPLICA_ID, TEST_WINDOW_INDEX))
+                        .unwrap()
+                })
+            })
+            .collect::<Vec<_>>();
+        for t in threads {
+            assert_eq!(expected, t.join().unwrap());
+        }
+    }
+
+    #[test]
+    fn test_mask_cache() {
+        let ctx = GPUContext::default(TEST_CONFIG, TreeOptions::Disabled).unwrap();
+        let gpu_config = GpuConfig {
+            mask_cache_size: 1,
+            ..ctx.gpu_config()
+        };
+        let mut gpu = GPU::with_gpu_config(ctx, TEST_CONFIG, gpu_config).unwrap();
+        let key_layers = |gpu: &mut GPU, window_index| {
+            let layers = crate::KeyGenerator::new(TEST_CONFIG, TEST_REPLICA_ID, window_index, gpu)
+                .unwrap()
+                .collect::<NSEResult<Vec<_>>>()
+                .unwrap();
+            (layers, gpu.take_timings())
+        };
+
+        let (layers, _) = key_layers(&mut gpu, TEST_WINDOW_INDEX);
+        let (cached_layers, timings) = key_layers(&mut gpu, TEST_WINDOW_INDEX);
+        assert_eq!(layers, cached_layers);
+        // The cached mask is pushed to the device instead of being read back from it.
+        let layer_bytes = (TEST_CONFIG.num_nodes_window * std::mem::size_of::<Node>()) as u64;
+        assert_eq!(
+            layer_bytes * (TEST_CONFIG.num_layers() as u64 - 1),
+            timings.bytes_read
+        );
+
+        // Another window evicts it.
+        key_layers(&mut gpu, WindowIndex(TEST_WINDOW_INDEX.0 + 1));
+        let (_, timings) = key_layers(&mut gpu, TEST_WINDOW_INDEX);
+        assert_eq!(
+            layer_bytes * TEST_CONFIG.num_layers() as u64,
+            timings.bytes_read
+        );
+    }
+
+    #[test]
+    fn test_expand_seed() {
+        let tagged = Config {
+            domain_tags: DomainTags {
+                mask: 7,
+                ..DomainTags::UNTAGGED
+            },
+            ..TEST_CONFIG
+        };
+        let aes = Config {
+            mask_prf: MaskPrf::Aes256Ctr,
+            ..TEST_CONFIG
+        };
+        let mut all_seeds = Vec::new();
+        for &config in [TEST_CONFIG, tagged, aes].iter() {
+            let ctx = GPUContext::default(config, TreeOptions::Disabled).unwrap();
+            let mut gpu = GPU::new(ctx, config).unwrap();
+            let seeds = gpu.expand_seed(TEST_REPLICA_ID, TEST_WINDOW_INDEX).unwrap();
+            let mask = gpu
+                .generate_mask_layer(TEST_REPLICA_ID, TEST_WINDOW_INDEX)
+                .unwrap();
+            assert_eq!(config.num_nodes_window, seeds.len());
+            assert_eq!(
+                mask.0,
+                seeds.iter().map(|seed| seed.to_node()).collect::<Vec<_>>()
+            );
+            all_seeds.push(seeds);
+        }
+        // The digest of the hash prefix of the node, as computed on the host.
+        let empty = Layer::default();
+        assert_eq!(
+            all_seeds[0][5].to_node(),
+            crate::label_node(
+                &TEST_CONFIG,
+                TEST_REPLICA_ID,
+                TEST_WINDOW_INDEX,
+                1,
+                &empty,
+                5
+            )
+            .unwrap()
+        );
+        // Domain separation.
+        assert_ne!(all_seeds[0], all_seeds[1]);
+        assert_ne!(all_seeds[0], all_seeds[2]);
+    }
+
+    #[test]
+    fn test_generate_layers_into() {
+        let ctx = GPUContext::default(TEST_CONFIG, TreeOptions::Disabled).unwrap();
+        let mut gpu = GPU::new(ctx, TEST_CONFIG).unwrap();
+        let layers =
+            crate::KeyGenerator::new(TEST_CONFIG, TEST_REPLICA_ID, TEST_WINDOW_INDEX, &mut gpu)
+                .unwrap()
+                .collect::<NSEResult<Vec<_>>>()
+                .unwrap();
+
+        // A single buffer is reused for all layers.
+        let mut out = vec![Node::default(); TEST_CONFIG.num_nodes_window];
+        for layer_index in 1..=TEST_CONFIG.num_layers() {
+            let result = if layer_index == 1 {
+                gpu.generate_mask_layer_into(TEST_REPLICA_ID, TEST_WINDOW_INDEX, &mut out)
+            } else if layer_index <= TEST_CONFIG.num_expander_layers {
+                gpu.generate_expander_layer_into(
+                    TEST_REPLICA_ID,
+                    TEST_WINDOW_INDEX,
+                    layer_index,
+                    &mut out,
+                )
+            } else {
+                gpu.generate_butterfly_layer_into(
+                    TEST_REPLICA_ID,
+                    TEST_WINDOW_INDEX,
+                    layer_index,
+                    &mut out,
+                )
+            };
+            result.unwrap();
+            assert_eq!(layers[layer_index - 1].0, out);
+        }
+
+        let mut short = vec![Node::default(); TEST_CONFIG.num_nodes_window - 1];
+        match gpu.generate_mask_layer_into(TEST_REPLICA_ID, TEST_WINDOW_INDEX, &mut short) {
+            Err(NSEError::InvalidInput(_)) => {}
+            other => panic!("Unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_reconfigure() {
+        let other_config = Config {
+            num_nodes_window: 2048,
+            degree_expander: 12,
+            ..TEST_CONFIG
+        };
+        let expected = |config| {
+            let ctx = GPUContext::default(config, TreeOptions::Disabled).unwrap();
+            let mut gpu = GPU::new(ctx, config).unwrap();
+            gpu.generate_mask_layer(TEST_REPLICA_ID, TEST_WINDOW_INDEX)
+                .unwrap()
+        };
+
+        let ctx = GPUContext::default(TEST_CONFIG, TreeOptions::Disabled).unwrap();
+        let mut gpu = GPU::new(ctx, TEST_CONFIG).unwrap();
+        for &config in [other_config, TEST_CONFIG].iter() {
+            gpu.reconfigure(config).unwrap();
+            assert_eq!(config, gpu.config());
+            assert_eq!(config.num_nodes_window, gpu.leaf_count());
+            assert_eq!(
+                expected(config),
+                gpu.generate_mask_layer(TEST_REPLICA_ID, TEST_WINDOW_INDEX)
+                    .unwrap()
+            );
+        }
+        assert!(gpu
+            .reconfigure(Config {
+                num_nodes_window: 1000,
+                ..TEST_CONFIG
+            })
+            .is_err());
+    }
+
+    #[test]
+    fn test_bench_k_sweep() {
+        let ctx = GPUContext::default(TEST_CONFIG, TreeOptions::Disabled).unwrap();
+        let gpu = GPU::new(ctx, TEST_CONFIG).unwrap();
+        let throughputs = gpu.bench_k_sweep(&[1, 8], 2).unwrap();
+        assert_eq!(
+            vec![1, 8],
+            throughputs.iter().map(|t| t.k).collect::<Vec<_>>()
+        );
+        assert!(throughputs.iter().all(|t| t.nodes_per_sec > 0f64));
+        assert!(gpu.bench_k_sweep(&[3], 2).is_err());
+        assert!(gpu.bench_k_sweep(&[1], 0).is_err());
+    }
+
+    #[test]
+    fn test_transfer_counters() {
+        let ctx = GPUContext::default(TEST_CONFIG, TreeOptions::Disabled).unwrap();
+        let mut gpu = GPU::new(ctx, TEST_CONFIG).unwrap();
+        let node_size = std::mem::size_of::<Node>() as u64;
+        let layer = incrementing_layer(123, TEST_CONFIG.num_nodes_window);
+        gpu.push_layer(&layer).unwrap();
+        gpu.finalize().unwrap();
+        gpu.combine_segment(10, &layer.0[..100], CombineMode::Encode)
+            .unwrap();
+        let timings = gpu.take_timings();
+        assert_eq!(
+            (TEST_CONFIG.num_nodes_window as u64 + 100) * node_size,
+            timings.bytes_written
+        );
+        assert_eq!(100 * node_size, timings.bytes_read);
+
+        // Measuring doesn't count as transfers of the sealing work.
+        let bandwidth = gpu.measure_bandwidth().unwrap();
+        assert!(bandwidth.host_to_device > 0f64 && bandwidth.device_to_host > 0f64);
+        assert_eq!(OpTimings::default(), gpu.take_timings());
+    }
+
+    #[test]
+    fn test_label_small_windows() {
+        let config = Config {
+            num_nodes_window: 64,
+            degree_expander: 12,
+            ..TEST_CONFIG
+        };
+        let mut rng = thread_rng();
+        let windows = (0..1000)
+            .map(|_| {
+                (
+                    ReplicaId::random(&mut rng),
+                    WindowIndex::from(rng.gen::<u32>()),
+                )
+            })
+            .collect::<Vec<_>>();
+
+        let ctx = GPUContext::default(config, TreeOptions::Disabled).unwrap();
+        let mut gpu = GPU::new(ctx, config).unwrap();
+        let labeled = gpu.label_small_windows(&windows).unwrap();
+        assert_eq!(windows.len(), labeled.len());
+
+        // Some windows labeled one at a time.
+        for &w in [0, 1, 999].iter() {
+            let (replica_id, window_index) = windows[w];
+            let layers = crate::KeyGenerator::new(config, replica_id, window_index, &mut gpu)
+                .unwrap()
+                .collect::<NSEResult<Vec<_>>>()
+                .unwrap();
+            assert_eq!(layers, labeled[w]);
+        }
+        assert!(gpu.label_small_windows(&[]).is_err());
+
+        // In batches of 300 windows, but not if a single window doesn't fit.
+        let window_bytes = config.num_layers() * config.num_nodes_window * NODE_SIZE;
+        let chunked = |max_alloc_chunk| {
+            let ctx = GPUContext::default(config, TreeOptions::Disabled).unwrap();
+            let gpu_config = GpuConfig {
+                max_alloc_chunk: Some(max_alloc_chunk),
+                ..GpuConfig::default()
+            };
+            GPU::with_gpu_config(ctx, config, gpu_config).unwrap()
+        };
+        assert_eq!(
+            labeled,
+            chunked(300 * window_bytes)
+                .label_small_windows(&windows)
+                .unwrap()
+        );
+        assert!(chunked(window_bytes - 1)
+            .label_small_windows(&windows)
+            .is_err());
+    }
+
+    #[test]
+    fn test_batch_key_generator() {
+        let windows = [
+            (TEST_REPLICA_ID, TEST_WINDOW_INDEX),
+            (TEST_REPLICA_ID, WindowIndex(TEST_WINDOW_INDEX.0 + 1)),
+            (ReplicaId([45u8; 32]), WindowIndex(3)),
+        ];
+        let data = incrementing_layer(567, TEST_CONFIG.num_nodes_window);
+
+        // Expected layers, generated one window at a time.
+        let mut expected = Vec::new();
+        for &(replica_id, window_index) in windows.iter() {
+            let ctx = GPUContext::default(TEST_CONFIG, TreeOptions::Disabled).unwrap();
+            let mut gpu = GPU::new(ctx, TEST_CONFIG).unwrap();
+            let mut keygen =
+                crate::KeyGenerator::new(TEST_CONFIG, replica_id, window_index, &mut gpu).unwrap();
+            let layers = keygen.by_ref().collect::<NSEResult<Vec<_>>>().unwrap();
+            let replica = keygen.combine_layer(&data, CombineMode::Encode).unwrap();
+            expected.push((layers, replica));
+        }
+
+        // Layers are read back in chunks not dividing them.
+        let gpu_config = GpuConfig {
+            readback_chunk_size: 100,
+            ..GpuConfig::default()
+        };
+        let ctx = GPUContext::default(TEST_CONFIG, TreeOptions::Disabled).unwrap();
+        let mut gpu = GPU::with_gpu_config(ctx, TEST_CONFIG, gpu_config).unwrap();
+        let mut batch = gpu.batch_key_generator(&windows).unwrap();
+        assert_eq!(batch.len(), expected[0].0.len());
+        for (i, layers) in batch.by_ref().enumerate() {
+            let layers = layers.unwrap();
+            assert_eq!(layers.len(), windows.len());
+            for (w, l) in layers.iter().enumerate() {
+                assert_eq!(&expected[w].0[i], l);
+            }
+        }
+        let inputs = vec![data.clone(); windows.len()];
+        let replicas = batch.combine_layers(&inputs, CombineMode::Encode).unwrap();
+        for (w, r) in replicas.iter().enumerate() {
+            assert_eq!(&expected[w].1, r);
+        }
+        assert_eq!(
+            inputs,
+            batch
+                .combine_layers(&replicas, CombineMode::Decode)
+                .unwrap()
+        );
+
+        // The layers of the batch must fit in a single allocation.
+        let ctx = GPUContext::default(TEST_CONFIG, TreeOptions::Disabled).unwrap();
+        let gpu_config = GpuConfig {
+            max_alloc_chunk: Some(2 * TEST_CONFIG.num_nodes_window * NODE_SIZE),
+            ..GpuConfig::default()
+        };
+        let mut gpu = GPU::with_gpu_config(ctx, TEST_CONFIG, gpu_config).unwrap();
+        assert!(gpu.batch_key_generator(&windows).is_err());
+        assert!(gpu.batch_key_generator(&windows[..2]).is_ok());
+    }
 }
@@ -0,0 +1,380 @@
+use crate::utils::Device;
+use crate::{Config, DeviceLockPolicy, GPUError, GPUResult, GpuPriority, NODE_SIZE};
+use std::fmt;
+use std::time::Duration;
+
+/// Runtime tuning knobs of the GPU implementation.
+///
+/// Unlike `Config`, none of these parameters affect the generated layers; they only change how
+/// the work is scheduled on the device, so they can be tuned freely per machine.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GpuConfig {
+    /// Number of command queues used for host <-> device transfers.
+    pub num_queues: usize,
+    /// Number of work-items launched per kernel. `None` launches one work-item per node.
+    /// Smaller values make each work-item process several nodes.
+    pub global_work_size: Option<usize>,
+    /// Work-group size of the kernels, clamped to the global work size of smaller launches.
+    /// `None` lets the driver decide.
+    pub local_work_size: Option<usize>,
+    /// Number of nodes read back from the device per transfer (`NODE_SIZE` bytes each). Nodes
+    /// are read straight into the layers returned, one chunk at a time, so host memory never
+    /// holds a staging copy of a layer or of a batch of windows.
+    pub readback_chunk_size: usize,
+    /// Allocate layer buffers in host-accessible (pinned) memory.
+    pub pinned_memory: bool,
+    /// Maximum number of bytes of layers kept in host memory per window. Layers of windows
+    /// exceeding it are spilled to disk, see `layer_store_for`. `None` means unlimited.
+    pub host_memory_budget: Option<usize>,
+    /// How sealing shares the GPUs with bellperson SNARK proving, see `GpuLock`.
+    pub priority: GpuPriority,
+    /// Maximum size in bytes of a single device allocation. Layers larger than it are split
+    /// into up to `MAX_LAYER_CHUNKS` buffers, which fit in fragmented device memory where a
+    /// whole layer wouldn't. `None` allocates each layer at once.
+    pub max_alloc_chunk: Option<usize>,
+    /// Compute the expander parents of all nodes once, and keep them in device memory
+    /// (`4 * degree_expander` bytes per node) for all expander layers, instead of hashing them
+    /// again for every node of every layer. The kernels read them from a single buffer, so they
+    /// are not cached if they don't fit in `max_alloc_chunk`.
+    pub cache_expander_parents: bool,
+    /// Maximum running time of a single kernel or transfer. Some drivers hang instead of
+    /// failing, with it a stuck operation fails with `NSEError::KernelTimeout` instead of
+    /// blocking forever, and the GPU must be recreated: its device buffers are leaked, and
+    /// transfers go through staging copies that can be. `None` waits without polling.
+    pub kernel_timeout: Option<Duration>,
+    /// Number of mask layers kept in host memory (`NODE_SIZE` bytes per node each), so that
+    /// windows sealed again or unsealed right after sealing skip generating them. 0 disables
+    /// the cache.
+    pub mask_cache_size: usize,
+    /// Lock the device for the duration of each sealed window, so that sealing processes
+    /// sharing the machine run one at a time on it, see `DeviceLock`. `None` doesn't lock.
+    pub device_lock: Option<DeviceLockPolicy>,
+}
+
+/// Maximum number of device buffers a layer can be split into, see `GpuConfig::max_alloc_chunk`.
+/// Must match `MAX_LAYER_CHUNKS` of the kernels.
+pub const MAX_LAYER_CHUNKS: usize = 4;
+
+/// Work-group information of a compiled kernel on a device, see `GPU::kernel_stats`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KernelStats {
+    pub name: String,
+    /// Largest work-group size the kernel can be launched with, limited by its resource usage.
+    pub max_work_group_size: usize,
+    /// Work-group sizes should be a multiple of it (i.e. the warp or wavefront size).
+    pub preferred_work_group_size_multiple: usize,
+    /// Bytes of local memory used by a work-group.
+    pub local_mem_size: u64,
+    /// Bytes of private memory used by a work-item. Non-zero usually means spilled registers.
+    pub private_mem_size: u64,
+}
+
+/// Throughput of transfers between host and device, in bytes per second, see
+/// `GPU::measure_bandwidth`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Bandwidth {
+    pub host_to_device: f64,
+    pub device_to_host: f64,
+}
+
+/// Expander labeling throughput of a config with a given `k`, see `GPU::bench_k_sweep`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct KThroughput {
+    pub k: u32,
+    /// 0 if the device reported no kernel time, e.g. timer resolution too coarse.
+    pub nodes_per_sec: f64,
+}
+
+/// Device memory a GPU needs at least to seal a window, see `GPU::minimum_memory`. It is
+/// reserved when the GPU is created, so that a device too small for the config fails right away
+/// with `NSEError::InsufficientDeviceMemory`, instead of in the middle of a window. It is a lower
+/// bound of the peak: batched operations (e.g. `GPU::encode_pairs`, `GPU::label_small_windows`),
+/// the temporaries of the final combine, data staged by `SealerBuilder::stage_data` and windows
+/// suspended by preempting jobs allocate more on top of it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MinimumMemory {
+    /// Bytes of a layer.
+    pub layer_bytes: u64,
+    /// Number of layers alive at once: the current one, and the one being labeled or combined.
+    pub layer_buffers: u64,
+    /// Bytes of the expander parents, see `GpuConfig::cache_expander_parents`.
+    pub parent_cache_bytes: u64,
+    /// Bytes of the largest single allocation, a chunk of a layer or the parent cache.
+    pub max_alloc_bytes: u64,
+}
+
+impl MinimumMemory {
+    pub fn new(config: &Config, gpu_config: &GpuConfig) -> Self {
+        let leaf_count = config.leaf_count() as u64;
+        let chunk_bytes = (gpu_config.layer_chunk_len(config.leaf_count()) * NODE_SIZE) as u64;
+        let parent_cache_bytes = gpu_config.parent_cache_bytes(config);
+        MinimumMemory {
+            layer_bytes: leaf_count * NODE_SIZE as u64,
+            layer_buffers: 2,
+            parent_cache_bytes,
+            max_alloc_bytes: std::cmp::max(chunk_bytes, parent_cache_bytes),
+        }
+    }
+
+    pub fn total_bytes(&self) -> u64 {
+        self.layer_bytes * self.layer_buffers + self.parent_cache_bytes
+    }
+}
+
+impl fmt::Display for MinimumMemory {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} bytes ({} layers of {} bytes, {} bytes of parent cache)",
+            self.total_bytes(),
+            self.layer_buffers,
+            self.layer_bytes,
+            self.parent_cache_bytes
+        )
+    }
+}
+
+/// Size of the buffer transferred by `GPU::measure_bandwidth`.
+pub const BANDWIDTH_TEST_BYTES: usize = 64 << 20;
+
+pub const GPU_NVIDIA_VENDOR_NAME: &str = "NVIDIA";
+pub const GPU_AMD_VENDOR_NAME: &str = "Advanced Micro Devices";
+
+const DEFAULT_READBACK_CHUNK_SIZE: usize = 1 << 20;
+
+impl Default for GpuConfig {
+    fn default() -> Self {
+        GpuConfig {
+            num_queues: 1,
+            global_work_size: None,
+            local_work_size: None,
+            readback_chunk_size: DEFAULT_READBACK_CHUNK_SIZE,
+            pinned_memory: false,
+            host_memory_budget: None,
+            priority: GpuPriority::default(),
+            max_alloc_chunk: None,
+            cache_expander_parents: false,
+            kernel_timeout: None,
+            mask_cache_size: 0,
+            device_lock: None,
+        }
+    }
+}
+
+impl GpuConfig {
+    /// Returns sane defaults for the vendor of the given device.
+    pub fn for_device(device: Device) -> GPUResult<GpuConfig> {
+        let vendor = device.vendor()?;
+        Ok(if vendor.contains(GPU_NVIDIA_VENDOR_NAME) {
+            GpuConfig {
+                num_queues: 2,
+                local_work_size: Some(128),
+                pinned_memory: true,
+                ..GpuConfig::default()
+            }
+        } else if vendor.contains(GPU_AMD_VENDOR_NAME) {
+            GpuConfig {
+                num_queues: 2,
+                local_work_size: Some(64), // Wavefront size
+                ..GpuConfig::default()
+            }
+        } else {
+            GpuConfig::default()
+        })
+    }
+
+    /// Checks the knobs are usable for a window of `leaf_count` nodes.
+    pub fn validate(&self, leaf_count: usize) -> GPUResult<()> {
+        if self.num_queues == 0 {
+            return Err(GPUError::Other(
+                "At least one command queue is needed!".into(),
+            ));
+        }
+        if self.readback_chunk_size == 0 {
+            return Err(GPUError::Other(
+                "Readback chunk size cannot be zero!".into(),
+            ));
+        }
+        let global_work_size = self.global_work_size(leaf_count);
+        if global_work_size == 0 {
+            return Err(GPUError::Other("Global work size cannot be zero!".into()));
+        }
+        if let Some(local_work_size) = self.local_work_size {
+            // Larger work-groups are clamped to the launch, see `local_work_size`.
+            if local_work_size == 0
+                || (global_work_size > local_work_size && global_work_size % local_work_size != 0)
+            {
+                return Err(GPUError::Other(format!(
+                    "Global work size ({}) should be a multiple of local work size ({})!",
+                    global_work_size, local_work_size
+                )));
+            }
+        }
+        if self.kernel_timeout == Some(Duration::from_secs(0)) {
+            return Err(GPUError::Other("Kernel timeout cannot be zero!".into()));
+        }
+        let chunk_len = self.layer_chunk_len(leaf_count);
+        if chunk_len == 0 || leaf_count > chunk_len * MAX_LAYER_CHUNKS {
+            return Err(GPUError::Other(format!(
+                "Layers of {} nodes do not fit in {} allocations of at most {:?} bytes!",
+                leaf_count, MAX_LAYER_CHUNKS, self.max_alloc_chunk
+            )));
+        }
+        Ok(())
+    }
+
+    /// Number of nodes per device buffer of a layer of `leaf_count` nodes: the largest power
+    /// of two within `max_alloc_chunk`, so that kernels locate nodes with shifts and masks.
+    pub fn layer_chunk_len(&self, leaf_count: usize) -> usize {
+        match self.max_alloc_chunk {
+            Some(max) if max / NODE_SIZE < leaf_count => {
+                let max_nodes = max / NODE_SIZE;
+                if max_nodes == 0 {
+                    0
+                } else {
+                    1 << (63 - (max_nodes as u64).leading_zeros())
+                }
+            }
+            _ => leaf_count,
+        }
+    }
+
+    /// Bytes of the expander parents cached for `config`, 0 if they are not: without
+    /// `cache_expander_parents`, or if they don't fit in a single allocation.
+    pub fn parent_cache_bytes(&self, config: &Config) -> u64 {
+        let bytes = 4 * config.degree_expander as u64 * config.leaf_count() as u64;
+        if self.cache_expander_parents && self.items_per_alloc(bytes as usize, 1) == 1 {
+            bytes
+        } else {
+            0
+        }
+    }
+
+    /// Number of items of `item_bytes` bytes, at most `count`, fitting in a single allocation of
+    /// at most `max_alloc_chunk` bytes, so that batched operations are split into several
+    /// allocations. 0 if a single item doesn't fit.
+    pub(crate) fn items_per_alloc(&self, item_bytes: usize, count: usize) -> usize {
+        match self.max_alloc_chunk {
+            Some(max) => std::cmp::min(count, max / item_bytes),
+            None => count,
+        }
+    }
+
+    pub fn global_work_size(&self, leaf_count: usize) -> usize {
+        self.global_work_size.unwrap_or(leaf_count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_gpu_config_validation() {
+        assert!(GpuConfig::default().validate(1024).is_ok());
+        assert!(GpuConfig {
+            local_work_size: Some(64),
+            ..GpuConfig::default()
+        }
+        .validate(1024)
+        .is_ok());
+        // NVIDIA defaults on a window smaller than a work-group.
+        assert!(GpuConfig {
+            local_work_size: Some(128),
+            ..GpuConfig::default()
+        }
+        .validate(64)
+        .is_ok());
+        assert!(GpuConfig {
+            global_work_size: Some(100),
+            local_work_size: Some(64),
+            ..GpuConfig::default()
+        }
+        .validate(1024)
+        .is_err());
+        assert!(GpuConfig {
+            num_queues: 0,
+            ..GpuConfig::default()
+        }
+        .validate(1024)
+        .is_err());
+        assert!(GpuConfig {
+            kernel_timeout: Some(Duration::from_secs(0)),
+            ..GpuConfig::default()
+        }
+        .validate(1024)
+        .is_err());
+    }
+
+    #[test]
+    fn test_layer_chunk_len() {
+        let chunked = |max| GpuConfig {
+            max_alloc_chunk: Some(max),
+            ..GpuConfig::default()
+        };
+        assert_eq!(1024, GpuConfig::default().layer_chunk_len(1024));
+        assert_eq!(1024, chunked(1024 * NODE_SIZE).layer_chunk_len(1024));
+        assert_eq!(256, chunked(300 * NODE_SIZE).layer_chunk_len(1024));
+        assert!(chunked(256 * NODE_SIZE).validate(1024).is_ok());
+        assert!(chunked(255 * NODE_SIZE).validate(1024).is_err());
+        assert!(chunked(NODE_SIZE - 1).validate(1024).is_err());
+    }
+
+    #[test]
+    fn test_minimum_memory() {
+        use crate::{DomainTags, EncodingMode, MaskPrf};
+        let config = Config {
+            k: 2,
+            num_nodes_window: 1024,
+            degree_expander: 96,
+            degree_butterfly: 4,
+            num_expander_layers: 4,
+            num_butterfly_layers: 3,
+            encoding_mode: EncodingMode::FieldAdd,
+            domain_tags: DomainTags::UNTAGGED,
+            mask_prf: MaskPrf::Sha256,
+        };
+        let estimate = MinimumMemory::new(&config, &GpuConfig::default());
+        assert_eq!(1024 * NODE_SIZE as u64, estimate.layer_bytes);
+        assert_eq!(2 * estimate.layer_bytes, estimate.total_bytes());
+        assert_eq!(estimate.layer_bytes, estimate.max_alloc_bytes);
+
+        let cached = GpuConfig {
+            cache_expander_parents: true,
+            ..GpuConfig::default()
+        };
+        let estimate = MinimumMemory::new(&config, &cached);
+        assert_eq!(4 * 96 * 1024, estimate.parent_cache_bytes);
+        assert_eq!(estimate.parent_cache_bytes, estimate.max_alloc_bytes);
+        assert_eq!(
+            2 * estimate.layer_bytes + estimate.parent_cache_bytes,
+            estimate.total_bytes()
+        );
+        assert!(estimate
+            .to_string()
+            .starts_with(&estimate.total_bytes().to_string()));
+
+        // The parents don't fit in a chunk, and are not cached.
+        let estimate = MinimumMemory::new(
+            &config,
+            &GpuConfig {
+                max_alloc_chunk: Some(256 * NODE_SIZE),
+                ..cached
+            },
+        );
+        assert_eq!(0, estimate.parent_cache_bytes);
+        assert_eq!(256 * NODE_SIZE as u64, estimate.max_alloc_bytes);
+    }
+
+    #[test]
+    fn test_items_per_alloc() {
+        assert_eq!(7, GpuConfig::default().items_per_alloc(100, 7));
+        let chunked = GpuConfig {
+            max_alloc_chunk: Some(250),
+            ..GpuConfig::default()
+        };
+        assert_eq!(2, chunked.items_per_alloc(100, 7));
+        assert_eq!(1, chunked.items_per_alloc(100, 1));
+        assert_eq!(0, chunked.items_per_alloc(300, 7));
+    }
+}
@@ -0,0 +1,209 @@
+//! Cooperation with bellperson, which serializes the SNARK proofs of all processes of a machine
+//! with lock files in the temporary directory: `bellman.gpu.lock` is held while using the GPUs,
+//! all of them, and `bellman.priority.lock` by high-priority proofs, that others should yield
+//! the GPUs to.
+//!
+//! Sealing processes may also lock the device they run on for the duration of a window, see
+//! `DeviceLock`, so that they don't oversubscribe its memory. Unlike the GPU lock, it is taken
+//! per device, and only among sealing processes.
+use crate::{NSEError, NSEResult};
+use fs2::FileExt;
+use std::fs::{File, OpenOptions};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const GPU_LOCK_NAME: &str = "bellman.gpu.lock";
+const PRIORITY_LOCK_NAME: &str = "bellman.priority.lock";
+
+/// Delay between two checks of the priority lock while yielding.
+const PRIORITY_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Delay between two attempts to take a device lock, see `DeviceLockPolicy::Timeout`.
+const DEVICE_LOCK_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How labeling shares the GPUs with bellperson SNARK proving, see `GpuConfig::priority`.
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Hash)]
+pub enum GpuPriority {
+    /// Don't take part in the locking, the GPU is shared with proofs without coordination.
+    Ignore,
+    /// Hold the GPU lock while sealing, and release it between layers whenever a high-priority
+    /// proof is waiting, until the proof is done.
+    Low,
+    /// Hold both the GPU and the priority lock while sealing, making low-priority proofs yield.
+    High,
+}
+
+impl Default for GpuPriority {
+    fn default() -> Self {
+        GpuPriority::Ignore
+    }
+}
+
+fn lock_path(dir: &Path, name: &str) -> PathBuf {
+    dir.join(name)
+}
+
+// `%TEMP%` on Windows, where bellperson locks too.
+fn lock_dir() -> PathBuf {
+    std::env::temp_dir()
+}
+
+// Lock files are never truncated: on Windows, where locks are mandatory, truncating a file locked
+// by another process fails instead of waiting for the lock.
+fn open_lock_file(path: &Path) -> NSEResult<File> {
+    Ok(OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .open(path)?)
+}
+
+/// Exclusive use of the GPUs, shared with bellperson. Released when dropped.
+pub struct GpuLock {
+    _priority: Option<File>, // Held for `GpuPriority::High` only
+    _gpu: File,
+}
+
+impl GpuLock {
+    /// Blocks until the GPU lock (and the priority lock for `GpuPriority::High`) is acquired.
+    /// bellperson has a single lock for all the GPUs of the machine, so proofs are kept off all
+    /// of them meanwhile.
+    pub fn lock(priority: GpuPriority) -> NSEResult<Self> {
+        Self::lock_in(&lock_dir(), priority)
+    }
+
+    fn lock_in(dir: &Path, priority: GpuPriority) -> NSEResult<Self> {
+        // Same order as bellperson, priority lock first.
+        let priority = if priority == GpuPriority::High {
+            Some(lock_exclusive(&lock_path(dir, PRIORITY_LOCK_NAME))?)
+        } else {
+            None
+        };
+        Ok(GpuLock {
+            _priority: priority,
+            _gpu: lock_exclusive(&lock_path(dir, GPU_LOCK_NAME))?,
+        })
+    }
+
+    /// Whether a high-priority proof holds the priority lock.
+    pub fn priority_requested() -> NSEResult<bool> {
+        priority_requested_in(&lock_dir())
+    }
+
+    /// Blocks while a high-priority proof holds the priority lock.
+    pub fn wait_for_priority() -> NSEResult<()> {
+        while Self::priority_requested()? {
+            thread::sleep(PRIORITY_POLL_INTERVAL);
+        }
+        Ok(())
+    }
+}
+
+/// What a sealing process does when the device it runs on is locked by another one, see
+/// `GpuConfig::device_lock`.
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Hash)]
+pub enum DeviceLockPolicy {
+    /// Wait until the device is released, however long it takes.
+    Wait,
+    /// Wait at most the given duration, then fail with `NSEError::DeviceBusy`.
+    Timeout(Duration),
+    /// Fail with `NSEError::DeviceBusy` right away.
+    Fail,
+}
+
+/// Exclusive use of a device among the sealing processes of the machine, advisory: only
+/// processes taking it are serialized. Released when dropped.
+pub struct DeviceLock {
+    _file: File,
+}
+
+impl DeviceLock {
+    /// Takes the lock of the device identified by `device_key` (see `GPU::device_key`),
+    /// waiting for the processes holding it as long as `policy` allows.
+    pub fn lock(device_key: &str, policy: DeviceLockPolicy) -> NSEResult<Self> {
+        Self::lock_in(&lock_dir(), device_key, policy)
+    }
+
+    fn lock_in(dir: &Path, device_key: &str, policy: DeviceLockPolicy) -> NSEResult<Self> {
+        let path = lock_path(dir, &format!("nse.device-{}.lock", device_key));
+        let file = match policy {
+            DeviceLockPolicy::Wait => lock_exclusive(&path)?,
+            DeviceLockPolicy::Timeout(timeout) => {
+                let start = Instant::now();
+                loop {
+                    if let Some(file) = try_lock_exclusive(&path)? {
+                        break file;
+                    }
+                    if start.elapsed() >= timeout {
+                        return Err(NSEError::DeviceBusy(device_key.to_string()));
+                    }
+                    thread::sleep(DEVICE_LOCK_POLL_INTERVAL);
+                }
+            }
+            DeviceLockPolicy::Fail => try_lock_exclusive(&path)?
+                .ok_or_else(|| NSEError::DeviceBusy(device_key.to_string()))?,
+        };
+        Ok(DeviceLock { _file: file })
+    }
+}
+
+// Locks are released when their file is closed.
+fn lock_exclusive(path: &Path) -> NSEResult<File> {
+    let file = open_lock_file(path)?;
+    file.lock_exclusive()?;
+    Ok(file)
+}
+
+// `None` if the lock is held by someone else.
+fn try_lock_exclusive(path: &Path) -> NSEResult<Option<File>> {
+    let file = open_lock_file(path)?;
+    Ok(file.try_lock_exclusive().ok().map(|_| file))
+}
+
+fn priority_requested_in(dir: &Path) -> NSEResult<bool> {
+    let file = open_lock_file(&lock_path(dir, PRIORITY_LOCK_NAME))?;
+    if file.try_lock_exclusive().is_err() {
+        return Ok(true);
+    }
+    file.unlock()?;
+    Ok(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_gpu_lock() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(!priority_requested_in(dir.path()).unwrap());
+        let low = GpuLock::lock_in(dir.path(), GpuPriority::Low).unwrap();
+        assert!(!priority_requested_in(dir.path()).unwrap());
+        drop(low);
+        let high = GpuLock::lock_in(dir.path(), GpuPriority::High).unwrap();
+        assert!(priority_requested_in(dir.path()).unwrap());
+        // The very file bellperson locks.
+        let gpu = open_lock_file(&lock_path(dir.path(), "bellman.gpu.lock")).unwrap();
+        assert!(gpu.try_lock_exclusive().is_err());
+        drop(high);
+        assert!(!priority_requested_in(dir.path()).unwrap());
+        assert!(gpu.try_lock_exclusive().is_ok());
+    }
+
+    #[test]
+    fn test_device_lock() {
+        let dir = tempfile::tempdir().unwrap();
+        let lock = DeviceLock::lock_in(dir.path(), "bus-1", DeviceLockPolicy::Wait).unwrap();
+        // Other devices are independent.
+        assert!(DeviceLock::lock_in(dir.path(), "bus-2", DeviceLockPolicy::Fail).is_ok());
+        match DeviceLock::lock_in(dir.path(), "bus-1", DeviceLockPolicy::Fail) {
+            Err(NSEError::DeviceBusy(key)) => assert_eq!("bus-1", key),
+            _ => panic!("Device should be busy!"),
+        }
+        let timeout = DeviceLockPolicy::Timeout(Duration::from_millis(200));
+        assert!(DeviceLock::lock_in(dir.path(), "bus-1", timeout).is_err());
+        drop(lock);
+        assert!(DeviceLock::lock_in(dir.path(), "bus-1", timeout).is_ok());
+    }
+}
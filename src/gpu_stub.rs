@@ -0,0 +1,451 @@
+//! Stand-in for the `gpu` module in builds without the `gpu` feature. It keeps the public API
+//! intact, so downstream crates compile on machines without OpenCL, but no `GPUContext` (and so
+//! no `GPU`) can ever be created: constructors return `NSEError::NoGpuSupport`.
+
+use super::{
+    Bandwidth, CombineMode, Config, DataCommitment, GPUResult, GpuConfig, HostAllocator,
+    KThroughput, KernelStats, Layer, LayerContentStats, MinimumMemory, NSEError, NSEResult,
+    NarrowStackedExpander, Node, PoseidonConstants, ReplicaId, Sha256Domain, WindowIndex,
+};
+use crate::utils::Device;
+use generic_array::typenum::U8;
+use neptune::tree_builder::TreeBuilder;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+// No value of it exists, which makes every method taking a `GPUContext` or a `GPU` unreachable.
+enum Never {}
+
+pub fn is_little_endian(d: Device) -> GPUResult<bool> {
+    match d {}
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum TreeOptions {
+    Enabled {
+        rows_to_discard: usize,
+    },
+    /// Trees are built on `device` rather than on the labeling device, e.g. to pair a strong
+    /// labeling card with a cheaper hashing one. Layers are shipped through the host.
+    EnabledOn {
+        rows_to_discard: usize,
+        device: Device,
+    },
+    Disabled,
+}
+
+impl TreeOptions {
+    pub fn is_enabled(&self) -> bool {
+        match self {
+            TreeOptions::Disabled => false,
+            _ => true,
+        }
+    }
+}
+
+pub struct GPUContext {
+    never: Never,
+}
+
+/// Time spent on the device since the timings were last taken.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct OpTimings {
+    /// Time spent running kernels, as profiled by the device.
+    pub kernel: Duration,
+    /// Time spent transferring data between host and device.
+    pub transfer: Duration,
+    /// Bytes written to the device.
+    pub bytes_written: u64,
+    /// Bytes read back from the device.
+    pub bytes_read: u64,
+}
+
+impl GPUContext {
+    pub fn default(_config: Config, _tree_options: TreeOptions) -> NSEResult<GPUContext> {
+        Err(NSEError::NoGpuSupport)
+    }
+
+    pub fn new(
+        device: Device,
+        _config: Config,
+        _tree_options: TreeOptions,
+    ) -> NSEResult<GPUContext> {
+        match device {}
+    }
+
+    pub fn gpu_config(&self) -> GpuConfig {
+        match self.never {}
+    }
+}
+
+pub struct GPU {
+    never: Never,
+    pub config: Config,
+}
+
+/// The state of a window set aside by `GPU::suspend`, to be restored by `GPU::resume`.
+pub struct SuspendedWindow {
+    never: Never,
+}
+
+/// A cloneable handle to a GPU, serializing all submissions through an internal lock.
+#[derive(Clone)]
+pub struct GpuHandle(Arc<Mutex<GPU>>);
+
+impl GpuHandle {
+    pub fn new(gpu: GPU) -> Self {
+        GpuHandle(Arc::new(Mutex::new(gpu)))
+    }
+
+    pub fn lock(&self) -> MutexGuard<GPU> {
+        self.0.lock().unwrap()
+    }
+
+    pub fn with<F: FnOnce(&mut GPU) -> R, R>(&self, f: F) -> R {
+        f(&mut self.lock())
+    }
+}
+
+impl From<GPU> for GpuHandle {
+    fn from(gpu: GPU) -> Self {
+        GpuHandle::new(gpu)
+    }
+}
+
+impl GPU {
+    pub fn new(context: GPUContext, _config: Config) -> NSEResult<Self> {
+        match context.never {}
+    }
+
+    pub fn with_gpu_config(
+        context: GPUContext,
+        _config: Config,
+        _gpu_config: GpuConfig,
+    ) -> NSEResult<Self> {
+        match context.never {}
+    }
+
+    pub fn minimum_memory(&self) -> MinimumMemory {
+        match self.never {}
+    }
+
+    pub fn gpu_config(&self) -> GpuConfig {
+        match self.never {}
+    }
+
+    pub fn set_host_allocator(&mut self, _allocator: Arc<dyn HostAllocator>) {
+        match self.never {}
+    }
+
+    pub fn device_key(&self) -> NSEResult<String> {
+        match self.never {}
+    }
+
+    pub fn device_name(&self) -> NSEResult<String> {
+        match self.never {}
+    }
+
+    pub(crate) fn release(self) {
+        match self.never {}
+    }
+
+    pub fn config(&self) -> Config {
+        match self.never {}
+    }
+
+    pub fn reconfigure(&mut self, _config: Config) -> NSEResult<()> {
+        match self.never {}
+    }
+
+    pub fn take_timings(&mut self) -> OpTimings {
+        match self.never {}
+    }
+
+    pub fn tree_builder(&mut self) -> Option<&mut TreeBuilder<U8>> {
+        match self.never {}
+    }
+
+    pub fn kernel_stats(&self) -> NSEResult<Vec<KernelStats>> {
+        match self.never {}
+    }
+
+    pub fn measure_bandwidth(&mut self) -> NSEResult<Bandwidth> {
+        match self.never {}
+    }
+
+    pub fn bench_k_sweep(&self, _ks: &[u32], _samples: usize) -> NSEResult<Vec<KThroughput>> {
+        match self.never {}
+    }
+
+    pub fn batch_key_generator(
+        &mut self,
+        _windows: &[(ReplicaId, WindowIndex)],
+    ) -> NSEResult<BatchKeyGenerator> {
+        match self.never {}
+    }
+
+    pub fn label_small_windows(
+        &mut self,
+        _windows: &[(ReplicaId, WindowIndex)],
+    ) -> NSEResult<Vec<Vec<Layer>>> {
+        match self.never {}
+    }
+
+    pub fn extract_nodes(&mut self, _node_indices: &[usize]) -> NSEResult<Vec<Node>> {
+        match self.never {}
+    }
+
+    pub fn extract_parents(
+        &mut self,
+        _layer_index: usize,
+        _node_indices: &[usize],
+    ) -> NSEResult<Vec<Node>> {
+        match self.never {}
+    }
+
+    pub fn push_layer(&mut self, _layer: &Layer) -> NSEResult<()> {
+        match self.never {}
+    }
+
+    pub fn generate_random_layer(&mut self, _seed: u64) -> NSEResult<()> {
+        match self.never {}
+    }
+
+    pub fn combine_segment_with_commitment(
+        &mut self,
+        _offset: usize,
+        _segment: &[Node],
+        _commitment: &mut DataCommitment,
+    ) -> NSEResult<Vec<Node>> {
+        match self.never {}
+    }
+
+    pub fn label_mask_layer(
+        &mut self,
+        _replica_id: ReplicaId,
+        _window_index: WindowIndex,
+    ) -> NSEResult<()> {
+        match self.never {}
+    }
+
+    pub fn label_expander_layer(
+        &mut self,
+        _replica_id: ReplicaId,
+        _window_index: WindowIndex,
+        _layer_index: usize,
+    ) -> NSEResult<()> {
+        match self.never {}
+    }
+
+    pub fn label_butterfly_layer(
+        &mut self,
+        _replica_id: ReplicaId,
+        _window_index: WindowIndex,
+        _layer_index: usize,
+    ) -> NSEResult<()> {
+        match self.never {}
+    }
+
+    pub fn expand_seed(
+        &mut self,
+        _replica_id: ReplicaId,
+        _window_index: WindowIndex,
+    ) -> NSEResult<Vec<Sha256Domain>> {
+        match self.never {}
+    }
+
+    pub fn generate_mask_layer_into(
+        &mut self,
+        _replica_id: ReplicaId,
+        _window_index: WindowIndex,
+        _out: &mut [Node],
+    ) -> NSEResult<()> {
+        match self.never {}
+    }
+
+    pub fn generate_expander_layer_into(
+        &mut self,
+        _replica_id: ReplicaId,
+        _window_index: WindowIndex,
+        _layer_index: usize,
+        _out: &mut [Node],
+    ) -> NSEResult<()> {
+        match self.never {}
+    }
+
+    pub fn generate_butterfly_layer_into(
+        &mut self,
+        _replica_id: ReplicaId,
+        _window_index: WindowIndex,
+        _layer_index: usize,
+        _out: &mut [Node],
+    ) -> NSEResult<()> {
+        match self.never {}
+    }
+
+    pub fn label_butterfly_and_combine(
+        &mut self,
+        _replica_id: ReplicaId,
+        _window_index: WindowIndex,
+        _layer_index: usize,
+        _data: &[Node],
+        _mode: impl Into<CombineMode>,
+    ) -> NSEResult<Vec<Node>> {
+        match self.never {}
+    }
+
+    pub fn label_butterfly_and_combine_staged(
+        &mut self,
+        _replica_id: ReplicaId,
+        _window_index: WindowIndex,
+        _layer_index: usize,
+        _mode: impl Into<CombineMode>,
+    ) -> NSEResult<Vec<Node>> {
+        match self.never {}
+    }
+
+    pub fn encode_pairs(&mut self, _pairs: &[(Layer, Layer)]) -> NSEResult<Vec<Layer>> {
+        match self.never {}
+    }
+
+    pub fn stage_data(&mut self, _data: Layer) -> NSEResult<()> {
+        match self.never {}
+    }
+
+    pub fn combine_staged(&mut self, _mode: impl Into<CombineMode>) -> NSEResult<Layer> {
+        match self.never {}
+    }
+
+    pub fn discard_staged_data(&mut self) -> NSEResult<()> {
+        match self.never {}
+    }
+
+    pub fn suspend(&mut self) -> NSEResult<SuspendedWindow> {
+        match self.never {}
+    }
+
+    pub fn resume(&mut self, suspended: SuspendedWindow) -> NSEResult<()> {
+        match suspended.never {}
+    }
+
+    pub fn layer_digest(&mut self) -> NSEResult<Sha256Domain> {
+        match self.never {}
+    }
+
+    pub fn layer_stats(&mut self, _layer: &Layer) -> NSEResult<LayerContentStats> {
+        match self.never {}
+    }
+
+    pub fn current_layer_stats(&mut self) -> NSEResult<LayerContentStats> {
+        match self.never {}
+    }
+
+    pub fn hash_columns(
+        &mut self,
+        _constants: &PoseidonConstants,
+        _layers: &[Layer],
+    ) -> NSEResult<Vec<Node>> {
+        match self.never {}
+    }
+}
+
+impl NarrowStackedExpander for GPU {
+    fn generate_mask_layer(
+        &mut self,
+        _replica_id: ReplicaId,
+        _window_index: WindowIndex,
+    ) -> NSEResult<Layer> {
+        match self.never {}
+    }
+
+    fn generate_expander_layer(
+        &mut self,
+        _replica_id: ReplicaId,
+        _window_index: WindowIndex,
+        _layer_index: usize,
+    ) -> NSEResult<Layer> {
+        match self.never {}
+    }
+
+    fn generate_butterfly_layer(
+        &mut self,
+        _replica_id: ReplicaId,
+        _window_index: WindowIndex,
+        _layer_index: usize,
+    ) -> NSEResult<Layer> {
+        match self.never {}
+    }
+
+    fn finalize(&mut self) -> NSEResult<()> {
+        match self.never {}
+    }
+
+    fn combine_segment(
+        &mut self,
+        _offset: usize,
+        _segment: &[Node],
+        _mode: CombineMode,
+    ) -> NSEResult<Vec<Node>> {
+        match self.never {}
+    }
+
+    fn combine_segment_into(
+        &mut self,
+        _offset: usize,
+        _segment: &[Node],
+        _mode: CombineMode,
+        _out: &mut [Node],
+    ) -> NSEResult<()> {
+        match self.never {}
+    }
+
+    fn combine_batch_size(&self) -> usize {
+        match self.never {}
+    }
+
+    fn leaf_count(&self) -> usize {
+        match self.never {}
+    }
+}
+
+pub struct BatchKeyGenerator<'a> {
+    gpu: &'a mut GPU,
+}
+
+impl<'a> BatchKeyGenerator<'a> {
+    pub fn new(gpu: &'a mut GPU, _windows: &[(ReplicaId, WindowIndex)]) -> NSEResult<Self> {
+        match gpu.never {}
+    }
+
+    pub fn batch_size(&self) -> usize {
+        match self.gpu.never {}
+    }
+
+    pub fn combine_layers(
+        &mut self,
+        _layers: &[Layer],
+        _mode: impl Into<CombineMode>,
+    ) -> NSEResult<Vec<Layer>> {
+        match self.gpu.never {}
+    }
+}
+
+impl<'a> Iterator for BatchKeyGenerator<'a> {
+    type Item = NSEResult<Vec<Layer>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.gpu.never {}
+    }
+}
+
+impl<'a> ExactSizeIterator for BatchKeyGenerator<'a> {
+    fn len(&self) -> usize {
+        match self.gpu.never {}
+    }
+}
+
+pub fn clear_program_cache() {}
+
+pub fn program_cache_len() -> usize {
+    0
+}
@@ -0,0 +1,153 @@
+use crate::{
+    check_combine_output, combine_nodes, segment_range, CombineMode, EncodingMode, Layer,
+    NSEResult, Node,
+};
+use rayon::prelude::*;
+
+/// Number of nodes each rayon task combines at once.
+const HOST_COMBINE_CHUNK_SIZE: usize = 4096;
+
+/// Combines a data segment with a key layer on the host, using all available CPU cores.
+/// This is the CPU counterpart of `NarrowStackedExpander::combine_segment`, meant to be used
+/// while the GPU is busy generating the labels of another window.
+pub struct HostCombiner {
+    key_layer: Layer, // Montgomery form, as returned by the key generator
+    encoding_mode: EncodingMode,
+}
+
+impl HostCombiner {
+    pub fn new(key_layer: Layer) -> Self {
+        Self::with_encoding_mode(key_layer, EncodingMode::default())
+    }
+
+    /// `encoding_mode` must match the one of the `Config` the key layer was generated with.
+    pub fn with_encoding_mode(key_layer: Layer, encoding_mode: EncodingMode) -> Self {
+        Self {
+            key_layer,
+            encoding_mode,
+        }
+    }
+
+    pub fn key_layer(&self) -> &Layer {
+        &self.key_layer
+    }
+
+    pub fn leaf_count(&self) -> usize {
+        self.key_layer.0.len()
+    }
+
+    pub fn combine_layer(&self, layer: &Layer, mode: impl Into<CombineMode>) -> NSEResult<Layer> {
+        let mut output = Layer(vec![Node::default(); layer.0.len()]);
+        self.combine_segment_into(0, &layer.0, mode, &mut output.0)?;
+        Ok(output)
+    }
+
+    pub fn combine_segment(
+        &self,
+        offset: usize,
+        segment: &[Node],
+        mode: impl Into<CombineMode>,
+    ) -> NSEResult<Vec<Node>> {
+        let mut output = vec![Node::default(); segment.len()];
+        self.combine_segment_into(offset, segment, mode, &mut output)?;
+        Ok(output)
+    }
+
+    /// Same as `combine_segment`, writing the result into `out`, which must have as many nodes
+    /// as `segment`.
+    pub fn combine_segment_into(
+        &self,
+        offset: usize,
+        segment: &[Node],
+        mode: impl Into<CombineMode>,
+        out: &mut [Node],
+    ) -> NSEResult<()> {
+        let range = segment_range(offset, segment.len(), self.leaf_count())?;
+        check_combine_output(segment, out)?;
+        let key = &self.key_layer.0[range];
+        let encoding_mode = self.encoding_mode;
+        let mode = mode.into();
+        out.copy_from_slice(segment);
+        out.par_chunks_mut(HOST_COMBINE_CHUNK_SIZE)
+            .zip(key.par_chunks(HOST_COMBINE_CHUNK_SIZE))
+            .map(|(data, key)| combine_nodes(data, key, mode, encoding_mode))
+            .collect::<NSEResult<()>>()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::incrementing_layer;
+    use ff::{Field, PrimeField};
+    use paired::bls12_381::Fr;
+
+    const TEST_LEAF_COUNT: usize = 1024;
+
+    fn accumulate(l: &[Node]) -> Node {
+        let mut acc = Fr::zero();
+        for n in l.iter() {
+            acc.add_assign(&n.0);
+        }
+        Node(acc)
+    }
+
+    #[test]
+    fn test_host_combine_layer() {
+        // Same inputs as `gpu::tests::test_combine_layer`, so both paths must agree.
+        let data = incrementing_layer(567, TEST_LEAF_COUNT);
+        let mask = incrementing_layer(234, TEST_LEAF_COUNT);
+        let combiner = HostCombiner::new(mask);
+        let encode = combiner.combine_layer(&data, CombineMode::Encode).unwrap();
+        let decode = combiner.combine_layer(&data, CombineMode::Decode).unwrap();
+        assert_eq!(Fr::from_str("1867776").unwrap(), accumulate(&encode.0).0);
+        assert_eq!(Fr::from_str("340992").unwrap(), accumulate(&decode.0).0);
+        assert_eq!(
+            data,
+            combiner
+                .combine_layer(&encode, CombineMode::Decode)
+                .unwrap()
+        );
+        // The former `is_decode` flags still work.
+        assert_eq!(decode, combiner.combine_layer(&data, true).unwrap());
+        assert_eq!(encode, combiner.combine_layer(&data, false).unwrap());
+    }
+
+    #[test]
+    fn test_host_combine_layer_xor() {
+        // Same inputs as `gpu::tests::test_combine_layer_xor`.
+        let data = incrementing_layer(567, TEST_LEAF_COUNT);
+        let mask = incrementing_layer(234, TEST_LEAF_COUNT);
+        let combiner = HostCombiner::with_encoding_mode(mask, EncodingMode::Xor);
+        let encode = combiner.combine_layer(&data, CombineMode::Encode).unwrap();
+        assert_eq!(Fr::from_str("1031168").unwrap(), accumulate(&encode.0).0);
+        assert_eq!(
+            data,
+            combiner
+                .combine_layer(&encode, CombineMode::Decode)
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn test_host_combine_segment_range() {
+        let data = incrementing_layer(567, TEST_LEAF_COUNT);
+        let combiner = HostCombiner::new(incrementing_layer(234, TEST_LEAF_COUNT));
+        let full = combiner.combine_layer(&data, CombineMode::Encode).unwrap();
+        let segment = combiner
+            .combine_segment(100, &data.0[100..300], CombineMode::Encode)
+            .unwrap();
+        assert_eq!(&full.0[100..300], segment.as_slice());
+        let mut out = vec![Node::default(); 200];
+        combiner
+            .combine_segment_into(100, &data.0[100..300], CombineMode::Encode, &mut out)
+            .unwrap();
+        assert_eq!(segment, out);
+        assert!(combiner
+            .combine_segment_into(100, &data.0[100..300], CombineMode::Encode, &mut out[1..])
+            .is_err());
+        assert!(combiner
+            .combine_segment(TEST_LEAF_COUNT - 10, &data.0[..20], CombineMode::Encode)
+            .is_err());
+    }
+}
@@ -0,0 +1,102 @@
+use crate::Node;
+use log::warn;
+use std::fmt;
+
+/// Allocates the host vectors nodes are read back into from the device (layers, segments,
+/// parents, ...), see `GPU::set_host_allocator`. The vectors are freed by the global allocator,
+/// so implementations allocate them on the heap and only tune the memory backing them.
+pub trait HostAllocator: Send + Sync + fmt::Debug {
+    /// Returns `len` zeroed nodes.
+    fn alloc_nodes(&self, len: usize) -> Vec<Node>;
+}
+
+/// Plain heap vectors, the default.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct HeapAllocator;
+
+impl HostAllocator for HeapAllocator {
+    fn alloc_nodes(&self, len: usize) -> Vec<Node> {
+        vec![Node::default(); len]
+    }
+}
+
+/// Size of the transparent huge pages of x86_64 and aarch64.
+const HUGE_PAGE_SIZE: usize = 2 << 20;
+
+/// Heap vectors backed by transparent huge pages where they span whole huge pages, which cuts
+/// the TLB misses of hashing and copying layers of large windows. Best-effort: if the kernel
+/// refuses (THP disabled), the vectors are backed by normal pages.
+#[cfg(target_os = "linux")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct HugePageAllocator;
+
+#[cfg(target_os = "linux")]
+impl HostAllocator for HugePageAllocator {
+    fn alloc_nodes(&self, len: usize) -> Vec<Node> {
+        let mut nodes = Vec::with_capacity(len);
+        // Advise before the pages are touched, so that they are faulted in as huge pages.
+        if let Some((ptr, len)) = aligned_range(nodes.as_mut_ptr() as usize, byte_len(len)) {
+            // Safe: the range lies within the allocation of `nodes`.
+            if unsafe { libc::madvise(ptr as *mut libc::c_void, len, libc::MADV_HUGEPAGE) } != 0 {
+                warn!(
+                    "Cannot back host vectors with huge pages: {}",
+                    std::io::Error::last_os_error()
+                );
+            }
+        }
+        nodes.resize(len, Node::default());
+        nodes
+    }
+}
+
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn byte_len(len: usize) -> usize {
+    len * std::mem::size_of::<Node>()
+}
+
+// Largest range of whole huge pages within `len` bytes at `start`, `None` if there are none.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn aligned_range(start: usize, len: usize) -> Option<(usize, usize)> {
+    let first = (start + HUGE_PAGE_SIZE - 1) / HUGE_PAGE_SIZE * HUGE_PAGE_SIZE;
+    let end = (start + len) / HUGE_PAGE_SIZE * HUGE_PAGE_SIZE;
+    if end > first {
+        Some((first, end - first))
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(allocator: &dyn HostAllocator) {
+        for &len in &[
+            0,
+            1,
+            1000,
+            3 * HUGE_PAGE_SIZE / std::mem::size_of::<Node>() + 5,
+        ] {
+            let nodes = allocator.alloc_nodes(len);
+            assert_eq!(len, nodes.len());
+            assert!(nodes.iter().all(|n| *n == Node::default()));
+        }
+    }
+
+    #[test]
+    fn test_host_allocators() {
+        check(&HeapAllocator);
+        #[cfg(target_os = "linux")]
+        check(&HugePageAllocator);
+
+        assert_eq!(None, aligned_range(1, HUGE_PAGE_SIZE));
+        assert_eq!(
+            Some((HUGE_PAGE_SIZE, HUGE_PAGE_SIZE)),
+            aligned_range(1, 3 * HUGE_PAGE_SIZE - 2)
+        );
+        assert_eq!(
+            Some((0, 2 * HUGE_PAGE_SIZE)),
+            aligned_range(0, 2 * HUGE_PAGE_SIZE + 7)
+        );
+    }
+}
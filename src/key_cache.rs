@@ -0,0 +1,209 @@
+use crate::{
+    read_layer_file, write_layer_file, Config, Layer, NSEResult, ReplicaId, WindowIndex,
+    LAYER_FILE_EXTENSION,
+};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// An on-disk cache of key layers, keyed by replica id and window index.
+///
+/// Unsealing the same window repeatedly would otherwise regenerate all key layers every time.
+/// A `Sealer` configured with a cache persists the final key layer (or all key layers, see
+/// `KeyCache::with_all_layers`), and `Unsealer::with_key_cache` skips key generation entirely
+/// when the final key layer of the window is found.
+#[derive(Debug, Clone)]
+pub struct KeyCache {
+    dir: PathBuf,
+    all_layers: bool,
+}
+
+impl KeyCache {
+    /// Opens (creating if needed) a cache persisting final key layers only.
+    pub fn new<P: AsRef<Path>>(dir: P) -> NSEResult<Self> {
+        fs::create_dir_all(dir.as_ref())?;
+        Ok(KeyCache {
+            dir: dir.as_ref().to_path_buf(),
+            all_layers: false,
+        })
+    }
+
+    /// Persist every key layer, not only the final one.
+    pub fn with_all_layers(mut self, all_layers: bool) -> Self {
+        self.all_layers = all_layers;
+        self
+    }
+
+    pub fn all_layers(&self) -> bool {
+        self.all_layers
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    fn path(
+        &self,
+        replica_id: ReplicaId,
+        window_index: WindowIndex,
+        layer_index: usize,
+    ) -> PathBuf {
+        let replica_id = replica_id
+            .0
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect::<String>();
+        self.dir.join(format!(
+            "{}-{}-{}.{}",
+            replica_id, window_index, layer_index, LAYER_FILE_EXTENSION
+        ))
+    }
+
+    /// Stores the key layer with (1-based) index `layer_index` of the given window, generated
+    /// with `config`.
+    pub fn store(
+        &self,
+        config: &Config,
+        replica_id: ReplicaId,
+        window_index: WindowIndex,
+        layer_index: usize,
+        layer: &Layer,
+    ) -> NSEResult<()> {
+        write_layer_file(
+            &self.path(replica_id, window_index, layer_index),
+            config,
+            layer,
+        )
+    }
+
+    /// Loads the key layer with (1-based) index `layer_index` of the given window, if cached.
+    /// Fails with `NSEError::ConfigMismatch` if it was generated with another config.
+    pub fn load(
+        &self,
+        config: &Config,
+        replica_id: ReplicaId,
+        window_index: WindowIndex,
+        layer_index: usize,
+    ) -> NSEResult<Option<Layer>> {
+        let path = self.path(replica_id, window_index, layer_index);
+        if path.exists() {
+            Ok(Some(read_layer_file(&path, config)?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    pub fn contains(
+        &self,
+        replica_id: ReplicaId,
+        window_index: WindowIndex,
+        layer_index: usize,
+    ) -> bool {
+        self.path(replica_id, window_index, layer_index).exists()
+    }
+
+    /// Removes the cached key layers of the given window, out of `num_layers` layers.
+    pub fn remove(
+        &self,
+        replica_id: ReplicaId,
+        window_index: WindowIndex,
+        num_layers: usize,
+    ) -> NSEResult<()> {
+        for layer_index in 1..=num_layers {
+            let path = self.path(replica_id, window_index, layer_index);
+            if path.exists() {
+                fs::remove_file(path)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::*;
+    use rand::thread_rng;
+
+    const TEST_NUM_LAYERS: usize = 7;
+
+    #[test]
+    fn test_key_cache_store_load() {
+        let mut rng = thread_rng();
+        let dir = tempfile::tempdir().unwrap();
+        let cache = KeyCache::new(dir.path()).unwrap();
+        let replica_id = ReplicaId::random(&mut rng);
+        let layer = Layer::random(&mut rng, TEST_CONFIG.num_nodes_window);
+
+        assert_eq!(
+            None,
+            cache
+                .load(&TEST_CONFIG, replica_id, WindowIndex(1), TEST_NUM_LAYERS)
+                .unwrap()
+        );
+        cache
+            .store(
+                &TEST_CONFIG,
+                replica_id,
+                WindowIndex(1),
+                TEST_NUM_LAYERS,
+                &layer,
+            )
+            .unwrap();
+        assert!(cache.contains(replica_id, WindowIndex(1), TEST_NUM_LAYERS));
+        assert!(!cache.contains(replica_id, WindowIndex(2), TEST_NUM_LAYERS));
+        assert_eq!(
+            Some(layer),
+            cache
+                .load(&TEST_CONFIG, replica_id, WindowIndex(1), TEST_NUM_LAYERS)
+                .unwrap()
+        );
+        // Layers of other configs are refused.
+        let other_config = TEST_CONFIG.with_domain_tags(DomainTags {
+            mask: 1,
+            expander: 2,
+            butterfly: 3,
+        });
+        match cache.load(&other_config, replica_id, WindowIndex(1), TEST_NUM_LAYERS) {
+            Err(NSEError::ConfigMismatch(_)) => {}
+            _ => panic!("Config mismatch should be detected!"),
+        }
+        cache
+            .remove(replica_id, WindowIndex(1), TEST_NUM_LAYERS)
+            .unwrap();
+        assert!(!cache.contains(replica_id, WindowIndex(1), TEST_NUM_LAYERS));
+    }
+
+    #[test]
+    fn test_unsealer_key_cache() {
+        let mut rng = thread_rng();
+        let dir = tempfile::tempdir().unwrap();
+        let cache = KeyCache::new(dir.path()).unwrap();
+        let original_data = Layer::random(&mut rng, TEST_CONFIG.num_nodes_window);
+        let replica_id = ReplicaId::random(&mut rng);
+        let window_index = WindowIndex(7);
+
+        let ctx = GPUContext::default(TEST_CONFIG, TreeOptions::Disabled).unwrap();
+        let mut gpu = GPU::new(ctx, TEST_CONFIG).unwrap();
+        let sealed_data = Sealer::builder(
+            TEST_CONFIG,
+            SealerInput {
+                replica_id,
+                window_index,
+                original_data: original_data.clone(),
+            },
+        )
+        .key_cache(cache.clone())
+        .build(&mut gpu)
+        .unwrap()
+        .last()
+        .unwrap()
+        .unwrap()
+        .base;
+        assert!(cache.contains(replica_id, window_index, TEST_NUM_LAYERS));
+
+        let mut unsealer =
+            Unsealer::with_key_cache(TEST_CONFIG, replica_id, window_index, &mut gpu, &cache)
+                .unwrap();
+        assert_eq!(original_data, unsealer.unseal_layer(sealed_data).unwrap());
+    }
+}
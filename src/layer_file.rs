@@ -0,0 +1,190 @@
+use crate::{Config, Layer, NSEError, NSEResult, CONFIG_FINGERPRINT_LEN, NODE_SIZE};
+use std::convert::TryInto;
+use std::fs;
+use std::hash::Hasher;
+use std::path::Path;
+use twox_hash::XxHash64;
+
+/// Extension of the layer files written by `write_layer_file`.
+pub const LAYER_FILE_EXTENSION: &str = "nse-layer";
+
+/// Version of the layer file format, bumped on any change of its encoding.
+pub const LAYER_FILE_VERSION: u32 = 1;
+
+const LAYER_FILE_MAGIC: [u8; 8] = *b"NSELAYER";
+
+/// Size of the header of layer files, which is followed by the nodes.
+pub const LAYER_FILE_HEADER_LEN: usize = 8 + 4 + CONFIG_FINGERPRINT_LEN + 8 + 8;
+
+/// Header of a layer file. Layers persisted across runs (checkpoints, `KeyCache`, layers dumped
+/// by the bench) are written as, all integers being little-endian:
+///
+/// - the magic bytes `NSELAYER`,
+/// - the format version, a `u32`, see `LAYER_FILE_VERSION`,
+/// - the fingerprint of the config the layer was generated with, see `Config::fingerprint`,
+/// - the number of nodes, a `u64`,
+/// - the XXH64 checksum (seed 0) of the nodes, a `u64`,
+/// - the nodes, in their canonical encoding, see `Vec::<u8>::from(&Layer)`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LayerFileHeader {
+    pub version: u32,
+    pub fingerprint: [u8; CONFIG_FINGERPRINT_LEN],
+    pub node_count: u64,
+    pub checksum: u64,
+}
+
+fn checksum(data: &[u8]) -> u64 {
+    let mut hasher = XxHash64::with_seed(0);
+    hasher.write(data);
+    hasher.finish()
+}
+
+fn invalid(path: &Path, reason: &str) -> NSEError {
+    NSEError::InvalidLayerFile(path.to_path_buf(), reason.to_string())
+}
+
+impl LayerFileHeader {
+    pub fn to_bytes(&self) -> [u8; LAYER_FILE_HEADER_LEN] {
+        let mut bytes = [0u8; LAYER_FILE_HEADER_LEN];
+        bytes[0..8].copy_from_slice(&LAYER_FILE_MAGIC);
+        bytes[8..12].copy_from_slice(&self.version.to_le_bytes());
+        bytes[12..44].copy_from_slice(&self.fingerprint);
+        bytes[44..52].copy_from_slice(&self.node_count.to_le_bytes());
+        bytes[52..60].copy_from_slice(&self.checksum.to_le_bytes());
+        bytes
+    }
+
+    /// Parses the header at the start of `bytes`, the contents of the file at `path`. Fails
+    /// with `NSEError::InvalidLayerFile` if they are not a layer file of a supported version.
+    pub fn from_bytes(path: &Path, bytes: &[u8]) -> NSEResult<Self> {
+        if bytes.len() < LAYER_FILE_HEADER_LEN || bytes[0..8] != LAYER_FILE_MAGIC {
+            return Err(invalid(path, "not a layer file"));
+        }
+        let version = u32::from_le_bytes(bytes[8..12].try_into().unwrap());
+        if version != LAYER_FILE_VERSION {
+            return Err(invalid(path, &format!("unsupported version {}", version)));
+        }
+        let mut fingerprint = [0u8; CONFIG_FINGERPRINT_LEN];
+        fingerprint.copy_from_slice(&bytes[12..44]);
+        Ok(LayerFileHeader {
+            version,
+            fingerprint,
+            node_count: u64::from_le_bytes(bytes[44..52].try_into().unwrap()),
+            checksum: u64::from_le_bytes(bytes[52..60].try_into().unwrap()),
+        })
+    }
+}
+
+/// Atomically writes `layer`, generated with `config`, to `path` in the layer file format, see
+/// `LayerFileHeader`.
+pub fn write_layer_file(path: &Path, config: &Config, layer: &Layer) -> NSEResult<()> {
+    let data = Vec::<u8>::from(layer);
+    let header = LayerFileHeader {
+        version: LAYER_FILE_VERSION,
+        fingerprint: config.fingerprint(),
+        node_count: layer.0.len() as u64,
+        checksum: checksum(&data),
+    };
+    let mut bytes = Vec::with_capacity(LAYER_FILE_HEADER_LEN + data.len());
+    bytes.extend_from_slice(&header.to_bytes());
+    bytes.extend(data);
+    let tmp_path = path.with_extension("tmp");
+    fs::write(&tmp_path, bytes)?;
+    fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+/// Reads the layer file at `path`. Fails with `NSEError::ConfigMismatch` if the layer was
+/// generated with another config than `config`, and with `NSEError::InvalidLayerFile` if the
+/// file is truncated or corrupted, or with `NSEError::InvalidInput` if its header claims more
+/// nodes than can be addressed.
+pub fn read_layer_file(path: &Path, config: &Config) -> NSEResult<Layer> {
+    let bytes = fs::read(path)?;
+    let header = LayerFileHeader::from_bytes(path, &bytes)?;
+    if header.fingerprint != config.fingerprint() {
+        return Err(NSEError::ConfigMismatch(path.to_path_buf()));
+    }
+    let data = &bytes[LAYER_FILE_HEADER_LEN..];
+    let data_len = header
+        .node_count
+        .checked_mul(NODE_SIZE as u64)
+        .ok_or_else(|| {
+            NSEError::InvalidInput(format!(
+                "Layer file {} claims {} nodes, too many to address",
+                path.display(),
+                header.node_count
+            ))
+        })?;
+    if data.len() as u64 != data_len {
+        return Err(invalid(
+            path,
+            &format!(
+                "{} bytes of nodes, expected {} nodes",
+                data.len(),
+                header.node_count
+            ),
+        ));
+    }
+    if checksum(data) != header.checksum {
+        return Err(invalid(path, "checksum mismatch"));
+    }
+    Layer::try_from_bytes(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{EncodingMode, TEST_CONFIG};
+
+    #[test]
+    fn test_layer_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(format!("layer.{}", LAYER_FILE_EXTENSION));
+        let layer = Layer::random(&mut rand::thread_rng(), TEST_CONFIG.num_nodes_window);
+        write_layer_file(&path, &TEST_CONFIG, &layer).unwrap();
+        assert_eq!(layer, read_layer_file(&path, &TEST_CONFIG).unwrap());
+
+        let bytes = fs::read(&path).unwrap();
+        assert_eq!(
+            LAYER_FILE_HEADER_LEN + layer.0.len() * NODE_SIZE,
+            bytes.len()
+        );
+        let header = LayerFileHeader::from_bytes(&path, &bytes).unwrap();
+        assert_eq!(LAYER_FILE_VERSION, header.version);
+        assert_eq!(layer.0.len() as u64, header.node_count);
+
+        let other_config = Config {
+            encoding_mode: EncodingMode::Xor,
+            ..TEST_CONFIG
+        };
+        match read_layer_file(&path, &other_config) {
+            Err(NSEError::ConfigMismatch(_)) => {}
+            other => panic!("Unexpected result {:?}", other),
+        }
+
+        let is_invalid = |bytes: &[u8]| {
+            fs::write(&path, bytes).unwrap();
+            match read_layer_file(&path, &TEST_CONFIG) {
+                Err(NSEError::InvalidLayerFile(..)) => true,
+                _ => false,
+            }
+        };
+        // Flipped bit in the nodes.
+        let mut corrupted = bytes.clone();
+        corrupted[LAYER_FILE_HEADER_LEN + 100] ^= 1;
+        assert!(is_invalid(&corrupted));
+        assert!(is_invalid(&bytes[..bytes.len() - 1]));
+        assert!(is_invalid(&bytes[LAYER_FILE_HEADER_LEN..]));
+        let mut future = bytes.clone();
+        future[8..12].copy_from_slice(&(LAYER_FILE_VERSION + 1).to_le_bytes());
+        assert!(is_invalid(&future));
+
+        let mut overflowing = bytes.clone();
+        overflowing[44..52].copy_from_slice(&u64::max_value().to_le_bytes());
+        fs::write(&path, &overflowing).unwrap();
+        match read_layer_file(&path, &TEST_CONFIG) {
+            Err(NSEError::InvalidInput(_)) => {}
+            other => panic!("Unexpected result {:?}", other),
+        }
+    }
+}
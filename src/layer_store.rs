@@ -0,0 +1,174 @@
+use crate::{
+    write_raw_layer_file, Config, GpuConfig, Layer, LayerOutput, NSEError, NSEResult, Node,
+};
+use memmap::Mmap;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use tempfile::TempDir;
+
+/// Host storage of the layers produced while sealing a window.
+pub trait LayerStore: Send {
+    fn push(&mut self, layer: LayerOutput) -> NSEResult<()>;
+    /// Returns the `index`th pushed layer (0-based).
+    fn get(&self, index: usize) -> NSEResult<LayerOutput>;
+    fn len(&self) -> usize;
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Returns a store for the layers of a window: in memory if they fit in the host memory budget,
+/// on disk otherwise.
+pub fn layer_store_for(config: &Config, gpu_config: &GpuConfig) -> NSEResult<Box<dyn LayerStore>> {
+    let required = config.num_layers() * config.window_byte_len();
+    Ok(match gpu_config.host_memory_budget {
+        Some(budget) if required > budget => Box::new(DiskLayerStore::new()?),
+        _ => Box::new(MemoryLayerStore::new()),
+    })
+}
+
+fn out_of_range(index: usize, len: usize) -> NSEError {
+    NSEError::InvalidInput(format!(
+        "Layer {} is out of range, store has {} layers!",
+        index, len
+    ))
+}
+
+#[derive(Default)]
+pub struct MemoryLayerStore {
+    layers: Vec<LayerOutput>,
+}
+
+impl MemoryLayerStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn into_layers(self) -> Vec<LayerOutput> {
+        self.layers
+    }
+}
+
+impl LayerStore for MemoryLayerStore {
+    fn push(&mut self, layer: LayerOutput) -> NSEResult<()> {
+        self.layers.push(layer);
+        Ok(())
+    }
+
+    fn get(&self, index: usize) -> NSEResult<LayerOutput> {
+        self.layers
+            .get(index)
+            .cloned()
+            .ok_or_else(|| out_of_range(index, self.layers.len()))
+    }
+
+    fn len(&self) -> usize {
+        self.layers.len()
+    }
+}
+
+/// Keeps the bases of layers in files of a temporary directory, which is removed when the store
+/// is dropped. Trees, much smaller, are kept in memory.
+pub struct DiskLayerStore {
+    dir: TempDir,
+    trees: Vec<Vec<Node>>,
+}
+
+impl DiskLayerStore {
+    /// Creates the store in the default temporary directory of the system.
+    pub fn new() -> NSEResult<Self> {
+        Self::new_in(std::env::temp_dir())
+    }
+
+    pub fn new_in<P: AsRef<Path>>(parent: P) -> NSEResult<Self> {
+        Ok(DiskLayerStore {
+            dir: TempDir::new_in(parent)?,
+            trees: Vec::new(),
+        })
+    }
+
+    pub fn dir(&self) -> &Path {
+        self.dir.path()
+    }
+
+    fn layer_path(&self, index: usize) -> PathBuf {
+        self.dir.path().join(format!("layer-{}.dat", index))
+    }
+}
+
+impl LayerStore for DiskLayerStore {
+    fn push(&mut self, layer: LayerOutput) -> NSEResult<()> {
+        write_raw_layer_file(&self.layer_path(self.trees.len()), &layer.base)?;
+        self.trees.push(layer.tree);
+        Ok(())
+    }
+
+    fn get(&self, index: usize) -> NSEResult<LayerOutput> {
+        let tree = self
+            .trees
+            .get(index)
+            .ok_or_else(|| out_of_range(index, self.trees.len()))?;
+        let file = File::open(self.layer_path(index))?;
+        // Safe as long as nobody else modifies the files of our temporary directory.
+        let mmap = unsafe { Mmap::map(&file)? };
+        Ok(LayerOutput {
+            base: Layer::try_from_bytes(&mmap[..])?,
+            tree: tree.clone(),
+        })
+    }
+
+    fn len(&self) -> usize {
+        self.trees.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TEST_CONFIG;
+    use rand::thread_rng;
+
+    fn check_store(store: &mut dyn LayerStore) {
+        let mut rng = thread_rng();
+        let layers = (0..3)
+            .map(|_| LayerOutput {
+                base: Layer::random(&mut rng, TEST_CONFIG.num_nodes_window),
+                tree: Layer::random(&mut rng, 10).0,
+            })
+            .collect::<Vec<_>>();
+        assert!(store.is_empty());
+        for l in layers.iter() {
+            store.push(l.clone()).unwrap();
+        }
+        assert_eq!(layers.len(), store.len());
+        for (i, l) in layers.iter().enumerate() {
+            assert_eq!(*l, store.get(i).unwrap());
+        }
+        assert!(store.get(layers.len()).is_err());
+    }
+
+    #[test]
+    fn test_layer_stores() {
+        check_store(&mut MemoryLayerStore::new());
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = DiskLayerStore::new_in(dir.path()).unwrap();
+        check_store(&mut store);
+        let store_dir = store.dir().to_path_buf();
+        assert!(store_dir.exists());
+        drop(store);
+        assert!(!store_dir.exists());
+    }
+
+    #[test]
+    fn test_layer_store_selection() {
+        let layer_size = TEST_CONFIG.window_byte_len();
+        let mut store = layer_store_for(&TEST_CONFIG, &GpuConfig::default()).unwrap();
+        check_store(store.as_mut());
+        let small = GpuConfig {
+            host_memory_budget: Some(layer_size),
+            ..GpuConfig::default()
+        };
+        let mut store = layer_store_for(&TEST_CONFIG, &small).unwrap();
+        check_store(store.as_mut());
+    }
+}
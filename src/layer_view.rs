@@ -0,0 +1,113 @@
+use crate::{segment_range, Layer, NSEResult, Node};
+use std::ops::{Deref, Range};
+use std::sync::Arc;
+
+/// A cheaply cloneable view over (a range of) the nodes of a layer, e.g. to hand the same
+/// layer to tree building, persistence and combine without copying it. Clones and slices
+/// share one allocation; `make_mut` copies the nodes only if they are shared.
+#[derive(Debug, Clone)]
+pub struct LayerView {
+    nodes: Arc<Vec<Node>>,
+    range: Range<usize>,
+}
+
+impl LayerView {
+    /// Returns a view of the nodes in `range`, relative to this view, without copying them.
+    pub fn slice(&self, range: Range<usize>) -> NSEResult<LayerView> {
+        let len = range.end.saturating_sub(range.start);
+        let range = segment_range(range.start, len, self.len())?;
+        Ok(LayerView {
+            nodes: Arc::clone(&self.nodes),
+            range: self.range.start + range.start..self.range.start + range.end,
+        })
+    }
+
+    /// Gives mutable access to the nodes of the view, copying them first if they are shared
+    /// with other views, or if the view covers part of the layer only.
+    pub fn make_mut(&mut self) -> &mut [Node] {
+        if self.range != (0..self.nodes.len()) {
+            self.nodes = Arc::new(self.nodes[self.range.clone()].to_vec());
+            self.range = 0..self.nodes.len();
+        }
+        Arc::make_mut(&mut self.nodes)
+    }
+
+    /// Whether other views share the nodes of this one.
+    pub fn is_shared(&self) -> bool {
+        Arc::strong_count(&self.nodes) > 1
+    }
+
+    /// Converts the view into an owned `Layer`, copying the nodes only if they are shared.
+    pub fn into_layer(mut self) -> Layer {
+        self.make_mut();
+        match Arc::try_unwrap(self.nodes) {
+            Ok(nodes) => Layer(nodes),
+            Err(nodes) => Layer(nodes.as_ref().clone()),
+        }
+    }
+}
+
+impl Deref for LayerView {
+    type Target = [Node];
+
+    fn deref(&self) -> &[Node] {
+        &self.nodes[self.range.clone()]
+    }
+}
+
+impl PartialEq for LayerView {
+    fn eq(&self, other: &LayerView) -> bool {
+        self[..] == other[..]
+    }
+}
+
+impl From<Layer> for LayerView {
+    fn from(layer: Layer) -> Self {
+        LayerView {
+            range: 0..layer.0.len(),
+            nodes: Arc::new(layer.0),
+        }
+    }
+}
+
+impl From<LayerView> for Layer {
+    fn from(view: LayerView) -> Self {
+        view.into_layer()
+    }
+}
+
+impl Layer {
+    /// Moves the nodes into a `LayerView`, to share them without copies.
+    pub fn into_view(self) -> LayerView {
+        LayerView::from(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::thread_rng;
+
+    #[test]
+    fn test_layer_view() {
+        let layer = Layer::random(&mut thread_rng(), 100);
+        let view = layer.clone().into_view();
+        assert_eq!(layer.0.as_slice(), &view[..]);
+        assert!(!view.is_shared());
+
+        let slice = view.slice(10..30).unwrap();
+        assert!(view.is_shared());
+        assert_eq!(&layer.0[10..30], &slice[..]);
+        assert_eq!(&layer.0[15..20], &slice.slice(5..10).unwrap()[..]);
+        assert!(slice.slice(10..21).is_err());
+
+        let mut copy = view.clone();
+        copy.make_mut()[0] = Node::default();
+        assert_eq!(layer.0[0], view[0]); // `view` is left untouched
+        assert_eq!(Node::default(), copy[0]);
+        assert!(!copy.is_shared());
+
+        drop(slice);
+        assert_eq!(layer, view.into_layer());
+    }
+}
@@ -0,0 +1,62 @@
+//! Debugging aid tracking the device buffers allocated by a `GPUContext`, to report the ones
+//! still alive when the GPU is dropped, along with the backtrace of their creation.
+use backtrace::Backtrace;
+use ocl::core::{self, MemInfo, MemInfoResult};
+use ocl::{Buffer, OclPrm};
+
+type MemCore = core::Mem;
+
+struct Allocation {
+    mem: MemCore, // Holds a reference, the buffer is alive elsewhere while the count exceeds 1
+    len: usize,
+    backtrace: Backtrace,
+}
+
+#[derive(Default)]
+pub(crate) struct AllocationTracker {
+    allocations: Vec<Allocation>,
+}
+
+fn reference_count(mem: &MemCore) -> u32 {
+    match core::get_mem_object_info(mem, MemInfo::ReferenceCount) {
+        Ok(MemInfoResult::ReferenceCount(count)) => count,
+        _ => 0,
+    }
+}
+
+impl AllocationTracker {
+    pub fn track<T: OclPrm>(&mut self, buffer: &Buffer<T>) {
+        self.prune();
+        self.allocations.push(Allocation {
+            mem: buffer.as_core().clone(),
+            len: buffer.len(),
+            backtrace: Backtrace::new(),
+        });
+    }
+
+    // Forget the buffers nobody else references anymore.
+    fn prune(&mut self) {
+        self.allocations.retain(|a| reference_count(&a.mem) > 1);
+    }
+
+    /// Number of tracked buffers still alive.
+    pub fn live(&mut self) -> usize {
+        self.prune();
+        self.allocations.len()
+    }
+
+    /// Describes the buffers still alive, except the `owned` ones.
+    pub fn leaks(&mut self, owned: &[&MemCore]) -> Vec<String> {
+        self.prune();
+        self.allocations
+            .iter()
+            .filter(|a| owned.iter().all(|o| o.as_ptr() != a.mem.as_ptr()))
+            .map(|a| {
+                format!(
+                    "Buffer of {} elements, created at:\n{:?}",
+                    a.len, a.backtrace
+                )
+            })
+            .collect()
+    }
+}
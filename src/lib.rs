@@ -1,20 +1,106 @@
+mod backend;
+mod build_info;
+mod cancellation;
+#[cfg(feature = "capi")]
+pub mod capi;
+#[cfg(feature = "cl_test")]
+mod cl_test;
+mod commitment;
+#[cfg(test)]
+mod conformance;
+mod domain;
 mod error;
+#[cfg(feature = "fault-injection")]
+mod fault_injection;
+#[cfg(feature = "gpu")]
+mod gpu;
+#[cfg(not(feature = "gpu"))]
+#[path = "gpu_stub.rs"]
 mod gpu;
+mod gpu_config;
+mod gpu_lock;
+#[cfg(feature = "host-combine")]
+mod host;
+mod host_alloc;
+mod key_cache;
+mod layer_file;
+mod layer_store;
+mod layer_view;
+#[cfg(feature = "leak-detection")]
+mod leak_detection;
+#[cfg(feature = "gpu")]
+mod mask_cache;
+mod paranoid;
+mod permutation;
 mod pool;
+mod poseidon;
+mod preemption;
+pub mod prelude;
+#[cfg(feature = "gpu")]
+mod program_cache;
+mod reader;
+mod receipt;
+mod roundtrip;
 mod sources;
+mod spot_check;
 pub mod utils;
 
+pub use backend::*;
+pub use build_info::*;
+pub use cancellation::*;
+#[cfg(feature = "cl_test")]
+pub use cl_test::*;
+pub use commitment::*;
+pub use domain::*;
 pub use error::*;
+#[cfg(feature = "fault-injection")]
+pub use fault_injection::*;
 use ff::{Field, PrimeField};
 pub use gpu::*;
+pub use gpu_config::*;
+pub use gpu_lock::*;
+#[cfg(feature = "host-combine")]
+pub use host::*;
+pub use host_alloc::*;
+pub use key_cache::*;
+pub use layer_file::*;
+pub use layer_store::*;
+pub use layer_view::*;
+use log::{info, warn};
+use memmap::Mmap;
 use neptune::tree_builder::TreeBuilderTrait;
 use paired::bls12_381::{Fr, FrRepr};
+pub use paranoid::*;
+pub use permutation::*;
 pub use pool::*;
+pub use poseidon::*;
+pub use preemption::*;
+#[cfg(feature = "gpu")]
+pub use program_cache::{clear_program_cache, program_cache_len};
 use rand::{Rng, RngCore};
+pub use reader::*;
+pub use receipt::*;
+pub use roundtrip::*;
+#[cfg(feature = "json")]
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+pub use sources::KernelVariant;
+pub use spot_check::*;
+use std::collections::{BTreeSet, HashMap};
+use std::convert::TryFrom;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
 
 // TODO: Move these constants into configuration of GPU, Sealer, KeyGenerator, etc.
 const COMBINE_BATCH_SIZE: usize = 500000;
 
+/// `KeyGenerator::label_subset` labels the nodes of a layer on the host while they are at most
+/// `1 / SUBSET_HOST_FRACTION` of the window, and whole layers on the device beyond.
+const SUBSET_HOST_FRACTION: usize = 16;
+
 #[derive(PartialEq, Debug, Clone, Copy)]
 #[repr(transparent)]
 /// Nodes are always assumed to be in Montgomery Form.
@@ -50,6 +136,45 @@ impl Node {
 
         unsafe { std::slice::from_raw_parts(frs.as_ptr() as *const () as *const Node, frs.len()) }
     }
+
+    /// Canonical encoding of the node, independent of the endianness of the host: the limbs of
+    /// its (non-Montgomery) representation, least significant first, each in little-endian.
+    pub fn to_le_bytes(&self) -> [u8; NODE_SIZE] {
+        let mut bytes = [0u8; NODE_SIZE];
+        for (chunk, limb) in bytes.chunks_mut(8).zip(self.0.into_repr().as_ref().iter()) {
+            chunk.copy_from_slice(&limb.to_le_bytes());
+        }
+        bytes
+    }
+
+    /// Inverse of `to_le_bytes`, `None` if the bytes don't encode a field element.
+    pub fn from_le_bytes(bytes: &[u8; NODE_SIZE]) -> Option<Node> {
+        let mut repr = FrRepr::default();
+        for (limb, chunk) in repr.as_mut().iter_mut().zip(bytes.chunks(8)) {
+            let mut limb_bytes = [0u8; 8];
+            limb_bytes.copy_from_slice(chunk);
+            *limb = u64::from_le_bytes(limb_bytes);
+        }
+        Fr::from_repr(repr).ok().map(Node)
+    }
+
+    /// Whether the node is a field element, i.e. its (Montgomery) representation is below the
+    /// modulus. Nodes built through the API of `Fr` always are, nodes transmuted from arbitrary
+    /// bytes may not be.
+    pub fn is_valid(&self) -> bool {
+        let repr = unsafe { std::mem::transmute::<Fr, FrRepr>(self.0) };
+        repr < Fr::char()
+    }
+
+    /// Whether the node is valid original data for `encoding_mode`: a field element, and for
+    /// `EncodingMode::Xor` below 2^253, see `XOR_MASK`.
+    pub fn is_valid_data(&self, encoding_mode: EncodingMode) -> bool {
+        self.is_valid()
+            && match encoding_mode {
+                EncodingMode::FieldAdd => true,
+                EncodingMode::Xor => self.0.into_repr().0[3] & !XOR_MASK == 0,
+            }
+    }
 }
 
 impl Default for Node {
@@ -73,46 +198,221 @@ impl ReplicaId {
     }
 }
 
+/// Index of a window within its sector. Labels hash the absolute index of each node,
+/// `window_index * num_nodes_window + node`, as a 64-bit integer (see `hash_prefix` in the
+/// kernels), so window indices are 64-bit whatever the width of `usize` on the host, and the
+/// absolute indices of the nodes of a window must fit in 64 bits, see `WindowIndex::validate`.
+#[derive(PartialEq, Eq, PartialOrd, Ord, Debug, Clone, Copy, Default, Hash)]
+#[cfg_attr(feature = "json", derive(Serialize, Deserialize), serde(transparent))]
+#[repr(transparent)]
+pub struct WindowIndex(pub u64);
+
+impl WindowIndex {
+    /// Absolute index of node `node` of the window, i.e. of the node of the sector. Fails if it
+    /// doesn't fit in 64 bits.
+    pub fn node_index(&self, config: &Config, node: usize) -> NSEResult<u64> {
+        self.0
+            .checked_mul(config.num_nodes_window as u64)
+            .and_then(|first| first.checked_add(node as u64))
+            .ok_or_else(|| {
+                NSEError::InvalidInput(format!(
+                    "Node {} of window {} is out of range, sectors have at most 2^64 nodes of \
+                     windows of {} nodes",
+                    node, self.0, config.num_nodes_window
+                ))
+            })
+    }
+
+    /// Checks the absolute indices of all nodes of the window fit in 64 bits.
+    pub fn validate(&self, config: &Config) -> NSEResult<()> {
+        self.node_index(config, config.num_nodes_window - 1)
+            .map(|_| ())
+    }
+}
+
+impl From<u32> for WindowIndex {
+    fn from(index: u32) -> Self {
+        WindowIndex(index.into())
+    }
+}
+
+impl fmt::Display for WindowIndex {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// Maps the replica id and window index of a window to the replica id its labels are seeded
+/// with. Production code always uses `default_labeling_seed`; tests substitute fixed seeds to
+/// replay published test vectors, see `SealerBuilder::seed_fn`.
+pub type LabelingSeedFn = fn(ReplicaId, WindowIndex) -> ReplicaId;
+
+/// Seeds the labels of a window with its replica id.
+pub fn default_labeling_seed(replica_id: ReplicaId, _window_index: WindowIndex) -> ReplicaId {
+    replica_id
+}
+
 #[derive(PartialEq, Debug, Clone, Default)]
 pub struct Layer(pub Vec<Node>);
 
-impl From<&Vec<u8>> for Layer {
-    fn from(data: &Vec<u8>) -> Self {
-        assert_eq!(std::mem::size_of::<FrRepr>(), NODE_SIZE);
-        let mut nodes = Vec::with_capacity(data.len() / NODE_SIZE);
-        let mut temp = [0u8; NODE_SIZE];
-        for slice in data.chunks_exact(NODE_SIZE) {
-            temp.copy_from_slice(&slice[..]);
-            nodes.push(Node(
-                Fr::from_repr(unsafe { std::mem::transmute::<[u8; NODE_SIZE], FrRepr>(temp) })
-                    .unwrap(),
-            ));
-        }
-        Layer(nodes)
+impl TryFrom<&Vec<u8>> for Layer {
+    type Error = NSEError;
+
+    fn try_from(data: &Vec<u8>) -> NSEResult<Self> {
+        Layer::try_from_bytes(data)
+    }
+}
+
+impl TryFrom<&[u8]> for Layer {
+    type Error = NSEError;
+
+    fn try_from(data: &[u8]) -> NSEResult<Self> {
+        Layer::try_from_bytes(data)
     }
 }
 
 impl From<&Layer> for Vec<u8> {
     fn from(layer: &Layer) -> Self {
-        assert_eq!(std::mem::size_of::<FrRepr>(), NODE_SIZE);
         let mut ret = Vec::with_capacity(layer.0.len() * NODE_SIZE);
         for n in layer.0.iter() {
-            ret.extend(unsafe {
-                std::slice::from_raw_parts(
-                    &n.0.into_repr() as *const FrRepr as *const u8,
-                    std::mem::size_of::<FrRepr>(),
-                )
-                .to_vec()
-            });
+            ret.extend_from_slice(&n.to_le_bytes());
         }
         ret
     }
 }
 
+impl Layer {
+    /// Inverse of `From<&Layer> for Vec<u8>`, failing if `bytes` don't encode a whole number of
+    /// field elements. Same as `Layer::try_from(bytes)`.
+    pub fn try_from_bytes(bytes: &[u8]) -> NSEResult<Layer> {
+        if bytes.len() % NODE_SIZE != 0 {
+            return Err(NSEError::InvalidInput(format!(
+                "Layer encoding of {} bytes is not a whole number of nodes!",
+                bytes.len()
+            )));
+        }
+        let mut temp = [0u8; NODE_SIZE];
+        let nodes = bytes
+            .chunks(NODE_SIZE)
+            .enumerate()
+            .map(|(i, slice)| {
+                temp.copy_from_slice(slice);
+                Node::from_le_bytes(&temp).ok_or_else(|| {
+                    NSEError::InvalidInput(format!("Node {} is not a field element!", i))
+                })
+            })
+            .collect::<NSEResult<Vec<_>>>()?;
+        Ok(Layer(nodes))
+    }
+
+    /// Root of the binary SHA-254 tree over the nodes, i.e. `comm_d` of the layer, whose node
+    /// count must be a power of two. Same as `GPU::layer_digest` for the current layer.
+    pub fn tree_digest(&self) -> NSEResult<Sha256Domain> {
+        comm_d(self)
+    }
+
+    /// Statistics of the nodes, for debugging. Same as `GPU::layer_stats`, which computes them
+    /// on the device. The node count must be a power of two, see `tree_digest`.
+    pub fn content_stats(&self) -> NSEResult<LayerContentStats> {
+        let digest = self.tree_digest()?;
+        let value = |node: &&Node| node.0.into_repr();
+        Ok(LayerContentStats {
+            zero_nodes: self.0.iter().filter(|n| **n == Node::default()).count() as u64,
+            repeated_nodes: self.0.windows(2).filter(|w| w[0] == w[1]).count() as u64,
+            min: *self
+                .0
+                .iter()
+                .min_by_key(value)
+                .expect("Layers are not empty"),
+            max: *self
+                .0
+                .iter()
+                .max_by_key(value)
+                .expect("Layers are not empty"),
+            digest,
+        })
+    }
+
+    /// Index of the first node that is not a field element, see `Node::is_valid`.
+    pub fn first_invalid_node(&self) -> Option<usize> {
+        self.0.iter().position(|node| !node.is_valid())
+    }
+
+    /// Index of the first node that is not valid original data, see `Node::is_valid_data`.
+    pub fn first_invalid_data_node(&self, encoding_mode: EncodingMode) -> Option<usize> {
+        self.0
+            .iter()
+            .position(|node| !node.is_valid_data(encoding_mode))
+    }
+
+    /// SHA-256 digest of the canonical encoding of the layer, i.e. of `Vec::<u8>::from(layer)`.
+    /// It only depends on the nodes, so layers computed on different machines or backends can
+    /// be compared through their digests.
+    pub fn digest(&self) -> Sha256Domain {
+        let mut hasher = Sha256::new();
+        for n in self.0.iter() {
+            hasher.input(&n.to_le_bytes());
+        }
+        let mut digest = [0u8; 32];
+        digest.copy_from_slice(hasher.result().as_slice());
+        Sha256Domain(digest)
+    }
+
+    /// The first `limit` nodes at which the layers differ, as `(index, node of self, node of
+    /// other)`, e.g. to locate kernel mismatches between backends. Only the nodes of the
+    /// shortest layer are compared.
+    pub fn diff(&self, other: &Layer, limit: usize) -> Vec<(usize, Node, Node)> {
+        self.0
+            .iter()
+            .zip(other.0.iter())
+            .enumerate()
+            .filter(|(_, (a, b))| a != b)
+            .take(limit)
+            .map(|(i, (a, b))| (i, *a, *b))
+            .collect()
+    }
+
+    /// Same as `==`, comparing all nodes instead of returning on the first difference, so that
+    /// the time taken doesn't reveal where layers differ. Only their lengths may leak.
+    pub fn eq_constant_time(&self, other: &Layer) -> bool {
+        if self.0.len() != other.0.len() {
+            return false;
+        }
+        let mut acc = 0u8;
+        for (a, b) in self.0.iter().zip(other.0.iter()) {
+            for (x, y) in a.to_le_bytes().iter().zip(b.to_le_bytes().iter()) {
+                acc |= x ^ y;
+            }
+        }
+        acc == 0
+    }
+}
+
+// Index of the first node of `bytes`, in the byte representation of nodes, that does not
+// encode a field element.
+fn first_invalid_encoded_node(bytes: &[u8], encoding_mode: EncodingMode) -> Option<usize> {
+    let mut temp = [0u8; NODE_SIZE];
+    bytes.chunks_exact(NODE_SIZE).position(|chunk| {
+        temp.copy_from_slice(chunk);
+        match Node::from_le_bytes(&temp) {
+            Some(node) => !node.is_valid_data(encoding_mode),
+            None => true,
+        }
+    })
+}
+
+/// Atomically writes `layer` to `path`, in its byte representation, e.g. to be memory-mapped.
+pub(crate) fn write_raw_layer_file(path: &Path, layer: &Layer) -> NSEResult<()> {
+    let tmp_path = path.with_extension("tmp");
+    fs::write(&tmp_path, Vec::<u8>::from(layer))?;
+    fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
 #[derive(PartialEq, Debug, Clone)]
 pub struct SealerInput {
     pub replica_id: ReplicaId,
-    pub window_index: usize,
+    pub window_index: WindowIndex,
     pub original_data: Layer,
 }
 
@@ -122,42 +422,297 @@ pub struct LayerOutput {
     pub tree: Vec<Node>,
 }
 
+/// The kind of a layer produced while sealing.
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Hash)]
+#[cfg_attr(feature = "json", derive(Serialize, Deserialize))]
+pub enum LayerKind {
+    Mask,
+    Expander,
+    Butterfly,
+    /// The last key layer combined with the original data.
+    Replica,
+}
+
+/// Position of a `KeyGenerator` in the key layers of a window: the layer it generates next,
+/// or `Done` once all of them are generated. Expander and butterfly layers are numbered from 0
+/// within their kind, i.e. `Expander(0)` is layer 2, right after the mask layer, and
+/// `Butterfly(0)` is layer `num_expander_layers + 1`.
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Hash)]
+pub enum LayerState {
+    Mask,
+    Expander(usize),
+    Butterfly(usize),
+    Done,
+}
+
+impl LayerState {
+    /// State of a generator which has generated the first `completed` layers of a window.
+    pub fn after(config: &Config, completed: usize) -> NSEResult<Self> {
+        if completed > config.num_layers() {
+            return Err(NSEError::InvalidInput(format!(
+                "There are only {} layers, not {}!",
+                config.num_layers(),
+                completed
+            )));
+        }
+        Ok(if completed == 0 {
+            LayerState::Mask
+        } else if completed < config.num_expander_layers {
+            LayerState::Expander(completed - 1)
+        } else if completed < config.num_layers() {
+            LayerState::Butterfly(completed - config.num_expander_layers)
+        } else {
+            LayerState::Done
+        })
+    }
+
+    /// Number of layers generated before this state.
+    pub fn completed(&self, config: &Config) -> usize {
+        match *self {
+            LayerState::Mask => 0,
+            LayerState::Expander(i) => i + 1,
+            LayerState::Butterfly(i) => config.num_expander_layers + i,
+            LayerState::Done => config.num_layers(),
+        }
+    }
+
+    /// 1-based index of the layer generated next, `None` once done.
+    pub fn layer_index(&self, config: &Config) -> Option<usize> {
+        match self {
+            LayerState::Done => None,
+            _ => Some(self.completed(config) + 1),
+        }
+    }
+
+    /// Kind of the layer generated next, `None` once done.
+    pub fn kind(&self) -> Option<LayerKind> {
+        match self {
+            LayerState::Mask => Some(LayerKind::Mask),
+            LayerState::Expander(_) => Some(LayerKind::Expander),
+            LayerState::Butterfly(_) => Some(LayerKind::Butterfly),
+            LayerState::Done => None,
+        }
+    }
+
+    /// State once the next layer is generated.
+    pub fn next(self, config: &Config) -> Self {
+        // The mask layer counts as one of the `num_expander_layers`.
+        let expander_layers = config.num_expander_layers - 1;
+        match self {
+            LayerState::Mask if expander_layers > 0 => LayerState::Expander(0),
+            LayerState::Expander(i) if i + 1 < expander_layers => LayerState::Expander(i + 1),
+            LayerState::Mask | LayerState::Expander(_) => LayerState::Butterfly(0),
+            LayerState::Butterfly(i) if i + 1 < config.num_butterfly_layers => {
+                LayerState::Butterfly(i + 1)
+            }
+            LayerState::Butterfly(_) | LayerState::Done => LayerState::Done,
+        }
+    }
+
+    /// Whether the state exists in the layers of `config`.
+    pub fn is_valid(&self, config: &Config) -> bool {
+        match LayerState::after(config, self.completed(config)) {
+            Ok(state) => state == *self,
+            Err(_) => false,
+        }
+    }
+}
+
+/// Timing of the device work done to produce a layer.
+#[derive(PartialEq, Debug, Clone)]
+#[cfg_attr(feature = "json", derive(Serialize, Deserialize))]
+pub struct LayerStats {
+    /// 1-based index of the layer.
+    pub layer_index: usize,
+    pub kind: LayerKind,
+    pub kernel_ms: f64,
+    pub transfer_ms: f64,
+    /// Bytes transferred between host and device, both ways.
+    pub transfer_bytes: u64,
+    pub nodes_per_sec: f64,
+}
+
+impl LayerStats {
+    fn new(layer_index: usize, kind: LayerKind, timings: OpTimings, node_count: usize) -> Self {
+        let kernel_ms = timings.kernel.as_secs_f64() * 1000f64;
+        let transfer_ms = timings.transfer.as_secs_f64() * 1000f64;
+        let total_secs = (kernel_ms + transfer_ms) / 1000f64;
+        LayerStats {
+            layer_index,
+            kind,
+            kernel_ms,
+            transfer_ms,
+            transfer_bytes: timings.bytes_written + timings.bytes_read,
+            nodes_per_sec: if total_secs > 0f64 {
+                node_count as f64 / total_secs
+            } else {
+                0f64
+            },
+        }
+    }
+}
+
+/// What the nodes of a layer look like, see `GPU::layer_stats`, e.g. to spot kernels silently
+/// writing zeros or the same nodes over and over because of bad offsets.
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct LayerContentStats {
+    pub zero_nodes: u64,
+    /// Nodes equal to the node before them.
+    pub repeated_nodes: u64,
+    /// Smallest and largest nodes, by value.
+    pub min: Node,
+    pub max: Node,
+    /// See `Layer::tree_digest`.
+    pub digest: Sha256Domain,
+}
+
+/// A layer along with its position in the window, see `KeyGenerator::labeled` and
+/// `Sealer::labeled`. `L` is a `LayerOutput` for layers produced by a `Sealer`.
+#[derive(PartialEq, Debug, Clone)]
+pub struct LabeledLayer<L = Layer> {
+    /// 1-based index of the layer.
+    pub index: usize,
+    pub kind: LayerKind,
+    pub layer: L,
+}
+
+/// All layers produced by a `Sealer`, along with the per-layer timings.
+#[derive(Debug, Clone)]
+pub struct SealOutput {
+    pub layers: Vec<LayerOutput>,
+    /// See `SealerBuilder::comm_d`.
+    pub comm_d: Option<Sha256Domain>,
+    pub stats: Vec<LayerStats>,
+    pub receipt: SealReceipt,
+}
+
+/// Same as `SealOutput`, with layers kept in a `LayerStore`.
+pub struct StoredSealOutput {
+    pub layers: Box<dyn LayerStore>,
+    pub comm_d: Option<Sha256Domain>,
+    pub stats: Vec<LayerStats>,
+    pub receipt: SealReceipt,
+}
+
 impl Layer {
+    /// Uniformly random nodes drawn from `rng`, rejection-sampled so that they are all valid
+    /// field elements.
     pub fn random<R: RngCore>(rng: &mut R, node_count: usize) -> Self {
         Layer((0..node_count).map(|_| Node::random(rng)).collect())
     }
+
+    /// Pseudo-random nodes derived from `seed`, the same as `GPU::generate_random_layer`
+    /// generates on the device. Cheap but not uniform: nodes are below 2^253.
+    pub fn from_seed(seed: u64, node_count: usize) -> Self {
+        fn splitmix64(mut x: u64) -> u64 {
+            x = x.wrapping_add(0x9e37_79b9_7f4a_7c15);
+            x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+            x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+            x ^ (x >> 31)
+        }
+        Layer(
+            (0..node_count as u64)
+                .map(|i| {
+                    let mut limbs = [0u64; 4];
+                    for (j, limb) in limbs.iter_mut().enumerate() {
+                        *limb = splitmix64(seed.wrapping_add(i * 4 + j as u64));
+                    }
+                    limbs[3] &= 0x1fff_ffff_ffff_ffff;
+                    Node(Fr::from_repr(FrRepr(limbs)).unwrap())
+                })
+                .collect(),
+        )
+    }
+
+    /// Layer whose node `i` is `i`, test data that is easy to recognize when debugging.
+    pub fn sequential(node_count: usize) -> Self {
+        Layer(
+            (0..node_count as u64)
+                .map(|i| Node(Fr::from_repr(FrRepr::from(i)).unwrap()))
+                .collect(),
+        )
+    }
 }
 
-pub trait NarrowStackedExpander: Sized {
-    fn new(context: GPUContext, config: Config) -> NSEResult<Self>;
+/// Operations of an NSE implementation on the window it holds. The trait is object-safe, so
+/// that schedulers can hold `Box<dyn NarrowStackedExpander>` of different implementations;
+/// they are created by a `BackendFactory`.
+pub trait NarrowStackedExpander {
     fn generate_mask_layer(
         &mut self,
         replica_id: ReplicaId,
-        window_index: usize,
+        window_index: WindowIndex,
     ) -> NSEResult<Layer>;
     fn generate_expander_layer(
         &mut self,
         replica_id: ReplicaId,
-        window_index: usize,
+        window_index: WindowIndex,
         layer_index: usize,
     ) -> NSEResult<Layer>;
     fn generate_butterfly_layer(
         &mut self,
         replica_id: ReplicaId,
-        window_index: usize,
+        window_index: WindowIndex,
         layer_index: usize,
     ) -> NSEResult<Layer>;
     fn finalize(&mut self) -> NSEResult<()>;
     // Combine functions need to get `&mut self`, as they modify internal state of GPU buffers
-    fn combine_layer(&mut self, layer: &Layer, is_decode: bool) -> NSEResult<Layer> {
-        Ok(Layer(self.combine_segment(0, &layer.0, is_decode)?))
+    fn combine_layer(&mut self, layer: &Layer, mode: CombineMode) -> NSEResult<Layer> {
+        let mut output = Layer(vec![Node::default(); layer.0.len()]);
+        self.combine_segment_into(0, &layer.0, mode, &mut output.0)?;
+        Ok(output)
     }
     fn combine_segment(
         &mut self,
         offset: usize,
         segment: &[Node],
-        is_decode: bool,
-    ) -> NSEResult<Vec<Node>>;
+        mode: CombineMode,
+    ) -> NSEResult<Vec<Node>> {
+        let mut output = vec![Node::default(); segment.len()];
+        self.combine_segment_into(offset, segment, mode, &mut output)?;
+        Ok(output)
+    }
+    /// Same as `combine_segment`, writing the result into `out`, which must have as many nodes
+    /// as `segment`, instead of a new vector.
+    fn combine_segment_into(
+        &mut self,
+        offset: usize,
+        segment: &[Node],
+        mode: CombineMode,
+        out: &mut [Node],
+    ) -> NSEResult<()>;
+    /// Same as `combine_segment`, in batches of `combine_batch_size` nodes. `on_batch` is called
+    /// with the range (in the window) and the result of each batch, so that the results of the
+    /// other batches are kept when one fails, and only the failed ones need to be resubmitted.
+    fn combine_segment_batches(
+        &mut self,
+        offset: usize,
+        segment: &[Node],
+        mode: CombineMode,
+        on_batch: &mut dyn FnMut(Range<usize>, NSEResult<Vec<Node>>),
+    ) -> NSEResult<()> {
+        segment_range(offset, segment.len(), self.leaf_count())?;
+        let batch_size = self.combine_batch_size();
+        for (i, batch) in segment.chunks(batch_size).enumerate() {
+            let start = offset + i * batch_size;
+            on_batch(
+                start..start + batch.len(),
+                self.combine_segment(start, batch, mode),
+            );
+        }
+        Ok(())
+    }
+    /// Combines several segments, each one encoded or decoded as its mode says, e.g. to seal a
+    /// window while unsealing parts of it. Returns the combined segments in the same order.
+    fn combine_batches(
+        &mut self,
+        batches: Vec<(usize, &[Node], CombineMode)>,
+    ) -> NSEResult<Vec<Vec<Node>>> {
+        batches
+            .into_iter()
+            .map(|(offset, segment, mode)| self.combine_segment(offset, segment, mode))
+            .collect()
+    }
     fn combine_batch_size(&self) -> usize;
     fn leaf_count(&self) -> usize;
 }
@@ -166,11 +721,12 @@ pub trait NarrowStackedExpander: Sized {
 // layers are 1-indexed,
 
 /// The configuration parameters for NSE.
-#[derive(Debug, Clone, Copy)]
+#[derive(PartialEq, Eq, Hash, Debug, Clone, Copy)]
 pub struct Config {
-    /// Batch hashing factor.
+    /// Batch hashing factor: each expander parent is the sum of `k` consecutive nodes. A power
+    /// of two in `[MIN_K, MAX_K]`, smaller than `num_nodes_window`.
     pub k: u32,
-    /// Number of nodes per window
+    /// Number of nodes per window, a power of two of at most 2^32.
     pub num_nodes_window: usize,
     /// Degree of the expander graph.
     pub degree_expander: usize,
@@ -180,273 +736,2879 @@ pub struct Config {
     pub num_expander_layers: usize, // 8
     /// Number of butterfly layers.
     pub num_butterfly_layers: usize, // 7
+    /// How key layers are combined with data.
+    pub encoding_mode: EncodingMode,
+    /// Domain separation of the labeling hashes of each layer kind.
+    pub domain_tags: DomainTags,
+    /// Pseudo-random function generating the mask layer.
+    pub mask_prf: MaskPrf,
 }
 
-pub struct Sealer<'a> {
-    original_data: Layer,
-    key_generator: KeyGenerator<'a>,
-    build_trees: bool,
+/// Tags domain-separating the labeling hashes by layer kind. The tag of the kind of a layer is
+/// written in the hash prefix of its labels, as a big-endian `u32` following the node index.
+/// `UNTAGGED`, the default, follows the current NSE spec; other tags let the crate track
+/// changes of the spec without kernel edits.
+#[derive(PartialEq, Eq, Hash, Debug, Clone, Copy)]
+pub struct DomainTags {
+    pub mask: u32,
+    pub expander: u32,
+    pub butterfly: u32,
 }
 
-impl<'a> Sealer<'a> {
-    pub fn new(
-        config: Config,
-        input: SealerInput,
-        gpu: &'a mut GPU,
-        build_trees: bool,
-    ) -> NSEResult<Self> {
-        Ok(Self {
-            original_data: input.original_data,
-            key_generator: KeyGenerator::new(config, input.replica_id, input.window_index, gpu)?,
-            build_trees,
-        })
-    }
-
-    pub fn seek(&mut self, target_layer_index: usize, target_layer_data: &Layer) -> NSEResult<()> {
-        self.key_generator
-            .seek(target_layer_index, target_layer_data)
-    }
+impl DomainTags {
+    pub const UNTAGGED: DomainTags = DomainTags {
+        mask: 0,
+        expander: 0,
+        butterfly: 0,
+    };
+}
 
-    pub fn new_from_layer(
-        provided_layer_index: usize,
-        provided_layer: &Layer,
-        config: Config,
-        input: SealerInput,
-        gpu: &'a mut GPU,
-        build_trees: bool,
-    ) -> NSEResult<Self> {
-        let mut sealer = Self::new(config, input, gpu, build_trees)?;
-        sealer.seek(provided_layer_index, provided_layer)?;
-        Ok(sealer)
+impl Default for DomainTags {
+    fn default() -> Self {
+        DomainTags::UNTAGGED
     }
 }
 
-impl<'a> Iterator for Sealer<'a> {
-    type Item = NSEResult<LayerOutput>;
+/// The pseudo-random function the labels of the mask layer are computed with.
+#[derive(PartialEq, Eq, Hash, Debug, Clone, Copy)]
+pub enum MaskPrf {
+    /// SHA-256 of the hash prefix of each node, as specified by NSE.
+    Sha256 = 0,
+    /// AES-256 in counter mode keyed by the replica id, for experiments comparing the cost of
+    /// the PRF. Not part of the NSE spec, and not supported by `SpotCheckConfig`.
+    Aes256Ctr = 1,
+}
 
-    /// Returns successive layers, starting with mask layer, and ending with sealed replica layer.
-    fn next(&mut self) -> Option<Self::Item> {
-        if let Some(next_key_layer) = self.key_generator.next() {
-            Some(|| -> NSEResult<LayerOutput> {
-                let layer = if self.key_generator.layers_remaining() == 0 {
-                    self.key_generator.combine_layer(&self.original_data, false)
-                } else {
-                    next_key_layer
-                }?;
-                if self.build_trees {
-                    let tree_builder = self.key_generator.gpu.tree_builder().as_mut().unwrap(); // WARN: unwrap()
-                    let frs = Node::as_frs(layer.0.as_slice());
-                    let (_, fr_tree) = tree_builder.add_final_leaves(frs)?;
-                    let tree = Node::from_frs(&fr_tree).to_vec();
-                    Ok(LayerOutput { base: layer, tree })
-                } else {
-                    Ok(LayerOutput {
-                        base: layer,
-                        tree: Vec::new(), // Maybe change Vec<Node> to Option<Vec<Node>> and return None?
-                    })
-                }
-            }())
-        } else {
-            None
-        }
+impl Default for MaskPrf {
+    fn default() -> Self {
+        MaskPrf::Sha256
     }
 }
 
-impl<'a> ExactSizeIterator for Sealer<'a> {
-    fn len(&self) -> usize {
-        self.key_generator.len()
+impl MaskPrf {
+    fn from_u8(value: u8) -> NSEResult<Self> {
+        match value {
+            0 => Ok(MaskPrf::Sha256),
+            1 => Ok(MaskPrf::Aes256Ctr),
+            _ => Err(NSEError::InvalidInput(format!(
+                "Unknown mask PRF {}!",
+                value
+            ))),
+        }
     }
 }
 
-pub struct Unsealer<'a> {
-    #[allow(dead_code)]
-    key_generator: KeyGenerator<'a>,
+/// The operation combining the last key layer with the original data.
+#[derive(PartialEq, Eq, Hash, Debug, Clone, Copy)]
+pub enum EncodingMode {
+    /// Replica is `data + key`, in the field.
+    FieldAdd = 0,
+    /// Replica is `data ^ key`, over the low 253 bits of the canonical representations of the
+    /// nodes, see `XOR_MASK`. Data nodes must fit in 253 bits, which `SealerBuilder::validate_data`
+    /// checks, or their top bits are lost.
+    Xor = 1,
 }
 
-impl<'a> Unsealer<'a> {
-    pub fn new(
-        config: Config,
-        replica_id: ReplicaId,
-        window_index: usize,
-        gpu: &'a mut GPU,
-    ) -> NSEResult<Self> {
-        Ok(Self {
-            key_generator: KeyGenerator::new(config, replica_id, window_index, gpu)?,
-        })
-    }
-
-    #[allow(dead_code)]
-    fn unseal_range(&mut self, offset: usize, sealed_data: &[Node]) -> NSEResult<Vec<Node>> {
-        while let Some(layer) = self.key_generator.next() {
-            layer?;
-        }
+/// Mask of the top limb of the canonical representation of a node kept by
+/// `EncodingMode::Xor`, i.e. of its low 253 bits. Nodes below 2^253 are field elements, so XOR
+/// over them never leaves the field, on the device as on the host.
+pub(crate) const XOR_MASK: u64 = u64::max_value() >> 3;
 
-        self.key_generator
-            .combine_segment(offset, sealed_data, true)
-    }
+/// Direction of a combine of data with the key layer: sealing encodes the original data into
+/// the replica, unsealing decodes the replica back, as `EncodingMode` says. A bool converts into
+/// it, `true` decoding, so the combines of `KeyGenerator`, `HostCombiner` and `GPU` take either.
+/// The `NarrowStackedExpander` methods take a `CombineMode` only, for the trait to stay
+/// object-safe.
+#[derive(PartialEq, Eq, Hash, Debug, Clone, Copy)]
+pub enum CombineMode {
+    /// Replica from original data, e.g. `data + key`.
+    Encode = 0,
+    /// Original data from replica, e.g. `replica - key`.
+    Decode = 1,
+}
 
-    #[allow(dead_code)]
-    fn unseal_layer(&mut self, sealed: Layer) -> NSEResult<Layer> {
-        Ok(Layer(self.unseal_range(0, &sealed.0)?))
+impl CombineMode {
+    pub fn is_decode(self) -> bool {
+        self == CombineMode::Decode
     }
 }
 
-pub struct KeyGenerator<'a> {
-    replica_id: ReplicaId,
-    window_index: usize,
-    current_layer_index: usize,
-    gpu: &'a mut GPU,
+impl From<bool> for CombineMode {
+    fn from(is_decode: bool) -> Self {
+        if is_decode {
+            CombineMode::Decode
+        } else {
+            CombineMode::Encode
+        }
+    }
 }
 
-impl<'a> KeyGenerator<'a> {
-    fn new(
-        config: Config,
-        replica_id: ReplicaId,
-        window_index: usize,
-        gpu: &'a mut GPU,
-    ) -> NSEResult<Self> {
-        assert_eq!(config.num_nodes_window, gpu.leaf_count());
-        Ok(Self {
-            replica_id,
-            window_index,
-            current_layer_index: 0, // Initial value of 0 means the current layer precedes any generated layer.
-            gpu,
-        })
-    }
-    pub fn seek(&mut self, target_layer_index: usize, target_layer_data: &Layer) -> NSEResult<()> {
-        self.current_layer_index = target_layer_index + 1;
-        self.gpu.push_layer(&target_layer_data)
+impl Config {
+    /// Number of nodes of a window, i.e. number of leaves of the tree of a layer.
+    pub fn leaf_count(&self) -> usize {
+        self.num_nodes_window
     }
 
-    fn config(&self) -> Config {
-        self.gpu.config
+    /// Number of bytes of a window, or of any of its layers.
+    pub fn window_byte_len(&self) -> usize {
+        self.num_nodes_window * NODE_SIZE
     }
 
-    fn layers_remaining(&self) -> usize {
-        self.len() - self.current_layer_index
+    /// Number of layers of a window, the last one being the replica (or key) layer.
+    pub fn num_layers(&self) -> usize {
+        self.num_expander_layers + self.num_butterfly_layers
     }
 
-    // Generate maske layer on GPU from seeds.
-    fn generate_mask_layer(&mut self) -> NSEResult<Layer> {
-        self.gpu
-            .generate_mask_layer(self.replica_id, self.window_index)
+    /// Checks the parameters are supported by the kernels.
+    pub fn validate(&self) -> NSEResult<()> {
+        match self.invalid_reason() {
+            Some(reason) => Err(NSEError::InvalidInput(format!(
+                "{}:\n{}",
+                reason,
+                self.describe()
+            ))),
+            None => Ok(()),
+        }
     }
 
-    // Generate expander layer on GPU, using previous layer already loaded.
-    fn generate_expander_layer(&mut self) -> NSEResult<Layer> {
-        self.gpu.generate_expander_layer(
-            self.replica_id,
-            self.window_index,
-            self.current_layer_index,
-        )
-    }
-    // Generate butterfly layer on GPU, using previous layer already loaded.
-    fn generate_butterfly_layer(&mut self) -> NSEResult<Layer> {
-        self.gpu.generate_butterfly_layer(
-            self.replica_id,
-            self.window_index,
-            self.current_layer_index,
-        )
+    fn invalid_reason(&self) -> Option<String> {
+        if !self.k.is_power_of_two() || self.k < MIN_K || self.k > MAX_K {
+            return Some(format!(
+                "k must be a power of two in [{}, {}]",
+                MIN_K, MAX_K
+            ));
+        }
+        if !self.num_nodes_window.is_power_of_two()
+            || self.num_nodes_window as u64 > 1 << 32
+            || self.num_nodes_window <= self.k as usize
+        {
+            return Some("Window size must be a power of two in (k, 2^32]".into());
+        }
+        if self.degree_expander == 0 || self.degree_expander % 2 != 0 {
+            return Some("Expander degree must be even".into());
+        }
+        if self.degree_butterfly < 2 || !self.degree_butterfly.is_power_of_two() {
+            return Some("Butterfly degree must be a power of two".into());
+        }
+        if self.num_expander_layers == 0 || self.num_butterfly_layers == 0 {
+            return Some("There must be at least one expander and one butterfly layer".into());
+        }
+        None
     }
 
-    fn combine_layer(&mut self, layer: &Layer, is_decode: bool) -> NSEResult<Layer> {
-        self.gpu.combine_layer(layer, is_decode)
+    /// Multi-line description of the parameters and of the quantities derived from them, e.g.
+    /// for logs and CLIs. Sizes of invalid configs are computed without overflowing, device
+    /// memory is only computed for valid ones.
+    pub fn describe(&self) -> String {
+        let nodes = self.num_nodes_window as u128;
+        let layers = self.num_expander_layers as u128 + self.num_butterfly_layers as u128;
+        let mut lines = vec![
+            format!("{:?}", self),
+            format!("  nodes per window: {}", nodes),
+            format!("  window size: {} bytes", nodes * NODE_SIZE as u128),
+            format!(
+                "  layers: {} ({} expander, the first being the mask layer, {} butterfly)",
+                layers, self.num_expander_layers, self.num_butterfly_layers
+            ),
+            format!(
+                "  nodes labeled per window: {}",
+                nodes.saturating_mul(layers)
+            ),
+            format!(
+                "  parents per node: {} expander (sums of {} nodes), {} butterfly",
+                self.degree_expander, self.k, self.degree_butterfly
+            ),
+        ];
+        if self.invalid_reason().is_none() {
+            let minimum = MinimumMemory::new(self, &GpuConfig::default());
+            let cached = MinimumMemory::new(
+                self,
+                &GpuConfig {
+                    cache_expander_parents: true,
+                    ..GpuConfig::default()
+                },
+            );
+            lines.push(format!(
+                "  device memory: at least {} bytes, {} with cached expander parents",
+                minimum.total_bytes(),
+                cached.total_bytes()
+            ));
+        }
+        lines.push(format!(
+            "  replica size: {} bytes",
+            nodes * NODE_SIZE as u128
+        ));
+        lines.join("\n")
     }
 
-    fn finalize(&mut self) -> NSEResult<()> {
-        self.gpu.finalize()
+    /// Returns the same config, with the labeling hashes domain-separated by `domain_tags`.
+    pub fn with_domain_tags(self, domain_tags: DomainTags) -> Config {
+        Config {
+            domain_tags,
+            ..self
+        }
     }
 
-    fn combine_segment(
-        &mut self,
-        offset: usize,
-        segment: &[Node],
-        is_decode: bool,
-    ) -> NSEResult<Vec<Node>> {
-        self.gpu.combine_segment(offset, segment, is_decode)
+    /// SHA-256 of the canonical encoding of the config. Persisted key layers carry the
+    /// fingerprint of the config they were generated with, and are refused by other configs.
+    pub fn fingerprint(&self) -> [u8; CONFIG_FINGERPRINT_LEN] {
+        let mut fingerprint = [0u8; CONFIG_FINGERPRINT_LEN];
+        fingerprint.copy_from_slice(&Sha256::digest(&self.to_bytes()));
+        fingerprint
     }
 
-    fn last_index(&self) -> usize {
-        self.config().num_expander_layers + self.config().num_butterfly_layers
-    }
+    /// Canonical, little-endian, encoding of the config, for persistence. The encoding mode and
+    /// the mask PRF share the last byte (low and high nibble), so that configs with the default
+    /// PRF keep the encoding, and fingerprint, they had before it could be chosen.
+    pub fn to_bytes(&self) -> [u8; CONFIG_BYTE_LEN] {
+        let mut bytes = [0u8; CONFIG_BYTE_LEN];
+        bytes[..4].copy_from_slice(&self.k.to_le_bytes());
+        let fields = [
+            self.num_nodes_window,
+            self.degree_expander,
+            self.degree_butterfly,
+            self.num_expander_layers,
+            self.num_butterfly_layers,
+        ];
+        for (chunk, field) in bytes[4..CONFIG_TAGS_OFFSET]
+            .chunks_mut(8)
+            .zip(fields.iter())
+        {
+            chunk.copy_from_slice(&(*field as u64).to_le_bytes());
+        }
+        let tags = [
+            self.domain_tags.mask,
+            self.domain_tags.expander,
+            self.domain_tags.butterfly,
+        ];
+        for (chunk, tag) in bytes[CONFIG_TAGS_OFFSET..CONFIG_BYTE_LEN - 1]
+            .chunks_mut(4)
+            .zip(tags.iter())
+        {
+            chunk.copy_from_slice(&tag.to_le_bytes());
+        }
+        bytes[CONFIG_BYTE_LEN - 1] = self.encoding_mode as u8 | (self.mask_prf as u8) << 4;
+        bytes
+    }
+
+    /// Inverse of `to_bytes`. The decoded config is not validated.
+    pub fn from_bytes(bytes: &[u8]) -> NSEResult<Config> {
+        if bytes.len() != CONFIG_BYTE_LEN {
+            return Err(NSEError::InvalidInput(format!(
+                "Config encoding has {} bytes, expected {}!",
+                bytes.len(),
+                CONFIG_BYTE_LEN
+            )));
+        }
+        let mut k = [0u8; 4];
+        k.copy_from_slice(&bytes[..4]);
+        let mut fields = [0usize; 5];
+        for (field, chunk) in fields
+            .iter_mut()
+            .zip(bytes[4..CONFIG_TAGS_OFFSET].chunks(8))
+        {
+            let mut value = [0u8; 8];
+            value.copy_from_slice(chunk);
+            let value = u64::from_le_bytes(value);
+            if value > std::usize::MAX as u64 {
+                return Err(NSEError::InvalidInput(format!(
+                    "Config value {} doesn't fit the platform!",
+                    value
+                )));
+            }
+            *field = value as usize;
+        }
+        let mut tags = [0u32; 3];
+        for (tag, chunk) in tags
+            .iter_mut()
+            .zip(bytes[CONFIG_TAGS_OFFSET..CONFIG_BYTE_LEN - 1].chunks(4))
+        {
+            let mut value = [0u8; 4];
+            value.copy_from_slice(chunk);
+            *tag = u32::from_le_bytes(value);
+        }
+        Ok(Config {
+            k: u32::from_le_bytes(k),
+            num_nodes_window: fields[0],
+            degree_expander: fields[1],
+            degree_butterfly: fields[2],
+            num_expander_layers: fields[3],
+            num_butterfly_layers: fields[4],
+            encoding_mode: EncodingMode::from_u8(bytes[CONFIG_BYTE_LEN - 1] & 0x0f)?,
+            domain_tags: DomainTags {
+                mask: tags[0],
+                expander: tags[1],
+                butterfly: tags[2],
+            },
+            mask_prf: MaskPrf::from_u8(bytes[CONFIG_BYTE_LEN - 1] >> 4)?,
+        })
+    }
 }
 
-impl<'a> Iterator for KeyGenerator<'a> {
-    type Item = NSEResult<Layer>;
+impl Default for EncodingMode {
+    fn default() -> Self {
+        EncodingMode::FieldAdd
+    }
+}
 
-    fn next(&mut self) -> Option<Self::Item> {
-        let last_index = self.last_index();
+impl EncodingMode {
+    fn from_u8(value: u8) -> NSEResult<Self> {
+        match value {
+            0 => Ok(EncodingMode::FieldAdd),
+            1 => Ok(EncodingMode::Xor),
+            _ => Err(NSEError::InvalidInput(format!(
+                "Unknown encoding mode {}!",
+                value
+            ))),
+        }
+    }
+}
 
-        // If current index is last, then we have already finished generating layers.
-        if self.current_layer_index >= last_index {
-            return None;
+/// Size of the byte encoding of a `Config`, see `Config::to_bytes`.
+pub const CONFIG_BYTE_LEN: usize = CONFIG_TAGS_OFFSET + 3 * 4 + 1;
+const CONFIG_TAGS_OFFSET: usize = 4 + 5 * 8;
+/// Size of `Config::fingerprint`.
+pub const CONFIG_FINGERPRINT_LEN: usize = 32;
+/// Smallest supported `Config::k`.
+pub const MIN_K: u32 = 1;
+/// Largest supported `Config::k`. Expander labels sum `k` nodes per parent, so their cost grows
+/// linearly with it, see `GPU::bench_k_sweep`.
+pub const MAX_K: u32 = 256;
+
+/// Returns the range of nodes `[offset, offset + len)`, if it lies within a window of
+/// `leaf_count` nodes.
+pub fn segment_range(offset: usize, len: usize, leaf_count: usize) -> NSEResult<Range<usize>> {
+    match offset.checked_add(len) {
+        Some(end) if end <= leaf_count => Ok(offset..end),
+        _ => Err(NSEError::InvalidRange {
+            offset,
+            len,
+            leaf_count,
+        }),
+    }
+}
+
+/// Checks `out` can hold the result of combining `segment`, i.e. has as many nodes.
+pub(crate) fn check_combine_output(segment: &[Node], out: &[Node]) -> NSEResult<()> {
+    if segment.len() != out.len() {
+        return Err(NSEError::InvalidInput(format!(
+            "Cannot combine {} nodes into {} nodes!",
+            segment.len(),
+            out.len()
+        )));
+    }
+    Ok(())
+}
+
+/// Combines `data` in place with the nodes of `key` at the same positions, the host
+/// counterpart of the combine kernels.
+pub(crate) fn combine_nodes(
+    data: &mut [Node],
+    key: &[Node],
+    mode: CombineMode,
+    encoding_mode: EncodingMode,
+) -> NSEResult<()> {
+    for (d, k) in data.iter_mut().zip(key.iter()) {
+        match encoding_mode {
+            EncodingMode::FieldAdd if mode == CombineMode::Decode => d.0.sub_assign(&k.0),
+            EncodingMode::FieldAdd => d.0.add_assign(&k.0),
+            EncodingMode::Xor => d.0 = xor(&d.0, &k.0),
         }
-        self.current_layer_index += 1;
+    }
+    Ok(())
+}
+
+/// XOR of the low 253 bits of `a` and `b`, see `XOR_MASK`.
+fn xor(a: &Fr, b: &Fr) -> Fr {
+    let mut repr = a.into_repr();
+    for (l, r) in repr.0.iter_mut().zip(b.into_repr().0.iter()) {
+        *l ^= *r;
+    }
+    repr.0[3] &= XOR_MASK;
+    Fr::from_repr(repr).expect("Below 2^253, in the field")
+}
 
-        // First layer is mask layer.
-        if self.current_layer_index == 1 {
-            return Some(self.generate_mask_layer());
+/// How a `Sealer` handles original data that doesn't fill a whole window, e.g. the trailing
+/// window of a sector. Windows themselves always have `num_nodes_window` nodes, a power of two.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum WindowPadding {
+    /// Original data must have exactly `num_nodes_window` nodes.
+    Strict,
+    /// Shorter original data is padded with zero nodes, the replica covers the whole window.
+    Pad,
+    /// Shorter original data is padded with zero nodes, the replica is truncated back to the
+    /// length of the original data. Trees are still built over the whole window.
+    PadAndTruncate,
+}
+
+impl Default for WindowPadding {
+    fn default() -> Self {
+        WindowPadding::Strict
+    }
+}
+
+impl WindowPadding {
+    // Checks original data of `len` nodes can be sealed in a window of `leaf_count` nodes.
+    fn check(&self, len: usize, leaf_count: usize) -> NSEResult<()> {
+        if len > leaf_count || (len < leaf_count && *self == WindowPadding::Strict) {
+            return Err(NSEError::InvalidInput(format!(
+                "Original data has {} nodes, expected {}!",
+                len, leaf_count
+            )));
         }
+        Ok(())
+    }
+
+    fn apply(&self, data: &mut Layer, leaf_count: usize) -> NSEResult<()> {
+        self.check(data.0.len(), leaf_count)?;
+        data.0.resize(leaf_count, Node::default());
+        Ok(())
+    }
+}
+
+/// Which layers a `Sealer` keeps, see `SealerBuilder::retention`. The outputs of the other
+/// layers carry an empty `base` (their trees are still built if enabled), and the key layers
+/// nothing else needs on the host (trees, checkpoints, caches or a layer sink) are not even
+/// read back from the device.
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Hash)]
+pub enum RetentionPolicy {
+    /// No layer, e.g. when the layers are handed to a layer sink, or only trees are needed.
+    None,
+    /// The replica only.
+    LastOnly,
+    /// Every layer.
+    All,
+    /// Layers `n`, `2n`, `3n`... (1-based), and the replica.
+    EveryNth(usize),
+}
+
+impl Default for RetentionPolicy {
+    fn default() -> Self {
+        RetentionPolicy::All
+    }
+}
 
-        // When current index equals number of expander layers, we need to generate the last expander layer.
-        // Before that, generate earlier expander layers.
-        if self.current_layer_index <= self.config().num_expander_layers {
-            return Some(self.generate_expander_layer());
+impl RetentionPolicy {
+    /// Whether layer `layer_index` (1-based) out of `num_layers` is kept, the replica being the
+    /// last one.
+    pub fn retains(&self, layer_index: usize, num_layers: usize) -> bool {
+        match *self {
+            RetentionPolicy::None => false,
+            RetentionPolicy::LastOnly => layer_index == num_layers,
+            RetentionPolicy::All => true,
+            RetentionPolicy::EveryNth(n) => {
+                layer_index == num_layers || (n > 0 && layer_index % n == 0)
+            }
         }
+    }
+}
 
-        // When current index equals last index (having been incremented since the first check),
-        // we need to generate the last butterfly layer. Before that, generate earlier butterfly layers.
-        if self.current_layer_index <= last_index {
-            return Some(self.generate_butterfly_layer().and_then(|l| {
-                if self.current_layer_index == last_index {
-                    self.finalize()?;
+// Where a `Sealer` reads the original data from.
+enum OriginalData {
+    Memory(Layer),
+    // Nodes in their byte representation, streamed through the device in batches.
+    File(PathBuf),
+    // Uploaded to the device while the key layers are generated, see `SealerBuilder::stage_data`.
+    Staged,
+}
+
+/// Callback invoked after each layer is produced, with the 1-based index of the layer and the
+/// total number of layers.
+pub type ProgressCallback<'a> = Box<dyn FnMut(usize, usize) + 'a>;
+
+/// Receives each layer produced by a `Sealer`, see `Sealer::with_layer_sink`.
+pub type LayerSink<'a> = Box<dyn FnMut(LabeledLayer) -> NSEResult<()> + 'a>;
+
+pub struct Sealer<'a> {
+    original_data: OriginalData,
+    key_generator: KeyGenerator<'a>,
+    build_trees: bool,
+    retention: RetentionPolicy,
+    checkpoint_dir: Option<PathBuf>,
+    progress: Option<ProgressCallback<'a>>,
+    layer_sink: Option<LayerSink<'a>>,
+    cancellation: Option<CancellationToken>,
+    cancelled: bool,
+    gpu_lock: Option<GpuLock>,
+    device_lock: Option<DeviceLock>,
+    stats: Vec<LayerStats>,
+    // `Layer::digest` of each layer produced, if it was read back and `layer_digests`.
+    digests: Vec<Option<Sha256Domain>>,
+    layer_digests: bool,
+    record_build_info: bool,
+    key_cache: Option<KeyCache>,
+    data_len: usize,
+    padding: WindowPadding,
+    commitment: Option<DataCommitment>,
+    fuse_last_layer: bool,
+    preemption: Option<PreemptionQueue>,
+}
+
+impl<'a> Sealer<'a> {
+    pub fn new(
+        config: Config,
+        input: SealerInput,
+        gpu: &'a mut GPU,
+        build_trees: bool,
+    ) -> NSEResult<Self> {
+        SealerBuilder::new(config, input)
+            .build_trees(build_trees)
+            .build(gpu)
+    }
+
+    pub fn builder(config: Config, input: SealerInput) -> SealerBuilder<'a> {
+        SealerBuilder::new(config, input)
+    }
+
+    /// Resumes generation after a known layer. `target_layer_index` is 0-based, i.e. the next
+    /// generated layer has index `target_layer_index + 2`.
+    pub fn seek(&mut self, target_layer_index: usize, target_layer_data: &Layer) -> NSEResult<()> {
+        self.key_generator
+            .seek(target_layer_index, target_layer_data)
+    }
+
+    pub fn new_from_layer(
+        provided_layer_index: usize,
+        provided_layer: &Layer,
+        config: Config,
+        input: SealerInput,
+        gpu: &'a mut GPU,
+        build_trees: bool,
+    ) -> NSEResult<Self> {
+        let mut sealer = Self::new(config, input, gpu, build_trees)?;
+        sealer.seek(provided_layer_index, provided_layer)?;
+        Ok(sealer)
+    }
+
+    /// Seals the original data stored at `path`, in the byte representation of nodes (see
+    /// `From<&Layer> for Vec<u8>`). Data is streamed into the combine kernel in batches of
+    /// `combine_batch_size` nodes, so it is never loaded in memory as a whole. See
+    /// `SealerBuilder::from_file` to set other options.
+    pub fn seal_from_file<P: AsRef<Path>>(
+        config: Config,
+        replica_id: ReplicaId,
+        window_index: WindowIndex,
+        path: P,
+        gpu: &'a mut GPU,
+        build_trees: bool,
+    ) -> NSEResult<SealOutput> {
+        SealerBuilder::from_file(config, replica_id, window_index, path)
+            .build_trees(build_trees)
+            .build(gpu)?
+            .seal()
+    }
+
+    // Keyed by window like the `KeyCache`, so that a directory shared by several seals never
+    // resumes one from the layers of another.
+    fn checkpoint_path(
+        dir: &Path,
+        replica_id: ReplicaId,
+        window_index: WindowIndex,
+        layer_index: usize,
+    ) -> PathBuf {
+        dir.join(format!(
+            "{}-{}-layer-{}.{}",
+            Sha256Domain(replica_id.0),
+            window_index,
+            layer_index,
+            LAYER_FILE_EXTENSION
+        ))
+    }
+
+    // Persist a key layer, so that an interrupted seal can be resumed from it.
+    fn write_checkpoint(
+        dir: &Path,
+        key_generator: &KeyGenerator,
+        layer_index: usize,
+        layer: &Layer,
+    ) -> NSEResult<()> {
+        let path = Self::checkpoint_path(
+            dir,
+            key_generator.replica_id(),
+            key_generator.window_index(),
+            layer_index,
+        );
+        write_layer_file(&path, &key_generator.config(), layer)
+    }
+
+    // Seek to the latest key layer found in the checkpoint directory, if any.
+    fn resume_from_checkpoint(&mut self, dir: &Path) -> NSEResult<()> {
+        let last_key_layer = self.key_generator.len() - 1;
+        for layer_index in (1..=last_key_layer).rev() {
+            let path = Self::checkpoint_path(
+                dir,
+                self.key_generator.replica_id(),
+                self.key_generator.window_index(),
+                layer_index,
+            );
+            if path.exists() {
+                info!("Resuming from checkpoint: {}", path.display());
+                let layer = read_layer_file(&path, &self.key_generator.config())?;
+                return self.seek(layer_index - 1, &layer);
+            }
+        }
+        Ok(())
+    }
+
+    /// Hands each layer to `sink` as soon as it is produced (the replica last, truncated as
+    /// configured by `SealerBuilder::padding`), e.g. to persist the layers needed for proving.
+    /// The layers are moved into the sink: yielded outputs then carry an empty `base`, and
+    /// only their trees. An error of the sink is returned for the layer.
+    pub fn with_layer_sink<F: FnMut(LabeledLayer) -> NSEResult<()> + 'a>(
+        mut self,
+        sink: F,
+    ) -> Self {
+        self.layer_sink = Some(Box::new(sink));
+        self
+    }
+
+    /// Turns the sealer into an iterator yielding each layer along with its index and kind, the
+    /// last one being the `LayerKind::Replica`.
+    pub fn labeled(self) -> LabeledSealer<'a> {
+        LabeledSealer(self)
+    }
+
+    /// Timings of the layers produced so far.
+    pub fn stats(&self) -> &[LayerStats] {
+        &self.stats
+    }
+
+    /// The data commitment of the original data (padded to the whole window), if enabled with
+    /// `SealerBuilder::comm_d`. `None` until the replica is produced.
+    pub fn comm_d(&self) -> NSEResult<Option<Sha256Domain>> {
+        let replica_produced = self
+            .stats
+            .last()
+            .map(|stats| stats.kind == LayerKind::Replica)
+            .unwrap_or(false);
+        match &self.commitment {
+            Some(commitment) if replica_produced => Ok(Some(commitment.root()?)),
+            _ => Ok(None),
+        }
+    }
+
+    /// The receipt of the layers produced so far, see `SealReceipt`.
+    pub fn receipt(&self) -> NSEResult<SealReceipt> {
+        let gpu = &self.key_generator.gpu;
+        Ok(SealReceipt {
+            library_version: LIBRARY_VERSION.to_string(),
+            config_fingerprint: Sha256Domain(self.key_generator.config().fingerprint()),
+            replica_id: self.key_generator.replica_id(),
+            window_index: self.key_generator.window_index(),
+            device: DeviceIdentity {
+                name: gpu
+                    .device_name()
+                    .unwrap_or_else(|_| UNKNOWN_DEVICE.to_string()),
+                key: gpu.device_key()?,
+            },
+            layers: self
+                .stats
+                .iter()
+                .zip(self.digests.iter())
+                .map(|(stats, digest)| LayerReceipt {
+                    stats: stats.clone(),
+                    digest: *digest,
+                })
+                .collect(),
+            comm_d: self.comm_d()?,
+            build: if self.record_build_info {
+                Some(build_info())
+            } else {
+                None
+            },
+        })
+    }
+
+    /// Produces all remaining layers.
+    pub fn seal(mut self) -> NSEResult<SealOutput> {
+        let layers = (&mut self).collect::<NSEResult<Vec<_>>>()?;
+        Ok(SealOutput {
+            layers,
+            comm_d: self.comm_d()?,
+            receipt: self.receipt()?,
+            stats: std::mem::take(&mut self.stats),
+        })
+    }
+
+    /// Same as `seal`, but spills layers to disk if they don't fit in the host memory budget
+    /// of the GPU, see `GpuConfig::host_memory_budget`.
+    pub fn seal_to_store(mut self) -> NSEResult<StoredSealOutput> {
+        let mut layers = layer_store_for(
+            &self.key_generator.config(),
+            &self.key_generator.gpu.gpu_config(),
+        )?;
+        for layer in &mut self {
+            layers.push(layer?)?;
+        }
+        Ok(StoredSealOutput {
+            layers,
+            comm_d: self.comm_d()?,
+            receipt: self.receipt()?,
+            stats: std::mem::take(&mut self.stats),
+        })
+    }
+
+    // Takes the lock of the device before the first layer, held until the sealer is dropped,
+    // see `GpuConfig::device_lock`.
+    fn lock_device(&mut self) -> NSEResult<()> {
+        if self.device_lock.is_some() {
+            return Ok(());
+        }
+        if let Some(policy) = self.key_generator.gpu.gpu_config().device_lock {
+            let device_key = self.key_generator.gpu.device_key()?;
+            info!("Locking device {}...", device_key);
+            self.device_lock = Some(DeviceLock::lock(&device_key, policy)?);
+        }
+        Ok(())
+    }
+
+    // Takes the GPU lock shared with bellperson before the first layer, and yields it between
+    // layers while a high-priority proof is waiting, see `GpuConfig::priority`.
+    fn cooperate_with_provers(&mut self) -> NSEResult<()> {
+        let priority = self.key_generator.gpu.gpu_config().priority;
+        if priority == GpuPriority::Ignore {
+            return Ok(());
+        }
+        if self.gpu_lock.is_some() && priority == GpuPriority::Low && GpuLock::priority_requested()?
+        {
+            info!("Yielding the GPU to a high-priority proof...");
+            self.gpu_lock = None;
+            GpuLock::wait_for_priority()?;
+        }
+        if self.gpu_lock.is_none() {
+            self.gpu_lock = Some(GpuLock::lock(priority)?);
+        }
+        Ok(())
+    }
+
+    // Runs the jobs queued to preempt sealing, see `SealerBuilder::preemption`.
+    fn preempt(&mut self) -> NSEResult<()> {
+        if let Some(queue) = &self.preemption {
+            let jobs = queue.run_pending(self.key_generator.gpu)?;
+            if jobs > 0 {
+                info!("Sealing preempted by {} jobs.", jobs);
+            }
+        }
+        Ok(())
+    }
+
+    // Combines the original data with the final key layer, padded with zero nodes if shorter.
+    fn combine_original_data(&mut self) -> NSEResult<Layer> {
+        let path = match &self.original_data {
+            OriginalData::Memory(data) => {
+                return match &mut self.commitment {
+                    Some(commitment) => Ok(Layer(
+                        self.key_generator
+                            .combine_segment_with_commitment(0, &data.0, commitment)?,
+                    )),
+                    None => self.key_generator.combine_layer(data, CombineMode::Encode),
                 }
-                Ok(l)
-            }));
+            }
+            OriginalData::Staged => return self.key_generator.combine_staged(CombineMode::Encode),
+            OriginalData::File(path) => path,
+        };
+        let file = File::open(path)?;
+        // Empty files cannot be mapped.
+        let mmap = if self.data_len > 0 {
+            Some(unsafe { Mmap::map(&file)? })
+        } else {
+            None
+        };
+        let bytes = mmap.as_ref().map(|m| &m[..]).unwrap_or(&[]);
+        // The file may have been truncated since the sealer was built.
+        if bytes.len() < self.data_len * NODE_SIZE {
+            return Err(NSEError::InvalidInput(format!(
+                "Original data file has {} bytes, expected {}!",
+                bytes.len(),
+                self.data_len * NODE_SIZE
+            )));
+        }
+        let data_len = self.data_len;
+        let leaf_count = self.key_generator.config().leaf_count();
+        let batch_size = self.key_generator.gpu.combine_batch_size();
+        let mut replica = Vec::with_capacity(leaf_count);
+        let mut offset = 0;
+        while offset < leaf_count {
+            if offset > 0 {
+                self.preempt()?;
+            }
+            let end = std::cmp::min(offset + batch_size, leaf_count);
+            let data_start = std::cmp::min(offset, data_len);
+            let data_end = std::cmp::min(end, data_len);
+            let mut segment =
+                Layer::try_from_bytes(&bytes[data_start * NODE_SIZE..data_end * NODE_SIZE])?.0;
+            segment.resize(end - offset, Node::default());
+            replica.extend(match &mut self.commitment {
+                Some(commitment) => self
+                    .key_generator
+                    .combine_segment_with_commitment(offset, &segment, commitment)?,
+                None => {
+                    self.key_generator
+                        .combine_segment(offset, &segment, CombineMode::Encode)?
+                }
+            });
+            offset = end;
+        }
+        Ok(Layer(replica))
+    }
+
+    // Whether key layer `layer_index` is needed on the host, or may be left on the device.
+    fn needs_key_layer(&self, layer_index: usize) -> bool {
+        let num_layers = self.key_generator.len();
+        let cached = match &self.key_cache {
+            Some(cache) => layer_index == num_layers || cache.all_layers(),
+            None => false,
         };
+        if layer_index == num_layers {
+            // The replica is combined on the device.
+            return cached;
+        }
+        cached
+            || self.build_trees
+            || self.checkpoint_dir.is_some()
+            || self.layer_sink.is_some()
+            || self.retention.retains(layer_index, num_layers)
+    }
 
-        unreachable!();
+    // Whether layer `layer_index` is the replica, produced by a single kernel along with the last
+    // key layer, see `SealerBuilder::fuse_last_layer`. The key must not be needed afterwards.
+    fn fuses_replica(&self, layer_index: usize) -> bool {
+        let in_memory = match self.original_data {
+            OriginalData::Memory(_) | OriginalData::Staged => true,
+            OriginalData::File(_) => false,
+        };
+        self.fuse_last_layer
+            && in_memory
+            && layer_index == self.key_generator.len()
+            && !self.needs_key_layer(layer_index)
+            && self.commitment.is_none()
+            && self.key_generator.spot_check.is_none()
     }
-}
 
-impl<'a> ExactSizeIterator for KeyGenerator<'a> {
-    fn len(&self) -> usize {
-        self.config().num_expander_layers + self.config().num_butterfly_layers
+    fn fused_replica(&mut self) -> NSEResult<Layer> {
+        match &self.original_data {
+            OriginalData::Memory(data) => self
+                .key_generator
+                .combine_last_layer(data, CombineMode::Encode),
+            OriginalData::Staged => self
+                .key_generator
+                .combine_last_layer_staged(CombineMode::Encode),
+            OriginalData::File(_) => unreachable!(),
+        }
+    }
+
+    // `fused` if `next_key_layer` is the replica already, see `fuses_replica`.
+    fn process_layer(
+        &mut self,
+        next_key_layer: NSEResult<Layer>,
+        fused: bool,
+    ) -> NSEResult<LayerOutput> {
+        let key_layer = next_key_layer?;
+        let layer_index = self.key_generator.current_layer_index();
+        let is_replica = self.key_generator.layers_remaining() == 0;
+        if let Some(cache) = &self.key_cache {
+            if is_replica || cache.all_layers() {
+                cache.store(
+                    &self.key_generator.config(),
+                    self.key_generator.replica_id,
+                    self.key_generator.window_index,
+                    layer_index,
+                    &key_layer,
+                )?;
+            }
+        }
+        let layer = if fused {
+            key_layer
+        } else if is_replica {
+            self.combine_original_data()?
+        } else {
+            if let Some(dir) = &self.checkpoint_dir {
+                Self::write_checkpoint(dir, &self.key_generator, layer_index, &key_layer)?;
+            }
+            key_layer
+        };
+        let kind = if is_replica {
+            LayerKind::Replica
+        } else {
+            self.key_generator.layer_kind(layer_index)
+        };
+        // Key layers left on the device are empty on the host.
+        self.stats.push(LayerStats::new(
+            layer_index,
+            kind,
+            self.key_generator.gpu.take_timings(),
+            self.key_generator.config().leaf_count(),
+        ));
+        let tree = if self.build_trees {
+            let tree_builder = self.key_generator.gpu.tree_builder().unwrap(); // WARN: unwrap()
+            let frs = Node::as_frs(layer.0.as_slice());
+            let (_, fr_tree) = tree_builder.add_final_leaves(frs)?;
+            Node::from_frs(&fr_tree).to_vec()
+        } else {
+            Vec::new() // Maybe change Vec<Node> to Option<Vec<Node>> and return None?
+        };
+        let layer = if is_replica && self.padding == WindowPadding::PadAndTruncate {
+            let mut layer = layer;
+            layer.0.truncate(self.data_len);
+            layer
+        } else {
+            layer
+        };
+        self.digests
+            .push(if self.layer_digests && !layer.0.is_empty() {
+                Some(layer.digest())
+            } else {
+                None
+            });
+        let base = match self.layer_sink.as_mut() {
+            Some(sink) => {
+                sink(LabeledLayer {
+                    index: layer_index,
+                    kind,
+                    layer,
+                })?;
+                Layer::default()
+            }
+            None if self
+                .retention
+                .retains(layer_index, self.key_generator.len()) =>
+            {
+                layer
+            }
+            None => Layer::default(),
+        };
+        if let Some(progress) = self.progress.as_mut() {
+            progress(layer_index, self.key_generator.len());
+        }
+        Ok(LayerOutput { base, tree })
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use ff::PrimeField;
-    use paired::bls12_381::{Fr, FrRepr};
+/// Fluent construction of a `Sealer` with optional components.
+pub struct SealerBuilder<'a> {
+    config: Config,
+    replica_id: ReplicaId,
+    window_index: WindowIndex,
+    original_data: OriginalData,
+    build_trees: bool,
+    retention: RetentionPolicy,
+    checkpoint_dir: Option<PathBuf>,
+    progress: Option<ProgressCallback<'a>>,
+    cancellation: Option<CancellationToken>,
+    key_cache: Option<KeyCache>,
+    padding: WindowPadding,
+    // `None` for `default_labeling_seed`.
+    seed_fn: Option<LabelingSeedFn>,
+    comm_d: bool,
+    spot_check: Option<SpotCheckConfig>,
+    validate_data: bool,
+    fuse_last_layer: bool,
+    stage_data: bool,
+    preemption: Option<PreemptionQueue>,
+    layer_digests: bool,
+    build_info: bool,
+}
 
-    const TEST_CONFIG: Config = Config {
-        k: 2,
-        num_nodes_window: 512,
-        degree_expander: 96,
-        degree_butterfly: 4,
-        num_expander_layers: 4,
-        num_butterfly_layers: 3,
-    };
-    const TEST_WINDOW_INDEX: usize = 1234567890;
-    const TEST_REPLICA_ID: ReplicaId = ReplicaId([123u8; 32]);
+impl<'a> SealerBuilder<'a> {
+    pub fn new(config: Config, input: SealerInput) -> Self {
+        Self::with_original_data(
+            config,
+            input.replica_id,
+            input.window_index,
+            OriginalData::Memory(input.original_data),
+        )
+    }
+
+    /// Seals the original data stored at `path`, see `Sealer::seal_from_file`.
+    pub fn from_file<P: AsRef<Path>>(
+        config: Config,
+        replica_id: ReplicaId,
+        window_index: WindowIndex,
+        path: P,
+    ) -> Self {
+        Self::with_original_data(
+            config,
+            replica_id,
+            window_index,
+            OriginalData::File(path.as_ref().to_path_buf()),
+        )
+    }
+
+    fn with_original_data(
+        config: Config,
+        replica_id: ReplicaId,
+        window_index: WindowIndex,
+        original_data: OriginalData,
+    ) -> Self {
+        Self {
+            config,
+            replica_id,
+            window_index,
+            original_data,
+            build_trees: false,
+            retention: RetentionPolicy::default(),
+            checkpoint_dir: None,
+            progress: None,
+            cancellation: None,
+            key_cache: None,
+            padding: WindowPadding::default(),
+            seed_fn: None,
+            comm_d: false,
+            spot_check: None,
+            validate_data: true,
+            fuse_last_layer: true,
+            stage_data: false,
+            preemption: None,
+            layer_digests: false,
+            build_info: false,
+        }
+    }
+
+    /// Build a tree over each layer, using the tree builder of the GPU context.
+    pub fn build_trees(mut self, build_trees: bool) -> Self {
+        self.build_trees = build_trees;
+        self
+    }
+
+    /// Which layers the yielded outputs keep, `RetentionPolicy::All` by default.
+    pub fn retention(mut self, retention: RetentionPolicy) -> Self {
+        self.retention = retention;
+        self
+    }
+
+    /// When disabled, key layers are left on the device only: the yielded outputs of all but
+    /// the replica layer carry an empty `base`. Same as `RetentionPolicy::LastOnly`.
+    pub fn retain_key_layers(self, retain_key_layers: bool) -> Self {
+        self.retention(if retain_key_layers {
+            RetentionPolicy::All
+        } else {
+            RetentionPolicy::LastOnly
+        })
+    }
+
+    /// Persist every key layer in `dir` as it is generated. If `dir` already holds layers of a
+    /// previous, interrupted run, sealing resumes from the latest one.
+    pub fn checkpoint_dir<P: AsRef<Path>>(mut self, dir: P) -> Self {
+        self.checkpoint_dir = Some(dir.as_ref().to_path_buf());
+        self
+    }
+
+    pub fn progress<F: FnMut(usize, usize) + 'a>(mut self, progress: F) -> Self {
+        self.progress = Some(Box::new(progress));
+        self
+    }
+
+    /// Abort sealing between layers once `token` is cancelled.
+    pub fn cancellation(mut self, token: CancellationToken) -> Self {
+        self.cancellation = Some(token);
+        self
+    }
+
+    /// Persist the key layers of the window in `key_cache`, for later unsealing. Keys are
+    /// cached by replica id, it cannot be combined with `seed_fn`.
+    pub fn key_cache(mut self, key_cache: KeyCache) -> Self {
+        self.key_cache = Some(key_cache);
+        self
+    }
+
+    /// How to handle original data shorter than a window, `WindowPadding::Strict` by default.
+    pub fn padding(mut self, padding: WindowPadding) -> Self {
+        self.padding = padding;
+        self
+    }
+
+    /// Seed the labels with `seed_fn` instead of the replica id, for test vectors only.
+    pub fn seed_fn(mut self, seed_fn: LabelingSeedFn) -> Self {
+        self.seed_fn = Some(seed_fn);
+        self
+    }
+
+    /// Compute the data commitment (CommD) of the original data on the device while combining
+    /// it, see `Sealer::comm_d`.
+    pub fn comm_d(mut self, comm_d: bool) -> Self {
+        self.comm_d = comm_d;
+        self
+    }
+
+    /// Verify random nodes of each key layer on the host as it is generated, failing with
+    /// `NSEError::SpotCheckFailed` on a mismatch, see `SpotCheckConfig`.
+    pub fn spot_check(mut self, spot_check: SpotCheckConfig) -> Self {
+        self.spot_check = Some(spot_check);
+        self
+    }
+
+    /// Check that all nodes of the original data are field elements, below 2^253 with
+    /// `EncodingMode::Xor`, before generating any layer (the default), failing with
+    /// `NSEError::InvalidDataNode` and the index of the first invalid node. Unchecked, invalid
+    /// nodes are combined into garbage, or panic when read from a file.
+    pub fn validate_data(mut self, validate_data: bool) -> Self {
+        self.validate_data = validate_data;
+        self
+    }
+
+    /// Label the last key layer and combine it with the original data in a single kernel (the
+    /// default), see `KeyGenerator::combine_last_layer`. It only applies to data in memory, when
+    /// the key is not needed on the host (`key_cache`, `spot_check`) and without `comm_d`.
+    pub fn fuse_last_layer(mut self, fuse_last_layer: bool) -> Self {
+        self.fuse_last_layer = fuse_last_layer;
+        self
+    }
+
+    /// Upload the original data to the device as soon as the sealer is built, on a transfer
+    /// queue of its own, so that the upload overlaps with labeling instead of stalling the final
+    /// combine, see `GPU::stage_data`. The data is then not kept on the host, but takes one more
+    /// layer of device memory, until the final combine or until the sealer is dropped. It only
+    /// applies to data in memory, and cannot be combined with `comm_d`.
+    pub fn stage_data(mut self, stage_data: bool) -> Self {
+        self.stage_data = stage_data;
+        self
+    }
+
+    /// Run the jobs of `queue` between layers and between the batches of the final combine, with
+    /// the window suspended meanwhile, see `PreemptionQueue`.
+    pub fn preemption(mut self, queue: PreemptionQueue) -> Self {
+        self.preemption = Some(queue);
+        self
+    }
+
+    /// Record the `Layer::digest` of every layer read back in the `SealReceipt`, which hashes
+    /// each of them on the host. Disabled by default, it is required by `SectorManifest`.
+    pub fn layer_digests(mut self, layer_digests: bool) -> Self {
+        self.layer_digests = layer_digests;
+        self
+    }
+
+    /// Record `build_info()` in the `SealReceipt`, which hashes every kernel source. Disabled by
+    /// default.
+    pub fn build_info(mut self, build_info: bool) -> Self {
+        self.build_info = build_info;
+        self
+    }
+
+    pub fn build(mut self, gpu: &'a mut GPU) -> NSEResult<Sealer<'a>> {
+        if self.retention == RetentionPolicy::EveryNth(0) {
+            return Err(NSEError::InvalidInput(
+                "Cannot retain every 0th layer!".into(),
+            ));
+        }
+        if self.key_cache.is_some() && self.seed_fn.is_some() {
+            return Err(NSEError::InvalidInput(
+                "Keys seeded with a custom seed_fn cannot be cached!".into(),
+            ));
+        }
+        if self.stage_data && self.comm_d {
+            return Err(NSEError::InvalidInput(
+                "Data committed with comm_d cannot be staged!".into(),
+            ));
+        }
+        if let Some(dir) = &self.checkpoint_dir {
+            fs::create_dir_all(dir)?;
+        }
+        let leaf_count = self.config.leaf_count();
+        let data_len = match &mut self.original_data {
+            OriginalData::Memory(data) => {
+                if self.validate_data {
+                    if let Some(index) = data.first_invalid_data_node(self.config.encoding_mode) {
+                        return Err(NSEError::InvalidDataNode(index));
+                    }
+                }
+                let data_len = data.0.len();
+                self.padding.apply(data, leaf_count)?;
+                data_len
+            }
+            OriginalData::File(path) => {
+                let bytes = fs::metadata(path)?.len() as usize;
+                if bytes % NODE_SIZE != 0 {
+                    return Err(NSEError::InvalidInput(format!(
+                        "Original data file has {} bytes, not a whole number of nodes!",
+                        bytes
+                    )));
+                }
+                self.padding.check(bytes / NODE_SIZE, leaf_count)?;
+                // Empty files cannot be mapped.
+                if self.validate_data && bytes > 0 {
+                    let mmap = unsafe { Mmap::map(&File::open(path)?)? };
+                    if let Some(index) =
+                        first_invalid_encoded_node(&mmap, self.config.encoding_mode)
+                    {
+                        return Err(NSEError::InvalidDataNode(index));
+                    }
+                }
+                bytes / NODE_SIZE
+            }
+            // Only built sealers stage their data.
+            OriginalData::Staged => unreachable!(),
+        };
+        let mut key_generator =
+            KeyGenerator::new(self.config, self.replica_id, self.window_index, gpu)?
+                .spot_check(self.spot_check);
+        if let Some(seed_fn) = self.seed_fn {
+            key_generator = key_generator.seed_fn(seed_fn)?;
+        }
+        let original_data = match self.original_data {
+            OriginalData::Memory(data) if self.stage_data => {
+                key_generator.gpu.stage_data(data)?;
+                OriginalData::Staged
+            }
+            original_data => original_data,
+        };
+        let mut sealer = Sealer {
+            original_data,
+            key_generator,
+            build_trees: self.build_trees,
+            retention: self.retention,
+            checkpoint_dir: self.checkpoint_dir,
+            progress: self.progress,
+            cancellation: self.cancellation,
+            cancelled: false,
+            layer_sink: None,
+            gpu_lock: None,
+            device_lock: None,
+            stats: Vec::new(),
+            digests: Vec::new(),
+            layer_digests: self.layer_digests,
+            record_build_info: self.build_info,
+            key_cache: self.key_cache,
+            data_len,
+            padding: self.padding,
+            commitment: if self.comm_d {
+                Some(DataCommitment::new(leaf_count)?)
+            } else {
+                None
+            },
+            fuse_last_layer: self.fuse_last_layer,
+            preemption: self.preemption,
+        };
+        if let Some(dir) = sealer.checkpoint_dir.clone() {
+            sealer.resume_from_checkpoint(&dir)?;
+        }
+        Ok(sealer)
+    }
+}
+
+impl<'a> Iterator for Sealer<'a> {
+    type Item = NSEResult<LayerOutput>;
+
+    /// Returns successive layers, starting with mask layer, and ending with sealed replica layer.
+    /// If the sealer has been cancelled, `NSEError::Cancelled` is returned once, and then `None`.
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.cancelled || self.key_generator.layers_remaining() == 0 {
+            return None;
+        }
+        if let Some(token) = &self.cancellation {
+            if let Err(e) = token.check() {
+                self.cancelled = true;
+                return Some(Err(e));
+            }
+        }
+        if let Err(e) = self
+            .lock_device()
+            .and_then(|_| self.cooperate_with_provers())
+            .and_then(|_| self.preempt())
+        {
+            return Some(Err(e));
+        }
+        self.key_generator.gpu.take_timings(); // Discard device work not related to this layer
+        let layer_index = self.key_generator.current_layer_index() + 1;
+        if self.fuses_replica(layer_index) {
+            let replica = self.fused_replica();
+            return Some(self.process_layer(replica, true));
+        }
+        self.key_generator.read_back = self.needs_key_layer(layer_index);
+        let next_key_layer = self.key_generator.next()?;
+        Some(self.process_layer(next_key_layer, false))
+    }
+}
+
+// A sealer failing, cancelled or dropped before its final combine leaves its data staged on
+// the GPU, for the next sealer to stage its own.
+impl<'a> Drop for Sealer<'a> {
+    fn drop(&mut self) {
+        if let OriginalData::Staged = self.original_data {
+            if let Err(e) = self.key_generator.gpu.discard_staged_data() {
+                warn!("Cannot discard the staged data: {}", e);
+            }
+        }
+    }
+}
+
+impl<'a> ExactSizeIterator for Sealer<'a> {
+    fn len(&self) -> usize {
+        self.key_generator.len()
+    }
+}
+
+/// Yields the layers of a `Sealer` along with their index and kind, see `Sealer::labeled`.
+pub struct LabeledSealer<'a>(Sealer<'a>);
+
+impl<'a> Iterator for LabeledSealer<'a> {
+    type Item = NSEResult<LabeledLayer<LayerOutput>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let output = self.0.next()?;
+        Some(output.map(|layer| {
+            let stats = self.0.stats.last().unwrap(); // Pushed along with every output
+            LabeledLayer {
+                index: stats.layer_index,
+                kind: stats.kind,
+                layer,
+            }
+        }))
+    }
+}
+
+pub struct Unsealer<'a> {
+    key_generator: KeyGenerator<'a>,
+}
+
+impl<'a> Unsealer<'a> {
+    pub fn new(
+        config: Config,
+        replica_id: ReplicaId,
+        window_index: WindowIndex,
+        gpu: &'a mut GPU,
+    ) -> NSEResult<Self> {
+        Ok(Self {
+            key_generator: KeyGenerator::new(config, replica_id, window_index, gpu)?,
+        })
+    }
+
+    /// Creates an unsealer whose final key layer is loaded from `key_cache` if present, or
+    /// generated and stored in `key_cache` otherwise.
+    pub fn with_key_cache(
+        config: Config,
+        replica_id: ReplicaId,
+        window_index: WindowIndex,
+        gpu: &'a mut GPU,
+        key_cache: &KeyCache,
+    ) -> NSEResult<Self> {
+        let mut unsealer = Self::new(config, replica_id, window_index, gpu)?;
+        let key_layer_index = unsealer.key_generator.len();
+        match key_cache.load(&config, replica_id, window_index, key_layer_index)? {
+            Some(key_layer) => {
+                info!("Key layer found in cache, skipping key generation.");
+                unsealer.key_generator.load_key_layer(&key_layer)?;
+            }
+            None => {
+                let mut key_layer = Layer::default();
+                while let Some(layer) = unsealer.key_generator.next() {
+                    key_layer = layer?;
+                }
+                key_cache.store(
+                    &config,
+                    replica_id,
+                    window_index,
+                    key_layer_index,
+                    &key_layer,
+                )?;
+            }
+        }
+        Ok(unsealer)
+    }
+
+    pub fn unseal_range(&mut self, offset: usize, sealed_data: &[Node]) -> NSEResult<Vec<Node>> {
+        while let Some(layer) = self.key_generator.next() {
+            layer?;
+        }
+
+        self.key_generator
+            .combine_segment(offset, sealed_data, CombineMode::Decode)
+    }
+
+    /// Seed the labels with `seed_fn` instead of the replica id, for test vectors only. Fails
+    /// if any key layer was generated, e.g. for an unsealer created `with_key_cache`, whose
+    /// cached keys are always seeded with the replica id.
+    pub fn seed_fn(mut self, seed_fn: LabelingSeedFn) -> NSEResult<Self> {
+        self.key_generator = self.key_generator.seed_fn(seed_fn)?;
+        Ok(self)
+    }
+
+    /// Keeps the key layers on the device, only the unsealed data is transferred back to the
+    /// host, which roughly halves the traffic of unsealing a window. Must be called before any
+    /// key layer is generated.
+    pub fn decode_only(mut self) -> Self {
+        self.key_generator = self.key_generator.read_back(false);
+        self
+    }
+
+    /// Same as `unseal_range`, reporting the result of each batch to `on_batch`, see
+    /// `NarrowStackedExpander::combine_segment_batches`.
+    pub fn unseal_range_batches<F: FnMut(Range<usize>, NSEResult<Vec<Node>>)>(
+        &mut self,
+        offset: usize,
+        sealed_data: &[Node],
+        mut on_batch: F,
+    ) -> NSEResult<()> {
+        while let Some(layer) = self.key_generator.next() {
+            layer?;
+        }
+        self.key_generator.gpu.combine_segment_batches(
+            offset,
+            sealed_data,
+            CombineMode::Decode,
+            &mut on_batch,
+        )
+    }
+
+    // Gives the GPU back, e.g. to unseal another window.
+    pub(crate) fn into_gpu(self) -> &'a mut GPU {
+        self.key_generator.gpu
+    }
+
+    pub fn unseal_layer(&mut self, sealed: Layer) -> NSEResult<Layer> {
+        Ok(Layer(self.unseal_range(0, &sealed.0)?))
+    }
+
+    /// Unseals the nodes in `range` of the window replica stored at `replica_path`, and writes
+    /// the original nodes to `out_path`. Both files hold nodes in their byte representation
+    /// (see `From<&Layer> for Vec<u8>`), the replica file starting with the first node of the
+    /// window. Data is streamed through the GPU in batches of `combine_batch_size` nodes.
+    pub fn unseal_file<P: AsRef<Path>, Q: AsRef<Path>>(
+        &mut self,
+        replica_path: P,
+        out_path: Q,
+        range: Range<usize>,
+    ) -> NSEResult<()> {
+        let leaf_count = self.key_generator.config().leaf_count();
+        if range.start > range.end {
+            return Err(NSEError::InvalidRange {
+                offset: range.start,
+                len: 0,
+                leaf_count,
+            });
+        }
+        segment_range(range.start, range.len(), leaf_count)?;
+
+        let replica = File::open(replica_path)?;
+        if range.start == range.end {
+            File::create(out_path)?;
+            return Ok(());
+        }
+        if (replica.metadata()?.len() as usize) < range.end * NODE_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "Replica file is shorter than the requested range!",
+            )
+            .into());
+        }
+        let replica = unsafe { Mmap::map(&replica)? };
+        // Checked before `out_path` is truncated, which may hold a previous output.
+        let bytes = &replica[range.start * NODE_SIZE..range.end * NODE_SIZE];
+        if let Some(index) = first_invalid_encoded_node(bytes, EncodingMode::FieldAdd) {
+            return Err(NSEError::InvalidInput(format!(
+                "Node {} of the replica is not a field element!",
+                range.start + index
+            )));
+        }
+        let mut out = BufWriter::new(File::create(out_path)?);
+
+        let batch_size = self.key_generator.gpu.combine_batch_size();
+        let mut offset = range.start;
+        while offset < range.end {
+            let end = std::cmp::min(offset + batch_size, range.end);
+            let sealed = Layer::try_from_bytes(&replica[offset * NODE_SIZE..end * NODE_SIZE])?;
+            let unsealed = Layer(self.unseal_range(offset, &sealed.0)?);
+            out.write_all(&Vec::<u8>::from(&unsealed))?;
+            offset = end;
+        }
+        out.flush()?;
+        Ok(())
+    }
+}
+
+/// Generates the key layers of a window one at a time, as an iterator. `Sealer` and `Unsealer`
+/// are built on top of it; it can also be driven directly, e.g. to regenerate the specific
+/// layers a proof is challenged on.
+///
+/// Layer indices are 1-based: the mask layer is layer 1, followed by the expander layers and
+/// then the butterfly layers. The last butterfly layer is the key combined with the data.
+pub struct KeyGenerator<'a> {
+    replica_id: ReplicaId,
+    window_index: WindowIndex,
+    state: LayerState,
+    seed_fn: LabelingSeedFn,
+    spot_check: Option<SpotCheckConfig>,
+    // Last layer, kept on the host to spot check the next one.
+    previous_layer: Option<Layer>,
+    read_back: bool,
+    digests: bool,
+    // Device digest of the last layer generated, if `digests`.
+    last_digest: Option<Sha256Domain>,
+    gpu: &'a mut GPU,
+}
+
+impl<'a> KeyGenerator<'a> {
+    /// `config` must be the one `gpu` was created with.
+    pub fn new(
+        config: Config,
+        replica_id: ReplicaId,
+        window_index: WindowIndex,
+        gpu: &'a mut GPU,
+    ) -> NSEResult<Self> {
+        if config.leaf_count() != gpu.leaf_count() {
+            return Err(NSEError::InvalidInput(format!(
+                "Config has {} nodes per window, GPU has {}!",
+                config.leaf_count(),
+                gpu.leaf_count()
+            )));
+        }
+        window_index.validate(&config)?;
+        Ok(Self {
+            replica_id,
+            window_index,
+            state: LayerState::Mask,
+            seed_fn: default_labeling_seed,
+            spot_check: None,
+            previous_layer: None,
+            read_back: true,
+            digests: false,
+            last_digest: None,
+            gpu,
+        })
+    }
+
+    /// Seed the labels with `seed_fn` instead of the replica id, for test vectors only. Fails
+    /// if a layer was already generated or loaded, whose labels `seed_fn` would not apply to.
+    pub fn seed_fn(mut self, seed_fn: LabelingSeedFn) -> NSEResult<Self> {
+        if self.state != LayerState::Mask {
+            return Err(NSEError::InvalidInput(
+                "The labeling seed must be set before any layer is generated!".into(),
+            ));
+        }
+        self.seed_fn = seed_fn;
+        Ok(self)
+    }
+
+    /// Verify random nodes of the generated layers on the host, see `SpotCheckConfig`.
+    pub fn spot_check(mut self, spot_check: Option<SpotCheckConfig>) -> Self {
+        self.spot_check = spot_check;
+        self
+    }
+
+    /// Whether generated layers are read back to the host (the default). Without it, layers
+    /// stay on the device and the iterator yields empty layers, unless they are spot checked.
+    pub fn read_back(mut self, read_back: bool) -> Self {
+        self.read_back = read_back;
+        self
+    }
+
+    /// Compute the digest of each layer on the device as it is generated, see
+    /// `GPU::layer_digest` and `last_digest`.
+    pub fn digests(mut self, digests: bool) -> Self {
+        self.digests = digests;
+        self
+    }
+
+    /// Digest of the layer last yielded, if enabled with `digests`. It doesn't require the
+    /// layer to be read back, see `read_back`.
+    pub fn last_digest(&self) -> Option<Sha256Domain> {
+        self.last_digest
+    }
+
+    // Replica id the labels of the window are seeded with.
+    fn seed(&self) -> ReplicaId {
+        (self.seed_fn)(self.replica_id, self.window_index)
+    }
+
+    /// Resumes generation after a known layer. `target_layer_index` is 0-based, i.e. the next
+    /// generated layer has index `target_layer_index + 2`.
+    pub fn seek(&mut self, target_layer_index: usize, target_layer_data: &Layer) -> NSEResult<()> {
+        self.state = LayerState::after(&self.config(), target_layer_index + 1)?;
+        if self.spot_check.is_some() {
+            self.previous_layer = Some(target_layer_data.clone());
+        }
+        self.gpu.push_layer(&target_layer_data)
+    }
+
+    // Load the final key layer, skipping the generation of all layers.
+    fn load_key_layer(&mut self, key_layer: &Layer) -> NSEResult<()> {
+        self.seek(self.len() - 1, key_layer)?;
+        self.finalize()
+    }
+
+    fn config(&self) -> Config {
+        self.gpu.config
+    }
+
+    /// Returns the configuration the layers are generated with.
+    pub fn peek_config(&self) -> Config {
+        self.config()
+    }
+
+    /// The layer generated next, see `LayerState`.
+    pub fn state(&self) -> LayerState {
+        self.state
+    }
+
+    /// Resumes generation at `state`. `previous_layer` is the last layer generated before it,
+    /// ignored when resuming at `LayerState::Mask`; resuming at `LayerState::Done` loads it as
+    /// the final key layer.
+    pub fn resume(&mut self, state: LayerState, previous_layer: &Layer) -> NSEResult<()> {
+        let config = self.config();
+        if !state.is_valid(&config) {
+            return Err(NSEError::InvalidInput(format!(
+                "{:?} is not a state of {:?}!",
+                state, config
+            )));
+        }
+        match state {
+            LayerState::Mask => {
+                self.state = state;
+                self.previous_layer = None;
+                Ok(())
+            }
+            LayerState::Done => self.load_key_layer(previous_layer),
+            _ => self.seek(state.completed(&config) - 1, previous_layer),
+        }
+    }
+
+    /// Index of the last generated (or seeked) layer, 0 if no layer has been generated yet.
+    pub fn current_layer_index(&self) -> usize {
+        self.state.completed(&self.config())
+    }
+
+    /// Kind of the last generated (or seeked) layer, `None` if no layer has been generated yet.
+    pub fn current_layer_kind(&self) -> Option<LayerKind> {
+        match self.current_layer_index() {
+            0 => None,
+            index => Some(self.layer_kind(index)),
+        }
+    }
+
+    pub fn replica_id(&self) -> ReplicaId {
+        self.replica_id
+    }
+
+    pub fn window_index(&self) -> WindowIndex {
+        self.window_index
+    }
+
+    fn layers_remaining(&self) -> usize {
+        self.len() - self.current_layer_index()
+    }
+
+    // Spot checks need the layers on the host.
+    fn reads_back(&self) -> bool {
+        self.read_back || self.spot_check.is_some()
+    }
+
+    // Generate maske layer on GPU from seeds.
+    fn generate_mask_layer(&mut self) -> NSEResult<Layer> {
+        let seed = self.seed();
+        if !self.reads_back() {
+            self.gpu.label_mask_layer(seed, self.window_index)?;
+            return Ok(Layer::default());
+        }
+        self.gpu.generate_mask_layer(seed, self.window_index)
+    }
+
+    // Generate expander layer on GPU, using previous layer already loaded.
+    fn generate_expander_layer(&mut self, layer_index: usize) -> NSEResult<Layer> {
+        let seed = self.seed();
+        if !self.reads_back() {
+            self.gpu
+                .label_expander_layer(seed, self.window_index, layer_index)?;
+            return Ok(Layer::default());
+        }
+        self.gpu
+            .generate_expander_layer(seed, self.window_index, layer_index)
+    }
+    // Generate butterfly layer on GPU, using previous layer already loaded.
+    fn generate_butterfly_layer(&mut self, layer_index: usize) -> NSEResult<Layer> {
+        let seed = self.seed();
+        if !self.reads_back() {
+            self.gpu
+                .label_butterfly_layer(seed, self.window_index, layer_index)?;
+            return Ok(Layer::default());
+        }
+        self.gpu
+            .generate_butterfly_layer(seed, self.window_index, layer_index)
+    }
+
+    /// Reads the given nodes of layer `layer_index` from device memory, in Montgomery form like
+    /// generated layers. Only the current layer is kept on the device, so nodes have to be
+    /// extracted right after their layer has been generated.
+    pub fn extract_nodes(
+        &mut self,
+        layer_index: usize,
+        node_indices: &[usize],
+    ) -> NSEResult<Vec<Node>> {
+        self.check_on_device(layer_index)?;
+        self.gpu.extract_nodes(node_indices)
+    }
+
+    /// Reads the parents of the given nodes of the next layer from device memory, i.e. the
+    /// nodes of the current layer these nodes are labeled from. See `GPU::extract_parents` for
+    /// their layout. Parents have to be extracted before the next layer is generated.
+    pub fn extract_parents(&mut self, node_indices: &[usize]) -> NSEResult<Vec<Node>> {
+        let next_layer_index = self.state.layer_index(&self.config()).ok_or_else(|| {
+            NSEError::InvalidInput("The last layer is not a parent layer!".into())
+        })?;
+        self.gpu.extract_parents(next_layer_index, node_indices)
+    }
+
+    /// Labels of the given nodes of layer `layer_index`, e.g. the challenged nodes of a proof,
+    /// computed from their parents, the parents of their parents and so on, instead of whole
+    /// layers. Down to the first layer where too many nodes are needed (and always for an AES
+    /// mask layer), whole layers are labeled on the device, the nodes needed above it are
+    /// labeled on the host. Layers on the device are overwritten: the generator starts over
+    /// from the mask layer afterwards.
+    pub fn label_subset(&mut self, layer_index: usize, nodes: &[usize]) -> NSEResult<Vec<Node>> {
+        let config = self.config();
+        if layer_index == 0 || layer_index > config.num_layers() {
+            return Err(NSEError::InvalidInput(format!(
+                "Layer {} is out of range, there are {} layers!",
+                layer_index,
+                config.num_layers()
+            )));
+        }
+        if let Some(node) = nodes.iter().find(|&&n| n >= config.num_nodes_window) {
+            return Err(NSEError::InvalidInput(format!(
+                "Node {} is out of range, windows have {} nodes!",
+                node, config.num_nodes_window
+            )));
+        }
+        // Nodes needed of layers `layer_index`, `layer_index - 1`, ... down to `device_layer`.
+        let mut needed = vec![nodes.iter().cloned().collect::<BTreeSet<_>>()];
+        let mut device_layer = 0;
+        for l in (1..=layer_index).rev() {
+            let set = needed.last().expect("There is a set for each layer");
+            let dense = set.len() * SUBSET_HOST_FRACTION > config.num_nodes_window;
+            if dense || (l == 1 && config.mask_prf != MaskPrf::Sha256) {
+                device_layer = l;
+                break;
+            }
+            if l > 1 {
+                let parents = set
+                    .iter()
+                    .flat_map(|&node| label_parents(&config, l, node))
+                    .collect();
+                needed.push(parents);
+            }
+        }
+
+        let mut labels = HashMap::new();
+        if device_layer > 0 {
+            let indices = needed
+                .pop()
+                .expect("There is a set for each layer")
+                .into_iter()
+                .collect::<Vec<_>>();
+            let values = self.label_on_device(device_layer, &indices)?;
+            labels.extend(indices.into_iter().zip(values));
+        }
+        let seed = self.seed();
+        for (l, set) in (device_layer + 1..=layer_index).zip(needed.into_iter().rev()) {
+            labels = set
+                .into_iter()
+                .map(|node| {
+                    let label = spot_check::label_node_with(
+                        &config,
+                        seed,
+                        self.window_index,
+                        l,
+                        node,
+                        |parent| labels[&parent],
+                    )?;
+                    Ok((node, label))
+                })
+                .collect::<NSEResult<_>>()?;
+        }
+        Ok(nodes.iter().map(|node| labels[node]).collect())
+    }
+
+    // Labels the layers up to `layer_index` on the device and extracts the given nodes of the
+    // last one, then starts over from the mask layer.
+    fn label_on_device(
+        &mut self,
+        layer_index: usize,
+        node_indices: &[usize],
+    ) -> NSEResult<Vec<Node>> {
+        let (read_back, digests) = (self.read_back, self.digests);
+        let spot_check = self.spot_check.take();
+        self.read_back = false;
+        self.digests = false;
+        self.resume(LayerState::Mask, &Layer::default())?;
+        let mut result = Ok(());
+        while self.current_layer_index() < layer_index {
+            if let Some(Err(e)) = self.next() {
+                result = Err(e);
+                break;
+            }
+        }
+        let nodes = result.and_then(|_| self.extract_nodes(layer_index, node_indices));
+        self.read_back = read_back;
+        self.digests = digests;
+        self.spot_check = spot_check;
+        self.resume(LayerState::Mask, &Layer::default())?;
+        nodes
+    }
+
+    fn check_on_device(&self, layer_index: usize) -> NSEResult<()> {
+        let current_layer_index = self.current_layer_index();
+        if layer_index == 0 || layer_index != current_layer_index {
+            return Err(NSEError::InvalidInput(format!(
+                "Layer {} is not on the device, current layer is {}!",
+                layer_index, current_layer_index
+            )));
+        }
+        Ok(())
+    }
+
+    /// Generates the last key layer and combines it with `data`, a whole window, in a single
+    /// kernel, see `GPU::label_butterfly_and_combine`. The last layer must be the next one. The
+    /// key is then not on the device: later combines would use the previous layer instead.
+    pub fn combine_last_layer(
+        &mut self,
+        data: &Layer,
+        mode: impl Into<CombineMode>,
+    ) -> NSEResult<Layer> {
+        let layer_index = self.next_last_layer()?;
+        let replica = self.gpu.label_butterfly_and_combine(
+            self.seed(),
+            self.window_index,
+            layer_index,
+            &data.0,
+            mode,
+        )?;
+        self.state = LayerState::Done;
+        Ok(Layer(replica))
+    }
+
+    /// Same as `combine_last_layer`, with the data staged on the device, see `GPU::stage_data`.
+    pub fn combine_last_layer_staged(&mut self, mode: impl Into<CombineMode>) -> NSEResult<Layer> {
+        let layer_index = self.next_last_layer()?;
+        let replica = self.gpu.label_butterfly_and_combine_staged(
+            self.seed(),
+            self.window_index,
+            layer_index,
+            mode,
+        )?;
+        self.state = LayerState::Done;
+        Ok(Layer(replica))
+    }
+
+    // Index of the last layer, which must be the next one.
+    fn next_last_layer(&self) -> NSEResult<usize> {
+        let config = self.config();
+        if self.state.layer_index(&config) != Some(config.num_layers()) {
+            return Err(NSEError::InvalidInput(
+                "The last layer is not the next one!".into(),
+            ));
+        }
+        Ok(config.num_layers())
+    }
+
+    /// Combines `layer` with the key. Only valid once all layers have been generated.
+    pub fn combine_layer(
+        &mut self,
+        layer: &Layer,
+        mode: impl Into<CombineMode>,
+    ) -> NSEResult<Layer> {
+        self.gpu.combine_layer(layer, mode.into())
+    }
+
+    /// Same as `combine_layer`, with the data staged on the device, see `GPU::stage_data`.
+    pub fn combine_staged(&mut self, mode: impl Into<CombineMode>) -> NSEResult<Layer> {
+        self.gpu.combine_staged(mode)
+    }
+
+    fn finalize(&mut self) -> NSEResult<()> {
+        self.gpu.finalize()
+    }
+
+    /// Same as `combine_layer`, for the nodes of a layer starting at `offset`.
+    pub fn combine_segment(
+        &mut self,
+        offset: usize,
+        segment: &[Node],
+        mode: impl Into<CombineMode>,
+    ) -> NSEResult<Vec<Node>> {
+        self.gpu.combine_segment(offset, segment, mode.into())
+    }
+
+    /// Same as `combine_segment`, writing the result into `out`, see
+    /// `NarrowStackedExpander::combine_segment_into`.
+    pub fn combine_segment_into(
+        &mut self,
+        offset: usize,
+        segment: &[Node],
+        mode: impl Into<CombineMode>,
+        out: &mut [Node],
+    ) -> NSEResult<()> {
+        self.gpu
+            .combine_segment_into(offset, segment, mode.into(), out)
+    }
+
+    /// Same as `combine_segment` encoding `segment`, see `GPU::combine_segment_with_commitment`.
+    pub fn combine_segment_with_commitment(
+        &mut self,
+        offset: usize,
+        segment: &[Node],
+        commitment: &mut DataCommitment,
+    ) -> NSEResult<Vec<Node>> {
+        self.gpu
+            .combine_segment_with_commitment(offset, segment, commitment)
+    }
+
+    /// Same as `combine_segment` for several segments, in one submission to the device, see
+    /// `NarrowStackedExpander::combine_batches`.
+    pub fn combine_batches(
+        &mut self,
+        batches: Vec<(usize, &[Node], CombineMode)>,
+    ) -> NSEResult<Vec<Vec<Node>>> {
+        self.gpu.combine_batches(batches)
+    }
+
+    /// Turns the generator into an iterator yielding each key layer along with its index and kind.
+    pub fn labeled(self) -> LabeledKeyGenerator<'a> {
+        LabeledKeyGenerator(self)
+    }
+
+    /// Kind of the layer with the given 1-based index.
+    pub fn layer_kind(&self, layer_index: usize) -> LayerKind {
+        if layer_index == 1 {
+            LayerKind::Mask
+        } else if layer_index <= self.config().num_expander_layers {
+            LayerKind::Expander
+        } else {
+            LayerKind::Butterfly
+        }
+    }
+
+    // Spot checks the layer just generated, if enabled.
+    fn check_layer(&mut self, layer: Layer) -> NSEResult<Layer> {
+        if let Some(spot_check) = self.spot_check {
+            spot_check_layer(
+                &spot_check,
+                &self.config(),
+                self.seed(),
+                self.window_index,
+                self.current_layer_index(),
+                self.previous_layer.as_ref(),
+                &layer,
+            )?;
+            self.previous_layer = Some(layer.clone());
+        }
+        Ok(layer)
+    }
+
+    fn generate_next_layer(&mut self) -> Option<NSEResult<Layer>> {
+        let config = self.config();
+        let state = self.state;
+        // `None` once all layers are generated.
+        let layer_index = state.layer_index(&config)?;
+        self.state = state.next(&config);
+        Some(match state {
+            LayerState::Mask => self.generate_mask_layer(),
+            // Expander and butterfly layers are labeled from the previous layer, already loaded.
+            LayerState::Expander(_) => self.generate_expander_layer(layer_index),
+            LayerState::Butterfly(_) => self.generate_butterfly_layer(layer_index).and_then(|l| {
+                // The last butterfly layer is the key.
+                if self.state == LayerState::Done {
+                    self.finalize()?;
+                }
+                Ok(l)
+            }),
+            LayerState::Done => unreachable!(),
+        })
+    }
+}
+
+impl<'a> Iterator for KeyGenerator<'a> {
+    type Item = NSEResult<Layer>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let layer = self.generate_next_layer()?;
+        self.last_digest = None;
+        Some(layer.and_then(|l| {
+            if self.digests {
+                self.last_digest = Some(self.gpu.layer_digest()?);
+            }
+            self.check_layer(l)
+        }))
+    }
+}
+
+impl<'a> ExactSizeIterator for KeyGenerator<'a> {
+    fn len(&self) -> usize {
+        self.config().num_layers()
+    }
+}
+
+/// Yields the key layers of a `KeyGenerator` along with their index and kind, see
+/// `KeyGenerator::labeled`.
+pub struct LabeledKeyGenerator<'a>(KeyGenerator<'a>);
+
+impl<'a> Iterator for LabeledKeyGenerator<'a> {
+    type Item = NSEResult<LabeledLayer>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let layer = self.0.next()?;
+        let index = self.0.current_layer_index();
+        let kind = self.0.layer_kind(index);
+        Some(layer.map(|layer| LabeledLayer { index, kind, layer }))
+    }
+}
+
+/// Config of the tests of most modules: a window small enough to be sealed in a few
+/// milliseconds, with layers of every kind.
+#[cfg(test)]
+pub(crate) const TEST_CONFIG: Config = Config {
+    k: 2,
+    num_nodes_window: 512,
+    degree_expander: 96,
+    degree_butterfly: 4,
+    num_expander_layers: 4,
+    num_butterfly_layers: 3,
+    encoding_mode: EncodingMode::FieldAdd,
+    domain_tags: DomainTags::UNTAGGED,
+    mask_prf: MaskPrf::Sha256,
+};
+
+/// Same as `TEST_CONFIG` with fewer and narrower layers.
+#[cfg(test)]
+pub(crate) const SHALLOW_TEST_CONFIG: Config = Config {
+    degree_expander: 12,
+    num_expander_layers: 3,
+    num_butterfly_layers: 2,
+    ..TEST_CONFIG
+};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ff::PrimeField;
+    use paired::bls12_381::{Fr, FrRepr};
+
+    const TEST_WINDOW_INDEX: WindowIndex = WindowIndex(1234567890);
+    const TEST_REPLICA_ID: ReplicaId = ReplicaId([123u8; 32]);
+
+    pub fn incrementing_layer(start: usize, count: usize) -> Layer {
+        let mut layer = Layer::sequential(start + count);
+        layer.0.drain(..start);
+        layer
+    }
+
+    #[test]
+    fn test_config_helpers() {
+        assert_eq!(512, TEST_CONFIG.leaf_count());
+        assert_eq!(512 * 32, TEST_CONFIG.window_byte_len());
+        assert_eq!(7, TEST_CONFIG.num_layers());
+    }
+
+    #[test]
+    fn test_config_describe() {
+        let description = TEST_CONFIG.describe();
+        assert!(description.contains("nodes per window: 512"));
+        assert!(description.contains("window size: 16384 bytes"));
+        assert!(description.contains("layers: 7 (4 expander"));
+        assert!(description.contains("nodes labeled per window: 3584"));
+        assert!(description.contains("device memory: at least 32768 bytes, 229376 with cached"));
+        assert!(description.contains("replica size: 16384 bytes"));
+
+        let huge = Config {
+            num_nodes_window: std::usize::MAX,
+            num_expander_layers: std::usize::MAX,
+            ..TEST_CONFIG
+        };
+        let description = huge.describe();
+        assert!(!description.contains("device memory"));
+        match huge.validate() {
+            Err(NSEError::InvalidInput(msg)) => {
+                assert!(msg.starts_with("Window size must be"));
+                assert!(msg.contains(&description));
+            }
+            other => panic!("Unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_config_encoding() {
+        assert!(TEST_CONFIG.validate().is_ok());
+        let bytes = TEST_CONFIG.to_bytes();
+        assert_eq!(TEST_CONFIG, Config::from_bytes(&bytes).unwrap());
+        assert!(Config::from_bytes(&bytes[1..]).is_err());
+        let mut bad_mode = bytes;
+        bad_mode[CONFIG_BYTE_LEN - 1] = 2;
+        assert!(Config::from_bytes(&bad_mode).is_err());
+        let tagged = TEST_CONFIG.with_domain_tags(DomainTags {
+            mask: 1,
+            expander: 2,
+            butterfly: 0xdead_beef,
+        });
+        assert_ne!(bytes[..], tagged.to_bytes()[..]);
+        assert_eq!(tagged, Config::from_bytes(&tagged.to_bytes()).unwrap());
+        assert_eq!(TEST_CONFIG.fingerprint(), TEST_CONFIG.fingerprint());
+        assert_ne!(TEST_CONFIG.fingerprint(), tagged.fingerprint());
+        let aes = Config {
+            mask_prf: MaskPrf::Aes256Ctr,
+            ..TEST_CONFIG
+        };
+        assert_eq!(
+            bytes[..CONFIG_BYTE_LEN - 1],
+            aes.to_bytes()[..CONFIG_BYTE_LEN - 1]
+        );
+        assert_eq!(aes, Config::from_bytes(&aes.to_bytes()).unwrap());
+        bad_mode[CONFIG_BYTE_LEN - 1] = 0x20;
+        assert!(Config::from_bytes(&bad_mode).is_err());
+        assert!(Config {
+            num_nodes_window: 500,
+            ..TEST_CONFIG
+        }
+        .validate()
+        .is_err());
+        for &k in [MIN_K, MAX_K].iter() {
+            assert!(Config { k, ..TEST_CONFIG }.validate().is_ok());
+        }
+        for &k in [0, 3, 2 * MAX_K].iter() {
+            assert!(Config { k, ..TEST_CONFIG }.validate().is_err());
+        }
+        // Expander parents of 9 bits, not a whole number of bytes.
+        assert!(Config {
+            num_nodes_window: 1 << 10,
+            ..TEST_CONFIG
+        }
+        .validate()
+        .is_ok());
+    }
+
+    #[test]
+    fn test_layer_encoding() {
+        let layer = incrementing_layer(123, TEST_CONFIG.num_nodes_window);
+        let bytes = Vec::<u8>::from(&layer);
+        assert_eq!(123, bytes[0]);
+        assert!(bytes[1..NODE_SIZE].iter().all(|b| *b == 0));
+        assert_eq!(layer, Layer::try_from(&bytes).unwrap());
+        assert_eq!(
+            Sha256Domain([
+                59, 20, 76, 116, 56, 254, 70, 17, 62, 158, 3, 194, 55, 90, 248, 4, 130, 231, 198,
+                45, 83, 91, 230, 138, 109, 92, 60, 203, 147, 16, 13, 130
+            ]),
+            layer.digest()
+        );
+        assert_eq!(None, Node::from_le_bytes(&[0xff; NODE_SIZE]));
+        assert_eq!(layer, Layer::try_from_bytes(&bytes).unwrap());
+        assert!(Layer::try_from_bytes(&bytes[1..]).is_err());
+        assert!(Layer::try_from(&bytes[..bytes.len() - 1]).is_err());
+        assert!(Layer::try_from_bytes(&[0xff; NODE_SIZE]).is_err());
+    }
+
+    #[test]
+    fn test_layer_diff() {
+        let layer = incrementing_layer(123, 16);
+        let mut other = layer.clone();
+        for &i in [2, 5, 11].iter() {
+            other.0[i] = Node::random(&mut rand::thread_rng());
+        }
+        assert_eq!(
+            vec![(2, layer.0[2], other.0[2]), (5, layer.0[5], other.0[5])],
+            layer.diff(&other, 2)
+        );
+        assert_eq!(3, layer.diff(&other, std::usize::MAX).len());
+        assert!(layer.diff(&layer, std::usize::MAX).is_empty());
+
+        let sequential = Layer::sequential(16);
+        let stats = sequential.content_stats().unwrap();
+        assert_eq!(1, stats.zero_nodes);
+        assert_eq!(0, stats.repeated_nodes);
+        assert_eq!(sequential.0[0], stats.min);
+        assert_eq!(sequential.0[15], stats.max);
+        assert_eq!(sequential.tree_digest().unwrap(), stats.digest);
+        let stats = Layer(vec![layer.0[3]; 16]).content_stats().unwrap();
+        assert_eq!((0, 15), (stats.zero_nodes, stats.repeated_nodes));
+        assert!(Layer(Vec::new()).content_stats().is_err());
+
+        assert!(layer.eq_constant_time(&layer.clone()));
+        assert!(!layer.eq_constant_time(&other));
+        assert!(!layer.eq_constant_time(&Layer(layer.0[1..].to_vec())));
+    }
+
+    #[test]
+    fn test_layer_constructors() {
+        let sequential = Layer::sequential(100);
+        assert_eq!(Node(Fr::from_str("99").unwrap()), sequential.0[99]);
+        let mut rng = rand::thread_rng();
+        let random = Layer::random(&mut rng, 100);
+        assert_eq!(100, random.0.len());
+        assert_eq!(
+            random,
+            Layer::try_from_bytes(&Vec::<u8>::from(&random)).unwrap()
+        );
+        assert_ne!(random, Layer::random(&mut rng, 100));
+    }
+
+    #[test]
+    fn test_sealer() {
+        let ctx =
+            GPUContext::default(TEST_CONFIG, TreeOptions::Enabled { rows_to_discard: 2 }).unwrap();
+        let mut gpu = GPU::new(ctx, TEST_CONFIG).unwrap();
+        let original_data = incrementing_layer(123, TEST_CONFIG.num_nodes_window);
+        let sealer = Sealer::new(
+            TEST_CONFIG,
+            SealerInput {
+                replica_id: TEST_REPLICA_ID,
+                window_index: TEST_WINDOW_INDEX,
+                original_data: original_data.clone(),
+            },
+            &mut gpu,
+            true,
+        )
+        .unwrap();
+
+        let layer_index_to_restart = 3;
+        let layers = sealer.map(|x| x).collect::<NSEResult<Vec<_>>>().unwrap();
+        let roots = layers
+            .iter()
+            .map(|l| {
+                assert_eq!(1, l.tree.len());
+                l.tree[l.tree.len() - 1]
+            })
+            .collect::<Vec<_>>();
+
+        let layer_to_restart = &layers[layer_index_to_restart].base;
+
+        assert_eq!(
+            roots[..7].to_vec(),
+            vec![
+                Node(
+                    Fr::from_repr(FrRepr([
+                        0xba0280418b23a3c4,
+                        0x3d49ba6a2e216f46,
+                        0x65c38ddf4cde76f3,
+                        0x318dfbdd1cb3c701
+                    ]))
+                    .unwrap()
+                ),
+                Node(
+                    Fr::from_repr(FrRepr([
+                        0xaf6ffcb87a51a03f,
+                        0x4d8bff6bcf6f1961,
+                        0xb0d8e687e2d4c464,
+                        0x51d4ffb9de3a8a18
+                    ]))
+                    .unwrap()
+                ),
+                Node(
+                    Fr::from_repr(FrRepr([
+                        0xfa3ff052b0ef4205,
+                        0x285abfefd2861b21,
+                        0xf884bc316ee69685,
+                        0x4f39580cc5bafd0e
+                    ]))
+                    .unwrap()
+                ),
+                Node(
+                    Fr::from_repr(FrRepr([
+                        0xdb9d5e5df6796064,
+                        0x1aba373bd41020a2,
+                        0x114f262788304089,
+                        0x0bf168dec07a3ac4
+                    ]))
+                    .unwrap()
+                ),
+                Node(
+                    Fr::from_repr(FrRepr([
+                        0xd0382f0647cc5bc3,
+                        0x9a719d1d53da5f80,
+                        0x00aa9e6aa538779d,
+                        0x5187e91d102035af
+                    ]))
+                    .unwrap()
+                ),
+                Node(
+                    Fr::from_repr(FrRepr([
+                        0x646451246f10344b,
+                        0x8d6e697c269041c0,
+                        0xc5f1b27e2bdfdcbf,
+                        0x50c97f19b8f0e3e2
+                    ]))
+                    .unwrap()
+                ),
+                Node(
+                    Fr::from_repr(FrRepr([
+                        0x8d66677d0d708e31,
+                        0x4c34772776327789,
+                        0xf86d396bc0bd4cf2,
+                        0x64238b97c181baaf
+                    ]))
+                    .unwrap()
+                )
+            ]
+        );
+
+        let mut restarted_sealer = Sealer::new_from_layer(
+            layer_index_to_restart,
+            layer_to_restart,
+            TEST_CONFIG,
+            SealerInput {
+                replica_id: TEST_REPLICA_ID,
+                window_index: TEST_WINDOW_INDEX,
+                original_data: original_data.clone(),
+            },
+            &mut gpu,
+            true,
+        )
+        .unwrap();
+
+        let mut restarted_roots = Vec::new();
+
+        for r in &mut restarted_sealer {
+            let l = r.unwrap();
+            assert_eq!(1, l.tree.len());
+            restarted_roots.push(l.tree[l.tree.len() - 1]);
+        }
+
+        assert_eq!(
+            &roots[layer_index_to_restart + 1..],
+            restarted_roots.as_slice()
+        );
+
+        let seek_target = 2;
+        restarted_sealer
+            .seek(seek_target, &layers[seek_target].base)
+            .unwrap();
+
+        let sought_roots = restarted_sealer
+            .map(|r| {
+                let l = r.unwrap();
+                assert_eq!(1, l.tree.len());
+                l.tree[l.tree.len() - 1]
+            })
+            .collect::<Vec<_>>();
+
+        assert_eq!(&roots[seek_target + 1..], sought_roots.as_slice());
+    }
+
+    #[test]
+    fn test_sealer_checkpoint() {
+        let ctx =
+            GPUContext::default(TEST_CONFIG, TreeOptions::Enabled { rows_to_discard: 2 }).unwrap();
+        let mut gpu = GPU::new(ctx, TEST_CONFIG).unwrap();
+        let input = SealerInput {
+            replica_id: TEST_REPLICA_ID,
+            window_index: TEST_WINDOW_INDEX,
+            original_data: incrementing_layer(123, TEST_CONFIG.num_nodes_window),
+        };
+        let checkpoint_dir = tempfile::tempdir().unwrap();
+
+        let mut progress = Vec::new();
+        let roots = Sealer::builder(TEST_CONFIG, input.clone())
+            .build_trees(true)
+            .retain_key_layers(false)
+            .checkpoint_dir(checkpoint_dir.path())
+            .progress(|i, total| progress.push((i, total)))
+            .build(&mut gpu)
+            .unwrap()
+            .map(|r| {
+                let l = r.unwrap();
+                l.tree[l.tree.len() - 1]
+            })
+            .collect::<Vec<_>>();
+        assert_eq!((1..=7).map(|i| (i, 7)).collect::<Vec<_>>(), progress);
+
+        // Pretend the previous run was interrupted after the 4th layer.
+        for i in 5..7 {
+            std::fs::remove_file(Sealer::checkpoint_path(
+                checkpoint_dir.path(),
+                TEST_REPLICA_ID,
+                TEST_WINDOW_INDEX,
+                i,
+            ))
+            .unwrap();
+        }
+        let resumed_roots = Sealer::builder(TEST_CONFIG, input.clone())
+            .build_trees(true)
+            .checkpoint_dir(checkpoint_dir.path())
+            .build(&mut gpu)
+            .unwrap()
+            .map(|r| {
+                let l = r.unwrap();
+                l.tree[l.tree.len() - 1]
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(&roots[4..], resumed_roots.as_slice());
+
+        // Other windows sealed in the same directory start from scratch.
+        let mut progress = Vec::new();
+        Sealer::builder(
+            TEST_CONFIG,
+            SealerInput {
+                window_index: WindowIndex(TEST_WINDOW_INDEX.0 + 1),
+                ..input
+            },
+        )
+        .checkpoint_dir(checkpoint_dir.path())
+        .progress(|i, _| progress.push(i))
+        .build(&mut gpu)
+        .unwrap()
+        .seal()
+        .unwrap();
+        assert_eq!((1..=7).collect::<Vec<_>>(), progress);
+    }
+
+    #[test]
+    fn test_sealer_layer_sink() {
+        let ctx = GPUContext::default(TEST_CONFIG, TreeOptions::Disabled).unwrap();
+        let mut gpu = GPU::new(ctx, TEST_CONFIG).unwrap();
+        let input = SealerInput {
+            replica_id: TEST_REPLICA_ID,
+            window_index: TEST_WINDOW_INDEX,
+            original_data: incrementing_layer(123, TEST_CONFIG.num_nodes_window),
+        };
+        let expected = Sealer::new(TEST_CONFIG, input.clone(), &mut gpu, false)
+            .unwrap()
+            .seal()
+            .unwrap()
+            .layers;
+
+        let mut sunk = Vec::new();
+        let outputs = Sealer::builder(TEST_CONFIG, input.clone())
+            .retain_key_layers(false)
+            .build(&mut gpu)
+            .unwrap()
+            .with_layer_sink(|l| {
+                sunk.push(l);
+                Ok(())
+            })
+            .seal()
+            .unwrap()
+            .layers;
+        assert!(outputs.iter().all(|o| o.base.0.is_empty()));
+        assert_eq!(
+            (1..=7).collect::<Vec<_>>(),
+            sunk.iter().map(|l| l.index).collect::<Vec<_>>()
+        );
+        assert_eq!(LayerKind::Replica, sunk[6].kind);
+        for (l, o) in sunk.iter().zip(expected.iter()) {
+            assert_eq!(o.base, l.layer);
+        }
+
+        // Errors of the sink are returned.
+        let mut sealer = Sealer::new(TEST_CONFIG, input, &mut gpu, false)
+            .unwrap()
+            .with_layer_sink(|_| Err(NSEError::InvalidInput("Disk full".into())));
+        assert!(sealer.next().unwrap().is_err());
+    }
+
+    #[test]
+    fn test_retention_policy() {
+        let ctx = GPUContext::default(TEST_CONFIG, TreeOptions::Disabled).unwrap();
+        let mut gpu = GPU::new(ctx, TEST_CONFIG).unwrap();
+        let input = |window_index| SealerInput {
+            replica_id: TEST_REPLICA_ID,
+            window_index,
+            original_data: incrementing_layer(5, TEST_CONFIG.num_nodes_window),
+        };
+        let expected = Sealer::new(TEST_CONFIG, input(WindowIndex(1)), &mut gpu, false)
+            .unwrap()
+            .seal()
+            .unwrap()
+            .layers;
+        assert_eq!(7, expected.len());
+
+        // Other windows, so that masks are not cached.
+        for (window_index, retention, retained) in [
+            (2, RetentionPolicy::None, vec![]),
+            (3, RetentionPolicy::LastOnly, vec![7]),
+            (4, RetentionPolicy::EveryNth(3), vec![3, 6, 7]),
+        ]
+        .iter()
+        {
+            let output = Sealer::builder(TEST_CONFIG, input(WindowIndex(*window_index)))
+                .retention(*retention)
+                .build(&mut gpu)
+                .unwrap()
+                .seal()
+                .unwrap();
+            for (i, layer) in output.layers.iter().enumerate() {
+                if retained.contains(&(i + 1)) {
+                    assert!(!layer.base.0.is_empty());
+                } else {
+                    assert!(layer.base.0.is_empty());
+                }
+            }
+            // Dropped key layers are not read back from the device.
+            let dropped = output
+                .stats
+                .iter()
+                .filter(|s| s.kind != LayerKind::Replica && !retained.contains(&s.layer_index));
+            for stats in dropped {
+                assert_eq!(0, stats.transfer_bytes);
+            }
+        }
+        let last = Sealer::builder(TEST_CONFIG, input(WindowIndex(1)))
+            .retention(RetentionPolicy::LastOnly)
+            .build(&mut gpu)
+            .unwrap()
+            .last()
+            .unwrap()
+            .unwrap();
+        assert_eq!(expected[6], last);
+
+        assert!(Sealer::builder(TEST_CONFIG, input(WindowIndex(1)))
+            .retention(RetentionPolicy::EveryNth(0))
+            .build(&mut gpu)
+            .is_err());
+    }
+
+    #[test]
+    fn test_seal_receipt() {
+        let ctx = GPUContext::default(TEST_CONFIG, TreeOptions::Disabled).unwrap();
+        let mut gpu = GPU::new(ctx, TEST_CONFIG).unwrap();
+        let input = SealerInput {
+            replica_id: TEST_REPLICA_ID,
+            window_index: TEST_WINDOW_INDEX,
+            original_data: incrementing_layer(5, TEST_CONFIG.num_nodes_window),
+        };
+        let output = Sealer::builder(TEST_CONFIG, input.clone())
+            .retention(RetentionPolicy::LastOnly)
+            .layer_digests(true)
+            .build_info(true)
+            .build(&mut gpu)
+            .unwrap()
+            .seal()
+            .unwrap();
+        let receipt = output.receipt;
+        assert_eq!(TEST_REPLICA_ID, receipt.replica_id);
+        assert_eq!(TEST_WINDOW_INDEX, receipt.window_index);
+        assert_eq!(TEST_CONFIG.fingerprint(), receipt.config_fingerprint.0);
+        assert_eq!(gpu.device_key().unwrap(), receipt.device.key);
+        assert_eq!(output.stats.len(), receipt.layers.len());
+        for (layer, layer_receipt) in output.layers.iter().zip(receipt.layers.iter()) {
+            if layer.base.0.is_empty() {
+                assert_eq!(None, layer_receipt.digest);
+            } else {
+                assert_eq!(Some(layer.base.digest()), layer_receipt.digest);
+            }
+        }
+        assert!(receipt.layers.last().unwrap().digest.is_some());
+        assert_eq!(Some(build_info()), receipt.build);
+
+        // Neither is recorded by default.
+        let receipt = Sealer::builder(TEST_CONFIG, input)
+            .build(&mut gpu)
+            .unwrap()
+            .seal()
+            .unwrap()
+            .receipt;
+        assert!(receipt.layers.iter().all(|layer| layer.digest.is_none()));
+        assert_eq!(None, receipt.build);
+    }
+
+    #[test]
+    fn test_sealer_comm_d() {
+        let ctx = GPUContext::default(TEST_CONFIG, TreeOptions::Disabled).unwrap();
+        let mut gpu = GPU::new(ctx, TEST_CONFIG).unwrap();
+        let original_data = incrementing_layer(5, TEST_CONFIG.num_nodes_window);
+        let input = SealerInput {
+            replica_id: TEST_REPLICA_ID,
+            window_index: TEST_WINDOW_INDEX,
+            original_data: original_data.clone(),
+        };
+        let mut sealer = Sealer::builder(TEST_CONFIG, input.clone())
+            .comm_d(true)
+            .build(&mut gpu)
+            .unwrap();
+        sealer.next().unwrap().unwrap();
+        assert_eq!(None, sealer.comm_d().unwrap());
+        let output = sealer.seal().unwrap();
+        assert_eq!(Some(comm_d(&original_data).unwrap()), output.comm_d);
+        assert_eq!(output.comm_d, output.receipt.comm_d);
+
+        let output = Sealer::builder(TEST_CONFIG, input)
+            .build(&mut gpu)
+            .unwrap()
+            .seal()
+            .unwrap();
+        assert_eq!(None, output.comm_d);
+    }
+
+    #[test]
+    fn test_validate_data() {
+        let ctx = GPUContext::default(TEST_CONFIG, TreeOptions::Disabled).unwrap();
+        let mut gpu = GPU::new(ctx, TEST_CONFIG).unwrap();
+        let invalid =
+            Node(unsafe { std::mem::transmute::<FrRepr, Fr>(FrRepr([u64::max_value(); 4])) });
+        assert!(!invalid.is_valid());
+        let mut original_data = incrementing_layer(5, TEST_CONFIG.num_nodes_window);
+        original_data.0[42] = invalid;
+        original_data.0[100] = invalid;
+        assert_eq!(Some(42), original_data.first_invalid_node());
+        let input = SealerInput {
+            replica_id: TEST_REPLICA_ID,
+            window_index: TEST_WINDOW_INDEX,
+            original_data,
+        };
+        match Sealer::builder(TEST_CONFIG, input.clone()).build(&mut gpu) {
+            Err(NSEError::InvalidDataNode(42)) => {}
+            _ => panic!("Invalid data should be rejected!"),
+        }
+        assert!(Sealer::builder(TEST_CONFIG, input)
+            .validate_data(false)
+            .build(&mut gpu)
+            .is_ok());
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("data");
+        let mut bytes = Vec::<u8>::from(&incrementing_layer(5, 16));
+        bytes[3 * NODE_SIZE..4 * NODE_SIZE].copy_from_slice(&[0xffu8; NODE_SIZE]);
+        fs::write(&path, bytes).unwrap();
+        match SealerBuilder::from_file(TEST_CONFIG, TEST_REPLICA_ID, TEST_WINDOW_INDEX, &path)
+            .padding(WindowPadding::Pad)
+            .build(&mut gpu)
+        {
+            Err(NSEError::InvalidDataNode(3)) => {}
+            _ => panic!("Invalid data should be rejected!"),
+        }
+
+        // Field elements of 254 bits cannot be XOR encoded.
+        let xor_config = Config {
+            encoding_mode: EncodingMode::Xor,
+            ..TEST_CONFIG
+        };
+        let mut original_data = incrementing_layer(5, TEST_CONFIG.num_nodes_window);
+        original_data.0[7].0.negate();
+        assert!(original_data.0[7].is_valid_data(EncodingMode::FieldAdd));
+        let input = SealerInput {
+            replica_id: TEST_REPLICA_ID,
+            window_index: TEST_WINDOW_INDEX,
+            original_data,
+        };
+        assert!(Sealer::builder(TEST_CONFIG, input.clone())
+            .build(&mut gpu)
+            .is_ok());
+        match Sealer::builder(xor_config, input).build(&mut gpu) {
+            Err(NSEError::InvalidDataNode(7)) => {}
+            _ => panic!("Data above 2^253 should be rejected with XOR!"),
+        }
+    }
+
+    #[test]
+    fn test_unseal_file() {
+        let ctx = GPUContext::default(TEST_CONFIG, TreeOptions::Disabled).unwrap();
+        let mut gpu = GPU::new(ctx, TEST_CONFIG).unwrap();
+        let original_data = incrementing_layer(123, TEST_CONFIG.num_nodes_window);
+        let sealed_data = Sealer::new(
+            TEST_CONFIG,
+            SealerInput {
+                replica_id: TEST_REPLICA_ID,
+                window_index: TEST_WINDOW_INDEX,
+                original_data: original_data.clone(),
+            },
+            &mut gpu,
+            false,
+        )
+        .unwrap()
+        .last()
+        .unwrap()
+        .unwrap()
+        .base;
+
+        let dir = tempfile::tempdir().unwrap();
+        let replica_path = dir.path().join("replica");
+        let out_path = dir.path().join("unsealed");
+        std::fs::write(&replica_path, Vec::<u8>::from(&sealed_data)).unwrap();
+
+        let mut unsealer =
+            Unsealer::new(TEST_CONFIG, TEST_REPLICA_ID, TEST_WINDOW_INDEX, &mut gpu).unwrap();
+        unsealer
+            .unseal_file(&replica_path, &out_path, 100..300)
+            .unwrap();
+        let unsealed = Layer::try_from(&std::fs::read(&out_path).unwrap()).unwrap();
+        assert_eq!(&original_data.0[100..300], unsealed.0.as_slice());
+
+        assert!(unsealer
+            .unseal_file(
+                &replica_path,
+                &out_path,
+                0..TEST_CONFIG.num_nodes_window + 1
+            )
+            .is_err());
+
+        // Invalid replicas are rejected before the previous output is truncated.
+        let mut replica = Vec::<u8>::from(&sealed_data);
+        replica[150 * NODE_SIZE..151 * NODE_SIZE].copy_from_slice(&[0xffu8; NODE_SIZE]);
+        std::fs::write(&replica_path, replica).unwrap();
+        assert!(unsealer
+            .unseal_file(&replica_path, &out_path, 100..300)
+            .is_err());
+        assert_eq!(
+            unsealed,
+            Layer::try_from(&std::fs::read(&out_path).unwrap()).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_fused_last_layer() {
+        let ctx = GPUContext::default(TEST_CONFIG, TreeOptions::Disabled).unwrap();
+        let mut gpu = GPU::new(ctx, TEST_CONFIG).unwrap();
+        let original_data = incrementing_layer(11, TEST_CONFIG.num_nodes_window);
+        let input = SealerInput {
+            replica_id: TEST_REPLICA_ID,
+            window_index: TEST_WINDOW_INDEX,
+            original_data: original_data.clone(),
+        };
+        let seal = |gpu: &mut GPU, fuse_last_layer: bool| {
+            Sealer::builder(TEST_CONFIG, input.clone())
+                .fuse_last_layer(fuse_last_layer)
+                .build(gpu)
+                .unwrap()
+                .last()
+                .unwrap()
+                .unwrap()
+                .base
+        };
+        let replica = seal(&mut gpu, false);
+        assert_eq!(replica, seal(&mut gpu, true));
+
+        let mut key_generator =
+            KeyGenerator::new(TEST_CONFIG, TEST_REPLICA_ID, TEST_WINDOW_INDEX, &mut gpu)
+                .unwrap()
+                .read_back(false);
+        assert!(key_generator
+            .combine_last_layer(&replica, CombineMode::Decode)
+            .is_err());
+        for _ in 1..TEST_CONFIG.num_layers() {
+            key_generator.next().unwrap().unwrap();
+        }
+        assert_eq!(
+            original_data,
+            key_generator
+                .combine_last_layer(&replica, CombineMode::Decode)
+                .unwrap()
+        );
+        assert!(key_generator.next().is_none());
+    }
+
+    #[test]
+    fn test_staged_data() {
+        let ctx = GPUContext::default(TEST_CONFIG, TreeOptions::Disabled).unwrap();
+        let mut gpu = GPU::new(ctx, TEST_CONFIG).unwrap();
+        let input = SealerInput {
+            replica_id: TEST_REPLICA_ID,
+            window_index: TEST_WINDOW_INDEX,
+            original_data: incrementing_layer(11, TEST_CONFIG.num_nodes_window),
+        };
+        let seal = |gpu: &mut GPU, stage_data: bool, fuse_last_layer: bool| {
+            Sealer::builder(TEST_CONFIG, input.clone())
+                .stage_data(stage_data)
+                .fuse_last_layer(fuse_last_layer)
+                .build(gpu)
+                .unwrap()
+                .last()
+                .unwrap()
+                .unwrap()
+                .base
+        };
+        let replica = seal(&mut gpu, false, false);
+        assert_eq!(replica, seal(&mut gpu, true, false));
+        assert_eq!(replica, seal(&mut gpu, true, true));
+
+        // Nothing is staged once combined.
+        assert!(gpu.combine_staged(CombineMode::Encode).is_err());
+        assert!(gpu
+            .stage_data(Layer(input.original_data.0[1..].to_vec()))
+            .is_err());
+        gpu.stage_data(input.original_data.clone()).unwrap();
+        gpu.discard_staged_data().unwrap();
+        assert!(gpu.combine_staged(CombineMode::Encode).is_err());
+
+        // Nor once a sealer is dropped before its final combine.
+        let mut sealer = Sealer::builder(TEST_CONFIG, input.clone())
+            .stage_data(true)
+            .build(&mut gpu)
+            .unwrap();
+        sealer.next().unwrap().unwrap();
+        drop(sealer);
+        assert!(gpu.combine_staged(CombineMode::Encode).is_err());
+        assert_eq!(replica, seal(&mut gpu, true, false));
+
+        assert!(Sealer::builder(TEST_CONFIG, input)
+            .stage_data(true)
+            .comm_d(true)
+            .build(&mut gpu)
+            .is_err());
+    }
+
+    #[test]
+    fn test_key_layer_digests() {
+        let ctx = GPUContext::default(TEST_CONFIG, TreeOptions::Disabled).unwrap();
+        let mut gpu = GPU::new(ctx, TEST_CONFIG).unwrap();
+        let layers = KeyGenerator::new(TEST_CONFIG, TEST_REPLICA_ID, TEST_WINDOW_INDEX, &mut gpu)
+            .unwrap()
+            .collect::<NSEResult<Vec<_>>>()
+            .unwrap();
+
+        let mut key_generator =
+            KeyGenerator::new(TEST_CONFIG, TEST_REPLICA_ID, TEST_WINDOW_INDEX, &mut gpu)
+                .unwrap()
+                .read_back(false)
+                .digests(true);
+        assert_eq!(None, key_generator.last_digest());
+        for expected in layers.iter() {
+            assert!(key_generator.next().unwrap().unwrap().0.is_empty());
+            // The last layer is in Montgomery form on the device once finalized.
+            assert_eq!(
+                expected.tree_digest().unwrap(),
+                key_generator.last_digest().unwrap()
+            );
+        }
+        assert_ne!(
+            layers[0].tree_digest().unwrap(),
+            layers[1].tree_digest().unwrap()
+        );
+    }
 
-    pub fn incrementing_layer(start: usize, count: usize) -> Layer {
-        Layer(
-            (start..start + count)
-                .map(|i| Node(Fr::from_str(&i.to_string()).unwrap()))
-                .collect(),
+    #[test]
+    fn test_unseal_decode_only() {
+        let ctx = GPUContext::default(TEST_CONFIG, TreeOptions::Disabled).unwrap();
+        let mut gpu = GPU::new(ctx, TEST_CONFIG).unwrap();
+        let original_data = incrementing_layer(7, TEST_CONFIG.num_nodes_window);
+        let sealed_data = Sealer::new(
+            TEST_CONFIG,
+            SealerInput {
+                replica_id: TEST_REPLICA_ID,
+                window_index: TEST_WINDOW_INDEX,
+                original_data: original_data.clone(),
+            },
+            &mut gpu,
+            false,
         )
+        .unwrap()
+        .last()
+        .unwrap()
+        .unwrap()
+        .base;
+
+        // Another window, so that the mask is not cached.
+        gpu.take_timings();
+        Unsealer::new(TEST_CONFIG, TEST_REPLICA_ID, WindowIndex(1), &mut gpu)
+            .unwrap()
+            .unseal_layer(sealed_data.clone())
+            .unwrap();
+        let bytes_read = gpu.take_timings().bytes_read;
+
+        let unsealed = Unsealer::new(TEST_CONFIG, TEST_REPLICA_ID, TEST_WINDOW_INDEX, &mut gpu)
+            .unwrap()
+            .decode_only()
+            .unseal_layer(sealed_data)
+            .unwrap();
+        assert_eq!(original_data, unsealed);
+        // Only the unsealed data is read back.
+        assert_eq!(
+            TEST_CONFIG.window_byte_len() as u64,
+            gpu.take_timings().bytes_read
+        );
+        assert!(bytes_read > TEST_CONFIG.window_byte_len() as u64);
     }
 
     #[test]
-    fn test_sealer() {
-        let ctx =
-            GPUContext::default(TEST_CONFIG, TreeOptions::Enabled { rows_to_discard: 2 }).unwrap();
+    fn test_unseal_range_batches() {
+        let ctx = GPUContext::default(TEST_CONFIG, TreeOptions::Disabled).unwrap();
         let mut gpu = GPU::new(ctx, TEST_CONFIG).unwrap();
         let original_data = incrementing_layer(123, TEST_CONFIG.num_nodes_window);
-        let sealer = Sealer::new(
+        let sealed_data = Sealer::new(
             TEST_CONFIG,
             SealerInput {
                 replica_id: TEST_REPLICA_ID,
@@ -454,132 +3616,499 @@ mod tests {
                 original_data: original_data.clone(),
             },
             &mut gpu,
-            true,
+            false,
         )
-        .unwrap();
+        .unwrap()
+        .last()
+        .unwrap()
+        .unwrap()
+        .base;
 
-        let layer_index_to_restart = 3;
-        let layers = sealer.map(|x| x).collect::<NSEResult<Vec<_>>>().unwrap();
-        let roots = layers
-            .iter()
-            .map(|l| {
-                assert_eq!(1, l.tree.len());
-                l.tree[l.tree.len() - 1]
+        let mut unsealer =
+            Unsealer::new(TEST_CONFIG, TEST_REPLICA_ID, TEST_WINDOW_INDEX, &mut gpu).unwrap();
+        let mut unsealed = Vec::new();
+        unsealer
+            .unseal_range_batches(100, &sealed_data.0[100..300], |range, batch| {
+                unsealed.push((range, batch.unwrap()))
             })
-            .collect::<Vec<_>>();
+            .unwrap();
+        assert_eq!(1, unsealed.len()); // Fits in a single batch
+        assert_eq!(100..300, unsealed[0].0);
+        assert_eq!(&original_data.0[100..300], unsealed[0].1.as_slice());
 
-        let layer_to_restart = &layers[layer_index_to_restart].base;
+        assert!(unsealer
+            .unseal_range_batches(
+                TEST_CONFIG.num_nodes_window - 10,
+                &sealed_data.0[..20],
+                |_, _| { panic!("No batch expected!") }
+            )
+            .is_err());
+    }
 
-        assert_eq!(
-            roots[..7].to_vec(),
-            vec![
-                Node(
-                    Fr::from_repr(FrRepr([
-                        0xba0280418b23a3c4,
-                        0x3d49ba6a2e216f46,
-                        0x65c38ddf4cde76f3,
-                        0x318dfbdd1cb3c701
-                    ]))
-                    .unwrap()
-                ),
-                Node(
-                    Fr::from_repr(FrRepr([
-                        0xaf6ffcb87a51a03f,
-                        0x4d8bff6bcf6f1961,
-                        0xb0d8e687e2d4c464,
-                        0x51d4ffb9de3a8a18
-                    ]))
-                    .unwrap()
-                ),
-                Node(
-                    Fr::from_repr(FrRepr([
-                        0xfa3ff052b0ef4205,
-                        0x285abfefd2861b21,
-                        0xf884bc316ee69685,
-                        0x4f39580cc5bafd0e
-                    ]))
-                    .unwrap()
-                ),
-                Node(
-                    Fr::from_repr(FrRepr([
-                        0xdb9d5e5df6796064,
-                        0x1aba373bd41020a2,
-                        0x114f262788304089,
-                        0x0bf168dec07a3ac4
-                    ]))
-                    .unwrap()
-                ),
-                Node(
-                    Fr::from_repr(FrRepr([
-                        0xd0382f0647cc5bc3,
-                        0x9a719d1d53da5f80,
-                        0x00aa9e6aa538779d,
-                        0x5187e91d102035af
-                    ]))
-                    .unwrap()
-                ),
-                Node(
-                    Fr::from_repr(FrRepr([
-                        0x646451246f10344b,
-                        0x8d6e697c269041c0,
-                        0xc5f1b27e2bdfdcbf,
-                        0x50c97f19b8f0e3e2
-                    ]))
-                    .unwrap()
-                ),
-                Node(
-                    Fr::from_repr(FrRepr([
-                        0x8d66677d0d708e31,
-                        0x4c34772776327789,
-                        0xf86d396bc0bd4cf2,
-                        0x64238b97c181baaf
-                    ]))
-                    .unwrap()
-                )
-            ]
-        );
+    #[test]
+    fn test_sealer_cancellation() {
+        let ctx = GPUContext::default(TEST_CONFIG, TreeOptions::Disabled).unwrap();
+        let mut gpu = GPU::new(ctx, TEST_CONFIG).unwrap();
+        let token = CancellationToken::new();
+        let cancel_after_second = token.clone();
+        let outputs = Sealer::builder(
+            TEST_CONFIG,
+            SealerInput {
+                replica_id: TEST_REPLICA_ID,
+                window_index: TEST_WINDOW_INDEX,
+                original_data: incrementing_layer(123, TEST_CONFIG.num_nodes_window),
+            },
+        )
+        .cancellation(token)
+        .progress(move |i, _| {
+            if i == 2 {
+                cancel_after_second.cancel()
+            }
+        })
+        .build(&mut gpu)
+        .unwrap()
+        .collect::<Vec<_>>();
 
-        let mut restarted_sealer = Sealer::new_from_layer(
-            layer_index_to_restart,
-            layer_to_restart,
+        assert_eq!(3, outputs.len());
+        assert!(outputs[..2].iter().all(|o| o.is_ok()));
+        match outputs[2] {
+            Err(NSEError::Cancelled) => {}
+            _ => panic!("Sealer should have been cancelled!"),
+        }
+    }
+
+    #[test]
+    fn test_seal_stats() {
+        let ctx = GPUContext::default(TEST_CONFIG, TreeOptions::Disabled).unwrap();
+        let mut gpu = GPU::new(ctx, TEST_CONFIG).unwrap();
+        let output = Sealer::new(
             TEST_CONFIG,
             SealerInput {
                 replica_id: TEST_REPLICA_ID,
                 window_index: TEST_WINDOW_INDEX,
-                original_data: original_data.clone(),
+                original_data: incrementing_layer(123, TEST_CONFIG.num_nodes_window),
             },
             &mut gpu,
-            true,
+            false,
         )
+        .unwrap()
+        .seal()
         .unwrap();
 
-        let mut restarted_roots = Vec::new();
-
-        for r in &mut restarted_sealer {
-            let l = r.unwrap();
-            assert_eq!(1, l.tree.len());
-            restarted_roots.push(l.tree[l.tree.len() - 1]);
+        assert_eq!(output.layers.len(), output.stats.len());
+        assert_eq!(
+            vec![
+                LayerKind::Mask,
+                LayerKind::Expander,
+                LayerKind::Expander,
+                LayerKind::Expander,
+                LayerKind::Butterfly,
+                LayerKind::Butterfly,
+                LayerKind::Replica
+            ],
+            output.stats.iter().map(|s| s.kind).collect::<Vec<_>>()
+        );
+        for (i, stats) in output.stats.iter().enumerate() {
+            assert_eq!(i + 1, stats.layer_index);
+            assert!(stats.nodes_per_sec > 0f64);
         }
+    }
+
+    #[test]
+    fn test_sealer_padding() {
+        let ctx = GPUContext::default(TEST_CONFIG, TreeOptions::Disabled).unwrap();
+        let mut gpu = GPU::new(ctx, TEST_CONFIG).unwrap();
+        let data_len = TEST_CONFIG.num_nodes_window - 12;
+        let input = SealerInput {
+            replica_id: TEST_REPLICA_ID,
+            window_index: TEST_WINDOW_INDEX,
+            original_data: incrementing_layer(123, data_len),
+        };
+        let mut padded_input = input.clone();
+        padded_input
+            .original_data
+            .0
+            .resize(TEST_CONFIG.num_nodes_window, Node::default());
+
+        let seal = |gpu: &mut GPU, input: SealerInput, padding: WindowPadding| {
+            Sealer::builder(TEST_CONFIG, input)
+                .padding(padding)
+                .build(gpu)
+                .and_then(|s| s.seal())
+                .map(|o| o.layers.last().unwrap().base.clone())
+        };
 
+        assert!(seal(&mut gpu, input.clone(), WindowPadding::Strict).is_err());
+        let expected = seal(&mut gpu, padded_input, WindowPadding::Strict).unwrap();
         assert_eq!(
-            &roots[layer_index_to_restart + 1..],
-            restarted_roots.as_slice()
+            expected,
+            seal(&mut gpu, input.clone(), WindowPadding::Pad).unwrap()
+        );
+        assert_eq!(
+            &expected.0[..data_len],
+            seal(&mut gpu, input, WindowPadding::PadAndTruncate)
+                .unwrap()
+                .0
+                .as_slice()
         );
+    }
 
-        let seek_target = 2;
-        restarted_sealer
-            .seek(seek_target, &layers[seek_target].base)
+    #[test]
+    fn test_seal_from_file() {
+        let ctx = GPUContext::default(TEST_CONFIG, TreeOptions::Disabled).unwrap();
+        let mut gpu = GPU::new(ctx, TEST_CONFIG).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("data.dat");
+        let data_len = TEST_CONFIG.num_nodes_window - 12;
+        let original_data = incrementing_layer(123, data_len);
+        fs::write(&path, Vec::<u8>::from(&original_data)).unwrap();
+
+        let input = SealerInput {
+            replica_id: TEST_REPLICA_ID,
+            window_index: TEST_WINDOW_INDEX,
+            original_data,
+        };
+        let expected = Sealer::builder(TEST_CONFIG, input)
+            .padding(WindowPadding::Pad)
+            .build(&mut gpu)
+            .unwrap()
+            .seal()
             .unwrap();
+        let sealed =
+            SealerBuilder::from_file(TEST_CONFIG, TEST_REPLICA_ID, TEST_WINDOW_INDEX, &path)
+                .padding(WindowPadding::Pad)
+                .build(&mut gpu)
+                .unwrap()
+                .seal()
+                .unwrap();
+        assert_eq!(expected.layers, sealed.layers);
+        assert!(Sealer::seal_from_file(
+            TEST_CONFIG,
+            TEST_REPLICA_ID,
+            TEST_WINDOW_INDEX,
+            &path,
+            &mut gpu,
+            false
+        )
+        .is_err());
 
-        let sought_roots = restarted_sealer
-            .map(|r| {
-                let l = r.unwrap();
-                assert_eq!(1, l.tree.len());
-                l.tree[l.tree.len() - 1]
-            })
-            .collect::<Vec<_>>();
+        // Truncated after the sealer is built.
+        let sealer =
+            SealerBuilder::from_file(TEST_CONFIG, TEST_REPLICA_ID, TEST_WINDOW_INDEX, &path)
+                .padding(WindowPadding::Pad)
+                .build(&mut gpu)
+                .unwrap();
+        fs::write(&path, Vec::<u8>::from(&incrementing_layer(123, 10))).unwrap();
+        assert!(sealer.seal().is_err());
+    }
 
-        assert_eq!(&roots[seek_target + 1..], sought_roots.as_slice());
+    #[test]
+    fn test_key_generator_introspection() {
+        let ctx = GPUContext::default(TEST_CONFIG, TreeOptions::Disabled).unwrap();
+        let mut gpu = GPU::new(ctx, TEST_CONFIG).unwrap();
+        let mut keygen =
+            KeyGenerator::new(TEST_CONFIG, TEST_REPLICA_ID, TEST_WINDOW_INDEX, &mut gpu).unwrap();
+        assert_eq!(
+            TEST_CONFIG.num_nodes_window,
+            keygen.peek_config().num_nodes_window
+        );
+        assert_eq!(0, keygen.current_layer_index());
+        assert_eq!(None, keygen.current_layer_kind());
+
+        let mut kinds = Vec::new();
+        while let Some(l) = keygen.next() {
+            l.unwrap();
+            kinds.push(keygen.current_layer_kind().unwrap());
+            assert_eq!(kinds.len(), keygen.current_layer_index());
+        }
+        let mut expected = vec![LayerKind::Mask];
+        expected.extend(vec![
+            LayerKind::Expander;
+            TEST_CONFIG.num_expander_layers - 1
+        ]);
+        expected.extend(vec![LayerKind::Butterfly; TEST_CONFIG.num_butterfly_layers]);
+        assert_eq!(expected, kinds);
+
+        let config = Config {
+            num_nodes_window: TEST_CONFIG.num_nodes_window * 2,
+            ..TEST_CONFIG
+        };
+        assert!(KeyGenerator::new(config, TEST_REPLICA_ID, TEST_WINDOW_INDEX, &mut gpu).is_err());
+    }
+
+    #[test]
+    fn test_layer_state() {
+        let mut states = vec![LayerState::Mask];
+        while *states.last().unwrap() != LayerState::Done {
+            let next = states.last().unwrap().next(&TEST_CONFIG);
+            states.push(next);
+        }
+        let expanders = TEST_CONFIG.num_expander_layers - 1;
+        assert_eq!(TEST_CONFIG.num_layers() + 1, states.len());
+        assert_eq!(LayerState::Expander(expanders - 1), states[expanders]);
+        assert_eq!(LayerState::Butterfly(0), states[expanders + 1]);
+        for (completed, state) in states.iter().enumerate() {
+            assert!(state.is_valid(&TEST_CONFIG));
+            assert_eq!(completed, state.completed(&TEST_CONFIG));
+            assert_eq!(*state, LayerState::after(&TEST_CONFIG, completed).unwrap());
+        }
+        assert!(!LayerState::Expander(expanders).is_valid(&TEST_CONFIG));
+        assert!(LayerState::after(&TEST_CONFIG, states.len()).is_err());
+
+        // Resuming from any state produces the same layers.
+        let ctx = GPUContext::default(TEST_CONFIG, TreeOptions::Disabled).unwrap();
+        let mut gpu = GPU::new(ctx, TEST_CONFIG).unwrap();
+        let mut keygen =
+            KeyGenerator::new(TEST_CONFIG, TEST_REPLICA_ID, TEST_WINDOW_INDEX, &mut gpu).unwrap();
+        let mut expected = Vec::new();
+        while keygen.state() != LayerState::Done {
+            let state = keygen.state();
+            assert_eq!(
+                state.kind(),
+                Some(keygen.layer_kind(state.completed(&TEST_CONFIG) + 1))
+            );
+            expected.push(keygen.next().unwrap().unwrap());
+        }
+        assert!(keygen.next().is_none());
+        for (i, state) in states.iter().enumerate().skip(1) {
+            keygen.resume(*state, &expected[i - 1]).unwrap();
+            let resumed = keygen.by_ref().collect::<NSEResult<Vec<_>>>().unwrap();
+            assert_eq!(&expected[i..], resumed.as_slice());
+        }
+        assert!(keygen
+            .resume(LayerState::Butterfly(99), &expected[0])
+            .is_err());
+    }
+
+    #[test]
+    fn test_labeled_layers() {
+        let ctx = GPUContext::default(TEST_CONFIG, TreeOptions::Disabled).unwrap();
+        let mut gpu = GPU::new(ctx, TEST_CONFIG).unwrap();
+        let num_layers = TEST_CONFIG.num_layers();
+        let expected = KeyGenerator::new(TEST_CONFIG, TEST_REPLICA_ID, TEST_WINDOW_INDEX, &mut gpu)
+            .unwrap()
+            .collect::<NSEResult<Vec<_>>>()
+            .unwrap();
+        let labeled = KeyGenerator::new(TEST_CONFIG, TEST_REPLICA_ID, TEST_WINDOW_INDEX, &mut gpu)
+            .unwrap()
+            .labeled()
+            .collect::<NSEResult<Vec<_>>>()
+            .unwrap();
+        assert_eq!(num_layers, labeled.len());
+        for (i, (l, e)) in labeled.iter().zip(expected.iter()).enumerate() {
+            assert_eq!(i + 1, l.index);
+            assert_eq!(*e, l.layer);
+        }
+        assert_eq!(LayerKind::Mask, labeled[0].kind);
+        assert_eq!(LayerKind::Expander, labeled[1].kind);
+        assert_eq!(LayerKind::Butterfly, labeled[num_layers - 1].kind);
+
+        let input = SealerInput {
+            replica_id: TEST_REPLICA_ID,
+            window_index: TEST_WINDOW_INDEX,
+            original_data: Layer::random(&mut rand::thread_rng(), TEST_CONFIG.num_nodes_window),
+        };
+        let labeled = Sealer::new(TEST_CONFIG, input, &mut gpu, false)
+            .unwrap()
+            .labeled()
+            .collect::<NSEResult<Vec<_>>>()
+            .unwrap();
+        let indices = labeled.iter().map(|l| l.index).collect::<Vec<_>>();
+        assert_eq!((1..=num_layers).collect::<Vec<_>>(), indices);
+        assert_eq!(LayerKind::Replica, labeled[num_layers - 1].kind);
+        assert_eq!(expected[1], labeled[1].layer.base);
+    }
+
+    #[test]
+    fn test_label_subset() {
+        let aes = Config {
+            mask_prf: MaskPrf::Aes256Ctr,
+            ..TEST_CONFIG
+        };
+        for &config in [TEST_CONFIG, aes].iter() {
+            let ctx = GPUContext::default(config, TreeOptions::Disabled).unwrap();
+            let mut gpu = GPU::new(ctx, config).unwrap();
+            let mut keygen =
+                KeyGenerator::new(config, TEST_REPLICA_ID, TEST_WINDOW_INDEX, &mut gpu).unwrap();
+            let layers = keygen.by_ref().collect::<NSEResult<Vec<_>>>().unwrap();
+            // A few nodes are labeled on the host from the layers below, many on the device.
+            let few = [0, 7, 511];
+            let many = (0..100).map(|i| i * 5).collect::<Vec<_>>();
+            for layer_index in 1..=config.num_layers() {
+                for nodes in [&few[..], &many[..]].iter() {
+                    let expected = nodes
+                        .iter()
+                        .map(|&n| layers[layer_index - 1].0[n])
+                        .collect::<Vec<_>>();
+                    assert_eq!(expected, keygen.label_subset(layer_index, nodes).unwrap());
+                }
+            }
+            // The generator starts over.
+            assert_eq!(0, keygen.current_layer_index());
+            assert_eq!(layers[0], keygen.next().unwrap().unwrap());
+            assert!(keygen.label_subset(0, &few).is_err());
+            assert!(keygen.label_subset(config.num_layers() + 1, &few).is_err());
+            assert!(keygen.label_subset(1, &[512]).is_err());
+        }
+    }
+
+    #[test]
+    fn test_key_generator_seed_fn() {
+        const FIXED_SEED: ReplicaId = ReplicaId([7u8; 32]);
+        let ctx = GPUContext::default(TEST_CONFIG, TreeOptions::Disabled).unwrap();
+        let mut gpu = GPU::new(ctx, TEST_CONFIG).unwrap();
+        let expected = KeyGenerator::new(TEST_CONFIG, FIXED_SEED, TEST_WINDOW_INDEX, &mut gpu)
+            .unwrap()
+            .collect::<NSEResult<Vec<_>>>()
+            .unwrap();
+        let keygen = KeyGenerator::new(TEST_CONFIG, TEST_REPLICA_ID, TEST_WINDOW_INDEX, &mut gpu)
+            .unwrap()
+            .seed_fn(|_, _| FIXED_SEED)
+            .unwrap();
+        assert_eq!(TEST_REPLICA_ID, keygen.replica_id());
+        let layers = keygen.collect::<NSEResult<Vec<_>>>().unwrap();
+        assert_eq!(expected, layers);
+
+        let mut rng = rand::thread_rng();
+        let data = Layer::random(&mut rng, TEST_CONFIG.num_nodes_window);
+        let input = SealerInput {
+            replica_id: TEST_REPLICA_ID,
+            window_index: TEST_WINDOW_INDEX,
+            original_data: data.clone(),
+        };
+        let sealed = Sealer::builder(TEST_CONFIG, input)
+            .seed_fn(|_, _| FIXED_SEED)
+            .build(&mut gpu)
+            .unwrap()
+            .seal()
+            .unwrap()
+            .layers
+            .pop()
+            .unwrap()
+            .base;
+        let mut unsealer = Unsealer::new(TEST_CONFIG, TEST_REPLICA_ID, TEST_WINDOW_INDEX, &mut gpu)
+            .unwrap()
+            .seed_fn(|_, _| FIXED_SEED)
+            .unwrap();
+        assert_eq!(data, unsealer.unseal_layer(sealed).unwrap());
+    }
+
+    #[test]
+    fn test_key_cache_rejects_seed_fn() {
+        let ctx = GPUContext::default(TEST_CONFIG, TreeOptions::Disabled).unwrap();
+        let mut gpu = GPU::new(ctx, TEST_CONFIG).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let key_cache = KeyCache::new(dir.path()).unwrap();
+        assert!(Unsealer::with_key_cache(
+            TEST_CONFIG,
+            TEST_REPLICA_ID,
+            TEST_WINDOW_INDEX,
+            &mut gpu,
+            &key_cache,
+        )
+        .unwrap()
+        .seed_fn(|_, _| ReplicaId([7u8; 32]))
+        .is_err());
+    }
+
+    #[test]
+    fn test_sealer_key_cache_rejects_seed_fn() {
+        let ctx = GPUContext::default(TEST_CONFIG, TreeOptions::Disabled).unwrap();
+        let mut gpu = GPU::new(ctx, TEST_CONFIG).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let input = SealerInput {
+            replica_id: TEST_REPLICA_ID,
+            window_index: TEST_WINDOW_INDEX,
+            original_data: incrementing_layer(5, TEST_CONFIG.num_nodes_window),
+        };
+        assert!(Sealer::builder(TEST_CONFIG, input)
+            .key_cache(KeyCache::new(dir.path()).unwrap())
+            .seed_fn(|_, _| ReplicaId([7u8; 32]))
+            .build(&mut gpu)
+            .is_err());
+    }
+
+    #[test]
+    fn test_key_generator_extract_nodes() {
+        let ctx = GPUContext::default(TEST_CONFIG, TreeOptions::Disabled).unwrap();
+        let mut gpu = GPU::new(ctx, TEST_CONFIG).unwrap();
+        let mut keygen =
+            KeyGenerator::new(TEST_CONFIG, TEST_REPLICA_ID, TEST_WINDOW_INDEX, &mut gpu).unwrap();
+        let challenges = [0, 17, 300, TEST_CONFIG.num_nodes_window - 1];
+        let num_layers = keygen.len();
+        let log2_degree = TEST_CONFIG.degree_butterfly.trailing_zeros();
+
+        assert!(keygen.extract_nodes(1, &challenges).is_err());
+        assert!(keygen.extract_parents(&challenges).unwrap().is_empty()); // Mask layer
+        let mut previous = keygen.next().unwrap().unwrap();
+        for layer_index in 2..=num_layers {
+            let parents = keygen.extract_parents(&challenges).unwrap();
+            let layer = keygen.next().unwrap().unwrap();
+            let nodes = keygen.extract_nodes(layer_index, &challenges).unwrap();
+            for (c, n) in challenges.iter().zip(nodes.iter()) {
+                assert_eq!(layer.0[*c], *n);
+            }
+            match keygen.layer_kind(layer_index) {
+                LayerKind::Expander => {
+                    let degree = TEST_CONFIG.k as usize * TEST_CONFIG.degree_expander;
+                    assert_eq!(challenges.len() * degree, parents.len());
+                    assert!(parents.iter().all(|p| previous.0.contains(p)));
+                }
+                _ => {
+                    let factor = 1u32.wrapping_shl(log2_degree * (num_layers - layer_index) as u32);
+                    let degree = TEST_CONFIG.degree_butterfly;
+                    for (i, c) in challenges.iter().enumerate() {
+                        for j in 0..degree {
+                            let p = (*c as u32).wrapping_add(j as u32 * factor) as usize
+                                & (TEST_CONFIG.num_nodes_window - 1);
+                            assert_eq!(previous.0[p], parents[i * degree + j]);
+                        }
+                    }
+                }
+            }
+            previous = layer;
+        }
+        assert!(keygen.extract_parents(&challenges).is_err());
+        assert!(keygen
+            .extract_nodes(num_layers, &[TEST_CONFIG.num_nodes_window])
+            .is_err());
+    }
+
+    #[test]
+    fn test_window_index() {
+        let last = WindowIndex(u64::max_value() / TEST_CONFIG.num_nodes_window as u64);
+        assert!(last.validate(&TEST_CONFIG).is_ok());
+        assert_eq!(
+            u64::max_value() - TEST_CONFIG.num_nodes_window as u64 + 1,
+            last.node_index(&TEST_CONFIG, 0).unwrap()
+        );
+        assert!(WindowIndex(last.0 + 1).node_index(&TEST_CONFIG, 0).is_err());
+        assert!(WindowIndex(last.0 + 1).validate(&TEST_CONFIG).is_err());
+        assert!(WindowIndex(u64::max_value())
+            .validate(&TEST_CONFIG)
+            .is_err());
+        assert_eq!(
+            (1 << 32) * TEST_CONFIG.num_nodes_window as u64 + 5,
+            WindowIndex(1 << 32).node_index(&TEST_CONFIG, 5).unwrap()
+        );
+        assert_eq!(WindowIndex(7), WindowIndex::from(7u32));
+
+        let ctx = GPUContext::default(TEST_CONFIG, TreeOptions::Disabled).unwrap();
+        let mut gpu = GPU::new(ctx, TEST_CONFIG).unwrap();
+        assert!(KeyGenerator::new(
+            TEST_CONFIG,
+            TEST_REPLICA_ID,
+            WindowIndex(last.0 + 1),
+            &mut gpu
+        )
+        .is_err());
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn test_window_index_json() {
+        assert_eq!("7", serde_json::to_string(&WindowIndex(7)).unwrap());
+        assert_eq!(WindowIndex(7), serde_json::from_str("7").unwrap());
     }
 
     #[test]
@@ -589,7 +4118,7 @@ mod tests {
         let mut rng = thread_rng();
         let original_data = Layer::random(&mut rng, TEST_CONFIG.num_nodes_window);
         let replica_id = ReplicaId::random(&mut rng);
-        let window_index: usize = rng.gen();
+        let window_index = WindowIndex::from(rng.gen::<u32>());
 
         let ctx = GPUContext::default(TEST_CONFIG, TreeOptions::Disabled).unwrap();
         let mut gpu = GPU::new(ctx, TEST_CONFIG).unwrap();
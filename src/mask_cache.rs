@@ -0,0 +1,74 @@
+use crate::{Layer, ReplicaId, WindowIndex};
+use std::collections::VecDeque;
+
+/// Least recently used mask layers of a GPU, keyed by replica id and window index, see
+/// `GpuConfig::mask_cache_size`. Masks only depend on those and on the config, so sealing a
+/// window again after a failure, or unsealing it after sealing, reuses its mask.
+#[derive(Debug, Default)]
+pub(crate) struct MaskCache {
+    capacity: usize,
+    // Most recently used first.
+    masks: VecDeque<(ReplicaId, WindowIndex, Layer)>,
+}
+
+impl MaskCache {
+    pub fn new(capacity: usize) -> Self {
+        MaskCache {
+            capacity,
+            masks: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.capacity > 0
+    }
+
+    pub fn get(&mut self, replica_id: ReplicaId, window_index: WindowIndex) -> Option<&Layer> {
+        let i = self
+            .masks
+            .iter()
+            .position(|(id, w, _)| *id == replica_id && *w == window_index)?;
+        let entry = self.masks.remove(i)?;
+        self.masks.push_front(entry);
+        self.masks.front().map(|(_, _, mask)| mask)
+    }
+
+    pub fn insert(&mut self, replica_id: ReplicaId, window_index: WindowIndex, mask: Layer) {
+        if self.capacity == 0 {
+            return;
+        }
+        self.masks
+            .retain(|(id, w, _)| *id != replica_id || *w != window_index);
+        self.masks.truncate(self.capacity - 1);
+        self.masks.push_front((replica_id, window_index, mask));
+    }
+
+    pub fn clear(&mut self) {
+        self.masks.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mask_cache() {
+        let id = ReplicaId([1u8; 32]);
+        let mut cache = MaskCache::new(2);
+        cache.insert(id, WindowIndex(0), Layer::sequential(1));
+        cache.insert(id, WindowIndex(1), Layer::sequential(2));
+        assert_eq!(Some(&Layer::sequential(1)), cache.get(id, WindowIndex(0)));
+        // Window 1 is now the least recently used.
+        cache.insert(id, WindowIndex(2), Layer::sequential(3));
+        assert!(cache.get(id, WindowIndex(1)).is_none());
+        assert!(cache.get(id, WindowIndex(0)).is_some());
+        assert!(cache.get(ReplicaId([2u8; 32]), WindowIndex(0)).is_none());
+        cache.clear();
+        assert!(cache.get(id, WindowIndex(2)).is_none());
+
+        let mut disabled = MaskCache::new(0);
+        disabled.insert(id, WindowIndex(0), Layer::sequential(1));
+        assert!(disabled.get(id, WindowIndex(0)).is_none());
+    }
+}
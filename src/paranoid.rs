@@ -0,0 +1,507 @@
+//! Mirroring of every operation of a backend on the host, to qualify new drivers and devices.
+
+use crate::{
+    combine_nodes, default_labeling_seed, label_node, segment_range, CombineMode, Config, Layer,
+    MaskPrf, NSEError, NSEResult, NarrowStackedExpander, Node, OriginalData, ReplicaId,
+    RetentionPolicy, SealOutput, SealerBuilder, WindowIndex, WindowPadding, GPU,
+};
+use std::fs;
+
+/// Runs every operation of an NSE backend both on the backend and on the host reference (labels
+/// computed node by node like `label_node`, combines like `HostCombiner`), compares the digests
+/// of their results and fails with `NSEError::Divergence` at the first difference. It is orders
+/// of magnitude slower than the backend alone, and meant for qualifying new driver versions and
+/// new cards before they seal in production:
+///
+/// ```ignore
+/// let gpu = Backend::from_env()?.gpu(config, TreeOptions::Disabled)?;
+/// let mut expander = ParanoidMode::new(config, gpu)?;
+/// ```
+///
+/// Only the operations of `NarrowStackedExpander` are mirrored: layers are labeled from the
+/// layer generated before through the wrapper, and combined with the last one once finalized.
+/// The AES mask has no host implementation, so configs using it are rejected.
+///
+/// `Sealer`, `KeyGenerator` and `Unsealer` drive a `GPU` directly rather than an expander, so
+/// they cannot be wrapped: use `paranoid_seal` to check the production seal path.
+pub struct ParanoidMode<E: NarrowStackedExpander> {
+    inner: E,
+    config: Config,
+    // Last layer generated, the key layer once finalized.
+    previous: Option<Layer>,
+    finalized: bool,
+    checks: usize,
+}
+
+/// Seals a window with the `Sealer` built from `builder` on `gpu`, i.e. the production path with
+/// all its options (staged data, fused last layer, preemption...), then checks every layer it
+/// produced against the host reference like `ParanoidMode` does, failing with
+/// `NSEError::Divergence` at the first difference. Each layer is labeled on the host from the
+/// layer produced before it, and the replica is combined with the last key layer labeled on the
+/// host. All layers are retained, whatever the retention of `builder`. Checkpoints and key
+/// caches are rejected, the layers they skip could not be checked.
+pub fn paranoid_seal<'a>(builder: SealerBuilder<'a>, gpu: &'a mut GPU) -> NSEResult<SealOutput> {
+    let config = builder.config;
+    check_config(&config)?;
+    if builder.checkpoint_dir.is_some() || builder.key_cache.is_some() {
+        return Err(NSEError::InvalidInput(
+            "Layers resumed from checkpoints or key caches cannot be checked!".into(),
+        ));
+    }
+    let mut data = match &builder.original_data {
+        OriginalData::Memory(data) => data.clone(),
+        OriginalData::File(path) => Layer::try_from_bytes(&fs::read(path)?)?,
+        // Only built sealers stage their data.
+        OriginalData::Staged => unreachable!(),
+    };
+    let seed_fn = builder.seed_fn.unwrap_or(default_labeling_seed);
+    let seed = seed_fn(builder.replica_id, builder.window_index);
+    let (window_index, padding) = (builder.window_index, builder.padding);
+    let output = builder.retention(RetentionPolicy::All).build(gpu)?.seal()?;
+
+    let num_layers = config.num_layers();
+    let empty = Layer::default();
+    let mut previous = &empty;
+    for (i, layer) in output.layers.iter().enumerate() {
+        let layer_index = i + 1;
+        let host = host_layer(&config, seed, window_index, layer_index, previous)?;
+        if layer_index < num_layers {
+            let operation = format!("{} layer {}", layer_name(&config, layer_index), layer_index);
+            check_digests(operation, &layer.base.0, &host)?;
+            previous = &layer.base;
+        } else {
+            let data_len = data.0.len();
+            data.0.resize(config.num_nodes_window, Node::default());
+            combine_nodes(
+                &mut data.0,
+                &host.0,
+                CombineMode::Encode,
+                config.encoding_mode,
+            )?;
+            if padding == WindowPadding::PadAndTruncate {
+                data.0.truncate(data_len);
+            }
+            check_digests("replica".to_string(), &layer.base.0, &data)?;
+        }
+    }
+    Ok(output)
+}
+
+fn check_config(config: &Config) -> NSEResult<()> {
+    config.validate()?;
+    if config.mask_prf != MaskPrf::Sha256 {
+        return Err(NSEError::InvalidInput(
+            "The AES mask has no host reference to be checked against!".into(),
+        ));
+    }
+    Ok(())
+}
+
+fn layer_name(config: &Config, layer_index: usize) -> &'static str {
+    if layer_index == 1 {
+        "mask"
+    } else if layer_index <= config.num_expander_layers {
+        "expander"
+    } else {
+        "butterfly"
+    }
+}
+
+// Labels layer `layer_index` of a window node by node from `previous`, the layer before.
+fn host_layer(
+    config: &Config,
+    seed: ReplicaId,
+    window_index: WindowIndex,
+    layer_index: usize,
+    previous: &Layer,
+) -> NSEResult<Layer> {
+    Ok(Layer(
+        (0..config.num_nodes_window)
+            .map(|node| label_node(config, seed, window_index, layer_index, previous, node))
+            .collect::<NSEResult<_>>()?,
+    ))
+}
+
+// Fails with `NSEError::Divergence` if `device` differs from `host`.
+fn check_digests(operation: String, device: &[Node], host: &Layer) -> NSEResult<()> {
+    let device = Layer(device.to_vec());
+    let (device_digest, host_digest) = (device.digest(), host.digest());
+    if device_digest == host_digest {
+        return Ok(());
+    }
+    let node = device
+        .diff(host, 1)
+        .first()
+        .map_or(std::cmp::min(device.0.len(), host.0.len()), |(i, _, _)| *i);
+    Err(NSEError::Divergence {
+        operation,
+        node,
+        device: device_digest,
+        host: host_digest,
+    })
+}
+
+impl<E: NarrowStackedExpander> ParanoidMode<E> {
+    pub fn new(config: Config, inner: E) -> NSEResult<Self> {
+        check_config(&config)?;
+        if inner.leaf_count() != config.num_nodes_window {
+            return Err(NSEError::InvalidInput(format!(
+                "The backend holds windows of {} nodes, expected {}!",
+                inner.leaf_count(),
+                config.num_nodes_window
+            )));
+        }
+        Ok(ParanoidMode {
+            inner,
+            config,
+            previous: None,
+            finalized: false,
+            checks: 0,
+        })
+    }
+
+    pub fn inner(&mut self) -> &mut E {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> E {
+        self.inner
+    }
+
+    /// Number of operations checked against the host reference so far.
+    pub fn checks(&self) -> usize {
+        self.checks
+    }
+
+    fn check(&mut self, operation: String, device: &[Node], host: &Layer) -> NSEResult<()> {
+        self.checks += 1;
+        check_digests(operation, device, host)
+    }
+
+    fn host_layer(
+        &self,
+        replica_id: ReplicaId,
+        window_index: WindowIndex,
+        layer_index: usize,
+    ) -> NSEResult<Layer> {
+        let empty = Layer::default();
+        let previous = match &self.previous {
+            _ if layer_index == 1 => &empty,
+            Some(previous) => previous,
+            None => {
+                return Err(NSEError::InvalidInput(format!(
+                    "Layer {} cannot be checked without the previous layer!",
+                    layer_index
+                )))
+            }
+        };
+        host_layer(
+            &self.config,
+            replica_id,
+            window_index,
+            layer_index,
+            previous,
+        )
+    }
+
+    fn check_layer(
+        &mut self,
+        kind: &str,
+        replica_id: ReplicaId,
+        window_index: WindowIndex,
+        layer_index: usize,
+        layer: Layer,
+    ) -> NSEResult<Layer> {
+        let host = self.host_layer(replica_id, window_index, layer_index)?;
+        self.check(format!("{} layer {}", kind, layer_index), &layer.0, &host)?;
+        self.previous = Some(layer.clone());
+        self.finalized = false;
+        Ok(layer)
+    }
+
+    fn host_combine(&self, offset: usize, segment: &[Node], mode: CombineMode) -> NSEResult<Layer> {
+        let range = segment_range(offset, segment.len(), self.config.num_nodes_window)?;
+        let key = match &self.previous {
+            Some(key) if self.finalized => key,
+            _ => {
+                return Err(NSEError::InvalidInput(
+                    "Only key layers generated and finalized in paranoid mode can be combined!"
+                        .into(),
+                ))
+            }
+        };
+        let mut nodes = segment.to_vec();
+        combine_nodes(&mut nodes, &key.0[range], mode, self.config.encoding_mode)?;
+        Ok(Layer(nodes))
+    }
+}
+
+impl<E: NarrowStackedExpander> NarrowStackedExpander for ParanoidMode<E> {
+    fn generate_mask_layer(
+        &mut self,
+        replica_id: ReplicaId,
+        window_index: WindowIndex,
+    ) -> NSEResult<Layer> {
+        let layer = self.inner.generate_mask_layer(replica_id, window_index)?;
+        self.check_layer("mask", replica_id, window_index, 1, layer)
+    }
+
+    fn generate_expander_layer(
+        &mut self,
+        replica_id: ReplicaId,
+        window_index: WindowIndex,
+        layer_index: usize,
+    ) -> NSEResult<Layer> {
+        let layer = self
+            .inner
+            .generate_expander_layer(replica_id, window_index, layer_index)?;
+        self.check_layer("expander", replica_id, window_index, layer_index, layer)
+    }
+
+    fn generate_butterfly_layer(
+        &mut self,
+        replica_id: ReplicaId,
+        window_index: WindowIndex,
+        layer_index: usize,
+    ) -> NSEResult<Layer> {
+        let layer = self
+            .inner
+            .generate_butterfly_layer(replica_id, window_index, layer_index)?;
+        self.check_layer("butterfly", replica_id, window_index, layer_index, layer)
+    }
+
+    fn finalize(&mut self) -> NSEResult<()> {
+        self.inner.finalize()?;
+        self.finalized = true;
+        Ok(())
+    }
+
+    fn combine_segment_into(
+        &mut self,
+        offset: usize,
+        segment: &[Node],
+        mode: CombineMode,
+        out: &mut [Node],
+    ) -> NSEResult<()> {
+        let host = self.host_combine(offset, segment, mode)?;
+        self.inner
+            .combine_segment_into(offset, segment, mode, out)?;
+        let operation = format!("combine of nodes {}..{}", offset, offset + out.len());
+        self.check(operation, out, &host)
+    }
+
+    fn combine_batches(
+        &mut self,
+        batches: Vec<(usize, &[Node], CombineMode)>,
+    ) -> NSEResult<Vec<Vec<Node>>> {
+        let hosts = batches
+            .iter()
+            .map(|&(offset, segment, mode)| self.host_combine(offset, segment, mode))
+            .collect::<NSEResult<Vec<_>>>()?;
+        let ranges = batches
+            .iter()
+            .map(|&(offset, segment, _)| offset..offset + segment.len())
+            .collect::<Vec<_>>();
+        let combined = self.inner.combine_batches(batches)?;
+        for ((range, device), host) in ranges.iter().zip(combined.iter()).zip(hosts.iter()) {
+            let operation = format!("batched combine of nodes {:?}", range);
+            self.check(operation, device, host)?;
+        }
+        Ok(combined)
+    }
+
+    fn combine_batch_size(&self) -> usize {
+        self.inner.combine_batch_size()
+    }
+
+    fn leaf_count(&self) -> usize {
+        self.inner.leaf_count()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Backend, GPUContext, SealerInput, TreeOptions, TEST_CONFIG};
+
+    const REPLICA_ID: ReplicaId = ReplicaId([7u8; 32]);
+    const WINDOW_INDEX: WindowIndex = WindowIndex(3);
+
+    // Corrupts node `node` of layer `layer_index` as it is returned.
+    struct Corrupting<E> {
+        inner: E,
+        layer_index: usize,
+        node: usize,
+    }
+
+    impl<E> Corrupting<E> {
+        fn corrupt(&self, layer_index: usize, mut layer: Layer) -> Layer {
+            if layer_index == self.layer_index {
+                layer.0[self.node] = Node::default();
+            }
+            layer
+        }
+    }
+
+    impl<E: NarrowStackedExpander> NarrowStackedExpander for Corrupting<E> {
+        fn generate_mask_layer(
+            &mut self,
+            replica_id: ReplicaId,
+            window_index: WindowIndex,
+        ) -> NSEResult<Layer> {
+            let layer = self.inner.generate_mask_layer(replica_id, window_index)?;
+            Ok(self.corrupt(1, layer))
+        }
+
+        fn generate_expander_layer(
+            &mut self,
+            replica_id: ReplicaId,
+            window_index: WindowIndex,
+            layer_index: usize,
+        ) -> NSEResult<Layer> {
+            let layer =
+                self.inner
+                    .generate_expander_layer(replica_id, window_index, layer_index)?;
+            Ok(self.corrupt(layer_index, layer))
+        }
+
+        fn generate_butterfly_layer(
+            &mut self,
+            replica_id: ReplicaId,
+            window_index: WindowIndex,
+            layer_index: usize,
+        ) -> NSEResult<Layer> {
+            let layer =
+                self.inner
+                    .generate_butterfly_layer(replica_id, window_index, layer_index)?;
+            Ok(self.corrupt(layer_index, layer))
+        }
+
+        fn finalize(&mut self) -> NSEResult<()> {
+            self.inner.finalize()
+        }
+
+        fn combine_segment_into(
+            &mut self,
+            offset: usize,
+            segment: &[Node],
+            mode: CombineMode,
+            out: &mut [Node],
+        ) -> NSEResult<()> {
+            self.inner.combine_segment_into(offset, segment, mode, out)
+        }
+
+        fn combine_batch_size(&self) -> usize {
+            self.inner.combine_batch_size()
+        }
+
+        fn leaf_count(&self) -> usize {
+            self.inner.leaf_count()
+        }
+    }
+
+    // Generates the key layers of the window and encodes `data` with them.
+    fn seal<E: NarrowStackedExpander>(expander: &mut E, data: &Layer) -> NSEResult<Layer> {
+        expander.generate_mask_layer(REPLICA_ID, WINDOW_INDEX)?;
+        for layer_index in 2..=TEST_CONFIG.num_expander_layers {
+            expander.generate_expander_layer(REPLICA_ID, WINDOW_INDEX, layer_index)?;
+        }
+        for layer_index in TEST_CONFIG.num_expander_layers + 1..=TEST_CONFIG.num_layers() {
+            expander.generate_butterfly_layer(REPLICA_ID, WINDOW_INDEX, layer_index)?;
+        }
+        expander.finalize()?;
+        expander.combine_layer(data, CombineMode::Encode)
+    }
+
+    #[test]
+    fn test_paranoid_mode() {
+        let data = Layer::sequential(TEST_CONFIG.num_nodes_window);
+        // Backends not supported by the build are skipped.
+        for &backend in Backend::ALL.iter().filter(|b| b.is_supported()) {
+            let mut gpu = backend.gpu(TEST_CONFIG, TreeOptions::Disabled).unwrap();
+            let expected = seal(&mut gpu, &data).unwrap();
+
+            let mut paranoid = ParanoidMode::new(TEST_CONFIG, gpu).unwrap();
+            assert!(paranoid.combine_layer(&data, CombineMode::Encode).is_err());
+            assert_eq!(expected, seal(&mut paranoid, &data).unwrap());
+            assert_eq!(TEST_CONFIG.num_layers() + 1, paranoid.checks());
+            let batches = vec![
+                (0, &expected.0[..100], CombineMode::Decode),
+                (200, &data.0[200..300], CombineMode::Encode),
+            ];
+            let combined = paranoid.combine_batches(batches).unwrap();
+            assert_eq!(data.0[..100].to_vec(), combined[0]);
+            assert_eq!(TEST_CONFIG.num_layers() + 3, paranoid.checks());
+
+            let corrupting = Corrupting {
+                inner: paranoid.into_inner(),
+                layer_index: 3,
+                node: 5,
+            };
+            let mut paranoid = ParanoidMode::new(TEST_CONFIG, corrupting).unwrap();
+            match seal(&mut paranoid, &data) {
+                Err(NSEError::Divergence {
+                    ref operation,
+                    node: 5,
+                    ..
+                }) if operation == "expander layer 3" => {}
+                other => panic!("Unexpected result {:?}", other),
+            }
+
+            let aes = Config {
+                mask_prf: MaskPrf::Aes256Ctr,
+                ..TEST_CONFIG
+            };
+            let gpu = backend.gpu(aes, TreeOptions::Disabled).unwrap();
+            assert!(ParanoidMode::new(aes, gpu).is_err());
+            let gpu = backend.gpu(TEST_CONFIG, TreeOptions::Disabled).unwrap();
+            let larger = Config {
+                num_nodes_window: 1 << 10,
+                ..TEST_CONFIG
+            };
+            assert!(ParanoidMode::new(larger, gpu).is_err());
+        }
+    }
+
+    fn window_seed(replica_id: ReplicaId, window_index: WindowIndex) -> ReplicaId {
+        let mut seed = replica_id;
+        seed.0[0] ^= window_index.0 as u8;
+        seed
+    }
+
+    #[test]
+    fn test_paranoid_seal() {
+        let ctx = GPUContext::default(TEST_CONFIG, TreeOptions::Disabled).unwrap();
+        let mut gpu = GPU::new(ctx, TEST_CONFIG).unwrap();
+        let input = SealerInput {
+            replica_id: REPLICA_ID,
+            window_index: WINDOW_INDEX,
+            original_data: Layer::sequential(TEST_CONFIG.num_nodes_window - 10),
+        };
+        let builder = || {
+            SealerBuilder::new(TEST_CONFIG, input.clone())
+                .padding(WindowPadding::PadAndTruncate)
+                .seed_fn(window_seed)
+        };
+        let expected = builder().build(&mut gpu).unwrap().seal().unwrap();
+        for &stage_data in &[false, true] {
+            let output = paranoid_seal(
+                builder()
+                    .stage_data(stage_data)
+                    .retention(RetentionPolicy::LastOnly),
+                &mut gpu,
+            )
+            .unwrap();
+            assert_eq!(expected.layers, output.layers);
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        assert!(paranoid_seal(builder().checkpoint_dir(dir.path()), &mut gpu).is_err());
+        let aes = Config {
+            mask_prf: MaskPrf::Aes256Ctr,
+            ..TEST_CONFIG
+        };
+        let builder = SealerBuilder::new(aes, input);
+        assert!(paranoid_seal(builder, &mut gpu).is_err());
+    }
+}
@@ -0,0 +1,111 @@
+use crate::{Layer, NSEError, NSEResult};
+#[cfg(feature = "host-combine")]
+use rayon::prelude::*;
+
+/// A reordering of the nodes of a layer, e.g. the one expected by a tree builder, see
+/// `Layer::permute`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Permutation {
+    /// Node `i` moves to the index made of the bits of `i` in reverse order. Layers must have a
+    /// power of two nodes.
+    BitReversal,
+    /// Node `i` of the output is node `sources[i]` of the input, every input node being used
+    /// exactly once.
+    Gather(Vec<usize>),
+}
+
+impl Permutation {
+    fn check(&self, len: usize) -> NSEResult<()> {
+        match self {
+            Permutation::BitReversal if !len.is_power_of_two() => {
+                Err(NSEError::InvalidInput(format!(
+                    "Cannot bit-reverse a layer of {} nodes, not a power of two!",
+                    len
+                )))
+            }
+            Permutation::BitReversal => Ok(()),
+            Permutation::Gather(sources) => {
+                let mut seen = vec![false; len];
+                if sources.len() != len
+                    || sources
+                        .iter()
+                        .any(|&s| s >= len || std::mem::replace(&mut seen[s], true))
+                {
+                    return Err(NSEError::InvalidInput(format!(
+                        "Not a permutation of the {} nodes of the layer!",
+                        len
+                    )));
+                }
+                Ok(())
+            }
+        }
+    }
+
+    // Index in the input of the node landing at `index` in the output, for `len` nodes.
+    fn source(&self, index: usize, len: usize) -> usize {
+        match self {
+            Permutation::BitReversal if len <= 1 => index,
+            Permutation::BitReversal => {
+                let bits = len.trailing_zeros() as usize;
+                index.reverse_bits() >> (std::mem::size_of::<usize>() * 8 - bits)
+            }
+            Permutation::Gather(sources) => sources[index],
+        }
+    }
+}
+
+impl Layer {
+    /// Returns the nodes of the layer reordered by `permutation`, moved in parallel on all
+    /// available CPU cores with the `host-combine` feature.
+    pub fn permute(&self, permutation: &Permutation) -> NSEResult<Layer> {
+        let len = self.0.len();
+        permutation.check(len)?;
+        #[cfg(feature = "host-combine")]
+        let indices = (0..len).into_par_iter();
+        #[cfg(not(feature = "host-combine"))]
+        let indices = 0..len;
+        Ok(Layer(
+            indices
+                .map(|i| self.0[permutation.source(i, len)])
+                .collect(),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::incrementing_layer;
+
+    #[test]
+    fn test_permute() {
+        let layer = incrementing_layer(0, 8);
+        let reversed = layer.permute(&Permutation::BitReversal).unwrap();
+        let expected = [0, 4, 2, 6, 1, 5, 3, 7];
+        let gathered = layer.permute(&Permutation::Gather(expected.to_vec()));
+        assert_eq!(reversed, gathered.unwrap());
+        assert_eq!(
+            expected.iter().map(|&i| layer.0[i]).collect::<Vec<_>>(),
+            reversed.0
+        );
+        assert_eq!(layer, reversed.permute(&Permutation::BitReversal).unwrap());
+
+        let large = incrementing_layer(567, 1024);
+        let twice = large
+            .permute(&Permutation::BitReversal)
+            .and_then(|l| l.permute(&Permutation::BitReversal))
+            .unwrap();
+        assert_eq!(large, twice);
+
+        assert!(incrementing_layer(0, 6)
+            .permute(&Permutation::BitReversal)
+            .is_err());
+        assert!(layer.permute(&Permutation::Gather(vec![0, 1, 2])).is_err());
+        assert!(layer
+            .permute(&Permutation::Gather(vec![0, 1, 2, 3, 4, 5, 6, 6]))
+            .is_err());
+        assert!(layer
+            .permute(&Permutation::Gather(vec![0, 1, 2, 3, 4, 5, 6, 8]))
+            .is_err());
+    }
+}
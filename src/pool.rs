@@ -1,83 +1,570 @@
-use crate::NarrowStackedExpander;
-use crate::{Config, GPUContext, LayerOutput, NSEResult, Sealer, SealerInput, TreeOptions, GPU};
+use crate::utils::Device;
+use crate::{
+    Backend, CancellationToken, Config, GPUContext, LayerOutput, NSEError, NSEResult,
+    PreemptionQueue, Sealer, SealerInput, TreeOptions, GPU,
+};
 use log::*;
-use ocl::Device;
+use std::collections::{BTreeMap, VecDeque};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc;
 use std::sync::{Arc, Condvar, Mutex};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
+
+/// How often idle workers check whether to release their GPU (see `IdlePolicy`), or to run the
+/// jobs of the preemption queue.
+const IDLE_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+type SealerJob = (SealerInput, Option<CancellationToken>, JobSink);
+
+type JobCallback = Box<dyn FnOnce(NSEResult<Vec<LayerOutput>>) + Send>;
+
+// Where a worker sends the outputs of a job.
+enum JobSink {
+    Channel(mpsc::Sender<NSEResult<LayerOutput>>),
+    // The outputs are collected until the job is done, then passed to the callback by the worker.
+    Callback(JobCallback, NSEResult<Vec<LayerOutput>>),
+}
+
+impl JobSink {
+    fn callback<F: FnOnce(NSEResult<Vec<LayerOutput>>) + Send + 'static>(callback: F) -> Self {
+        JobSink::Callback(Box::new(callback), Ok(Vec::new()))
+    }
+
+    // Returns whether the requester is still there.
+    fn send(&mut self, output: NSEResult<LayerOutput>) -> bool {
+        match self {
+            JobSink::Channel(sender) => sender.send(output).is_ok(),
+            JobSink::Callback(_, outputs) => {
+                match output {
+                    Ok(output) => {
+                        if let Ok(collected) = outputs {
+                            collected.push(output);
+                        }
+                    }
+                    Err(e) => {
+                        if outputs.is_ok() {
+                            *outputs = Err(e);
+                        }
+                    }
+                }
+                true
+            }
+        }
+    }
+
+    // Called once the job is done.
+    fn finish(self) {
+        if let JobSink::Callback(callback, outputs) = self {
+            if panic::catch_unwind(AssertUnwindSafe(|| callback(outputs))).is_err() {
+                error!("The callback of a job panicked!");
+            }
+        }
+    }
+
+    // Fails a job that cannot run.
+    fn fail(mut self, e: NSEError) {
+        self.send(Err(e));
+        self.finish();
+    }
+}
+
+/// A sealing job submitted to a `SealerPool`, whose layers are being labeled on one of its GPUs.
+pub struct SealJob(mpsc::Receiver<NSEResult<LayerOutput>>);
+
+impl SealJob {
+    /// Blocks until the job is done, returning the outputs of all of its layers, or its first
+    /// error.
+    pub fn wait(self) -> NSEResult<Vec<LayerOutput>> {
+        self.0.iter().collect()
+    }
+
+    /// The outputs of the layers, received as soon as they are labeled.
+    pub fn into_receiver(self) -> mpsc::Receiver<NSEResult<LayerOutput>> {
+        self.0
+    }
+}
+
+/// Order in which `SealResults` yields the results of its jobs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompletionOrder {
+    /// In the order of the inputs, e.g. to assemble the windows of a sector. A slow job holds
+    /// back the results of the ones after it.
+    Submission,
+    /// As soon as each job is done, for the lowest latency.
+    AsAvailable,
+}
+
+/// The results of sealing several windows on a `SealerPool`, see
+/// `SealerPool::complete_in_submission_order` and `SealerPool::complete_as_available`. Each item
+/// is the index of a job in the inputs along with the outputs of all of its layers, or its first
+/// error. Jobs are submitted as GPUs become free while the results are consumed, so they must be
+/// consumed for all jobs to run.
+pub struct SealResults<'a> {
+    pool: &'a mut SealerPool,
+    order: CompletionOrder,
+    inputs: VecDeque<SealerInput>,
+    submitted: usize,
+    running: usize,
+    next: usize, // Index of the next result yielded in submission order
+    done: BTreeMap<usize, NSEResult<Vec<LayerOutput>>>,
+    sender: mpsc::Sender<(usize, NSEResult<Vec<LayerOutput>>)>,
+    receiver: mpsc::Receiver<(usize, NSEResult<Vec<LayerOutput>>)>,
+}
+
+impl<'a> SealResults<'a> {
+    fn new(pool: &'a mut SealerPool, inputs: Vec<SealerInput>, order: CompletionOrder) -> Self {
+        let (sender, receiver) = mpsc::channel();
+        SealResults {
+            pool,
+            order,
+            inputs: inputs.into(),
+            submitted: 0,
+            running: 0,
+            next: 0,
+            done: BTreeMap::new(),
+            sender,
+            receiver,
+        }
+    }
+
+    pub fn order(&self) -> CompletionOrder {
+        self.order
+    }
+
+    // Submits the remaining inputs to free GPUs, waiting for one for the first input if `wait`.
+    fn submit(&mut self, mut wait: bool) {
+        while let Some(input) = self.inputs.pop_front() {
+            let index = self.submitted;
+            let sender = self.sender.clone();
+            let sink = JobSink::callback(move |result| {
+                let _ = sender.send((index, result));
+            });
+            if wait {
+                wait = false;
+                // The result of a failed job is received like the others.
+                if let Err(sink) = self.pool.dispatch_waiting(input, None, sink) {
+                    sink.fail(NSEError::NoHealthyDevice);
+                }
+            } else if self.pool.try_seal_on_gpu(&input, None, sink).is_err() {
+                self.inputs.push_front(input);
+                break;
+            }
+            self.submitted += 1;
+            self.running += 1;
+        }
+    }
+}
+
+impl<'a> Iterator for SealResults<'a> {
+    type Item = (usize, NSEResult<Vec<LayerOutput>>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            self.submit(false);
+            let index = match self.order {
+                CompletionOrder::Submission => {
+                    Some(self.next).filter(|i| self.done.contains_key(i))
+                }
+                CompletionOrder::AsAvailable => self.done.keys().next().cloned(),
+            };
+            if let Some(index) = index {
+                self.next += 1;
+                return self.done.remove(&index).map(|result| (index, result));
+            }
+            if self.running == 0 {
+                if self.inputs.is_empty() {
+                    return None;
+                }
+                // All GPUs are busy with jobs submitted by others.
+                self.submit(true);
+                continue;
+            }
+            let (index, result) = self.receiver.recv().expect("The results hold a sender");
+            self.running -= 1;
+            self.done.insert(index, result);
+        }
+    }
+}
+
+/// What the workers of a `SealerPool` do with their GPU between jobs, see
+/// `SealerPool::set_idle_policy`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IdlePolicy {
+    /// Keep the GPU contexts and their buffers, so that jobs start right away.
+    KeepWarm,
+    /// Drop the GPU of a worker once no job arrived for `idle`, along with its cached program and
+    /// OpenCL context, freeing its device memory (e.g. for SNARK proving). It is created again
+    /// when the next job arrives, which compiles the kernels again.
+    ReleaseContexts { idle: Duration },
+}
+
+impl Default for IdlePolicy {
+    fn default() -> Self {
+        IdlePolicy::KeepWarm
+    }
+}
+
+/// When the workers of a `SealerPool` quarantine their device, see `SealerPool::health`.
+/// Quarantined devices get no more jobs until they are reinstated.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HealthPolicy {
+    /// Quarantine a device after this many failed jobs in a row. `None` never does.
+    pub max_consecutive_failures: Option<u64>,
+    /// Quarantine a device whose kernel times per job vary more than this, as the ratio of
+    /// their standard deviation to their mean, once `min_timed_jobs` jobs completed on it. Jobs
+    /// of a pool all have the same size, so widely varying kernel times point at a throttling
+    /// or flaky card. `None` never does.
+    pub max_kernel_time_variation: Option<f64>,
+    pub min_timed_jobs: u64,
+}
+
+impl Default for HealthPolicy {
+    fn default() -> Self {
+        HealthPolicy {
+            max_consecutive_failures: Some(3),
+            max_kernel_time_variation: Some(1.0),
+            min_timed_jobs: 5,
+        }
+    }
+}
+
+/// Job statistics of a device of a `SealerPool`, see `SealerPool::health`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeviceHealth {
+    /// Index of the device in the pool.
+    pub device: usize,
+    pub name: String,
+    pub jobs: u64,
+    /// Jobs that failed because of the device (driver errors, timeouts, failed checks of its
+    /// results, ...). Jobs cancelled or failing on their input are not counted.
+    pub failures: u64,
+    pub consecutive_failures: u64,
+    /// Mean and standard deviation of the time spent running kernels (see `OpTimings::kernel`)
+    /// by the jobs completed since the device was created or reinstated. Unlike the wall time of
+    /// the jobs, it leaves out the host work and the preempting jobs.
+    pub mean_kernel_ms: f64,
+    pub kernel_ms_stddev: f64,
+    pub quarantined: bool,
+    /// The worker of the device stopped, e.g. because its GPU could not be created.
+    pub died: bool,
+}
+
+// How a job ended on a worker.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum JobOutcome {
+    // Along with the time spent running kernels.
+    Completed(Duration),
+    // Because of the device.
+    Failed,
+    // Cancelled, failed on its input, or the requester went away.
+    Aborted,
+}
+
+#[derive(Debug, Default)]
+struct HealthStats {
+    jobs: u64,
+    failures: u64,
+    consecutive_failures: u64,
+    // Running mean and sum of squared deviations of the kernel times of the jobs (Welford).
+    timed_jobs: u64,
+    mean_ms: f64,
+    m2: f64,
+    quarantined: bool,
+}
+
+impl HealthStats {
+    fn stddev_ms(&self) -> f64 {
+        if self.timed_jobs < 2 {
+            0f64
+        } else {
+            (self.m2 / (self.timed_jobs - 1) as f64).sqrt()
+        }
+    }
+
+    // Records a job, returns whether the device was just quarantined.
+    fn record(&mut self, outcome: JobOutcome, policy: &HealthPolicy) -> bool {
+        self.jobs += 1;
+        match outcome {
+            JobOutcome::Completed(time) => {
+                self.consecutive_failures = 0;
+                let ms = time.as_secs_f64() * 1000f64;
+                self.timed_jobs += 1;
+                let delta = ms - self.mean_ms;
+                self.mean_ms += delta / self.timed_jobs as f64;
+                self.m2 += delta * (ms - self.mean_ms);
+            }
+            JobOutcome::Failed => {
+                self.failures += 1;
+                self.consecutive_failures += 1;
+            }
+            JobOutcome::Aborted => {}
+        }
+        let failing = policy
+            .max_consecutive_failures
+            .map_or(false, |max| self.consecutive_failures >= max);
+        let erratic = policy.max_kernel_time_variation.map_or(false, |max| {
+            self.timed_jobs >= policy.min_timed_jobs.max(2) && self.stddev_ms() > max * self.mean_ms
+        });
+        let quarantine = !self.quarantined && (failing || erratic);
+        self.quarantined |= quarantine;
+        quarantine
+    }
+
+    fn reinstate(&mut self) {
+        *self = HealthStats {
+            jobs: self.jobs,
+            failures: self.failures,
+            ..HealthStats::default()
+        };
+    }
+}
+
+// Only errors pointing at the device count against it, not the ones of the job itself.
+fn failure_outcome(e: &NSEError) -> JobOutcome {
+    match e {
+        NSEError::GPU(_)
+        | NSEError::KernelTimeout(_)
+        | NSEError::SpotCheckFailed { .. }
+        | NSEError::RoundtripMismatch { .. }
+        | NSEError::FieldMismatch { .. }
+        | NSEError::Divergence { .. } => JobOutcome::Failed,
+        _ => JobOutcome::Aborted,
+    }
+}
+
+// Runs the jobs of `preemption` on the GPU of worker `i` while it is idle, creating the GPU
+// again if it was released. Returns whether any job ran.
+fn run_preempting_jobs<F: Fn() -> NSEResult<GPU>>(
+    i: usize,
+    preemption: &PreemptionQueue,
+    gpu: &mut Option<GPU>,
+    new_gpu: F,
+    warm: &AtomicBool,
+) -> bool {
+    if preemption.is_empty() {
+        return false;
+    }
+    if gpu.is_none() {
+        info!("Device[{}]: Creating the GPU context again...", i);
+        match new_gpu() {
+            Ok(new) => {
+                *gpu = Some(new);
+                warm.store(true, Ordering::SeqCst);
+            }
+            Err(e) => {
+                error!("Device[{}]: Cannot create GPU context! Error: {}", i, e);
+                return false;
+            }
+        }
+    }
+    match preemption.run_pending(gpu.as_mut().unwrap()) {
+        Ok(jobs) => {
+            info!("Device[{}]: Ran {} preempting jobs.", i, jobs);
+            jobs > 0
+        }
+        Err(e) => {
+            error!("Device[{}]: Preempting jobs failed! Error: {}", i, e);
+            false
+        }
+    }
+}
 
 struct SealerWorker {
     died: bool,
+    name: String,
     busy: Arc<Mutex<bool>>,
-    channel: mpsc::Sender<(SealerInput, mpsc::Sender<NSEResult<LayerOutput>>)>,
+    warm: Arc<AtomicBool>, // Whether the worker holds a GPU
+    health: Arc<Mutex<HealthStats>>,
+    channel: mpsc::Sender<SealerJob>,
 }
 
+impl SealerWorker {
+    fn is_available(&self) -> bool {
+        !self.died && !self.health.lock().unwrap().quarantined
+    }
+}
+
+/// Seals windows on several devices, one job per device at a time. The layers of a job are
+/// yielded in order, but jobs complete in no particular order: seal several windows with
+/// `complete_in_submission_order` or `complete_as_available` to get their results in a known one.
 pub struct SealerPool {
     lock: Mutex<()>,
     cond: Arc<Condvar>,
     workers: Vec<SealerWorker>,
+    idle_policy: Arc<Mutex<IdlePolicy>>,
+    health_policy: Arc<Mutex<HealthPolicy>>,
+    preemption: PreemptionQueue,
 }
 
 impl SealerPool {
     pub fn new(devices: Vec<Device>, config: Config, tree_options: TreeOptions) -> NSEResult<Self> {
+        Self::with_tree_options(
+            devices.into_iter().map(|dev| (dev, tree_options)).collect(),
+            config,
+        )
+    }
+
+    /// Creates a pool labeling on the first device of each pair, and building trees on the
+    /// second one. Several labeling devices may share a tree building device.
+    pub fn with_tree_devices(
+        pairs: Vec<(Device, Device)>,
+        config: Config,
+        rows_to_discard: usize,
+    ) -> NSEResult<Self> {
+        Self::with_tree_options(
+            pairs
+                .into_iter()
+                .map(|(dev, tree_device)| {
+                    let tree_options = TreeOptions::EnabledOn {
+                        rows_to_discard,
+                        device: tree_device,
+                    };
+                    (dev, tree_options)
+                })
+                .collect(),
+            config,
+        )
+    }
+
+    fn with_tree_options(devices: Vec<(Device, TreeOptions)>, config: Config) -> NSEResult<Self> {
         info!("Creating a sealer pool of {} devices.", devices.len());
 
         let mut workers = Vec::new();
         let cond = Arc::new(Condvar::new());
+        let idle_policy = Arc::new(Mutex::new(IdlePolicy::default()));
+        let health_policy = Arc::new(Mutex::new(HealthPolicy::default()));
+        let preemption = PreemptionQueue::new();
 
-        let tree_enabled = if let TreeOptions::Enabled { rows_to_discard: _ } = tree_options {
-            true
-        } else {
-            false
-        };
-
-        for (i, dev) in devices.into_iter().enumerate() {
-            info!("Creating Sealer-Worker on device[{}]: {}", i, dev.name()?);
+        for (i, (dev, tree_options)) in devices.into_iter().enumerate() {
+            let name = dev.name()?;
+            info!("Creating Sealer-Worker on device[{}]: {}", i, name);
+            let tree_enabled = tree_options.is_enabled();
 
-            let (fn_tx, fn_rx): (
-                mpsc::Sender<(SealerInput, mpsc::Sender<NSEResult<LayerOutput>>)>,
-                mpsc::Receiver<(SealerInput, mpsc::Sender<NSEResult<LayerOutput>>)>,
-            ) = mpsc::channel();
+            let (fn_tx, fn_rx): (mpsc::Sender<SealerJob>, mpsc::Receiver<SealerJob>) =
+                mpsc::channel();
 
             let busy = Arc::new(Mutex::new(false));
+            let warm = Arc::new(AtomicBool::new(false));
+            let health = Arc::new(Mutex::new(HealthStats::default()));
             workers.push(SealerWorker {
                 channel: fn_tx,
+                name,
                 busy: Arc::clone(&busy),
+                warm: Arc::clone(&warm),
+                health: Arc::clone(&health),
                 died: false,
             });
 
             let cond = Arc::clone(&cond);
+            let idle_policy = Arc::clone(&idle_policy);
+            let health_policy = Arc::clone(&health_policy);
+            let preemption = preemption.clone();
             thread::spawn(move || {
-                match GPUContext::new(dev, config.clone(), tree_options.clone())
-                    .and_then(|ctx| GPU::new(ctx, config.clone()))
-                {
-                    Ok(mut gpu) => {
+                let new_gpu = || {
+                    GPUContext::new(dev, config.clone(), tree_options.clone())
+                        .and_then(|ctx| GPU::new(ctx, config.clone()))
+                };
+                match new_gpu() {
+                    Ok(gpu) => {
                         info!(
                             "Device[{}]: GPU context initialized, waiting for inputs...",
                             i
                         );
+                        warm.store(true, Ordering::SeqCst);
+                        let mut gpu = Some(gpu);
+                        let mut idle_since = Instant::now();
 
-                        for (inp, sender) in fn_rx.into_iter() {
+                        loop {
+                            let (inp, cancellation, mut sink) =
+                                match fn_rx.recv_timeout(IDLE_POLL_INTERVAL) {
+                                    Ok(job) => job,
+                                    Err(mpsc::RecvTimeoutError::Timeout) => {
+                                        if run_preempting_jobs(
+                                            i,
+                                            &preemption,
+                                            &mut gpu,
+                                            &new_gpu,
+                                            &warm,
+                                        ) {
+                                            idle_since = Instant::now();
+                                        }
+                                        let policy = *idle_policy.lock().unwrap();
+                                        if let IdlePolicy::ReleaseContexts { idle } = policy {
+                                            if gpu.is_some() && idle_since.elapsed() >= idle {
+                                                info!("Device[{}]: Idle, releasing the GPU.", i);
+                                                gpu.take().unwrap().release();
+                                                warm.store(false, Ordering::SeqCst);
+                                            }
+                                        }
+                                        continue;
+                                    }
+                                    Err(mpsc::RecvTimeoutError::Disconnected) => break,
+                                };
                             info!("Device[{}]: New sealing request!", i);
                             let mut busy = busy.lock().unwrap();
-                            match Sealer::new(config.clone(), inp, &mut gpu, tree_enabled) {
-                                Ok(sealer) => {
-                                    for output in sealer {
-                                        // If receiving channel is dead
-                                        if sender.send(output).is_err() {
-                                            error!("Device[{}]: Requester died!", i);
-                                            break;
-                                        }
+                            let mut outcome = JobOutcome::Failed;
+                            if gpu.is_none() {
+                                info!("Device[{}]: Creating the GPU context again...", i);
+                                match new_gpu() {
+                                    Ok(new) => {
+                                        gpu = Some(new);
+                                        warm.store(true, Ordering::SeqCst);
+                                    }
+                                    Err(e) => {
+                                        error!(
+                                            "Device[{}]: Cannot create GPU context! Error: {}",
+                                            i, e
+                                        );
+                                        sink.send(Err(e));
                                     }
                                 }
-                                Err(e) => {
-                                    error!("Device[{}]: Cannot create sealer! Error: {}", i, e);
+                            }
+                            if let Some(gpu) = gpu.as_mut() {
+                                // Leaves out the preempting jobs run meanwhile.
+                                gpu.take_timings();
+                                let mut builder = Sealer::builder(config.clone(), inp)
+                                    .build_trees(tree_enabled)
+                                    .preemption(preemption.clone());
+                                if let Some(token) = cancellation {
+                                    builder = builder.cancellation(token);
                                 }
+                                outcome = match builder.build(gpu) {
+                                    Ok(sealer) => {
+                                        let mut failure = None;
+                                        for output in sealer {
+                                            if let Err(e) = &output {
+                                                failure = Some(failure_outcome(e));
+                                            }
+                                            // If receiving channel is dead
+                                            if !sink.send(output) {
+                                                error!("Device[{}]: Requester died!", i);
+                                                failure = Some(JobOutcome::Aborted);
+                                                break;
+                                            }
+                                        }
+                                        failure.unwrap_or_else(|| {
+                                            JobOutcome::Completed(gpu.take_timings().kernel)
+                                        })
+                                    }
+                                    Err(e) => {
+                                        error!("Device[{}]: Cannot create sealer! Error: {}", i, e);
+                                        let outcome = failure_outcome(&e);
+                                        sink.send(Err(e));
+                                        outcome
+                                    }
+                                };
+                            }
+                            let policy = *health_policy.lock().unwrap();
+                            if health.lock().unwrap().record(outcome, &policy) {
+                                warn!("Device[{}]: Unhealthy, quarantined.", i);
                             }
                             *busy = false;
                             drop(busy);
                             cond.notify_all(); // Notify that one GPU is not busy anymore
+                            sink.finish();
+                            idle_since = Instant::now();
                             info!("Device[{}]: Sealing finished, waiting for inputs...", i);
                         }
                     }
@@ -93,50 +580,213 @@ impl SealerPool {
             workers,
             lock: Mutex::new(()),
             cond,
+            idle_policy,
+            health_policy,
+            preemption,
         })
     }
 
+    /// The queue of jobs the workers run between the layers of their seals, or as soon as they
+    /// are idle, typically to unseal replicas with a bounded latency, see `PreemptionQueue`.
+    pub fn preemption_queue(&self) -> PreemptionQueue {
+        self.preemption.clone()
+    }
+
+    pub fn health_policy(&self) -> HealthPolicy {
+        *self.health_policy.lock().unwrap()
+    }
+
+    /// Sets when devices are quarantined, `HealthPolicy::default()` by default. It applies from
+    /// the next job of each device.
+    pub fn set_health_policy(&self, policy: HealthPolicy) {
+        *self.health_policy.lock().unwrap() = policy;
+    }
+
+    /// Job statistics of each device, in the order the devices were given, so that operators
+    /// can tell which card is flaky.
+    pub fn health(&self) -> Vec<DeviceHealth> {
+        self.workers
+            .iter()
+            .enumerate()
+            .map(|(device, worker)| {
+                let stats = worker.health.lock().unwrap();
+                DeviceHealth {
+                    device,
+                    name: worker.name.clone(),
+                    jobs: stats.jobs,
+                    failures: stats.failures,
+                    consecutive_failures: stats.consecutive_failures,
+                    mean_kernel_ms: stats.mean_ms,
+                    kernel_ms_stddev: stats.stddev_ms(),
+                    quarantined: stats.quarantined,
+                    died: worker.died,
+                }
+            })
+            .collect()
+    }
+
+    /// Lifts the quarantine of `device` (its index in the pool), e.g. once the card has been
+    /// checked, resetting its consecutive failures and kernel times.
+    pub fn reinstate(&self, device: usize) -> NSEResult<()> {
+        let worker = self.workers.get(device).ok_or_else(|| {
+            NSEError::InvalidInput(format!(
+                "Device {} is out of range, the pool has {} devices!",
+                device,
+                self.workers.len()
+            ))
+        })?;
+        worker.health.lock().unwrap().reinstate();
+        self.cond.notify_all();
+        Ok(())
+    }
+
+    pub fn idle_policy(&self) -> IdlePolicy {
+        *self.idle_policy.lock().unwrap()
+    }
+
+    /// Sets what workers do with their GPU between jobs, `IdlePolicy::KeepWarm` by default.
+    /// Idle workers apply it within a fraction of a second.
+    pub fn set_idle_policy(&self, policy: IdlePolicy) {
+        *self.idle_policy.lock().unwrap() = policy;
+    }
+
+    /// Number of workers currently holding a GPU, i.e. not released by the `IdlePolicy`.
+    pub fn warm_devices(&self) -> usize {
+        self.workers
+            .iter()
+            .filter(|w| !w.died && w.warm.load(Ordering::SeqCst))
+            .count()
+    }
+
+    /// Creates a pool over the devices selected by the `NSE_GPU_BACKEND` and `NSE_GPU_DEVICES`
+    /// environment variables.
+    pub fn from_env(config: Config, tree_options: TreeOptions) -> NSEResult<Self> {
+        Self::new(Backend::from_env()?.devices()?, config, tree_options)
+    }
+
+    /// Submits a sealing job, waiting for a free GPU if all of them are busy, e.g. for
+    /// synchronous callers doing `pool.submit(input).wait()`. The job fails with
+    /// `NSEError::NoHealthyDevice` if every device of the pool died or is quarantined.
+    pub fn submit(&mut self, inp: SealerInput) -> SealJob {
+        SealJob(self.seal_on_gpu(inp))
+    }
+
+    /// Submits a sealing job like `submit`, calling `callback` with its result once it is done,
+    /// so services can complete their own futures or requests from it without the pool depending
+    /// on an executor. The callback runs on the worker thread of the device, once the device is
+    /// free again: it must return quickly, and must not wait for other jobs of the pool.
+    pub fn submit_with_callback<F>(&mut self, inp: SealerInput, callback: F)
+    where
+        F: FnOnce(NSEResult<Vec<LayerOutput>>) + Send + 'static,
+    {
+        if let Err(sink) = self.dispatch_waiting(inp, None, JobSink::callback(callback)) {
+            sink.fail(NSEError::NoHealthyDevice);
+        }
+    }
+
+    /// Seals `inputs`, yielding the result of each job in the order of `inputs`.
+    pub fn complete_in_submission_order(&mut self, inputs: Vec<SealerInput>) -> SealResults {
+        SealResults::new(self, inputs, CompletionOrder::Submission)
+    }
+
+    /// Seals `inputs`, yielding the result of each job as soon as it is done, along with its
+    /// index in `inputs`.
+    pub fn complete_as_available(&mut self, inputs: Vec<SealerInput>) -> SealResults {
+        SealResults::new(self, inputs, CompletionOrder::AsAvailable)
+    }
+
     /// Gets a SealerInput and returns a receiving output channel as soon as a free GPU is found.
     /// Blocks if all GPUs are busy.
     pub fn seal_on_gpu(&mut self, inp: SealerInput) -> mpsc::Receiver<NSEResult<LayerOutput>> {
+        self.seal_on_gpu_with_cancellation(inp, None)
+    }
+
+    /// Like `seal_on_gpu`, but the job stops between layers (yielding `NSEError::Cancelled`)
+    /// once `cancellation` is cancelled, freeing the GPU for other requests.
+    pub fn seal_on_gpu_with_cancellation(
+        &mut self,
+        inp: SealerInput,
+        cancellation: Option<CancellationToken>,
+    ) -> mpsc::Receiver<NSEResult<LayerOutput>> {
+        let (tx, rx) = mpsc::channel();
+        if let Err(sink) = self.dispatch_waiting(inp, cancellation, JobSink::Channel(tx)) {
+            sink.fail(NSEError::NoHealthyDevice);
+        }
+        rx
+    }
+
+    // Passes the job to a free GPU, waiting for one if all of them are busy. Gives the sink back
+    // if no device can run it, all of them having died or been quarantined.
+    fn dispatch_waiting(
+        &mut self,
+        inp: SealerInput,
+        cancellation: Option<CancellationToken>,
+        mut sink: JobSink,
+    ) -> Result<(), JobSink> {
         const TIMEOUT: Duration = Duration::from_millis(5000);
 
         // Lock until a free GPU is found
         let mut lock = self.lock.lock().unwrap();
 
         loop {
-            // Try finding a free GPU
-            for worker in self.workers.iter_mut().filter(|w| !w.died) {
-                // Check if GPU is free
-                match worker.busy.try_lock() {
-                    Ok(mut busy) => {
-                        if !*busy {
-                            *busy = true;
-                            // A free GPU found! Create a communication channel and pass inputs
-                            let (tx, rx): (
-                                mpsc::Sender<NSEResult<LayerOutput>>,
-                                mpsc::Receiver<NSEResult<LayerOutput>>,
-                            ) = mpsc::channel();
-                            if worker.channel.send((inp.clone(), tx)).is_err() {
+            sink = match Self::dispatch(&mut self.workers, &inp, &cancellation, sink) {
+                Ok(()) => return Ok(()),
+                Err(sink) => sink,
+            };
+
+            if !self.workers.iter().any(|w| w.is_available()) {
+                error!("No healthy device left in the pool!");
+                return Err(sink);
+            }
+
+            // No free GPUs found, wait for a GPU to notify us
+            info!("Waiting for a free GPU...");
+            lock = self.cond.wait_timeout(lock, TIMEOUT).unwrap().0;
+        }
+    }
+
+    // Like `dispatch_waiting`, gives the sink back if all GPUs are busy.
+    fn try_seal_on_gpu(
+        &mut self,
+        inp: &SealerInput,
+        cancellation: Option<CancellationToken>,
+        sink: JobSink,
+    ) -> Result<(), JobSink> {
+        let _lock = self.lock.lock().unwrap();
+        Self::dispatch(&mut self.workers, inp, &cancellation, sink)
+    }
+
+    // Passes the job to a free GPU, if any, gives the sink back otherwise.
+    fn dispatch(
+        workers: &mut [SealerWorker],
+        inp: &SealerInput,
+        cancellation: &Option<CancellationToken>,
+        mut sink: JobSink,
+    ) -> Result<(), JobSink> {
+        for worker in workers.iter_mut().filter(|w| w.is_available()) {
+            // Check if GPU is free
+            match worker.busy.try_lock() {
+                Ok(mut busy) => {
+                    if !*busy {
+                        *busy = true;
+                        // A free GPU found! Pass the inputs along with where to send the outputs
+                        match worker
+                            .channel
+                            .send((inp.clone(), cancellation.clone(), sink))
+                        {
+                            Ok(()) => return Ok(()),
+                            Err(mpsc::SendError((_, _, returned))) => {
                                 warn!("Dead worker found! Marking as dead...");
                                 worker.died = true;
-                                continue;
+                                sink = returned;
                             }
-                            return rx;
                         }
                     }
-                    Err(_) => {}
                 }
+                Err(_) => {}
             }
-
-            if self.workers.iter().filter(|w| !w.died).count() == 0 {
-                panic!("No workers exist!");
-            }
-
-            // No free GPUs found, wait for a GPU to notify us
-            info!("Waiting for a free GPU...");
-            lock = self.cond.wait_timeout(lock, TIMEOUT).unwrap().0;
         }
+        Err(sink)
     }
 }
 
@@ -146,15 +796,6 @@ mod tests {
     use crate::*;
     use rand::{thread_rng, Rng};
 
-    const TEST_CONFIG: Config = Config {
-        k: 2,
-        num_nodes_window: 512,
-        degree_expander: 96,
-        degree_butterfly: 4,
-        num_expander_layers: 4,
-        num_butterfly_layers: 3,
-    };
-
     #[test]
     fn test_sealer_pool() {
         const NUM_RUNS: usize = 10;
@@ -163,7 +804,7 @@ mod tests {
         let inputs: Vec<SealerInput> = (0..NUM_RUNS)
             .map(|_| SealerInput {
                 replica_id: ReplicaId::random(&mut rng),
-                window_index: rng.gen(),
+                window_index: WindowIndex::from(rng.gen::<u32>()),
                 original_data: Layer::random(&mut rng, TEST_CONFIG.num_nodes_window),
             })
             .collect();
@@ -200,4 +841,250 @@ mod tests {
 
         assert_eq!(pool_outputs, normal_outputs);
     }
+
+    #[test]
+    fn test_submit() {
+        let mut rng = thread_rng();
+        let inputs = (0..2)
+            .map(|_| SealerInput {
+                replica_id: ReplicaId::random(&mut rng),
+                window_index: WindowIndex::from(rng.gen::<u32>()),
+                original_data: Layer::random(&mut rng, TEST_CONFIG.num_nodes_window),
+            })
+            .collect::<Vec<_>>();
+        let mut pool = SealerPool::new(
+            utils::all_devices().unwrap(),
+            TEST_CONFIG,
+            TreeOptions::Disabled,
+        )
+        .unwrap();
+
+        let waited = pool.submit(inputs[0].clone()).wait().unwrap();
+        let (tx, rx) = mpsc::channel();
+        pool.submit_with_callback(inputs[0].clone(), move |result| {
+            tx.send(result).unwrap();
+        });
+        assert_eq!(waited, rx.recv().unwrap().unwrap());
+
+        let ctx = GPUContext::default(TEST_CONFIG, TreeOptions::Disabled).unwrap();
+        let mut gpu = GPU::new(ctx, TEST_CONFIG).unwrap();
+        let expected = Sealer::new(TEST_CONFIG, inputs[1].clone(), &mut gpu, false)
+            .unwrap()
+            .collect::<NSEResult<Vec<_>>>()
+            .unwrap();
+        assert_eq!(expected, pool.submit(inputs[1].clone()).wait().unwrap());
+    }
+
+    #[test]
+    fn test_completion_order() {
+        const NUM_JOBS: usize = 6;
+        let mut rng = thread_rng();
+        let inputs = (0..NUM_JOBS)
+            .map(|_| SealerInput {
+                replica_id: ReplicaId::random(&mut rng),
+                window_index: WindowIndex::from(rng.gen::<u32>()),
+                original_data: Layer::random(&mut rng, TEST_CONFIG.num_nodes_window),
+            })
+            .collect::<Vec<_>>();
+        let mut pool = SealerPool::new(
+            utils::all_devices().unwrap(),
+            TEST_CONFIG,
+            TreeOptions::Disabled,
+        )
+        .unwrap();
+
+        let in_order = pool
+            .complete_in_submission_order(inputs.clone())
+            .map(|(index, result)| (index, result.unwrap()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            (0..NUM_JOBS).collect::<Vec<_>>(),
+            in_order.iter().map(|(index, _)| *index).collect::<Vec<_>>()
+        );
+        let mut as_available = pool
+            .complete_as_available(inputs)
+            .map(|(index, result)| (index, result.unwrap()))
+            .collect::<Vec<_>>();
+        as_available.sort_by_key(|(index, _)| *index);
+        assert_eq!(in_order, as_available);
+        assert!(pool.complete_as_available(Vec::new()).next().is_none());
+    }
+
+    #[test]
+    fn test_idle_policy() {
+        let mut rng = thread_rng();
+        let input = SealerInput {
+            replica_id: ReplicaId::random(&mut rng),
+            window_index: WindowIndex::from(rng.gen::<u32>()),
+            original_data: Layer::random(&mut rng, TEST_CONFIG.num_nodes_window),
+        };
+        let mut pool = SealerPool::new(
+            utils::all_devices().unwrap(),
+            TEST_CONFIG,
+            TreeOptions::Disabled,
+        )
+        .unwrap();
+        let expected = pool.submit(input.clone()).wait().unwrap();
+        assert_eq!(IdlePolicy::KeepWarm, pool.idle_policy());
+        assert!(pool.warm_devices() > 0);
+
+        pool.set_idle_policy(IdlePolicy::ReleaseContexts {
+            idle: Duration::from_millis(10),
+        });
+        let start = Instant::now();
+        while pool.warm_devices() > 0 {
+            assert!(
+                start.elapsed() < Duration::from_secs(10),
+                "GPUs not released"
+            );
+            thread::sleep(IDLE_POLL_INTERVAL);
+        }
+        // The GPU is created again for the job.
+        pool.set_idle_policy(IdlePolicy::KeepWarm);
+        assert_eq!(expected, pool.submit(input).wait().unwrap());
+        assert!(pool.warm_devices() > 0);
+    }
+
+    #[test]
+    fn test_health_stats() {
+        let policy = HealthPolicy::default();
+        let mut stats = HealthStats::default();
+        assert!(!stats.record(JobOutcome::Failed, &policy));
+        assert!(!stats.record(JobOutcome::Aborted, &policy));
+        assert!(!stats.record(JobOutcome::Failed, &policy));
+        assert!(stats.record(JobOutcome::Failed, &policy));
+        assert!(stats.quarantined);
+        assert_eq!(
+            (4, 3, 3),
+            (stats.jobs, stats.failures, stats.consecutive_failures)
+        );
+        stats.reinstate();
+        assert!(!stats.quarantined);
+        assert_eq!(
+            (4, 3, 0),
+            (stats.jobs, stats.failures, stats.consecutive_failures)
+        );
+
+        // Steady job times, then one taking much longer than the others.
+        for &ms in &[100, 110, 90, 100, 100] {
+            let outcome = JobOutcome::Completed(Duration::from_millis(ms));
+            assert!(!stats.record(outcome, &policy));
+        }
+        assert!((stats.mean_ms - 100f64).abs() < 1e-6);
+        assert!((stats.stddev_ms() - 50f64.sqrt()).abs() < 1e-6);
+        assert!(stats.record(JobOutcome::Completed(Duration::from_secs(10)), &policy));
+
+        let lenient = HealthPolicy {
+            max_consecutive_failures: None,
+            max_kernel_time_variation: None,
+            ..policy
+        };
+        let mut stats = HealthStats::default();
+        for _ in 0..10 {
+            assert!(!stats.record(JobOutcome::Failed, &lenient));
+        }
+
+        let timeout = NSEError::KernelTimeout(Duration::from_secs(1));
+        assert_eq!(JobOutcome::Failed, failure_outcome(&timeout));
+        let spot_check = NSEError::SpotCheckFailed {
+            layer_index: 1,
+            node: 2,
+        };
+        assert_eq!(JobOutcome::Failed, failure_outcome(&spot_check));
+        let invalid = NSEError::InvalidInput("Wrong size".to_string());
+        assert_eq!(JobOutcome::Aborted, failure_outcome(&invalid));
+        assert_eq!(JobOutcome::Aborted, failure_outcome(&NSEError::Cancelled));
+    }
+
+    #[test]
+    fn test_pool_health() {
+        let mut rng = thread_rng();
+        let input = SealerInput {
+            replica_id: ReplicaId::random(&mut rng),
+            window_index: WindowIndex::from(rng.gen::<u32>()),
+            original_data: Layer::random(&mut rng, TEST_CONFIG.num_nodes_window),
+        };
+        let mut pool = SealerPool::new(
+            utils::all_devices().unwrap(),
+            TEST_CONFIG,
+            TreeOptions::Disabled,
+        )
+        .unwrap();
+        pool.submit(input.clone()).wait().unwrap();
+        let health = pool.health();
+        assert_eq!(1, health.iter().map(|h| h.jobs).sum::<u64>());
+        assert!(health
+            .iter()
+            .all(|h| h.failures == 0 && !h.quarantined && !h.died));
+        assert!(health.iter().any(|h| h.mean_kernel_ms > 0f64));
+
+        // Wrong-sized data fails, but not because of the device.
+        pool.set_health_policy(HealthPolicy {
+            max_consecutive_failures: Some(1),
+            ..HealthPolicy::default()
+        });
+        let invalid = SealerInput {
+            original_data: Layer::random(&mut rng, 3),
+            ..input.clone()
+        };
+        assert!(pool.submit(invalid).wait().is_err());
+        let health = pool.health();
+        assert_eq!(2, health.iter().map(|h| h.jobs).sum::<u64>());
+        assert!(health.iter().all(|h| h.failures == 0 && !h.quarantined));
+
+        // Jobs fail once every device is quarantined, and run again once one is reinstated.
+        for worker in &pool.workers {
+            worker.health.lock().unwrap().quarantined = true;
+        }
+        match pool.submit(input.clone()).wait() {
+            Err(NSEError::NoHealthyDevice) => {}
+            res => panic!("Unexpected result: {:?}", res.map(|_| ())),
+        }
+        let (tx, rx) = mpsc::channel();
+        pool.submit_with_callback(input.clone(), move |result| {
+            tx.send(result.map(|_| ())).unwrap();
+        });
+        match rx.recv().unwrap() {
+            Err(NSEError::NoHealthyDevice) => {}
+            res => panic!("Unexpected result: {:?}", res),
+        }
+        pool.reinstate(0).unwrap();
+        assert!(!pool.health()[0].quarantined);
+        pool.submit(input).wait().unwrap();
+        assert!(pool.reinstate(pool.health().len()).is_err());
+    }
+
+    #[test]
+    fn test_sealer_pool_tree_devices() {
+        let mut rng = thread_rng();
+        let input = SealerInput {
+            replica_id: ReplicaId::random(&mut rng),
+            window_index: WindowIndex::from(rng.gen::<u32>()),
+            original_data: Layer::random(&mut rng, TEST_CONFIG.num_nodes_window),
+        };
+
+        // Every device hashes its layers on the default one.
+        let tree_device = utils::default_device().unwrap();
+        let pairs = utils::all_devices()
+            .unwrap()
+            .into_iter()
+            .map(|dev| (dev, tree_device))
+            .collect();
+        let mut pool = SealerPool::with_tree_devices(pairs, TEST_CONFIG, 2).unwrap();
+        let pool_output = pool
+            .seal_on_gpu(input.clone())
+            .iter()
+            .collect::<NSEResult<Vec<_>>>()
+            .unwrap();
+
+        let ctx =
+            GPUContext::default(TEST_CONFIG, TreeOptions::Enabled { rows_to_discard: 2 }).unwrap();
+        let mut gpu = GPU::new(ctx, TEST_CONFIG).unwrap();
+        let normal_output = Sealer::new(TEST_CONFIG, input, &mut gpu, true)
+            .unwrap()
+            .collect::<NSEResult<Vec<_>>>()
+            .unwrap();
+
+        assert_eq!(pool_output, normal_output);
+    }
 }
@@ -0,0 +1,110 @@
+use crate::{NSEError, NSEResult, Node};
+use generic_array::typenum::Unsigned;
+use neptune::Arity;
+use paired::bls12_381::{Bls12, Fr};
+
+/// Largest arity hashed by `GPU::hash_columns`, bounded by the state of the kernel.
+pub const POSEIDON_MAX_ARITY: usize = 11;
+
+/// Round constants and MDS matrix of a Poseidon instance, see `GPU::hash_columns`. They are
+/// uploaded with every call rather than hard-coded in the kernels, so that columns are hashed
+/// exactly like neptune hashes them on the host; convert neptune's constants with `From`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PoseidonConstants {
+    pub arity: usize,
+    /// First element of the state, followed by the preimage.
+    pub domain_tag: Node,
+    /// Number of full rounds, half of them before the partial rounds and half after.
+    pub full_rounds: usize,
+    pub partial_rounds: usize,
+    /// `arity + 1` constants per round, added to the state before its S-boxes.
+    pub round_constants: Vec<Node>,
+    /// The `(arity + 1) * (arity + 1)` matrix, row after row. The state is multiplied by it as a
+    /// row vector.
+    pub mds: Vec<Node>,
+}
+
+impl PoseidonConstants {
+    /// Number of elements of the state.
+    pub fn width(&self) -> usize {
+        self.arity + 1
+    }
+
+    pub(crate) fn validate(&self) -> NSEResult<()> {
+        let width = self.width();
+        if self.arity == 0 || self.arity > POSEIDON_MAX_ARITY {
+            return Err(NSEError::InvalidInput(format!(
+                "Poseidon arity {} is not within [1, {}]!",
+                self.arity, POSEIDON_MAX_ARITY
+            )));
+        }
+        if self.full_rounds % 2 != 0 {
+            return Err(NSEError::InvalidInput(format!(
+                "Poseidon has an odd number of full rounds ({})!",
+                self.full_rounds
+            )));
+        }
+        let rounds = self.full_rounds + self.partial_rounds;
+        if self.round_constants.len() != rounds * width {
+            return Err(NSEError::InvalidInput(format!(
+                "Expected {} Poseidon round constants, got {}!",
+                rounds * width,
+                self.round_constants.len()
+            )));
+        }
+        if self.mds.len() != width * width {
+            return Err(NSEError::InvalidInput(format!(
+                "Expected a {}x{} MDS matrix, got {} entries!",
+                width,
+                width,
+                self.mds.len()
+            )));
+        }
+        Ok(())
+    }
+}
+
+impl<'a, A: Arity<Fr>> From<&'a neptune::poseidon::PoseidonConstants<Bls12, A>>
+    for PoseidonConstants
+{
+    fn from(constants: &neptune::poseidon::PoseidonConstants<Bls12, A>) -> Self {
+        PoseidonConstants {
+            arity: A::to_usize(),
+            domain_tag: Node(constants.domain_tag),
+            full_rounds: constants.full_rounds,
+            partial_rounds: constants.partial_rounds,
+            round_constants: constants
+                .round_constants
+                .iter()
+                .cloned()
+                .map(Node)
+                .collect(),
+            mds: constants
+                .mds_matrices
+                .m
+                .iter()
+                .flat_map(|row| row.iter().cloned().map(Node))
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use generic_array::typenum::{U2, U8};
+
+    #[test]
+    fn test_from_neptune() {
+        let constants =
+            PoseidonConstants::from(&neptune::poseidon::PoseidonConstants::<Bls12, U8>::new());
+        assert_eq!(8, constants.arity);
+        assert_eq!(81, constants.mds.len());
+        assert!(constants.validate().is_ok());
+
+        let mut invalid =
+            PoseidonConstants::from(&neptune::poseidon::PoseidonConstants::<Bls12, U2>::new());
+        invalid.round_constants.pop();
+        assert!(invalid.validate().is_err());
+    }
+}
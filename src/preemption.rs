@@ -0,0 +1,226 @@
+use crate::{Layer, NSEResult, ReplicaId, Unsealer, WindowIndex, GPU};
+use log::*;
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{mpsc, Arc, Mutex};
+
+/// A short job run on the GPU of a sealer between two of its layers, see `PreemptionQueue`.
+pub type PreemptingJob = Box<dyn FnOnce(&mut GPU) + Send>;
+
+struct QueuedJob {
+    priority: u32,
+    seq: u64,
+    job: PreemptingJob,
+}
+
+// Highest priority first, then first queued first.
+impl Ord for QueuedJob {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.priority
+            .cmp(&other.priority)
+            .then_with(|| other.seq.cmp(&self.seq))
+    }
+}
+
+impl PartialOrd for QueuedJob {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for QueuedJob {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for QueuedJob {}
+
+#[derive(Default)]
+struct QueueState {
+    jobs: BinaryHeap<QueuedJob>,
+    next_seq: u64,
+    max_jobs_per_point: Option<usize>,
+}
+
+/// A priority queue of short jobs, typically unsealing a replica, that preempt sealing: sealers
+/// built with `SealerBuilder::preemption` run them at each preemption point, between layers and
+/// between the batches of the final combine, which bounds the latency of retrievals on a GPU
+/// busy sealing. Clones share the queue.
+#[derive(Clone, Default)]
+pub struct PreemptionQueue(Arc<Mutex<QueueState>>);
+
+impl PreemptionQueue {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Queues `job`, run before the jobs of lower `priority`, and after the jobs of the same
+    /// priority queued earlier. A panicking job is logged and skipped.
+    pub fn push<F: FnOnce(&mut GPU) + Send + 'static>(&self, priority: u32, job: F) {
+        let mut state = self.0.lock().unwrap();
+        let seq = state.next_seq;
+        state.next_seq += 1;
+        state.jobs.push(QueuedJob {
+            priority,
+            seq,
+            job: Box::new(job),
+        });
+    }
+
+    /// Queues unsealing `replica`, a whole window sealed with the config of the GPU running the
+    /// job. The unsealed window is received from the returned channel.
+    pub fn unseal(
+        &self,
+        priority: u32,
+        replica_id: ReplicaId,
+        window_index: WindowIndex,
+        replica: Layer,
+    ) -> mpsc::Receiver<NSEResult<Layer>> {
+        let (tx, rx) = mpsc::channel();
+        self.push(priority, move |gpu: &mut GPU| {
+            let config = gpu.config();
+            let unsealed = Unsealer::new(config, replica_id, window_index, gpu)
+                .and_then(|unsealer| unsealer.decode_only().unseal_layer(replica));
+            // The requester may have given up.
+            let _ = tx.send(unsealed);
+        });
+        rx
+    }
+
+    /// Number of jobs waiting.
+    pub fn len(&self) -> usize {
+        self.0.lock().unwrap().jobs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Bounds the number of jobs run at each preemption point, trading the latency of the jobs
+    /// for the throughput of sealing. Unbounded (`None`) by default.
+    pub fn set_max_jobs_per_point(&self, max_jobs: Option<usize>) {
+        self.0.lock().unwrap().max_jobs_per_point = max_jobs;
+    }
+
+    pub fn max_jobs_per_point(&self) -> Option<usize> {
+        self.0.lock().unwrap().max_jobs_per_point
+    }
+
+    /// Runs the queued jobs on `gpu`, highest priority first, up to `max_jobs_per_point`. The
+    /// window being worked on is suspended meanwhile, see `GPU::suspend`. Returns the number of
+    /// jobs run.
+    pub fn run_pending(&self, gpu: &mut GPU) -> NSEResult<usize> {
+        let max_jobs = self.max_jobs_per_point().unwrap_or(usize::max_value());
+        if max_jobs == 0 || self.is_empty() {
+            return Ok(0);
+        }
+        let suspended = gpu.suspend()?;
+        let mut count = 0;
+        while count < max_jobs {
+            // Not locked while the job runs, so that others can be queued meanwhile.
+            let queued = match self.0.lock().unwrap().jobs.pop() {
+                Some(queued) => queued,
+                None => break,
+            };
+            let job = queued.job;
+            if panic::catch_unwind(AssertUnwindSafe(|| job(gpu))).is_err() {
+                error!("A preempting job of priority {} panicked!", queued.priority);
+            }
+            count += 1;
+        }
+        gpu.resume(suspended)?;
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::*;
+    use rand::{thread_rng, Rng};
+
+    #[test]
+    fn test_run_pending() {
+        let ctx = GPUContext::default(TEST_CONFIG, TreeOptions::Disabled).unwrap();
+        let mut gpu = GPU::new(ctx, TEST_CONFIG).unwrap();
+        let queue = PreemptionQueue::new();
+        assert_eq!(0, queue.run_pending(&mut gpu).unwrap());
+
+        let order = Arc::new(Mutex::new(Vec::new()));
+        for &(priority, name) in &[(1, "a"), (5, "b"), (1, "c"), (5, "d")] {
+            let order = Arc::clone(&order);
+            queue.push(priority, move |_: &mut GPU| {
+                order.lock().unwrap().push(name)
+            });
+        }
+        queue.push(3, |_: &mut GPU| panic!("Preempting job failure"));
+        assert_eq!(5, queue.len());
+
+        queue.set_max_jobs_per_point(Some(2));
+        assert_eq!(2, queue.run_pending(&mut gpu).unwrap());
+        assert_eq!(vec!["b", "d"], *order.lock().unwrap());
+        queue.set_max_jobs_per_point(None);
+        assert_eq!(3, queue.run_pending(&mut gpu).unwrap());
+        assert_eq!(vec!["b", "d", "a", "c"], *order.lock().unwrap());
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn test_unseal_preempting_seal() {
+        let mut rng = thread_rng();
+        let ctx = GPUContext::default(TEST_CONFIG, TreeOptions::Disabled).unwrap();
+        let mut gpu = GPU::new(ctx, TEST_CONFIG).unwrap();
+        let mut input = || SealerInput {
+            replica_id: ReplicaId::random(&mut rng),
+            window_index: WindowIndex::from(rng.gen::<u32>()),
+            original_data: Layer::random(&mut rng, TEST_CONFIG.num_nodes_window),
+        };
+        let (unsealed_input, sealed_input) = (input(), input());
+        let replica = SealerBuilder::new(TEST_CONFIG, unsealed_input.clone())
+            .build(&mut gpu)
+            .unwrap()
+            .seal()
+            .unwrap()
+            .layers
+            .pop()
+            .unwrap()
+            .base;
+        let expected = SealerBuilder::new(TEST_CONFIG, sealed_input.clone())
+            .build(&mut gpu)
+            .unwrap()
+            .seal()
+            .unwrap();
+
+        // Queued halfway through the seal, unsealed before its next layer.
+        let queue = PreemptionQueue::new();
+        let mut unsealed = None;
+        let output = {
+            let progress_queue = queue.clone();
+            let (replica_id, window_index) =
+                (unsealed_input.replica_id, unsealed_input.window_index);
+            let unsealed = &mut unsealed;
+            SealerBuilder::new(TEST_CONFIG, sealed_input)
+                .preemption(queue.clone())
+                .progress(move |done, _| {
+                    if done == 3 {
+                        *unsealed = Some(progress_queue.unseal(
+                            1,
+                            replica_id,
+                            window_index,
+                            replica.clone(),
+                        ));
+                    }
+                })
+                .build(&mut gpu)
+                .unwrap()
+                .seal()
+                .unwrap()
+        };
+        assert!(queue.is_empty());
+        assert_eq!(expected.layers, output.layers);
+        let unsealed = unsealed.unwrap().try_recv().unwrap().unwrap();
+        assert_eq!(unsealed_input.original_data, unsealed);
+    }
+}
@@ -0,0 +1,45 @@
+//! The stable API of the crate, for downstream crates to glob-import:
+//!
+//! ```ignore
+//! use rust_fil_nse_gpu::prelude::*;
+//! ```
+//!
+//! Everything exported here exists whichever backend features are enabled (without `gpu`, the
+//! GPU types are stand-ins whose constructors return `NSEError::NoGpuSupport`), so code written
+//! against the prelude keeps compiling as backends are added or disabled. Backend-specific items
+//! (`KernelHarness`, `FaultInjector`, `host_combine`, ...) are only exported from the crate root.
+
+pub use crate::{
+    Backend, BackendFactory, CombineMode, Config, DataCommitment, Domain, DomainTags, EncodingMode,
+    GPUContext, GpuConfig, KeyGenerator, LabeledLayer, Layer, LayerKind, LayerOutput, MaskPrf,
+    NSEError, NSEResult, NarrowStackedExpander, Node, ReplicaId, SealOutput, SealReceipt, Sealer,
+    SealerBuilder, SealerInput, SectorManifest, Sha256Domain, TreeOptions, Unsealer, WindowIndex,
+    WindowPadding, GPU, NODE_SIZE,
+};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prelude() {
+        let config = Config {
+            k: 2,
+            num_nodes_window: 512,
+            degree_expander: 96,
+            degree_butterfly: 4,
+            num_expander_layers: 4,
+            num_butterfly_layers: 3,
+            encoding_mode: EncodingMode::FieldAdd,
+            domain_tags: DomainTags::UNTAGGED,
+            mask_prf: MaskPrf::Sha256,
+        };
+        assert!(config.validate().is_ok());
+        let layer = Layer(vec![Node::default(); config.num_nodes_window]);
+        assert_eq!(config.window_byte_len(), layer.0.len() * NODE_SIZE);
+        // The GPU types exist, if only as stand-ins.
+        let gpu: NSEResult<GPU> = GPUContext::default(config, TreeOptions::Disabled)
+            .and_then(|ctx| GPU::new(ctx, config));
+        let _: Option<Box<dyn NarrowStackedExpander>> = gpu.ok().map(|gpu| Box::new(gpu) as _);
+    }
+}
@@ -0,0 +1,142 @@
+//! Compiled programs shared by all the GPU contexts of a process. Compiling the kernels takes
+//! seconds, creating many `GPUContext`s with the same `Config` on the same device (e.g. one
+//! `Sealer` per window) only pays it once.
+use crate::sources::{self, KernelVariant};
+use crate::{Config, GPUResult};
+use lazy_static::lazy_static;
+use log::{info, warn};
+use ocl::flags::CommandQueueProperties;
+use ocl::{Context, Device, ProQue, Program, Queue};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+#[derive(PartialEq, Eq, Hash, Clone, Copy)]
+struct ProgramKey {
+    device: Device,
+    config: Config,
+}
+
+// The program of a key, built by the first context needing it while the others wait. Programs
+// are bound to the context they were built in, which is cached along with them.
+#[derive(Default)]
+struct ProgramSlot {
+    program: Option<(Context, Program)>,
+    builds: usize,
+}
+
+lazy_static! {
+    // Only locked to look slots up, programs are built under the lock of their slot, so that
+    // contexts for other devices or configs are not blocked meanwhile.
+    static ref PROGRAMS: Mutex<HashMap<ProgramKey, Arc<Mutex<ProgramSlot>>>> =
+        Mutex::new(HashMap::new());
+}
+
+fn build_program(
+    device: Device,
+    config: Config,
+    variant: KernelVariant,
+) -> GPUResult<(Context, Program)> {
+    let code = sources::generate_nse_program(config, variant);
+    let context = Context::builder().devices(device).build()?;
+    let program = Program::builder()
+        .devices(device)
+        .src(code)
+        .build(&context)?;
+    Ok((context, program))
+}
+
+// Builds the best kernel variant for `device`, falling back to generic kernels.
+fn build_best_program(device: Device, config: Config) -> GPUResult<(Context, Program)> {
+    let variant = KernelVariant::for_device(device)?;
+    match build_program(device, config, variant) {
+        Err(e) if variant != KernelVariant::Generic => {
+            warn!(
+                "Cannot build {:?} kernels ({}), falling back to generic kernels...",
+                variant, e
+            );
+            build_program(device, config, KernelVariant::Generic)
+        }
+        res => res,
+    }
+}
+
+/// Returns a `ProQue` running the kernels of `config` on `device`, with a queue of its own,
+/// profiling the kernels (see `OpTimings::kernel`).
+pub(crate) fn pro_que(device: Device, config: Config) -> GPUResult<ProQue> {
+    let slot = Arc::clone(
+        PROGRAMS
+            .lock()
+            .unwrap()
+            .entry(ProgramKey { device, config })
+            .or_default(),
+    );
+    let (context, program) = {
+        let mut slot = slot.lock().unwrap();
+        match &slot.program {
+            Some(cached) => {
+                info!("Reusing compiled kernels...");
+                cached.clone()
+            }
+            None => {
+                // A failed build leaves the slot empty, for the next context to retry.
+                info!("Compiling kernels...");
+                let built = build_best_program(device, config)?;
+                slot.program = Some(built.clone());
+                slot.builds += 1;
+                built
+            }
+        }
+    };
+    let queue = Queue::new(
+        &context,
+        device,
+        Some(CommandQueueProperties::PROFILING_ENABLE),
+    )?;
+    Ok(ProQue::new(
+        context,
+        queue,
+        program,
+        Some(config.num_nodes_window),
+    ))
+}
+
+/// Drops all cached programs, e.g. to release device memory. GPU contexts already created keep
+/// their program alive.
+pub fn clear_program_cache() {
+    PROGRAMS.lock().unwrap().clear();
+}
+
+/// Drops the program of `config` on `device`, so that the next context built for them gets a
+/// fresh OpenCL context, e.g. after a kernel of the current one hung.
+pub(crate) fn evict(device: Device, config: Config) {
+    PROGRAMS
+        .lock()
+        .unwrap()
+        .remove(&ProgramKey { device, config });
+}
+
+/// Number of programs currently cached.
+pub fn program_cache_len() -> usize {
+    // Waits for the programs being built, without blocking the lookups meanwhile.
+    let slots = PROGRAMS
+        .lock()
+        .unwrap()
+        .values()
+        .cloned()
+        .collect::<Vec<_>>();
+    slots
+        .iter()
+        .filter(|slot| slot.lock().unwrap().program.is_some())
+        .count()
+}
+
+/// Number of times the program of `config` on `device` was built since it was last evicted.
+#[cfg(test)]
+pub(crate) fn build_count(device: Device, config: Config) -> usize {
+    PROGRAMS
+        .lock()
+        .unwrap()
+        .get(&ProgramKey { device, config })
+        .map(|slot| slot.lock().unwrap().builds)
+        .unwrap_or(0)
+}
@@ -0,0 +1,297 @@
+use crate::{
+    Config, KeyCache, Layer, NSEError, NSEResult, NarrowStackedExpander, ReplicaId, Unsealer,
+    WindowIndex, GPU, NODE_SIZE,
+};
+use memmap::Mmap;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::Path;
+
+/// Number of decoded segments kept in memory by default.
+const DEFAULT_CACHE_CAPACITY: usize = 4;
+
+// The key of a window is on the GPU while reading from it. Switching to another window
+// regenerates its key (or loads it from the key cache).
+enum KeyState<'a> {
+    Idle(&'a mut GPU),
+    Window(WindowIndex, Unsealer<'a>),
+}
+
+// Least recently used decoded segments, most recent first.
+struct SegmentCache {
+    capacity: usize,
+    segments: VecDeque<((usize, usize), Vec<u8>)>,
+}
+
+impl SegmentCache {
+    fn get(&mut self, key: (usize, usize)) -> Option<&[u8]> {
+        let pos = self.segments.iter().position(|(k, _)| *k == key)?;
+        let segment = self.segments.remove(pos)?;
+        self.segments.push_front(segment);
+        self.segments.front().map(|(_, s)| s.as_slice())
+    }
+
+    fn insert(&mut self, key: (usize, usize), segment: Vec<u8>) {
+        self.segments.push_front((key, segment));
+        self.segments.truncate(self.capacity);
+    }
+}
+
+/// File-like access to the original data of a sealed replica file, decoding only the segments
+/// of windows touched by reads. Window `i` of the file is the replica of window index `i`, the
+/// last window of the file may be truncated.
+pub struct UnsealedReader<'a> {
+    config: Config,
+    replica_id: ReplicaId,
+    replica: Mmap,
+    position: u64,
+    segment_len: usize, // In nodes
+    key_cache: Option<&'a KeyCache>,
+    key_state: Option<KeyState<'a>>,
+    cache: SegmentCache,
+}
+
+impl<'a> UnsealedReader<'a> {
+    pub fn new<P: AsRef<Path>>(
+        config: Config,
+        replica_id: ReplicaId,
+        gpu: &'a mut GPU,
+        replica_path: P,
+    ) -> NSEResult<Self> {
+        let replica = File::open(replica_path)?;
+        if replica.metadata()?.len() % NODE_SIZE as u64 != 0 {
+            return Err(NSEError::InvalidInput(
+                "Replica file is not a whole number of nodes!".into(),
+            ));
+        }
+        let replica = unsafe { Mmap::map(&replica)? };
+        Ok(UnsealedReader {
+            config,
+            replica_id,
+            replica,
+            position: 0,
+            segment_len: gpu.combine_batch_size(),
+            key_cache: None,
+            key_state: Some(KeyState::Idle(gpu)),
+            cache: SegmentCache {
+                capacity: DEFAULT_CACHE_CAPACITY,
+                segments: VecDeque::new(),
+            },
+        })
+    }
+
+    /// Loads (or stores) the key layers of windows from (or to) `key_cache`.
+    pub fn key_cache(mut self, key_cache: &'a KeyCache) -> Self {
+        self.key_cache = Some(key_cache);
+        self
+    }
+
+    /// Number of nodes decoded at once. Defaults to the combine batch size of the GPU.
+    pub fn segment_len(mut self, segment_len: usize) -> Self {
+        self.segment_len = std::cmp::max(segment_len, 1);
+        self.cache.segments.clear();
+        self
+    }
+
+    /// Number of decoded segments kept in memory.
+    pub fn cache_capacity(mut self, capacity: usize) -> Self {
+        self.cache.capacity = std::cmp::max(capacity, 1);
+        self.cache.segments.truncate(self.cache.capacity);
+        self
+    }
+
+    /// Length of the unsealed data, in bytes.
+    pub fn len(&self) -> u64 {
+        self.replica.len() as u64
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.replica.is_empty()
+    }
+
+    fn unsealer(&mut self, window_index: WindowIndex) -> NSEResult<&mut Unsealer<'a>> {
+        let state = match self.key_state.take() {
+            Some(KeyState::Window(w, unsealer)) if w == window_index => {
+                KeyState::Window(w, unsealer)
+            }
+            Some(state) => {
+                let gpu = match state {
+                    KeyState::Idle(gpu) => gpu,
+                    KeyState::Window(_, unsealer) => unsealer.into_gpu(),
+                };
+                let unsealer = match self.key_cache {
+                    Some(key_cache) => Unsealer::with_key_cache(
+                        self.config,
+                        self.replica_id,
+                        window_index,
+                        gpu,
+                        key_cache,
+                    ),
+                    None => Unsealer::new(self.config, self.replica_id, window_index, gpu),
+                }?;
+                KeyState::Window(window_index, unsealer)
+            }
+            None => unreachable!(), // Only taken temporarily
+        };
+        self.key_state = Some(state);
+        match self.key_state.as_mut() {
+            Some(KeyState::Window(_, unsealer)) => Ok(unsealer),
+            _ => unreachable!(),
+        }
+    }
+
+    fn decode_segment(&mut self, window: usize, segment: usize) -> NSEResult<Vec<u8>> {
+        let leaf_count = self.config.leaf_count();
+        let window_start = window * leaf_count;
+        let offset = segment * self.segment_len;
+        let end = std::cmp::min(
+            std::cmp::min(offset + self.segment_len, leaf_count),
+            self.replica.len() / NODE_SIZE - window_start,
+        );
+        let sealed = Layer::try_from_bytes(
+            &self.replica[(window_start + offset) * NODE_SIZE..(window_start + end) * NODE_SIZE],
+        )?;
+        let unsealed = self
+            .unsealer(WindowIndex(window as u64))?
+            .unseal_range(offset, &sealed.0)?;
+        Ok(Vec::<u8>::from(&Layer(unsealed)))
+    }
+
+    // Copies the bytes at the current position into `buf`, up to the end of their segment.
+    fn read_segment(&mut self, buf: &mut [u8]) -> NSEResult<usize> {
+        let window_byte_len = self.config.window_byte_len() as u64;
+        let segment_byte_len = (self.segment_len * NODE_SIZE) as u64;
+        let window = (self.position / window_byte_len) as usize;
+        let segment = (self.position % window_byte_len / segment_byte_len) as usize;
+        let start = (self.position % window_byte_len % segment_byte_len) as usize;
+        if self.cache.get((window, segment)).is_none() {
+            let decoded = self.decode_segment(window, segment)?;
+            self.cache.insert((window, segment), decoded);
+        }
+        let decoded = self.cache.get((window, segment)).unwrap();
+        let len = std::cmp::min(buf.len(), decoded.len() - start);
+        buf[..len].copy_from_slice(&decoded[start..start + len]);
+        Ok(len)
+    }
+}
+
+fn to_io_error(e: NSEError) -> io::Error {
+    match e {
+        NSEError::Io(e) => e,
+        e => io::Error::new(io::ErrorKind::Other, e.to_string()),
+    }
+}
+
+impl<'a> Read for UnsealedReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() || self.position >= self.len() {
+            return Ok(0);
+        }
+        let len = self.read_segment(buf).map_err(to_io_error)?;
+        self.position += len as u64;
+        Ok(len)
+    }
+}
+
+impl<'a> Seek for UnsealedReader<'a> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => offset_position(self.len(), offset),
+            SeekFrom::Current(offset) => offset_position(self.position, offset),
+        };
+        match position {
+            Some(position) => {
+                self.position = position;
+                Ok(position)
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Invalid seek to a negative or overflowing position",
+            )),
+        }
+    }
+}
+
+fn offset_position(base: u64, offset: i64) -> Option<u64> {
+    if offset >= 0 {
+        base.checked_add(offset as u64)
+    } else {
+        base.checked_sub(offset.wrapping_neg() as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{GPUContext, Sealer, SealerInput, TreeOptions, TEST_CONFIG};
+    use rand::thread_rng;
+    use std::io::Write;
+
+    const TEST_REPLICA_ID: ReplicaId = ReplicaId([123u8; 32]);
+
+    #[test]
+    fn test_unsealed_reader() {
+        let mut rng = thread_rng();
+        let ctx = GPUContext::default(TEST_CONFIG, TreeOptions::Disabled).unwrap();
+        let mut gpu = GPU::new(ctx, TEST_CONFIG).unwrap();
+
+        // Two full windows, and the first half of a third one.
+        let mut original = Vec::new();
+        let mut replica = tempfile::NamedTempFile::new().unwrap();
+        for window_index in 0..3 {
+            let data = Layer::random(&mut rng, TEST_CONFIG.leaf_count());
+            let sealed = Sealer::new(
+                TEST_CONFIG,
+                SealerInput {
+                    replica_id: TEST_REPLICA_ID,
+                    window_index: WindowIndex(window_index),
+                    original_data: data.clone(),
+                },
+                &mut gpu,
+                false,
+            )
+            .unwrap()
+            .seal()
+            .unwrap()
+            .layers
+            .pop()
+            .unwrap()
+            .base;
+            let mut data = Vec::<u8>::from(&data);
+            let mut sealed = Vec::<u8>::from(&sealed);
+            if window_index == 2 {
+                data.truncate(data.len() / 2);
+                sealed.truncate(sealed.len() / 2);
+            }
+            original.extend(data);
+            replica.write_all(&sealed).unwrap();
+        }
+        replica.flush().unwrap();
+
+        let mut reader =
+            UnsealedReader::new(TEST_CONFIG, TEST_REPLICA_ID, &mut gpu, replica.path())
+                .unwrap()
+                .segment_len(100)
+                .cache_capacity(2);
+        assert_eq!(original.len() as u64, reader.len());
+        let mut unsealed = Vec::new();
+        reader.read_to_end(&mut unsealed).unwrap();
+        assert_eq!(original, unsealed);
+
+        let pos = TEST_CONFIG.window_byte_len() as u64 + 1234;
+        assert_eq!(pos, reader.seek(SeekFrom::Start(pos)).unwrap());
+        let mut buf = vec![0u8; 5000];
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(&original[pos as usize..pos as usize + 5000], buf.as_slice());
+
+        assert!(reader
+            .seek(SeekFrom::Current(-(pos as i64) - 10000))
+            .is_err());
+        reader.seek(SeekFrom::End(-10)).unwrap();
+        let mut tail = Vec::new();
+        reader.read_to_end(&mut tail).unwrap();
+        assert_eq!(&original[original.len() - 10..], tail.as_slice());
+    }
+}
@@ -0,0 +1,333 @@
+use crate::{
+    BuildInfo, DataCommitment, LayerStats, NSEError, NSEResult, ReplicaId, Sha256Domain,
+    WindowIndex,
+};
+#[cfg(feature = "json")]
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// Version of the crate, recorded in `SealReceipt`s.
+pub const LIBRARY_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Device name recorded in `SealReceipt`s when the driver reports none.
+pub const UNKNOWN_DEVICE: &str = "unknown";
+
+/// The device a window was sealed on.
+#[derive(PartialEq, Eq, Debug, Clone)]
+#[cfg_attr(feature = "json", derive(Serialize, Deserialize))]
+pub struct DeviceIdentity {
+    /// See `GPU::device_name`.
+    pub name: String,
+    /// See `GPU::device_key`.
+    pub key: String,
+}
+
+/// A layer produced by a `Sealer`.
+#[derive(PartialEq, Debug, Clone)]
+#[cfg_attr(feature = "json", derive(Serialize, Deserialize))]
+pub struct LayerReceipt {
+    #[cfg_attr(feature = "json", serde(flatten))]
+    pub stats: LayerStats,
+    /// `Layer::digest` of the layer (the replica truncated as configured by
+    /// `SealerBuilder::padding`), `None` unless enabled with `SealerBuilder::layer_digests`, and
+    /// for key layers left on the device, see `RetentionPolicy`.
+    pub digest: Option<Sha256Domain>,
+}
+
+/// Machine-readable record of the sealing of a window, returned by `Sealer::seal`, so that
+/// sealing farms can audit results and reproduce them with the same config, replica id and
+/// window index. Digests and ids are serialized as hex strings.
+#[derive(PartialEq, Debug, Clone)]
+#[cfg_attr(feature = "json", derive(Serialize, Deserialize))]
+pub struct SealReceipt {
+    /// See `LIBRARY_VERSION`.
+    pub library_version: String,
+    /// See `Config::fingerprint`.
+    pub config_fingerprint: Sha256Domain,
+    pub replica_id: ReplicaId,
+    pub window_index: WindowIndex,
+    pub device: DeviceIdentity,
+    /// One per layer, the replica last.
+    pub layers: Vec<LayerReceipt>,
+    /// See `SealerBuilder::comm_d`.
+    pub comm_d: Option<Sha256Domain>,
+    /// See `SealerBuilder::build_info`, `None` if disabled or in receipts of versions before it
+    /// was recorded.
+    #[cfg_attr(feature = "json", serde(default))]
+    pub build: Option<BuildInfo>,
+}
+
+impl SealReceipt {
+    #[cfg(feature = "json")]
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("Receipts are serializable")
+    }
+
+    #[cfg(feature = "json")]
+    pub fn from_json(json: &str) -> NSEResult<Self> {
+        serde_json::from_str(json)
+            .map_err(|e| NSEError::InvalidInput(format!("Invalid seal receipt: {}", e)))
+    }
+
+    /// `Layer::digest` of the replica, the last layer.
+    pub fn replica_digest(&self) -> Option<Sha256Domain> {
+        self.layers.last().and_then(|layer| layer.digest)
+    }
+}
+
+/// Machine-readable record of the sealing of a whole sector, aggregating the `SealReceipt`s of
+/// its windows along with the sector-level commitments, so that downstream actors consume one
+/// artifact per sector. The crate seals windows, not sectors: once all windows of a sector are
+/// sealed, e.g. with `Sealer::seal`, the caller builds the manifest from their receipts, see
+/// `SealOutput::receipt`.
+#[derive(PartialEq, Debug, Clone)]
+#[cfg_attr(feature = "json", derive(Serialize, Deserialize))]
+pub struct SectorManifest {
+    pub replica_id: ReplicaId,
+    /// See `Config::fingerprint`.
+    pub config_fingerprint: Sha256Domain,
+    /// One per window, ordered by window index, from 0.
+    pub windows: Vec<SealReceipt>,
+    /// Data commitment of the sector: the root of the tree over the `comm_d` of the windows,
+    /// `None` unless all of them have one, see `SealerBuilder::comm_d`.
+    pub comm_d: Option<Sha256Domain>,
+    /// SHA-256 of the replica digests of the windows, in order.
+    pub replica_digest: Sha256Domain,
+}
+
+impl SectorManifest {
+    /// Aggregates the receipts of all windows of a sector, given in any order. They must have
+    /// the same replica id and config, window indices from 0 to the number of windows, which
+    /// must be a power of two, and a replica digest, see `SealerBuilder::layer_digests`.
+    pub fn new(mut windows: Vec<SealReceipt>) -> NSEResult<Self> {
+        windows.sort_by_key(|receipt| receipt.window_index);
+        let (replica_id, config_fingerprint) = match windows.first() {
+            Some(first) => (first.replica_id, first.config_fingerprint),
+            None => {
+                return Err(NSEError::InvalidInput(
+                    "A sector has at least one window!".into(),
+                ))
+            }
+        };
+        let (comm_d, replica_digest) =
+            sector_commitments(replica_id, config_fingerprint, &windows)?;
+        Ok(SectorManifest {
+            replica_id,
+            config_fingerprint,
+            windows,
+            comm_d,
+            replica_digest,
+        })
+    }
+
+    /// Checks that the windows are those of the sector, in order, and that the sector-level
+    /// commitments are theirs, e.g. after deserializing a manifest.
+    pub fn verify(&self) -> NSEResult<()> {
+        let (comm_d, replica_digest) =
+            sector_commitments(self.replica_id, self.config_fingerprint, &self.windows)?;
+        if comm_d != self.comm_d {
+            return Err(NSEError::InvalidInput(
+                "The comm_d of the sector does not match its windows!".into(),
+            ));
+        }
+        if replica_digest != self.replica_digest {
+            return Err(NSEError::InvalidInput(
+                "The replica digest of the sector does not match its windows!".into(),
+            ));
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "json")]
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("Manifests are serializable")
+    }
+
+    #[cfg(feature = "json")]
+    pub fn from_json(json: &str) -> NSEResult<Self> {
+        serde_json::from_str(json)
+            .map_err(|e| NSEError::InvalidInput(format!("Invalid sector manifest: {}", e)))
+    }
+}
+
+// Checks the windows of a sector, ordered by window index, and returns its `comm_d` and replica
+// digest, see `SectorManifest`.
+fn sector_commitments(
+    replica_id: ReplicaId,
+    config_fingerprint: Sha256Domain,
+    windows: &[SealReceipt],
+) -> NSEResult<(Option<Sha256Domain>, Sha256Domain)> {
+    // The windows are the leaves of the tree of `comm_d`.
+    if !windows.len().is_power_of_two() {
+        return Err(NSEError::InvalidInput(format!(
+            "A sector of {} windows, not a power of two!",
+            windows.len()
+        )));
+    }
+    let mut hasher = Sha256::new();
+    for (i, window) in windows.iter().enumerate() {
+        if window.window_index != WindowIndex(i as u64) {
+            return Err(NSEError::InvalidInput(format!(
+                "Window {} of the sector is window {}!",
+                i, window.window_index.0
+            )));
+        }
+        if window.replica_id != replica_id || window.config_fingerprint != config_fingerprint {
+            return Err(NSEError::InvalidInput(format!(
+                "Window {} belongs to another replica or config!",
+                i
+            )));
+        }
+        let digest = window.replica_digest().ok_or_else(|| {
+            NSEError::InvalidInput(format!("Window {} has no replica digest!", i))
+        })?;
+        hasher.input(&digest);
+    }
+    let mut replica_digest = [0u8; 32];
+    replica_digest.copy_from_slice(&hasher.result());
+
+    let comm_d = if windows.iter().all(|window| window.comm_d.is_some()) {
+        // Windows are aligned subtrees of the tree over the nodes of the sector.
+        let mut commitment = DataCommitment::<Sha256Domain>::new(windows.len())?;
+        for window in windows {
+            commitment.push_subtree(0, window.comm_d.unwrap())?;
+        }
+        Some(commitment.root()?)
+    } else {
+        None
+    };
+    Ok((comm_d, Sha256Domain(replica_digest)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{commitment_hash, LayerKind};
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn test_seal_receipt_json() {
+        let receipt = SealReceipt {
+            library_version: LIBRARY_VERSION.to_string(),
+            config_fingerprint: Sha256Domain([7u8; 32]),
+            replica_id: ReplicaId([123u8; 32]),
+            window_index: WindowIndex(3),
+            device: DeviceIdentity {
+                name: "Device".to_string(),
+                key: "bus-1".to_string(),
+            },
+            layers: vec![
+                LayerReceipt {
+                    stats: LayerStats {
+                        layer_index: 1,
+                        kind: LayerKind::Mask,
+                        kernel_ms: 1.5,
+                        transfer_ms: 0f64,
+                        transfer_bytes: 0,
+                        nodes_per_sec: 1000f64,
+                    },
+                    digest: None,
+                },
+                LayerReceipt {
+                    stats: LayerStats {
+                        layer_index: 2,
+                        kind: LayerKind::Replica,
+                        kernel_ms: 2.5,
+                        transfer_ms: 1f64,
+                        transfer_bytes: 1024,
+                        nodes_per_sec: 500f64,
+                    },
+                    digest: Some(Sha256Domain([0xabu8; 32])),
+                },
+            ],
+            comm_d: None,
+            build: Some(crate::build_info()),
+        };
+        let json = receipt.to_json();
+        assert!(json.contains(&"7b".repeat(32)));
+        assert!(json.contains(&"ab".repeat(32)));
+        assert!(json.contains("\"kind\": \"Replica\""));
+        assert!(json.contains("\"field.cl\""));
+        assert_eq!(receipt, SealReceipt::from_json(&json).unwrap());
+        assert!(SealReceipt::from_json("{}").is_err());
+    }
+
+    fn window_receipt(window_index: u64, comm_d: Option<Sha256Domain>) -> SealReceipt {
+        SealReceipt {
+            library_version: LIBRARY_VERSION.to_string(),
+            config_fingerprint: Sha256Domain([7u8; 32]),
+            replica_id: ReplicaId([123u8; 32]),
+            window_index: WindowIndex(window_index),
+            device: DeviceIdentity {
+                name: "Device".to_string(),
+                key: "bus-1".to_string(),
+            },
+            layers: vec![LayerReceipt {
+                stats: LayerStats {
+                    layer_index: 1,
+                    kind: LayerKind::Replica,
+                    kernel_ms: 2.5,
+                    transfer_ms: 1f64,
+                    transfer_bytes: 1024,
+                    nodes_per_sec: 500f64,
+                },
+                digest: Some(Sha256Domain([window_index as u8; 32])),
+            }],
+            comm_d,
+            build: None,
+        }
+    }
+
+    #[test]
+    fn test_sector_manifest() {
+        let comm_d = |i: u64| Some(Sha256Domain([0x10 + i as u8; 32]));
+        let windows = (0..4)
+            .map(|i| window_receipt(i, comm_d(i)))
+            .collect::<Vec<_>>();
+        let mut shuffled = windows.clone();
+        shuffled.swap(0, 3);
+        let manifest = SectorManifest::new(shuffled).unwrap();
+        assert_eq!(windows, manifest.windows);
+        let expected_comm_d = commitment_hash(
+            &commitment_hash(&comm_d(0).unwrap(), &comm_d(1).unwrap()),
+            &commitment_hash(&comm_d(2).unwrap(), &comm_d(3).unwrap()),
+        );
+        assert_eq!(Some(expected_comm_d), manifest.comm_d);
+        assert!(manifest.verify().is_ok());
+        #[cfg(feature = "json")]
+        assert_eq!(
+            manifest,
+            SectorManifest::from_json(&manifest.to_json()).unwrap()
+        );
+
+        // Without comm_d of every window, there is none for the sector.
+        let mut partial = windows.clone();
+        partial[2].comm_d = None;
+        assert_eq!(None, SectorManifest::new(partial).unwrap().comm_d);
+
+        // Missing, duplicated and foreign windows.
+        assert!(SectorManifest::new(Vec::new()).is_err());
+        assert!(SectorManifest::new(windows[1..].to_vec()).is_err());
+        match SectorManifest::new(windows[..3].to_vec()) {
+            Err(NSEError::InvalidInput(msg)) => assert!(msg.contains("power of two")),
+            other => panic!("{:?}", other),
+        }
+        let mut duplicated = windows.clone();
+        duplicated[3].window_index = WindowIndex(2);
+        assert!(SectorManifest::new(duplicated).is_err());
+        let mut foreign = windows.clone();
+        foreign[1].replica_id = ReplicaId([1u8; 32]);
+        assert!(SectorManifest::new(foreign).is_err());
+
+        // Tampered manifests.
+        let mut tampered = manifest.clone();
+        tampered.windows[1].layers[0].digest = Some(Sha256Domain([0xffu8; 32]));
+        assert!(tampered.verify().is_err());
+        let mut tampered = manifest.clone();
+        tampered.windows[1].comm_d = comm_d(5);
+        assert!(tampered.verify().is_err());
+        let mut tampered = manifest;
+        tampered.windows.swap(1, 2);
+        assert!(tampered.verify().is_err());
+    }
+}
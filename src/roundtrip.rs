@@ -0,0 +1,183 @@
+//! Roundtrip checks of the encoding: unsealing the replica of a window must give its original
+//! data back, whichever backend, config and unsealing path is used, and sealing must produce
+//! the same replica whether or not the last layer is fused with the combine.
+
+use crate::{
+    Backend, Config, Layer, NSEError, NSEResult, ReplicaId, SealerBuilder, SealerInput,
+    TreeOptions, Unsealer, WindowIndex,
+};
+use rand::Rng;
+
+/// Number of nodes of the segments unsealed by `roundtrip_check_on`, not a power of two so that
+/// segments straddle the batches of the kernels.
+const ROUNDTRIP_SEGMENT_LEN: usize = 100;
+
+/// Same as `roundtrip_check_on`, on the backend selected by `NSE_GPU_BACKEND`.
+pub fn roundtrip_check(config: Config) -> NSEResult<()> {
+    roundtrip_check_on(Backend::from_env()?, config)
+}
+
+/// Seals random data of a window with `config` on `backend`, with and without the fused last
+/// layer, then unseals the replica as a whole, with the key layers kept on the device, in
+/// segments and, with the `host-combine` feature, on the host. Fails with
+/// `NSEError::RoundtripMismatch` at the first of them that differs.
+pub fn roundtrip_check_on(backend: Backend, config: Config) -> NSEResult<()> {
+    config.validate()?;
+    let mut rng = rand::thread_rng();
+    let replica_id = ReplicaId::random(&mut rng);
+    let window_index = WindowIndex::from(rng.gen::<u32>());
+    // Below 2^253, as required by `EncodingMode::Xor`.
+    let original_data = Layer::from_seed(rng.gen(), config.num_nodes_window);
+    let mut gpu = backend.gpu(config, TreeOptions::Disabled)?;
+
+    let mut replicas = Vec::with_capacity(2);
+    for &fuse_last_layer in &[true, false] {
+        let input = SealerInput {
+            replica_id,
+            window_index,
+            original_data: original_data.clone(),
+        };
+        let output = SealerBuilder::new(config, input)
+            .fuse_last_layer(fuse_last_layer)
+            .build(&mut gpu)?
+            .seal()?;
+        let replica = output
+            .layers
+            .last()
+            .expect("There is a replica")
+            .base
+            .clone();
+        replicas.push(replica);
+    }
+    check("the unfused replica", &replicas[0], &replicas[1])?;
+    let replica = replicas.pop().unwrap();
+
+    let unsealed = Unsealer::new(config, replica_id, window_index, &mut gpu)?
+        .decode_only()
+        .unseal_layer(replica.clone())?;
+    check("the unsealed window", &original_data, &unsealed)?;
+
+    let mut unsealer = Unsealer::new(config, replica_id, window_index, &mut gpu)?;
+    let mut segments = Vec::with_capacity(replica.0.len());
+    for (i, segment) in replica.0.chunks(ROUNDTRIP_SEGMENT_LEN).enumerate() {
+        segments.extend(unsealer.unseal_range(i * ROUNDTRIP_SEGMENT_LEN, segment)?);
+    }
+    check("the unsealed segments", &original_data, &Layer(segments))?;
+
+    #[cfg(feature = "host-combine")]
+    {
+        let key_layer = crate::KeyGenerator::new(config, replica_id, window_index, &mut gpu)?
+            .last()
+            .expect("There is a key layer")?;
+        let unsealed = crate::HostCombiner::with_encoding_mode(key_layer, config.encoding_mode)
+            .combine_layer(&replica, crate::CombineMode::Decode)?;
+        check("the window unsealed on the host", &original_data, &unsealed)?;
+    }
+    Ok(())
+}
+
+fn check(what: &'static str, expected: &Layer, actual: &Layer) -> NSEResult<()> {
+    let node = expected
+        .diff(actual, 1)
+        .first()
+        .map(|(i, _, _)| *i)
+        .or_else(|| {
+            if expected.0.len() != actual.0.len() {
+                Some(expected.0.len().min(actual.0.len()))
+            } else {
+                None
+            }
+        });
+    match node {
+        Some(node) => Err(NSEError::RoundtripMismatch { what, node }),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{DomainTags, EncodingMode, MaskPrf, TEST_CONFIG};
+
+    // Byte-aligned and unaligned expander parents, and a larger k with few parents, each with
+    // both encoding modes and both mask PRFs.
+    fn configs() -> Vec<Config> {
+        let shapes = [
+            TEST_CONFIG,
+            Config {
+                num_nodes_window: 1 << 10,
+                ..TEST_CONFIG
+            },
+            Config {
+                k: 4,
+                num_nodes_window: 256,
+                degree_expander: 8,
+                degree_butterfly: 2,
+                num_expander_layers: 2,
+                num_butterfly_layers: 3,
+                domain_tags: DomainTags {
+                    mask: 1,
+                    expander: 2,
+                    butterfly: 3,
+                },
+                ..TEST_CONFIG
+            },
+        ];
+        let mut configs = Vec::new();
+        for shape in shapes.iter() {
+            for &encoding_mode in &[EncodingMode::FieldAdd, EncodingMode::Xor] {
+                for &mask_prf in &[MaskPrf::Sha256, MaskPrf::Aes256Ctr] {
+                    configs.push(Config {
+                        encoding_mode,
+                        mask_prf,
+                        ..*shape
+                    });
+                }
+            }
+        }
+        configs
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        // Backends not supported by the build are skipped.
+        for &backend in Backend::ALL.iter().filter(|b| b.is_supported()) {
+            for config in configs() {
+                if let Err(e) = roundtrip_check_on(backend, config) {
+                    panic!("Backend {}, {:?}: {}", backend, config, e);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_roundtrip_check_errors() {
+        let invalid = Config {
+            k: 3,
+            ..TEST_CONFIG
+        };
+        match roundtrip_check_on(Backend::OpenCl, invalid) {
+            Err(NSEError::InvalidInput(_)) => {}
+            other => panic!("Unexpected result {:?}", other),
+        }
+        if !Backend::OpenCl.is_supported() {
+            match roundtrip_check_on(Backend::OpenCl, TEST_CONFIG) {
+                Err(NSEError::UnsupportedBackend(Backend::OpenCl)) => {}
+                other => panic!("Unexpected result {:?}", other),
+            }
+        }
+
+        let layer = Layer::sequential(4);
+        assert!(check("layer", &layer, &layer).is_ok());
+        let mut other = layer.clone();
+        other.0[2] = layer.0[1];
+        match check("layer", &layer, &other) {
+            Err(NSEError::RoundtripMismatch { node: 2, .. }) => {}
+            other => panic!("Unexpected result {:?}", other),
+        }
+        match check("layer", &layer, &Layer(layer.0[..3].to_vec())) {
+            Err(NSEError::RoundtripMismatch { node: 3, .. }) => {}
+            other => panic!("Unexpected result {:?}", other),
+        }
+    }
+}
@@ -1,28 +1,82 @@
-use super::Config;
+use super::{Config, GPUResult, MaskPrf, GPU_AMD_VENDOR_NAME, GPU_NVIDIA_VENDOR_NAME};
+use crate::utils::Device;
 use itertools::join;
 use paired::bls12_381::Fr;
 
-static SHA256_SRC: &str = include_str!("cl/hash/sha256.cl");
-static COMMON_SRC: &str = include_str!("cl/common.cl");
-static MASK_SRC: &str = include_str!("cl/mask.cl");
-static EXPANDER_SRC: &str = include_str!("cl/expander.cl");
-static BUTTERFLY_SRC: &str = include_str!("cl/butterfly.cl");
-static COMBINE_SRC: &str = include_str!("cl/combine.cl");
+// The kernels of every program, by file name relative to `src/cl`, in the order they follow the
+// defines, the vendor intrinsics and the field arithmetic.
+static KERNEL_FILES: &[(&str, &str)] = &[
+    ("hash/sha256.cl", include_str!("cl/hash/sha256.cl")),
+    ("common.cl", include_str!("cl/common.cl")),
+    ("aes.cl", include_str!("cl/aes.cl")),
+    ("mask.cl", include_str!("cl/mask.cl")),
+    ("expander.cl", include_str!("cl/expander.cl")),
+    ("butterfly.cl", include_str!("cl/butterfly.cl")),
+    ("combine.cl", include_str!("cl/combine.cl")),
+    ("gather.cl", include_str!("cl/gather.cl")),
+    ("commitment.cl", include_str!("cl/commitment.cl")),
+    ("small_windows.cl", include_str!("cl/small_windows.cl")),
+    ("poseidon.cl", include_str!("cl/poseidon.cl")),
+];
+
+/// Kernel variant, using the intrinsics of a GPU vendor for the hot paths (SHA-256 rotations).
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Hash)]
+pub enum KernelVariant {
+    Generic,
+    Amd,
+    Nvidia,
+}
+
+impl KernelVariant {
+    /// Returns the variant best suited for `device`, `Generic` for unknown vendors.
+    pub fn for_device(device: Device) -> GPUResult<KernelVariant> {
+        let vendor = device.vendor()?;
+        Ok(if vendor.contains(GPU_NVIDIA_VENDOR_NAME) {
+            KernelVariant::Nvidia
+        } else if vendor.contains(GPU_AMD_VENDOR_NAME) {
+            KernelVariant::Amd
+        } else {
+            KernelVariant::Generic
+        })
+    }
+
+    const ALL: [KernelVariant; 3] = [
+        KernelVariant::Generic,
+        KernelVariant::Amd,
+        KernelVariant::Nvidia,
+    ];
+
+    // File name relative to `src/cl` and source of the intrinsics of the variant.
+    fn source(&self) -> (&'static str, &'static str) {
+        match self {
+            KernelVariant::Generic => ("vendor/generic.cl", include_str!("cl/vendor/generic.cl")),
+            KernelVariant::Amd => ("vendor/amd.cl", include_str!("cl/vendor/amd.cl")),
+            KernelVariant::Nvidia => ("vendor/nvidia.cl", include_str!("cl/vendor/nvidia.cl")),
+        }
+    }
+}
 
 static SHA256_BITS: usize = 256;
 
-fn config(conf: Config) -> String {
+/// Kernels hash node indices as 32-bit integers.
+pub(crate) const MAX_NUM_NODES_WINDOW: u64 = 1 << 32;
+
+/// The defines of the program of `conf`. Mirrored by `build.rs` for the `validate-kernels`
+/// feature.
+pub(crate) fn config(conf: Config) -> String {
     assert!(conf.num_nodes_window > conf.k as usize);
+    assert!(conf.num_nodes_window as u64 <= MAX_NUM_NODES_WINDOW);
     assert!(conf.num_nodes_window.count_ones() == 1);
     assert!(conf.k.count_ones() == 1);
-    let bit_size = (conf.num_nodes_window as f64 / conf.k as f64).log2() as usize;
-    assert!(bit_size % 8 == 0);
+    // Number of bits of a non-expanded expander parent, at most 32.
+    let bit_size = (conf.num_nodes_window.trailing_zeros() - conf.k.trailing_zeros()) as usize;
+    assert!(bit_size > 0 && bit_size <= 32);
     assert!(conf.degree_butterfly.count_ones() == 1);
     let stream_hash_count =
         ((conf.degree_expander * bit_size) as f64 / SHA256_BITS as f64).ceil() as usize;
 
     format!(
-        "#define N ({})
+        "#define N ({}ul)
          #define K ({})
          #define LOG2_K ({})
          #define DEGREE_EXPANDER ({})
@@ -31,7 +85,11 @@ fn config(conf: Config) -> String {
          #define NUM_EXPANDER_LAYERS ({})
          #define NUM_BUTTERFLY_LAYERS ({})
          #define BIT_SIZE ({})
-         #define STREAM_HASH_COUNT ({})\n",
+         #define STREAM_HASH_COUNT ({})
+         #define DOMAIN_TAG_MASK ({}u)
+         #define DOMAIN_TAG_EXPANDER ({}u)
+         #define DOMAIN_TAG_BUTTERFLY ({}u)
+         #define MASK_PRF_AES ({})\n",
         conf.num_nodes_window,
         conf.k,
         (conf.k as f64).log2() as u32,
@@ -42,21 +100,77 @@ fn config(conf: Config) -> String {
         conf.num_butterfly_layers,
         bit_size,
         stream_hash_count,
+        conf.domain_tags.mask,
+        conf.domain_tags.expander,
+        conf.domain_tags.butterfly,
+        (conf.mask_prf == MaskPrf::Aes256Ctr) as u32,
     )
 }
 
-pub fn generate_nse_program(conf: Config) -> String {
-    join(
-        &[
-            config(conf),
-            ff_cl_gen::field::<Fr>("Fr"),
-            SHA256_SRC.to_string(),
-            COMMON_SRC.to_string(),
-            MASK_SRC.to_string(),
-            EXPANDER_SRC.to_string(),
-            BUTTERFLY_SRC.to_string(),
-            COMBINE_SRC.to_string(),
-        ],
-        "\n",
-    )
+/// The kernel sources the programs are built from, by file name relative to `src/cl`, the field
+/// arithmetic generated by `ff_cl_gen` as `field.cl`.
+pub(crate) fn kernel_sources() -> Vec<(&'static str, String)> {
+    let mut sources = vec![("field.cl", ff_cl_gen::field::<Fr>("Fr"))];
+    sources.extend(
+        KERNEL_FILES
+            .iter()
+            .map(|&(file, src)| (file, src.to_string())),
+    );
+    sources.extend(KernelVariant::ALL.iter().map(|variant| {
+        let (file, src) = variant.source();
+        (file, src.to_string())
+    }));
+    sources
+}
+
+#[cfg_attr(not(feature = "gpu"), allow(dead_code))]
+pub fn generate_nse_program(conf: Config, variant: KernelVariant) -> String {
+    let mut sources = vec![
+        config(conf),
+        variant.source().1.to_string(),
+        ff_cl_gen::field::<Fr>("Fr"),
+    ];
+    sources.extend(KERNEL_FILES.iter().map(|(_, src)| src.to_string()));
+    join(&sources, "\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TEST_CONFIG;
+
+    #[test]
+    fn test_kernel_variants() {
+        let generic = generate_nse_program(TEST_CONFIG, KernelVariant::Generic);
+        let amd = generate_nse_program(TEST_CONFIG, KernelVariant::Amd);
+        let nvidia = generate_nse_program(TEST_CONFIG, KernelVariant::Nvidia);
+        assert!(!generic.contains("amd_bitalign") && !generic.contains("asm("));
+        assert!(amd.contains("amd_bitalign"));
+        assert!(nvidia.contains("shf.l.wrap.b32"));
+        for program in [generic, amd, nvidia].iter() {
+            assert_eq!(1, program.matches("#define ROTL32").count());
+        }
+    }
+
+    #[test]
+    fn test_unaligned_parent_bits() {
+        let config = Config {
+            num_nodes_window: 1 << 10,
+            ..TEST_CONFIG
+        };
+        let program = generate_nse_program(config, KernelVariant::Generic);
+        assert!(program.contains("#define BIT_SIZE (9)"));
+    }
+
+    #[test]
+    fn test_mask_prf() {
+        let sha = generate_nse_program(TEST_CONFIG, KernelVariant::Generic);
+        assert!(sha.contains("#define MASK_PRF_AES (0)"));
+        let config = Config {
+            mask_prf: MaskPrf::Aes256Ctr,
+            ..TEST_CONFIG
+        };
+        let aes = generate_nse_program(config, KernelVariant::Generic);
+        assert!(aes.contains("#define MASK_PRF_AES (1)"));
+    }
 }
@@ -0,0 +1,353 @@
+use crate::{
+    Config, Layer, MaskPrf, NSEError, NSEResult, Node, ReplicaId, Sha256Domain, WindowIndex,
+};
+use ff::Field;
+use rand::seq::index;
+use rand::Rng;
+use sha2::{Digest, Sha256};
+
+/// Host-side verification of generated key layers, see `SealerBuilder::spot_check`: with
+/// probability `probability`, `nodes` random nodes of a layer are labeled again on the CPU from
+/// the previous layer, and sealing fails if any of them differs from the one of the device.
+/// Silent memory corruption of the GPU is then caught before the window is wasted, at the cost
+/// of keeping the previous layer on the host.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SpotCheckConfig {
+    pub probability: f64,
+    pub nodes: usize,
+}
+
+impl Default for SpotCheckConfig {
+    fn default() -> Self {
+        SpotCheckConfig {
+            probability: 1.0,
+            nodes: 16,
+        }
+    }
+}
+
+const SHA256_BITS: usize = 256;
+
+// First block hashed for every label, the same as `hash_prefix` of the kernels.
+fn hash_prefix(
+    config: &Config,
+    seed: ReplicaId,
+    window_index: WindowIndex,
+    layer_index: usize,
+    node: usize,
+) -> NSEResult<[u8; 64]> {
+    let tag = if layer_index == 1 {
+        config.domain_tags.mask
+    } else if layer_index <= config.num_expander_layers {
+        config.domain_tags.expander
+    } else {
+        config.domain_tags.butterfly
+    };
+    let absolute_index = window_index.node_index(config, node)?;
+    let mut prefix = [0u8; 64];
+    prefix[0..4].copy_from_slice(&(layer_index as u32).to_be_bytes());
+    prefix[4..12].copy_from_slice(&absolute_index.to_be_bytes());
+    prefix[12..16].copy_from_slice(&tag.to_be_bytes());
+    prefix[32..64].copy_from_slice(&seed.0);
+    Ok(prefix)
+}
+
+// Non-expanded expander parents of `node`, read from the bit-stream of the node like
+// `get_parent` of the kernels.
+fn expander_parents(config: &Config, node: usize) -> Vec<usize> {
+    let bit_size = (config.num_nodes_window.trailing_zeros() - config.k.trailing_zeros()) as usize;
+    let hash_count = (config.degree_expander * bit_size + SHA256_BITS - 1) / SHA256_BITS;
+    let mut stream = Vec::with_capacity(hash_count * 32);
+    for i in 0..hash_count {
+        let mut block = [0u8; 64];
+        block[0..4].copy_from_slice(&(node as u32).to_be_bytes());
+        block[4..8].copy_from_slice(&(i as u32).to_be_bytes());
+        stream.extend_from_slice(&Sha256::digest(&block));
+    }
+    (0..config.degree_expander)
+        .map(|i| {
+            (0..bit_size).fold(0, |parent, j| {
+                let bit = i * bit_size + j;
+                parent | (((stream[bit / 8] >> (bit % 8)) & 1) as usize) << j
+            })
+        })
+        .collect()
+}
+
+// Butterfly parent `i` of `node` of layer `layer_index`.
+fn butterfly_parent(config: &Config, layer_index: usize, node: usize, i: usize) -> usize {
+    let log2_degree = config.degree_butterfly.trailing_zeros() as usize;
+    let factor = 1 << (log2_degree * (config.num_layers() - layer_index));
+    (node + i * factor) & (config.num_nodes_window - 1)
+}
+
+/// Nodes of the layer before layer `layer_index` that the label of `node` depends on, i.e. the
+/// expanded expander parents or the butterfly parents of the node, none for the mask layer.
+pub fn label_parents(config: &Config, layer_index: usize, node: usize) -> Vec<usize> {
+    if layer_index == 1 {
+        Vec::new()
+    } else if layer_index <= config.num_expander_layers {
+        let k = config.k as usize;
+        expander_parents(config, node)
+            .into_iter()
+            .flat_map(|parent| parent * k..(parent + 1) * k)
+            .collect()
+    } else {
+        (0..config.degree_butterfly)
+            .map(|i| butterfly_parent(config, layer_index, node, i))
+            .collect()
+    }
+}
+
+/// Label of `node` of layer `layer_index` of a window, computed on the host from `previous`,
+/// the layer before (ignored for the mask layer). Both are in Montgomery form, like generated
+/// layers. Masks are computed with `MaskPrf::Sha256`, whatever the PRF of `config`. Fails if
+/// the absolute index of the node doesn't fit in 64 bits, see `WindowIndex::node_index`.
+pub fn label_node(
+    config: &Config,
+    seed: ReplicaId,
+    window_index: WindowIndex,
+    layer_index: usize,
+    previous: &Layer,
+    node: usize,
+) -> NSEResult<Node> {
+    label_node_with(config, seed, window_index, layer_index, node, |i| {
+        previous.0[i]
+    })
+}
+
+/// Same as `label_node`, reading the nodes of the previous layer with `previous`, which is
+/// only called with the `label_parents` of `node`.
+pub(crate) fn label_node_with<F: Fn(usize) -> Node>(
+    config: &Config,
+    seed: ReplicaId,
+    window_index: WindowIndex,
+    layer_index: usize,
+    node: usize,
+    previous: F,
+) -> NSEResult<Node> {
+    let mut hasher = Sha256::new();
+    hasher.input(&hash_prefix(config, seed, window_index, layer_index, node)?[..]);
+    let pairs: Vec<(Node, Node)> = if layer_index == 1 {
+        Vec::new()
+    } else if layer_index <= config.num_expander_layers {
+        let k = config.k as usize;
+        let parents = expander_parents(config, node);
+        let expanded = |i: usize| parents[i / k] * k + i % k;
+        let sum = |i: usize| {
+            let mut x = Node::default();
+            for j in 0..k {
+                x.0.add_assign(&previous(expanded(i + j * config.degree_expander)).0);
+            }
+            x
+        };
+        (0..config.degree_expander / 2)
+            .map(|i| (sum(2 * i), sum(2 * i + 1)))
+            .collect()
+    } else {
+        let parent = |i: usize| previous(butterfly_parent(config, layer_index, node, i));
+        (0..config.degree_butterfly / 2)
+            .map(|i| (parent(2 * i), parent(2 * i + 1)))
+            .collect()
+    };
+    for (a, b) in pairs {
+        hasher.input(&a.to_le_bytes());
+        hasher.input(&b.to_le_bytes());
+    }
+    Ok(Sha256Domain::from_slice(&hasher.result())
+        .expect("SHA-256 has 32 bytes")
+        .to_node())
+}
+
+/// Compares random nodes of `layer`, layer `layer_index` of a window, with their labels
+/// computed on the host, as configured by `spot_check`. `previous` is the layer before, which
+/// is required unless `layer_index` is 1.
+pub(crate) fn spot_check_layer(
+    spot_check: &SpotCheckConfig,
+    config: &Config,
+    seed: ReplicaId,
+    window_index: WindowIndex,
+    layer_index: usize,
+    previous: Option<&Layer>,
+    layer: &Layer,
+) -> NSEResult<()> {
+    if !(0.0..=1.0).contains(&spot_check.probability) {
+        return Err(NSEError::InvalidInput(format!(
+            "Spot check probability {} is not within [0, 1]!",
+            spot_check.probability
+        )));
+    }
+    let mut rng = rand::thread_rng();
+    // The AES mask has no host implementation.
+    let unsupported = layer_index == 1 && config.mask_prf != MaskPrf::Sha256;
+    if unsupported || !rng.gen_bool(spot_check.probability) {
+        return Ok(());
+    }
+    let empty = Layer::default();
+    let previous = match previous {
+        Some(previous) => previous,
+        None if layer_index == 1 => &empty,
+        None => {
+            return Err(NSEError::InvalidInput(format!(
+                "Layer {} cannot be spot checked without the previous layer!",
+                layer_index
+            )))
+        }
+    };
+    let count = std::cmp::min(spot_check.nodes, layer.0.len());
+    for node in index::sample(&mut rng, layer.0.len(), count).into_iter() {
+        let expected = label_node(config, seed, window_index, layer_index, previous, node)?;
+        if layer.0[node] != expected {
+            return Err(NSEError::SpotCheckFailed { layer_index, node });
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        DomainTags, EncodingMode, GPUContext, KeyGenerator, Sealer, SealerInput, TreeOptions, GPU,
+        SHALLOW_TEST_CONFIG,
+    };
+
+    const TEST_REPLICA_ID: ReplicaId = ReplicaId([5u8; 32]);
+
+    #[test]
+    fn test_label_node() {
+        // Unaligned parents and domain tags.
+        let config = Config {
+            k: 4,
+            num_nodes_window: 256,
+            degree_expander: 8,
+            degree_butterfly: 2,
+            num_expander_layers: 2,
+            num_butterfly_layers: 3,
+            encoding_mode: EncodingMode::Xor,
+            domain_tags: DomainTags {
+                mask: 1,
+                expander: 2,
+                butterfly: 3,
+            },
+            mask_prf: MaskPrf::Sha256,
+        };
+        // Windows beyond 2^32 check the window index reaches the kernels in 64 bits.
+        let cases = [
+            (SHALLOW_TEST_CONFIG, WindowIndex(3)),
+            (config, WindowIndex(3)),
+            (SHALLOW_TEST_CONFIG, WindowIndex((1 << 40) + 3)),
+        ];
+        for &(config, window_index) in cases.iter() {
+            let ctx = GPUContext::default(config, TreeOptions::Disabled).unwrap();
+            let mut gpu = GPU::new(ctx, config).unwrap();
+            let layers = KeyGenerator::new(config, TEST_REPLICA_ID, window_index, &mut gpu)
+                .unwrap()
+                .collect::<NSEResult<Vec<_>>>()
+                .unwrap();
+            let empty = Layer::default();
+            for (i, layer) in layers.iter().enumerate() {
+                let previous = if i == 0 { &empty } else { &layers[i - 1] };
+                for &node in [0, 1, 100, config.num_nodes_window - 1].iter() {
+                    assert_eq!(
+                        layer.0[node],
+                        label_node(
+                            &config,
+                            TEST_REPLICA_ID,
+                            window_index,
+                            i + 1,
+                            previous,
+                            node
+                        )
+                        .unwrap()
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_spot_check_layer() {
+        let spot_check = SpotCheckConfig {
+            probability: 1.0,
+            nodes: SHALLOW_TEST_CONFIG.num_nodes_window,
+        };
+        let ctx = GPUContext::default(SHALLOW_TEST_CONFIG, TreeOptions::Disabled).unwrap();
+        let mut gpu = GPU::new(ctx, SHALLOW_TEST_CONFIG).unwrap();
+        let layers = KeyGenerator::new(
+            SHALLOW_TEST_CONFIG,
+            TEST_REPLICA_ID,
+            WindowIndex(0),
+            &mut gpu,
+        )
+        .unwrap()
+        .take(2)
+        .collect::<NSEResult<Vec<_>>>()
+        .unwrap();
+        let check = |previous, layer| {
+            spot_check_layer(
+                &spot_check,
+                &SHALLOW_TEST_CONFIG,
+                TEST_REPLICA_ID,
+                WindowIndex(0),
+                2,
+                previous,
+                layer,
+            )
+        };
+        assert!(check(Some(&layers[0]), &layers[1]).is_ok());
+        assert!(check(None, &layers[1]).is_err());
+
+        let mut corrupted = layers[1].clone();
+        corrupted.0[42] = Node::random(&mut rand::thread_rng());
+        match check(Some(&layers[0]), &corrupted) {
+            Err(NSEError::SpotCheckFailed { layer_index, node }) => {
+                assert_eq!((2, 42), (layer_index, node))
+            }
+            other => panic!("Unexpected result {:?}", other),
+        }
+        // Never checked.
+        let never = SpotCheckConfig {
+            probability: 0.0,
+            ..spot_check
+        };
+        assert!(spot_check_layer(
+            &never,
+            &SHALLOW_TEST_CONFIG,
+            TEST_REPLICA_ID,
+            WindowIndex(0),
+            2,
+            Some(&layers[0]),
+            &corrupted
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn test_seal_with_spot_check() {
+        let ctx = GPUContext::default(SHALLOW_TEST_CONFIG, TreeOptions::Disabled).unwrap();
+        let mut gpu = GPU::new(ctx, SHALLOW_TEST_CONFIG).unwrap();
+        let input = SealerInput {
+            replica_id: TEST_REPLICA_ID,
+            window_index: WindowIndex(1),
+            original_data: Layer::random(
+                &mut rand::thread_rng(),
+                SHALLOW_TEST_CONFIG.num_nodes_window,
+            ),
+        };
+        let expected = Sealer::new(SHALLOW_TEST_CONFIG, input.clone(), &mut gpu, false)
+            .unwrap()
+            .seal()
+            .unwrap();
+        let checked = Sealer::builder(SHALLOW_TEST_CONFIG, input)
+            .spot_check(SpotCheckConfig::default())
+            .build(&mut gpu)
+            .unwrap()
+            .seal()
+            .unwrap();
+        assert_eq!(
+            expected.layers.last().unwrap().base,
+            checked.layers.last().unwrap().base
+        );
+    }
+}
@@ -1,6 +1,27 @@
 use crate::{GPUError, GPUResult};
-use ocl::{Device, Platform};
+#[cfg(feature = "gpu")]
+pub use ocl::Device;
+#[cfg(feature = "gpu")]
+use ocl::Platform;
 
+/// Stands in for `ocl::Device` in builds without the `gpu` feature. It has no values, so any code
+/// receiving a device is unreachable.
+#[cfg(not(feature = "gpu"))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Device {}
+
+#[cfg(not(feature = "gpu"))]
+impl Device {
+    pub fn name(&self) -> GPUResult<String> {
+        match *self {}
+    }
+
+    pub fn vendor(&self) -> GPUResult<String> {
+        match *self {}
+    }
+}
+
+#[cfg(feature = "gpu")]
 pub fn get_bus_id(d: Device) -> GPUResult<u32> {
     const CL_DEVICE_PCI_BUS_ID_NV: u32 = 0x4008;
     let result = d.info_raw(CL_DEVICE_PCI_BUS_ID_NV)?;
@@ -10,8 +31,14 @@ pub fn get_bus_id(d: Device) -> GPUResult<u32> {
         + ((result[3] as u32) << 24))
 }
 
+#[cfg(not(feature = "gpu"))]
+pub fn get_bus_id(d: Device) -> GPUResult<u32> {
+    match d {}
+}
+
 pub const GPU_NVIDIA_PLATFORM_NAME: &str = "NVIDIA CUDA";
 
+#[cfg(feature = "gpu")]
 pub fn get_devices(platform_name: &str) -> GPUResult<Vec<Device>> {
     let platform = Platform::list()?.into_iter().find(|&p| match p.name() {
         Ok(p) => p == platform_name,
@@ -23,10 +50,20 @@ pub fn get_devices(platform_name: &str) -> GPUResult<Vec<Device>> {
     }
 }
 
+#[cfg(not(feature = "gpu"))]
+pub fn get_devices(_platform_name: &str) -> GPUResult<Vec<Device>> {
+    Err(GPUError::Other(
+        "GPU platform not found, built without the `gpu` feature!".into(),
+    ))
+}
+
 pub fn all_devices() -> GPUResult<Vec<Device>> {
     get_devices(GPU_NVIDIA_PLATFORM_NAME)
 }
 
 pub fn default_device() -> GPUResult<Device> {
-    Ok(all_devices()?[0])
+    all_devices()?
+        .into_iter()
+        .next()
+        .ok_or_else(|| GPUError::Other("No GPU device found!".into()))
 }